@@ -0,0 +1,304 @@
+//! Общий интерфейс файловых систем БК (MK-DOS, ANDOS, RT-11, ...)
+//!
+//! Каждый формат реализует `BkFileSystem` поверх своего API, а fuse
+//! драйвер (и все, кому нужен "просто том с файлами") работает только
+//! с трейтом. Иноды задает сам формат, корень всегда `ROOT_INODE`.
+//! Запись необязательна: по умолчанию том только для чтения.
+
+use std::{fmt, path::Path, time::SystemTime};
+
+use thiserror::Error;
+
+/// Inode of the root directory
+pub const ROOT_INODE: u64 = 1;
+
+/// What went wrong, independent of the filesystem format
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    #[error("entry not found")]
+    NotFound,
+    #[error("volume is read only")]
+    ReadOnly,
+    #[error("entry already exists")]
+    Exists,
+    #[error("name is too long")]
+    NameTooLong,
+    #[error("bad name")]
+    BadName,
+    #[error("no space left")]
+    NoSpace,
+    #[error("operation not permitted")]
+    Protected,
+    #[error("entry is a directory")]
+    IsDirectory,
+    #[error("entry is not a directory")]
+    NotDirectory,
+    #[error("operation is not supported")]
+    Unsupported,
+    #[error("volume is unavailable")]
+    Unavailable,
+    #[error("i/o error")]
+    Io,
+}
+
+/// Error of `BkFileSystem` operation: kind and (optional) error of the format
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl Error {
+    pub fn new<E>(kind: ErrorKind, source: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Self {
+            kind,
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self { kind, source: None }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source.as_ref() {
+            Some(source) => source.fmt(f),
+            None => self.kind.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|e| e.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+}
+
+/// File or directory of a volume (getattr-like metadata and name)
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub inode: u64,
+    pub parent_inode: u64,
+    pub name: &'a str,
+    pub kind: FileKind,
+    pub size: u64,
+    /// blocks occupied on disk
+    pub blocks: u64,
+    /// unix mode
+    pub mode: u16,
+    /// `None` - use volume modification time
+    pub mtime: Option<SystemTime>,
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Directory
+    }
+}
+
+/// Size of BK `.bin` header in bytes
+pub const BIN_HEADER_SIZE: u64 = 4;
+
+/// Header of BK `.bin` file: load address and length (little endian words)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BinHeader {
+    pub start_address: u16,
+    pub length: u16,
+}
+
+impl BinHeader {
+    /// Header at start of `data`, `None` if its length does not match
+    /// the rest of `data` (there is no header then)
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < BIN_HEADER_SIZE as usize {
+            return None;
+        }
+        let header = Self {
+            start_address: u16::from_le_bytes([data[0], data[1]]),
+            length: u16::from_le_bytes([data[2], data[3]]),
+        };
+        (header.length as usize == data.len() - BIN_HEADER_SIZE as usize).then_some(header)
+    }
+
+    pub fn to_bytes(self) -> [u8; BIN_HEADER_SIZE as usize] {
+        let [a0, a1] = self.start_address.to_le_bytes();
+        let [l0, l1] = self.length.to_le_bytes();
+        [a0, a1, l0, l1]
+    }
+}
+
+/// Volume totals for statfs, in blocks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VolumeStats {
+    pub blocks: u64,
+    pub free: u64,
+    /// file slots (inodes), used ones if the format has no limit
+    pub files: u64,
+    pub free_files: u64,
+    /// max file name length
+    pub namelen: u32,
+}
+
+/// Volume of any BK disk format
+///
+/// Root directory (`ROOT_INODE`) is not an entry, `metadata(ROOT_INODE)`
+/// may return `None`. Write methods are optional and fail with
+/// `ErrorKind::ReadOnly` by default.
+pub trait BkFileSystem {
+    /// Entry `name` in directory `parent_inode`
+    fn lookup(&self, parent_inode: u64, name: &str) -> Option<Entry<'_>>;
+
+    /// Entry with inode `inode`
+    fn metadata(&self, inode: u64) -> Option<Entry<'_>>;
+
+    /// Entries of directory `inode`
+    fn list(&self, inode: u64) -> Result<Vec<Entry<'_>>>;
+
+    /// Read up to `size` bytes of file `inode` from `offset`,
+    /// result is short (or empty) at end of file
+    fn read(&self, inode: u64, offset: u64, size: usize) -> Result<Vec<u8>>;
+
+    /// Like `read()`, but into `buf` without allocation, returns number
+    /// of bytes read (less than `buf.len()` at end of file)
+    fn read_into(&self, inode: u64, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let data = self.read(inode, offset, buf.len())?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Size of the whole volume in bytes, `None` if the format
+    /// has no raw access (see `read_raw()`)
+    fn raw_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Read up to `size` raw bytes of the volume from `offset` (as they
+    /// are on disk, inversion undone), result is short at end of volume
+    fn read_raw(&self, _offset: u64, _size: usize) -> Result<Vec<u8>> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Save copy of the whole image to `dest`, returns its size in bytes.
+    /// Nothing can be written while `&self` is borrowed, so the copy
+    /// is consistent
+    fn snapshot(&self, _dest: &Path) -> Result<u64> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    fn stats(&self) -> VolumeStats;
+
+    /// Modification time of the volume (used for entries without own time)
+    fn last_modified(&self) -> SystemTime;
+
+    fn block_size(&self) -> u32 {
+        512
+    }
+
+    /// Extended attributes of entry `inode` as (name, value),
+    /// format specific metadata which has no place in `Entry`
+    fn xattrs(&self, _inode: u64) -> Vec<(String, Vec<u8>)> {
+        Vec::new()
+    }
+
+    /// `.bin` header of file `inode`, `None` if the format has no load addresses
+    fn bin_header(&self, _inode: u64) -> Option<BinHeader> {
+        None
+    }
+
+    /// Changed by somebody else, `refresh()` should be called
+    fn is_modified(&self) -> bool {
+        false
+    }
+
+    /// Reread volume if it was changed
+    fn refresh(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Volume can't be read now (last `refresh()` failed)
+    fn is_degraded(&self) -> bool {
+        false
+    }
+
+    /// Incremented on every reread that may give inodes to other files,
+    /// inodes of older generation are stale
+    fn generation(&self) -> u64 {
+        0
+    }
+
+    /// Generation of the part of volume holding `inode`, handles compare
+    /// with it: where parts are reread separately, a reread of one doesn't
+    /// make inodes of others stale
+    fn inode_generation(&self, _inode: u64) -> u64 {
+        self.generation()
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    /// Create empty file, returns its inode
+    fn create(&mut self, _parent_inode: u64, _name: &str) -> Result<u64> {
+        Err(ErrorKind::ReadOnly.into())
+    }
+
+    /// Returns number of bytes written
+    fn write(&mut self, _inode: u64, _offset: u64, _data: &[u8]) -> Result<usize> {
+        Err(ErrorKind::ReadOnly.into())
+    }
+
+    fn truncate(&mut self, _inode: u64, _size: u64) -> Result<()> {
+        Err(ErrorKind::ReadOnly.into())
+    }
+
+    fn unlink(&mut self, _parent_inode: u64, _name: &str) -> Result<()> {
+        Err(ErrorKind::ReadOnly.into())
+    }
+
+    /// Rename inside directory `parent_inode`, existing `new_name`
+    /// is replaced if `replace`
+    fn rename(
+        &mut self,
+        _parent_inode: u64,
+        _name: &str,
+        _new_name: &str,
+        _replace: bool,
+    ) -> Result<()> {
+        Err(ErrorKind::ReadOnly.into())
+    }
+
+    /// Move `.bin` header of file `inode` (if it has one) to format metadata,
+    /// returns the header. Formats without load addresses leave file as is
+    fn strip_bin_header(&mut self, _inode: u64) -> Result<Option<BinHeader>> {
+        Ok(None)
+    }
+
+    /// Write cached changes to disk
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Volume is unmounted
+    fn destroy(&mut self) {}
+}
@@ -0,0 +1,116 @@
+//! Загрузчики: блок 0 винчестера и поиск двоичных файлов загрузчиков
+//!
+//! У АльтПро загрузчик занимает блоки до таблицы разделов (0..7) и, как
+//! весь диск, хранится инвертированным. У Самары под загрузчик только блок
+//! 0, таблица уже в блоке 1. Загрузочный раздел выбирается в самой таблице
+//! (`PartitionTable::set_boot_drive()`).
+//!
+//! Двоичные файлы загрузчиков с утилитами не поставляются: они берутся по
+//! имени из каталога `$BKTOOLS_BOOT_DIR` (`NAME.bin`) или по пути к файлу,
+//! например снятые с заведомо загружающегося диска.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{Controller, HDIError, AHDD_PT_SEC, BLOCK_SIZE, SHDD_PT_SEC};
+
+/// Environment variable with directory of bootloader files (`NAME.bin`)
+pub const BOOT_DIR_ENV: &str = "BKTOOLS_BOOT_DIR";
+const BOOT_EXTENSION: &str = "bin";
+
+/// Bytes before partition table available for boot code
+pub fn boot_area_size(controller: Controller) -> usize {
+    match controller {
+        Controller::AltPro => AHDD_PT_SEC * BLOCK_SIZE,
+        Controller::Samara => SHDD_PT_SEC * BLOCK_SIZE,
+    }
+}
+
+/// Boot area of disk (see `boot_area_size()`), AltPro data is de-inverted.
+/// `offset` is the start of disk data (`BLOCK_SIZE` for HDI)
+pub fn read_boot_area(
+    fname: &str,
+    controller: Controller,
+    offset: u64,
+) -> Result<Vec<u8>, HDIError> {
+    let mut fh = fs::File::open(fname)?;
+    fh.seek(SeekFrom::Start(offset))?;
+    let mut area = vec![0u8; boot_area_size(controller)];
+    fh.read_exact(&mut area)?;
+    if controller == Controller::AltPro {
+        area.iter_mut().for_each(|b| *b = !*b);
+    }
+    Ok(area)
+}
+
+/// Write `code` from block 0, the rest of boot area is zeroed.
+/// AltPro data is inverted on write
+pub fn write_boot_code(
+    fname: &str,
+    controller: Controller,
+    offset: u64,
+    code: &[u8],
+) -> Result<(), HDIError> {
+    let size = boot_area_size(controller);
+    if code.len() > size {
+        return Err(HDIError::BootCodeSize(code.len(), size));
+    }
+    let mut area = vec![0u8; size];
+    area[..code.len()].copy_from_slice(code);
+    if controller == Controller::AltPro {
+        area.iter_mut().for_each(|b| *b = !*b);
+    }
+    let mut fh = OpenOptions::new().write(true).open(fname)?;
+    fh.seek(SeekFrom::Start(offset))?;
+    fh.write_all(&area)?;
+    fh.sync_data()?;
+    Ok(())
+}
+
+fn boot_dir() -> Option<PathBuf> {
+    std::env::var_os(BOOT_DIR_ENV).map(PathBuf::from)
+}
+
+/// Names of bootloaders found in `$BKTOOLS_BOOT_DIR`
+pub fn bootloaders() -> Vec<String> {
+    let Some(dir) = boot_dir() else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == BOOT_EXTENSION))
+        .filter_map(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Bootloader code by file path or by name in `$BKTOOLS_BOOT_DIR`
+pub fn find_bootloader(name: &str) -> io::Result<Vec<u8>> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return fs::read(path);
+    }
+    if let Some(dir) = boot_dir() {
+        let path = dir.join(format!("{}.{}", name, BOOT_EXTENSION));
+        if path.is_file() {
+            return fs::read(path);
+        }
+    }
+    let known = bootloaders();
+    let hint = if known.is_empty() {
+        format!("set {} to directory with NAME.bin files", BOOT_DIR_ENV)
+    } else {
+        format!("known: {}", known.join(", "))
+    };
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("bootloader {:?} not found ({})", name, hint),
+    ))
+}
@@ -0,0 +1,300 @@
+//! Проверка таблицы разделов (`bkhdd check`)
+//!
+//! При открытии таблица проверяется только в ParseMode::Strict и до первой
+//! ошибки. `check_image()` читает ее как можно мягче (АльтПро даже с
+//! неверной контрольной суммой) и собирает в отчет все несоответствия:
+//! контрольную сумму, геометрию, C/H/S разделов, выход за диск и за образ,
+//! пересечения разделов между собой и с самой таблицей.
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::{
+    detect_partition_table, Controller, Geometry, HDIError, ParseMode, PartitionTable, AHDD,
+    AHDD_PT_SEC, BLOCK_SIZE, HDI, SHDD_PT_SEC,
+};
+
+/// Heads of AltPro table: 4 bits of head in partition entry
+const AHDD_MAX_HEADS: u16 = 16;
+
+/// Problem found by `check_image()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "issue", rename_all = "snake_case"))]
+pub enum CheckIssue {
+    /// neither AltPro nor Samara table found
+    NoTable,
+    /// stored AltPro checksum differs from computed one
+    BadChecksum {
+        stored: u16,
+        computed: u16,
+    },
+    /// zero or impossible C/H/S in table
+    BadGeometry {
+        cylinders: u16,
+        heads: u16,
+        sectors: u16,
+    },
+    /// C/H/S in HDI header differs from table
+    GeometryMismatch {
+        hdi: Geometry,
+        table: Geometry,
+    },
+    /// start or end of partition is not a valid C/H/S of the disk
+    BadChs {
+        partition: usize,
+        cylinder: u32,
+        head: u16,
+        sector: u16,
+    },
+    EmptyPartition {
+        partition: usize,
+    },
+    /// partition ends past C * H * S blocks
+    BeyondDisk {
+        partition: usize,
+        end: u32,
+        disk: u32,
+    },
+    /// partition ends past end of image file
+    BeyondImage {
+        partition: usize,
+        end: u32,
+        image: u64,
+    },
+    /// partition covers block of partition table
+    OverlapsTable {
+        partition: usize,
+        block: u32,
+    },
+    Overlap {
+        first: usize,
+        second: usize,
+    },
+}
+
+impl fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoTable => write!(f, "No AltPro or Samara partition table found"),
+            Self::BadChecksum { stored, computed } => write!(
+                f,
+                "Table checksum is {:06o} but {:06o} computed",
+                stored, computed
+            ),
+            Self::BadGeometry {
+                cylinders,
+                heads,
+                sectors,
+            } => write!(
+                f,
+                "Bad geometry C/H/S {}/{}/{} in table",
+                cylinders, heads, sectors
+            ),
+            Self::GeometryMismatch { hdi, table } => {
+                write!(f, "HDI header C/H/S {} differs from table {}", hdi, table)
+            }
+            Self::BadChs {
+                partition,
+                cylinder,
+                head,
+                sector,
+            } => write!(
+                f,
+                "Partition {} has C/H/S {}/{}/{} outside of disk geometry",
+                partition, cylinder, head, sector
+            ),
+            Self::EmptyPartition { partition } => write!(f, "Partition {} is empty", partition),
+            Self::BeyondDisk {
+                partition,
+                end,
+                disk,
+            } => write!(
+                f,
+                "Partition {} ends at block {} beyond disk size {}",
+                partition, end, disk
+            ),
+            Self::BeyondImage {
+                partition,
+                end,
+                image,
+            } => write!(
+                f,
+                "Partition {} ends at block {} beyond image of {} blocks",
+                partition, end, image
+            ),
+            Self::OverlapsTable { partition, block } => write!(
+                f,
+                "Partition {} covers partition table block {}",
+                partition, block
+            ),
+            Self::Overlap { first, second } => {
+                write!(f, "Partitions {} and {} overlap", first, second)
+            }
+        }
+    }
+}
+
+/// Result of `check_image()`
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CheckReport {
+    pub controller: Option<Controller>,
+    /// C/H/S from partition table
+    pub geometry: Option<Geometry>,
+    pub partitions: usize,
+    pub issues: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Таблица АльтПро с неверной контрольной суммой: читаем без проверки
+fn unchecked_ahdd(fname: &str, offset: u64) -> Option<AHDD> {
+    let mut ahdd = AHDD::new(fname);
+    ahdd.set_offset(offset);
+    ahdd.open_unchecked().ok()?;
+    Some(ahdd)
+}
+
+/// Check partition table of HDD image (raw or HDI) and collect all problems
+pub fn check_image(fname: &str) -> Result<CheckReport, HDIError> {
+    let is_hdi = HDI::probe(fname)?;
+    let data_offset = if is_hdi { BLOCK_SIZE as u64 } else { 0 };
+    let image_blocks =
+        std::fs::metadata(fname)?.len().saturating_sub(data_offset) / BLOCK_SIZE as u64;
+
+    let mut report = CheckReport::default();
+    let table: Box<dyn PartitionTable> =
+        match detect_partition_table(fname, data_offset, ParseMode::Permissive, true)? {
+            Some(table) => table,
+            None => match unchecked_ahdd(fname, data_offset) {
+                Some(ahdd) => Box::new(ahdd),
+                None => {
+                    report.issues.push(CheckIssue::NoTable);
+                    return Ok(report);
+                }
+            },
+        };
+    let controller = table.controller();
+    let geometry = table.geometry();
+    let Geometry {
+        cylinders,
+        heads,
+        sectors,
+    } = geometry;
+    report.controller = Some(controller);
+    report.geometry = Some(geometry);
+    report.partitions = table.partitions().len();
+    let issues = &mut report.issues;
+
+    if let Some((stored, computed)) = table.checksum_mismatch() {
+        issues.push(CheckIssue::BadChecksum { stored, computed });
+    }
+    // C * H * S за пределами LBA - тоже мусор
+    if !geometry.is_valid()
+        || geometry.checked_blocks().is_none()
+        || (controller == Controller::AltPro && heads > AHDD_MAX_HEADS)
+    {
+        issues.push(CheckIssue::BadGeometry {
+            cylinders,
+            heads,
+            sectors,
+        });
+    }
+    if is_hdi {
+        let mut hdi = HDI::new(fname);
+        // заголовок HDI читается раньше таблицы, ее ошибки тут не важны
+        let _ = hdi.try_open();
+        let info = hdi.info();
+        let hdi = Geometry::new(info.cylinders, info.heads, info.sectors);
+        // у Самары цилиндры посчитаны по размеру образа
+        let same = match controller {
+            Controller::AltPro => hdi == geometry,
+            Controller::Samara => (hdi.heads, hdi.sectors) == (heads, sectors),
+        };
+        if !same {
+            issues.push(CheckIssue::GeometryMismatch {
+                hdi,
+                table: geometry,
+            });
+        }
+    }
+
+    let disk = geometry.blocks();
+    let table_block = match controller {
+        Controller::AltPro => AHDD_PT_SEC,
+        Controller::Samara => SHDD_PT_SEC,
+    } as u32;
+    let parts = table.partitions();
+    for (n, part) in parts.iter().enumerate() {
+        if part.length == 0 {
+            issues.push(CheckIssue::EmptyPartition { partition: n });
+            continue;
+        }
+        // конец раздела - первый блок после него, C/H/S берем у последнего;
+        // цилиндр в u32, чтобы конец за 65535 цилиндрами не завернулся внутрь диска
+        for (cylinder, head, sector) in [
+            (
+                part.start_cylinder as u32,
+                part.start_head,
+                part.start_sector,
+            ),
+            geometry.lba_to_wide_chs(part.end_block - 1),
+        ] {
+            let inside =
+                u16::try_from(cylinder).is_ok_and(|c| geometry.contains((c, head, sector)));
+            if !inside {
+                issues.push(CheckIssue::BadChs {
+                    partition: n,
+                    cylinder,
+                    head,
+                    sector,
+                });
+                break;
+            }
+        }
+        if part.end_block > disk {
+            issues.push(CheckIssue::BeyondDisk {
+                partition: n,
+                end: part.end_block,
+                disk,
+            });
+        }
+        if part.end_block as u64 > image_blocks {
+            issues.push(CheckIssue::BeyondImage {
+                partition: n,
+                end: part.end_block,
+                image: image_blocks,
+            });
+        }
+        if (part.lba..part.end_block).contains(&table_block) {
+            issues.push(CheckIssue::OverlapsTable {
+                partition: n,
+                block: table_block,
+            });
+        }
+    }
+
+    let mut order: Vec<usize> = (0..parts.len()).filter(|&n| parts[n].length != 0).collect();
+    order.sort_by_key(|&n| parts[n].lba);
+    // каждый раздел сравниваем со всеми, что начинаются раньше его конца
+    for (i, &a) in order.iter().enumerate() {
+        for &b in order[i + 1..].iter() {
+            if parts[b].lba >= parts[a].end_block {
+                break;
+            }
+            issues.push(CheckIssue::Overlap {
+                first: a.min(b),
+                second: a.max(b),
+            });
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,276 @@
+//! Геометрия диска C/H/S и пересчет C/H/S <-> LBA
+//!
+//! Блоки диска идут цилиндр за цилиндром, внутри цилиндра головка за
+//! головкой, внутри дорожки секторы с 1. Разделы АльтПро начинаются с
+//! начала дорожки (в записи только цилиндр и головка), разделы Самары - с
+//! начала цилиндра.
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Disk geometry: cylinders, heads and sectors per track
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Geometry {
+    pub cylinders: u16,
+    pub heads: u16,
+    pub sectors: u16,
+}
+
+impl Geometry {
+    pub fn new(cylinders: u16, heads: u16, sectors: u16) -> Self {
+        Self {
+            cylinders,
+            heads,
+            sectors,
+        }
+    }
+
+    /// No zero values
+    pub fn is_valid(&self) -> bool {
+        self.cylinders != 0 && self.heads != 0 && self.sectors != 0
+    }
+
+    /// Disk size in blocks (C * H * S), `u32::MAX` if it doesn't fit
+    /// (see `checked_blocks()`)
+    pub fn blocks(&self) -> u32 {
+        self.checked_blocks().unwrap_or(u32::MAX)
+    }
+
+    /// Disk size in blocks, `None` if C * H * S doesn't fit in LBA
+    /// (garbage in partition table)
+    pub fn checked_blocks(&self) -> Option<u32> {
+        (self.cylinders as u32).checked_mul(self.cylinder_blocks())
+    }
+
+    /// Blocks per cylinder (H * S)
+    pub fn cylinder_blocks(&self) -> u32 {
+        self.heads as u32 * self.sectors as u32
+    }
+
+    // нулевая геометрия бывает в битых таблицах, делить на 0 нельзя
+    fn heads_or_one(&self) -> u32 {
+        (self.heads as u32).max(1)
+    }
+
+    fn sectors_or_one(&self) -> u32 {
+        (self.sectors as u32).max(1)
+    }
+
+    /// C/H/S of block `lba` (sectors from 1), cylinder stops at `u16::MAX`
+    /// (never inside the disk, see `lba_to_wide_chs()`)
+    pub fn lba_to_chs(&self, lba: u32) -> (u16, u16, u16) {
+        let (cylinder, head, sector) = self.lba_to_wide_chs(lba);
+        (cylinder.min(u16::MAX as u32) as u16, head, sector)
+    }
+
+    /// Like `lba_to_chs()`, but cylinder is as is
+    pub fn lba_to_wide_chs(&self, lba: u32) -> (u32, u16, u16) {
+        let (heads, sectors) = (self.heads_or_one(), self.sectors_or_one());
+        (
+            lba / (heads * sectors),
+            (lba / sectors % heads) as u16,
+            (lba % sectors + 1) as u16,
+        )
+    }
+
+    /// Block of C/H/S, `None` if head or sector is not on the disk.
+    /// Cylinder is not checked: tables point beyond the disk sometimes
+    pub fn chs_to_lba(&self, cylinder: u16, head: u16, sector: u16) -> Option<u32> {
+        if head >= self.heads || sector == 0 || sector > self.sectors {
+            return None;
+        }
+        self.checked_track_start(cylinder, head)?
+            .checked_add(sector as u32 - 1)
+    }
+
+    /// First block of track `cylinder`/`head` (head is not checked),
+    /// `u32::MAX` if it doesn't fit (see `checked_track_start()`)
+    pub fn track_start(&self, cylinder: u16, head: u16) -> u32 {
+        self.checked_track_start(cylinder, head).unwrap_or(u32::MAX)
+    }
+
+    /// First block of track `cylinder`/`head`, `None` if it doesn't fit in LBA
+    pub fn checked_track_start(&self, cylinder: u16, head: u16) -> Option<u32> {
+        let track = cylinder as u64 * self.heads as u64 + head as u64;
+        u32::try_from(track * self.sectors as u64).ok()
+    }
+
+    /// C/H/S is inside the disk
+    pub fn contains(&self, (cylinder, head, sector): (u16, u16, u16)) -> bool {
+        cylinder < self.cylinders && head < self.heads && sector != 0 && sector <= self.sectors
+    }
+
+    pub fn is_track_aligned(&self, lba: u32) -> bool {
+        lba.is_multiple_of(self.sectors_or_one())
+    }
+
+    /// Start of track holding `lba`
+    pub fn align_down_to_track(&self, lba: u32) -> u32 {
+        lba - lba % self.sectors_or_one()
+    }
+
+    /// `lba` or start of next track
+    pub fn align_up_to_track(&self, lba: u32) -> u32 {
+        self.align_down_to_track(lba + self.sectors_or_one() - 1)
+    }
+
+    pub fn is_cylinder_aligned(&self, lba: u32) -> bool {
+        lba.is_multiple_of(self.heads_or_one() * self.sectors_or_one())
+    }
+
+    /// `lba` or start of next cylinder
+    pub fn align_up_to_cylinder(&self, lba: u32) -> u32 {
+        let cylinder = self.heads_or_one() * self.sectors_or_one();
+        lba.div_ceil(cylinder) * cylinder
+    }
+}
+
+impl From<(u16, u16, u16)> for Geometry {
+    fn from((cylinders, heads, sectors): (u16, u16, u16)) -> Self {
+        Self::new(cylinders, heads, sectors)
+    }
+}
+
+impl From<Geometry> for (u16, u16, u16) {
+    fn from(g: Geometry) -> Self {
+        (g.cylinders, g.heads, g.sectors)
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.cylinders, self.heads, self.sectors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seagate ST-225 под АльтПро: разделы с начала дорожки
+    const ALTPRO: Geometry = Geometry {
+        cylinders: 615,
+        heads: 4,
+        sectors: 17,
+    };
+    // CF карта под Самарой: цилиндр 16 * 63 блоков
+    const SAMARA: Geometry = Geometry {
+        cylinders: 100,
+        heads: 16,
+        sectors: 63,
+    };
+
+    #[test]
+    fn round_trip() {
+        for g in [ALTPRO, SAMARA] {
+            for lba in 0..g.blocks() {
+                let (c, h, s) = g.lba_to_chs(lba);
+                assert!(g.contains((c, h, s)), "{g} {lba}");
+                assert_eq!(g.chs_to_lba(c, h, s), Some(lba), "{g} {lba}");
+            }
+        }
+    }
+
+    #[test]
+    fn altpro_partition() {
+        // раздел с цилиндра 1 головки 2 на 1000 блоков
+        let lba = ALTPRO.track_start(1, 2);
+        assert_eq!(lba, 102);
+        assert_eq!(ALTPRO.lba_to_chs(lba), (1, 2, 1));
+        assert!(ALTPRO.is_track_aligned(lba));
+        assert!(!ALTPRO.is_cylinder_aligned(lba));
+        assert_eq!(ALTPRO.lba_to_chs(lba + 1000 - 1), (16, 0, 14));
+        assert_eq!(ALTPRO.lba_to_chs(0), (0, 0, 1));
+        assert_eq!(ALTPRO.lba_to_chs(ALTPRO.blocks() - 1), (614, 3, 17));
+        assert_eq!(ALTPRO.blocks(), 41820);
+    }
+
+    #[test]
+    fn samara_partition() {
+        // раздел с цилиндра 2
+        let lba = 2 * SAMARA.cylinder_blocks();
+        assert_eq!(lba, 2016);
+        assert_eq!(SAMARA.lba_to_chs(lba), (2, 0, 1));
+        assert!(SAMARA.is_cylinder_aligned(lba));
+        assert_eq!(SAMARA.lba_to_chs(lba - 1), (1, 15, 63));
+        assert_eq!(SAMARA.chs_to_lba(2, 0, 1), Some(lba));
+    }
+
+    #[test]
+    fn chs_out_of_disk() {
+        assert_eq!(ALTPRO.chs_to_lba(0, 4, 1), None);
+        assert_eq!(ALTPRO.chs_to_lba(0, 0, 0), None);
+        assert_eq!(ALTPRO.chs_to_lba(0, 0, 18), None);
+        // цилиндр за концом диска не проверяется
+        assert_eq!(ALTPRO.chs_to_lba(615, 0, 1), Some(41820));
+        assert!(!ALTPRO.contains((615, 0, 1)));
+        assert!(!ALTPRO.contains((0, 0, 0)));
+    }
+
+    #[test]
+    fn align() {
+        assert_eq!(ALTPRO.align_down_to_track(18), 17);
+        assert_eq!(ALTPRO.align_up_to_track(18), 34);
+        assert_eq!(ALTPRO.align_up_to_track(34), 34);
+        assert_eq!(ALTPRO.align_up_to_cylinder(69), 136);
+        assert_eq!(ALTPRO.align_up_to_cylinder(68), 68);
+        assert_eq!(SAMARA.align_up_to_cylinder(1), 1008);
+        assert!(!SAMARA.is_track_aligned(64));
+        assert!(SAMARA.is_track_aligned(126));
+    }
+
+    #[test]
+    fn zero_geometry() {
+        let g = Geometry::default();
+        assert!(!g.is_valid());
+        assert_eq!(g.blocks(), 0);
+        assert_eq!(g.lba_to_chs(5), (5, 0, 1));
+        assert_eq!(g.chs_to_lba(0, 0, 1), None);
+        assert!(!g.contains((0, 0, 1)));
+        assert!(g.is_track_aligned(7));
+        assert!(g.is_cylinder_aligned(7));
+        assert_eq!(g.align_down_to_track(7), 7);
+        assert_eq!(g.align_up_to_track(7), 7);
+        assert_eq!(g.align_up_to_cylinder(7), 7);
+
+        // без головок дорожка - один цилиндр
+        let g = Geometry::new(10, 0, 17);
+        assert_eq!(g.lba_to_chs(20), (1, 0, 4));
+        assert_eq!(g.chs_to_lba(1, 0, 4), None);
+        assert_eq!(g.align_up_to_cylinder(20), 34);
+    }
+
+    #[test]
+    fn wide_cylinder() {
+        // 70000 цилиндров по одному блоку в u16 не влезают
+        let g = Geometry::new(10, 1, 1);
+        assert_eq!(g.lba_to_wide_chs(70000), (70000, 0, 1));
+        assert_eq!(g.lba_to_chs(70000), (u16::MAX, 0, 1));
+        assert!(!g.contains(g.lba_to_chs(70000)));
+        assert_eq!(ALTPRO.lba_to_wide_chs(1101), (16, 0, 14));
+    }
+
+    #[test]
+    fn overflow_geometry() {
+        // мусор в таблице: C * H * S не влезает в u32
+        let g = Geometry::new(u16::MAX, u16::MAX, u16::MAX);
+        assert_eq!(g.checked_blocks(), None);
+        assert_eq!(g.blocks(), u32::MAX);
+        assert_eq!(g.checked_track_start(u16::MAX, 0), None);
+        assert_eq!(g.track_start(u16::MAX, 0), u32::MAX);
+        assert_eq!(g.chs_to_lba(u16::MAX, 0, 1), None);
+        assert_eq!(g.chs_to_lba(0, 1, 1), Some(u16::MAX as u32));
+        assert!(g.contains(g.lba_to_chs(u32::MAX)));
+        // АльтПро с мусорными головками
+        let g = Geometry::new(u16::MAX, 257, 256);
+        assert_eq!(g.checked_blocks(), None);
+        assert_eq!(g.checked_track_start(u16::MAX - 1, 256), None);
+        assert_eq!(
+            Geometry::new(u16::MAX, 256, 256).checked_blocks(),
+            Some(0xffff_0000)
+        );
+    }
+}
@@ -1,4 +1,13 @@
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+#[cfg(feature = "async")]
+use futures_io::{AsyncRead, AsyncSeek};
 
 pub struct BinInvertedReader<R>(R);
 
@@ -29,32 +38,201 @@ impl<R: Seek> Seek for BinInvertedReader<R> {
     }
 }
 
-pub struct ReverseReader<R>(R);
+pub struct ReverseReader<R> {
+    inner: R,
+    #[cfg(feature = "async")]
+    state: ReverseState,
+}
+
+/// Шаг асинхронного обратного чтения
+/// (seek назад, чтение, снова seek назад)
+#[cfg(feature = "async")]
+#[derive(Debug, Clone, Copy)]
+enum ReverseState {
+    SeekBack,
+    Read,
+    Rewind(usize),
+}
 
 impl<R> ReverseReader<R>
 where
     R: Read + Seek,
 {
     pub fn new(reader: R) -> Self {
-        Self(reader)
+        Self {
+            inner: reader,
+            #[cfg(feature = "async")]
+            state: ReverseState::SeekBack,
+        }
     }
 
     pub fn into_inner(self) -> R {
-        self.0
+        self.inner
     }
 }
 
 impl<R: Read + Seek> Read for ReverseReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = buf.len();
-        let _pos = self.0.seek(SeekFrom::Current(-(len as i64)))?;
-        let size = self.0.read(buf)?;
-        let _pos = self.0.seek(SeekFrom::Current(-(len as i64)))?;
+        let _pos = self.inner.seek(SeekFrom::Current(-(len as i64)))?;
+        let size = self.inner.read(buf)?;
+        let _pos = self.inner.seek(SeekFrom::Current(-(len as i64)))?;
         Ok(size)
     }
 }
 
 impl<R: Seek> Seek for ReverseReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> AsyncRead for BinInvertedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let size = ready!(Pin::new(&mut self.0).poll_read(cx, buf))?;
+        buf[..size].iter_mut().for_each(|b| *b = !*b);
+        Poll::Ready(Ok(size))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncSeek + Unpin> AsyncSeek for BinInvertedReader<R> {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.0).poll_seek(cx, pos)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncRead for ReverseReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let len = buf.len();
+        // каждый шаг может вернуть Pending, поэтому помним на чем остановились,
+        // чтобы не сдвинуться назад второй раз
+        loop {
+            match self.state {
+                ReverseState::SeekBack => {
+                    let _pos =
+                        ready!(Pin::new(&mut self.inner)
+                            .poll_seek(cx, SeekFrom::Current(-(len as i64))))?;
+                    self.state = ReverseState::Read;
+                }
+                ReverseState::Read => {
+                    let size = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+                    self.state = ReverseState::Rewind(size);
+                }
+                ReverseState::Rewind(size) => {
+                    let _pos =
+                        ready!(Pin::new(&mut self.inner)
+                            .poll_seek(cx, SeekFrom::Current(-(len as i64))))?;
+                    self.state = ReverseState::SeekBack;
+                    return Poll::Ready(Ok(size));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncSeek + Unpin> AsyncSeek for ReverseReader<R> {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_seek(cx, pos)
+    }
+}
+
+/// Образ диска целиком в памяти
+/// Используется вместо файла в тестах, фаззерах, сборщике образов
+/// и там, где файловой системы нет вовсе (WASM)
+#[derive(Debug, Default, Clone)]
+pub struct MemImage(Cursor<Vec<u8>>);
+
+impl MemImage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Zero filled image of `blocks` blocks
+    pub fn with_blocks(blocks: usize) -> Self {
+        Self::from(vec![0u8; blocks * crate::BLOCK_SIZE])
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.get_ref().len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.get_ref().is_empty()
+    }
+
+    /// Image size in blocks (incomplete last block is not counted)
+    pub fn blocks(&self) -> u64 {
+        self.len() / crate::BLOCK_SIZE as u64
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.get_ref()
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.0.get_mut()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0.into_inner()
+    }
+}
+
+impl From<Vec<u8>> for MemImage {
+    fn from(data: Vec<u8>) -> Self {
+        Self(Cursor::new(data))
+    }
+}
+
+impl From<&[u8]> for MemImage {
+    fn from(data: &[u8]) -> Self {
+        Self::from(data.to_vec())
+    }
+}
+
+impl AsRef<[u8]> for MemImage {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Read for MemImage {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for MemImage {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Seek for MemImage {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
         self.0.seek(pos)
     }
@@ -1,15 +1,32 @@
 use std::fs::{self, OpenOptions};
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
 use binrw::{binrw, BinRead};
 use byteordered::ByteOrdered;
 use io::BinInvertedReader;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
+use tracing::warn;
 
 use crate::io::ReverseReader;
+use crate::progress::{copy_with_progress, Operation, Progress};
 
+pub mod boot;
+mod check;
+mod geometry;
 pub mod io;
+pub mod nbd;
+#[cfg(feature = "serde")]
+pub mod octal;
+pub mod output;
+pub mod progress;
+mod table;
+
+pub use check::{check_image, CheckIssue, CheckReport};
+pub use geometry::Geometry;
+pub use table::{detect_partition_table, Controller, PartitionTable};
 
 #[derive(Error, Debug)]
 pub enum AHDDError {
@@ -22,9 +39,17 @@ pub enum AHDDError {
     #[error("Header read error size {} != {0}", BLOCK_SIZE)]
     ReadHeaderSize(usize),
     #[error("Header partitions count error {0} > 124")]
-    HeaderPartitionsCount(u8),
+    HeaderPartitionsCount(usize),
     #[error("Header checksum error {0} != {1}")]
     CheckSum(u16, u16),
+    #[error("Partition {0} ends at block {1} beyond disk size {2}")]
+    PartitionOutOfDisk(usize, u32, u32),
+    #[error("Partitions {0} and {1} overlap")]
+    PartitionsOverlap(usize, usize),
+    #[error("Partition {0} can't be stored in table")]
+    BadPartition(usize),
+    #[error("Opened read only")]
+    ReadOnly,
     #[error("Io Error")] //
     Io {
         #[from]
@@ -39,6 +64,56 @@ pub enum AHDDError {
     Unknown,
 }
 
+#[derive(Error, Debug)]
+pub enum SHDDError {
+    #[error("Can't get fh as mut")]
+    FhMut,
+    #[error("File name is not set")]
+    EmptyName,
+    #[error("Header read error size {} != {0}", BLOCK_SIZE)]
+    ReadHeaderSize(usize),
+    #[error("Bad geometry: cylinder volume {0} != {1} sectors * {2} heads")]
+    BadGeometry(u16, u8, u16),
+    #[error("No partitions found")]
+    NoPartitions,
+    #[error("Partition {0} starts before previous one")]
+    PartitionsOrder(usize),
+    #[error("Partition {0} ends at block {1} beyond disk size {2}")]
+    PartitionOutOfDisk(usize, u32, u32),
+    #[error("Partitions {0} and {1} overlap")]
+    PartitionsOverlap(usize, usize),
+    #[error("Partition {0} can't be stored in table")]
+    BadPartition(usize),
+    #[error("Opened read only")]
+    ReadOnly,
+    #[error("Io Error")] //
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("BinRW Error")]
+    BinRW {
+        #[from]
+        source: binrw::Error,
+    },
+}
+
+/// Как реагировать на несоответствия в таблице разделов и в метаданных
+/// тома (один режим и для `bkhdd`, и для `mkdosfs`)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ParseMode {
+    /// любое несоответствие - ошибка (разделы за концом диска, пересечения,
+    /// счетчики мета блока; для пакетной проверки образов)
+    Strict,
+    /// ошибка только если таблицу нельзя прочитать (неверная контрольная
+    /// сумма), остальное - предупреждения
+    #[default]
+    Permissive,
+    /// сохраняем все для восстановления данных: таблицу с неверной
+    /// контрольной суммой, мусорные записи каталога (`is_garbage`)
+    Forensic,
+}
+
 fn swap_pairs_adaptor<'a, T: 'a>(slice: &'a [T]) -> impl Iterator<Item = T> + 'a
 where
     T: Clone + Copy,
@@ -98,6 +173,7 @@ pub const AHDD_HEADER_WORDS: usize = 4;
 #[binrw]
 #[brw(little)]
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AHDDLayout {
     /// u16 количество цидиндров (-2)
     cylinders: u16, // 0o776
@@ -123,6 +199,7 @@ pub struct AHDDLayout {
 #[binrw]
 #[brw(little)]
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AHDDPattionEntrie {
     /// Номер цилиндра и головки (если инвертировано, то защищен)
     /// Биты:
@@ -141,6 +218,9 @@ pub struct AHDD {
     partitions: Vec<Partition>,
     checksum: u16,
     layout: AHDDLayout,
+    parse_mode: ParseMode,
+    /// несоответствия последнего read_header(), не ошибки в parse_mode
+    diagnostics: Vec<CheckIssue>,
     raw: [u8; BLOCK_SIZE],
 }
 
@@ -154,23 +234,30 @@ impl Default for AHDD {
             partitions: Vec::new(),
             checksum: AHDD_CS_INIT,
             layout: Default::default(),
+            parse_mode: ParseMode::default(),
+            diagnostics: Vec::new(),
             raw: [0u8; BLOCK_SIZE],
         }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Partition {
     pub start_cylinder: u16,
     pub start_head: u16,
     pub start_sector: u16,
     pub lba: u32,
     pub length: u32,
+    /// first block after partition
     pub end_block: u32,
+    /// C/H/S of the last block of partition
     pub end_cylinder: u16,
     pub end_head: u16,
     pub end_sector: u16,
     pub protected: bool,
+    /// Блок параметров лог. диска (только Самара)
+    pub shdd_params: Option<SHDDParamBlock>,
 }
 
 impl AHDD {
@@ -219,6 +306,32 @@ impl AHDD {
         self.offset = offset;
     }
 
+    /// Allow `write_back()` (file is reopened)
+    pub fn set_read_only(&mut self, read_only: bool) {
+        if self.read_only != read_only {
+            self.read_only = read_only;
+            self.fh = None;
+        }
+    }
+
+    pub fn set_parse_mode(&mut self, mode: ParseMode) {
+        self.parse_mode = mode;
+    }
+
+    pub fn parse_mode(&self) -> ParseMode {
+        self.parse_mode
+    }
+
+    pub fn layout(&self) -> &AHDDLayout {
+        &self.layout
+    }
+
+    /// C/H/S from partition table
+    pub fn geometry(&self) -> Geometry {
+        let layout = &self.layout;
+        Geometry::new(layout.cylinders, layout.heads as u16, layout.sectors)
+    }
+
     pub fn read_header(&mut self) -> Result<(), AHDDError> {
         if self.fh.is_none() {
             self.open()?
@@ -237,36 +350,39 @@ impl AHDD {
             // читаем в обратном порядке
             let mut rr = ReverseReader::new(c);
             self.layout = AHDDLayout::read(&mut rr)?;
+            self.partitions.clear();
+            self.diagnostics.clear();
             // dbg!(&layout);
-            self.checksum = self.checksum()?;
-            let layout = &self.layout;
-            for entrie in layout.part_entries.iter() {
-                let mut part = Partition::default();
-                let len = entrie.blocks as u32;
-                part.length = len;
-                let (head, cyl) = if entrie.cyl_head & 0x8000 != 0 {
-                    part.protected = true;
-                    (!entrie.cyl_head & 0xF, !entrie.cyl_head >> 4)
+            match self.checksum() {
+                Ok(cs) => self.checksum = cs,
+                Err(e @ AHDDError::CheckSum(stored, computed))
+                    if self.parse_mode == ParseMode::Forensic =>
+                {
+                    warn!("{}", e);
+                    self.diagnostics
+                        .push(CheckIssue::BadChecksum { stored, computed });
+                }
+                Err(e) => return Err(e),
+            }
+            for n in 0..self.layout.part_entries.len() {
+                let entrie = &self.layout.part_entries[n];
+                let (head, cyl, protected) = if entrie.cyl_head & 0x8000 != 0 {
+                    (!entrie.cyl_head & 0xF, !entrie.cyl_head >> 4, true)
                 } else {
-                    (entrie.cyl_head & 0xF, entrie.cyl_head >> 4)
+                    (entrie.cyl_head & 0xF, entrie.cyl_head >> 4, false)
                 };
-                // рассчитываем начало раздела в блоках
-                let lba: u32 =
-                    (cyl as u32 * layout.heads as u32 + head as u32) * layout.sectors as u32;
-                part.lba = lba;
-                part.start_cylinder = cyl;
-                part.start_head = head;
-                part.start_sector = 1;
-                // конец раздела
-                let end = lba + len;
-                part.end_block = end;
-                part.end_cylinder = (end / (layout.heads as u16 * layout.sectors) as u32) as u16;
-                part.end_head = ((end / layout.sectors as u32) % layout.heads as u32) as u16;
-                part.end_sector = (end % layout.sectors as u32 + 1) as u16;
-
+                let part = self.make_partition(cyl, head, entrie.blocks, protected);
                 self.partitions.push(part);
             }
             // dbg!(&self.partitions);
+            if self.parse_mode == ParseMode::Strict {
+                self.validate_partitions()?;
+            } else {
+                for issue in self.partition_issues() {
+                    warn!("{}", issue);
+                    self.diagnostics.push(issue);
+                }
+            }
         } else {
             return Err(AHDDError::FhMut);
         }
@@ -278,12 +394,71 @@ impl AHDD {
         &self.partitions
     }
 
+    /// Все разделы внутри диска и не пересекаются
+    pub fn validate_partitions(&self) -> Result<(), AHDDError> {
+        match self.partition_issues().into_iter().next() {
+            Some(CheckIssue::BeyondDisk {
+                partition,
+                end,
+                disk,
+            }) => Err(AHDDError::PartitionOutOfDisk(partition, end, disk)),
+            Some(CheckIssue::Overlap { first, second }) => {
+                Err(AHDDError::PartitionsOverlap(first, second))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Разделы за концом диска, затем пересечения соседних по началу разделов
+    fn partition_issues(&self) -> Vec<CheckIssue> {
+        let mut issues = Vec::new();
+        let disk = self.geometry().blocks();
+        for (n, part) in self.partitions.iter().enumerate() {
+            if part.end_block > disk {
+                issues.push(CheckIssue::BeyondDisk {
+                    partition: n,
+                    end: part.end_block,
+                    disk,
+                });
+            }
+        }
+        let mut order: Vec<usize> = (0..self.partitions.len()).collect();
+        order.sort_by_key(|&n| self.partitions[n].lba);
+        for pair in order.windows(2) {
+            let (a, b) = (&self.partitions[pair[0]], &self.partitions[pair[1]]);
+            if a.end_block > b.lba {
+                issues.push(CheckIssue::Overlap {
+                    first: pair[0],
+                    second: pair[1],
+                });
+            }
+        }
+        issues
+    }
+
+    /// Problems of the table found by last read (not errors in current parse mode)
+    pub fn diagnostics(&self) -> &[CheckIssue] {
+        &self.diagnostics
+    }
+
     pub fn checksum(&self) -> Result<u16, AHDDError> {
+        let cs = self.computed_checksum()?;
+        if self.layout.checksum != cs {
+            return Err(AHDDError::CheckSum(self.layout.checksum, cs));
+        }
+
+        Ok(cs)
+    }
+
+    /// Контрольная сумма по заголовку и записям разделов (без сравнения с записанной)
+    fn computed_checksum(&self) -> Result<u16, AHDDError> {
         let c = Cursor::new(&self.raw[..]);
         let mut rr = ReverseReader::new(c);
 
         if self.layout.partitions > 124 {
-            return Err(AHDDError::HeaderPartitionsCount(self.layout.partitions));
+            return Err(AHDDError::HeaderPartitionsCount(
+                self.layout.partitions as usize,
+            ));
         }
         rr.seek(SeekFrom::Start(BLOCK_SIZE as u64))?;
         let mut br = ByteOrdered::le(&mut rr);
@@ -291,12 +466,216 @@ impl AHDD {
         for _ in 0..(AHDD_HEADER_WORDS + self.layout.partitions as usize * 2) {
             cs = cs.wrapping_add(br.read_u16()?);
         }
-        if self.layout.checksum != cs {
-            return Err(AHDDError::CheckSum(self.layout.checksum, cs));
-        }
 
         Ok(cs)
     }
+
+    /// Read the table even if checksum is wrong (to rescue data or repair it)
+    pub fn open_unchecked(&mut self) -> Result<(), AHDDError> {
+        let mode = self.parse_mode;
+        self.parse_mode = ParseMode::Forensic;
+        let res = self.read_header();
+        self.parse_mode = mode;
+        res
+    }
+
+    /// Write correct checksum if stored one is wrong, the rest of table is not touched.
+    /// Returns `(stored, fixed)` checksums if table was changed
+    pub fn repair_checksum(&mut self) -> Result<Option<(u16, u16)>, AHDDError> {
+        let cs = self.computed_checksum()?;
+        let stored = self.layout.checksum;
+        if stored == cs {
+            return Ok(None);
+        }
+        if self.read_only {
+            return Err(AHDDError::ReadOnly);
+        }
+        // контрольная сумма - следующее слово после записей разделов
+        let pos = BLOCK_SIZE - 2 * (AHDD_HEADER_WORDS + self.layout.partitions as usize * 2 + 1);
+        self.raw[pos..pos + 2].copy_from_slice(&cs.to_le_bytes());
+        if self.fh.is_none() {
+            self.open()?
+        }
+        let fh = self.fh.as_mut().ok_or(AHDDError::FhMut)?;
+        let offset = self.offset + (AHDD_PT_SEC * BLOCK_SIZE) as u64 + pos as u64;
+        fh.seek(SeekFrom::Start(offset))?;
+        fh.write_all(&(!cs).to_le_bytes())?;
+        fh.sync_data()?;
+        self.layout.checksum = cs;
+        self.checksum = cs;
+
+        Ok(Some((stored, cs)))
+    }
+
+    /// Раздел с началом на цилиндре `cyl` и головке `head`
+    fn make_partition(&self, cyl: u16, head: u16, blocks: u16, protected: bool) -> Partition {
+        let geometry = self.geometry();
+        let mut part = Partition {
+            length: blocks as u32,
+            protected,
+            ..Default::default()
+        };
+        // рассчитываем начало раздела в блоках
+        let lba = geometry.track_start(cyl, head);
+        part.lba = lba;
+        part.start_cylinder = cyl;
+        part.start_head = head;
+        part.start_sector = 1;
+        // конец раздела, C/H/S последнего блока
+        let end = lba.saturating_add(part.length);
+        part.end_block = end;
+        (part.end_cylinder, part.end_head, part.end_sector) =
+            geometry.lba_to_chs(end.saturating_sub(1).max(lba));
+        part
+    }
+
+    /// Применяет изменение к копии разделов, если таблица осталась верной - берет ее
+    fn edit_partitions<F>(&mut self, edit: F) -> Result<(), AHDDError>
+    where
+        F: FnOnce(&mut Vec<Partition>),
+    {
+        let mut parts = self.partitions.clone();
+        edit(&mut parts);
+        if parts.len() > 124 {
+            return Err(AHDDError::HeaderPartitionsCount(parts.len()));
+        }
+        let saved = std::mem::replace(&mut self.partitions, parts);
+        if let Err(e) = self.validate_partitions() {
+            self.partitions = saved;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Add partition starting at `cylinder`/`head`, returns its index.
+    /// Table is changed in memory only, see [`AHDD::write_header()`]
+    pub fn add_partition(
+        &mut self,
+        cylinder: u16,
+        head: u16,
+        blocks: u16,
+        protected: bool,
+    ) -> Result<usize, AHDDError> {
+        let n = self.partitions.len();
+        if cylinder > 0x7ff || head >= self.layout.heads as u16 || blocks == 0 {
+            return Err(AHDDError::BadPartition(n));
+        }
+        let part = self.make_partition(cylinder, head, blocks, protected);
+        self.edit_partitions(|parts| parts.push(part))?;
+        Ok(n)
+    }
+
+    /// Remove partition `n` (following partitions are renumbered)
+    pub fn delete_partition(&mut self, n: usize) -> Result<Partition, AHDDError> {
+        if n >= self.partitions.len() {
+            return Err(AHDDError::BadPartition(n));
+        }
+        Ok(self.partitions.remove(n))
+    }
+
+    /// Change size of partition `n`, start stays in place
+    pub fn resize_partition(&mut self, n: usize, blocks: u16) -> Result<(), AHDDError> {
+        let part = self.partitions.get(n).ok_or(AHDDError::BadPartition(n))?;
+        if blocks == 0 {
+            return Err(AHDDError::BadPartition(n));
+        }
+        let resized =
+            self.make_partition(part.start_cylinder, part.start_head, blocks, part.protected);
+        self.edit_partitions(|parts| parts[n] = resized)
+    }
+
+    /// Number of boot partition (logical disk)
+    pub fn boot_drive(&self) -> u8 {
+        self.layout.drv
+    }
+
+    /// Boot from partition `n` (written with the table, see [`AHDD::write_header()`])
+    pub fn set_boot_drive(&mut self, n: usize) -> Result<(), AHDDError> {
+        if n >= self.partitions.len() {
+            return Err(AHDDError::BadPartition(n));
+        }
+        self.layout.drv = n as u8;
+        Ok(())
+    }
+
+    /// Set or clear write protection of partition `n` and write the table
+    /// with new checksum. Returns `false` if the flag was already so
+    pub fn set_protected(&mut self, n: usize, protected: bool) -> Result<bool, AHDDError> {
+        if self.read_only {
+            return Err(AHDDError::ReadOnly);
+        }
+        let part = self
+            .partitions
+            .get_mut(n)
+            .ok_or(AHDDError::BadPartition(n))?;
+        if part.protected == protected {
+            return Ok(false);
+        }
+        part.protected = protected;
+        // запись раздела инвертируется целиком, проще переписать таблицу
+        if let Err(e) = self.write_header() {
+            self.partitions[n].protected = !protected;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Store partitions back to the table with new checksum
+    pub fn write_header(&mut self) -> Result<(), AHDDError> {
+        if self.read_only {
+            return Err(AHDDError::ReadOnly);
+        }
+        if self.partitions.len() > 124 {
+            return Err(AHDDError::HeaderPartitionsCount(self.partitions.len()));
+        }
+        let mut entries = Vec::with_capacity(self.partitions.len());
+        for (n, part) in self.partitions.iter().enumerate() {
+            if part.start_cylinder > 0x7ff || part.start_head > 0xf || part.length > 0xffff {
+                return Err(AHDDError::BadPartition(n));
+            }
+            let cyl_head = part.start_cylinder << 4 | part.start_head;
+            entries.push(AHDDPattionEntrie {
+                // защищенный раздел хранится инвертированным
+                cyl_head: if part.protected { !cyl_head } else { cyl_head },
+                blocks: part.length as u16,
+            });
+        }
+        self.layout.partitions = entries.len() as u8;
+        self.layout.part_entries = entries;
+
+        // таблица лежит сверху вниз, слово за словом
+        let mut words = vec![
+            self.layout.cylinders,
+            u16::from_le_bytes([self.layout.heads, self.layout.drv]),
+            self.layout.sectors,
+            u16::from_le_bytes([self.layout.partitions, self.layout.uni]),
+        ];
+        for e in self.layout.part_entries.iter() {
+            words.push(e.cyl_head);
+            words.push(e.blocks);
+        }
+        let cs = words.iter().fold(AHDD_CS_INIT, |cs, &w| cs.wrapping_add(w));
+        words.push(cs);
+        for (n, w) in words.iter().enumerate() {
+            let pos = BLOCK_SIZE - 2 * (n + 1);
+            self.raw[pos..pos + 2].copy_from_slice(&w.to_le_bytes());
+        }
+        self.layout.checksum = cs;
+        self.checksum = cs;
+
+        if self.fh.is_none() {
+            self.open()?
+        }
+        let fh = self.fh.as_mut().ok_or(AHDDError::FhMut)?;
+        let inverted: Vec<u8> = self.raw.iter().map(|b| !b).collect();
+        fh.seek(SeekFrom::Start(
+            self.offset + (AHDD_PT_SEC * BLOCK_SIZE) as u64,
+        ))?;
+        fh.write_all(&inverted)?;
+        fh.sync_data()?;
+
+        Ok(())
+    }
 }
 
 ///
@@ -323,6 +702,8 @@ pub const SHDD_SEC_B: usize = 4;
 pub const SHDD_HEAD_B: usize = 5;
 /// таблица разделов
 pub const SHDD_PART_B: usize = 6;
+/// устройство первого лог. диска винчестера (C)
+pub const SHDD_FIRST_LD_DEVICE: u16 = 2;
 
 ///
 /// константы для доступа к данным начального блока раздела Самара
@@ -339,6 +720,396 @@ pub const SHDD_ADR_BOOT_W: usize = 3;
 pub const SHHD_ADR_PAR_W: usize = 4;
 /// состояние регистра страниц
 pub const SHDD_PAGE_W: usize = 5;
+/// размер блока параметров в словах
+pub const SHDD_PARAM_WORDS: usize = 6;
+
+/// Блок параметров в начальном блоке раздела Самара
+/// (см. константы SHDD_*_W выше)
+#[doc(alias = "SamaraPartitionInfo")]
+#[binrw]
+#[brw(little)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SHDDParamBlock {
+    /// номер лог. диска
+    pub ld_number: u16,
+    /// размер лог. диска в блоках
+    pub length: u16,
+    /// флаги - признаки
+    #[cfg_attr(feature = "serde", serde(with = "octal"))]
+    pub flags: u16,
+    /// адрес загрузки загрузчика лог. диска
+    #[cfg_attr(feature = "serde", serde(with = "octal"))]
+    pub boot_address: u16,
+    /// адрес блока параметров для загрузчика
+    #[cfg_attr(feature = "serde", serde(with = "octal"))]
+    pub params_address: u16,
+    /// состояние регистра страниц
+    #[cfg_attr(feature = "serde", serde(with = "octal"))]
+    pub page: u16,
+}
+
+impl SHDDParamBlock {
+    /// Parse parameter block from the first block of partition
+    pub fn from_block(block: &[u8]) -> Result<Self, SHDDError> {
+        if block.len() < SHDD_PARAM_WORDS * 2 {
+            return Err(SHDDError::ReadHeaderSize(block.len()));
+        }
+        let mut c = Cursor::new(block);
+        Ok(Self::read(&mut c)?)
+    }
+
+    /// Words in order of SHDD_*_W constants
+    pub fn to_words(&self) -> [u16; SHDD_PARAM_WORDS] {
+        [
+            self.ld_number,
+            self.length,
+            self.flags,
+            self.boot_address,
+            self.params_address,
+            self.page,
+        ]
+    }
+
+    /// Лог. диск загрузочный, если задан адрес загрузки загрузчика
+    pub fn is_bootable(&self) -> bool {
+        self.boot_address != 0
+    }
+}
+
+/// Samara HDD Layout (блок SHDD_PT_SEC, читается как есть, без инверсии)
+/// Формат (words):
+/// 0 - устройство для загрузки по умолчанию
+/// 1 - объём цилиндра в блоках (H * S)
+/// 2 - секторов на дорожке (мл. байт) и номер последней головки (ст. байт)
+/// 3.. - таблица разделов: начальный цилиндр каждого лог. диска,
+///       по возрастанию, до 0 (или 0177777) или конца блока
+#[binrw]
+#[brw(little)]
+#[derive(Default, Debug)]
+pub struct SHDDLayout {
+    /// устройство для загрузки по умолчанию (0 - А, 2 - С ...)
+    boot: u16,
+    /// объём цилиндра (H * S)
+    cyl_volume: u16,
+    /// количество секторов на дорожке
+    sectors: u8,
+    /// номер последней головки (H - 1)
+    last_head: u8,
+    /// начальные цилиндры разделов
+    #[br(count = BLOCK_SIZE / 2 - SHDD_PART_W)]
+    part_cylinders: Vec<u16>,
+}
+
+impl SHDDLayout {
+    pub fn heads(&self) -> u16 {
+        self.last_head as u16 + 1
+    }
+}
+
+/// Таблица разделов контроллера Самара
+pub struct SHDD {
+    file_name: String,
+    fh: Option<fs::File>,
+    read_only: bool,
+    offset: u64,
+    partitions: Vec<Partition>,
+    layout: SHDDLayout,
+    /// размер диска по образу
+    disk_blocks: u32,
+    parse_mode: ParseMode,
+    /// несоответствия последнего read_header(), не ошибки в parse_mode
+    diagnostics: Vec<CheckIssue>,
+    raw: [u8; BLOCK_SIZE],
+}
+
+impl Default for SHDD {
+    fn default() -> Self {
+        Self {
+            file_name: Default::default(),
+            fh: None,
+            read_only: true,
+            offset: 0,
+            partitions: Vec::new(),
+            layout: Default::default(),
+            disk_blocks: 0,
+            parse_mode: ParseMode::default(),
+            diagnostics: Vec::new(),
+            raw: [0u8; BLOCK_SIZE],
+        }
+    }
+}
+
+/// Число цилиндров по размеру образа; больше u16 в геометрию не влезает
+fn shdd_cylinders(disk_blocks: u32, cyl_volume: u32) -> u16 {
+    (disk_blocks / cyl_volume).min(u16::MAX as u32) as u16
+}
+
+impl SHDD {
+    pub fn new(fname: &str) -> Self {
+        Self {
+            file_name: String::from(fname),
+            read_only: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn open(&mut self) -> Result<(), SHDDError> {
+        if self.file_name.is_empty() {
+            return Err(SHDDError::EmptyName);
+        }
+
+        let fh = OpenOptions::new()
+            .read(true)
+            .write(!self.read_only)
+            .append(false)
+            .truncate(false)
+            .open(&self.file_name)?;
+
+        self.fh = Some(fh);
+
+        Ok(())
+    }
+
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    /// Allow `write_back()` (file is reopened)
+    pub fn set_read_only(&mut self, read_only: bool) {
+        if self.read_only != read_only {
+            self.read_only = read_only;
+            self.fh = None;
+        }
+    }
+
+    pub fn set_parse_mode(&mut self, mode: ParseMode) {
+        self.parse_mode = mode;
+    }
+
+    pub fn parse_mode(&self) -> ParseMode {
+        self.parse_mode
+    }
+
+    pub fn layout(&self) -> &SHDDLayout {
+        &self.layout
+    }
+
+    /// Default boot device (0 - A, 2 - C ...)
+    pub fn boot_device(&self) -> u16 {
+        self.layout.boot
+    }
+
+    /// Set default boot device (written with the table, see [`SHDD::write_back()`])
+    pub fn set_boot_device(&mut self, device: u16) {
+        self.layout.boot = device;
+    }
+
+    /// C/H/S, cylinders are counted by image size
+    pub fn geometry(&self) -> Geometry {
+        let cyl_volume = (self.layout.cyl_volume as u32).max(1);
+        Geometry::new(
+            shdd_cylinders(self.disk_blocks, cyl_volume),
+            self.layout.heads(),
+            self.layout.sectors as u16,
+        )
+    }
+
+    pub fn read_header(&mut self) -> Result<(), SHDDError> {
+        if self.fh.is_none() {
+            self.open()?
+        }
+        let fh = self.fh.as_mut().ok_or(SHDDError::FhMut)?;
+        let offset = self.offset + (SHDD_PT_SEC * BLOCK_SIZE) as u64;
+        let _pos = fh.seek(SeekFrom::Start(offset))?;
+        let size = fh.read(&mut self.raw[..])?;
+        if size != BLOCK_SIZE {
+            return Err(SHDDError::ReadHeaderSize(size));
+        }
+        let mut c = Cursor::new(&self.raw[..]);
+        self.layout = SHDDLayout::read(&mut c)?;
+        self.diagnostics.clear();
+        let layout = &self.layout;
+        // геометрия должна сходиться, иначе это не Самара
+        if layout.sectors == 0
+            || layout.cyl_volume == 0
+            || layout.cyl_volume != layout.sectors as u16 * layout.heads()
+        {
+            return Err(SHDDError::BadGeometry(
+                layout.cyl_volume,
+                layout.sectors,
+                layout.heads(),
+            ));
+        }
+
+        let disk_blocks =
+            ((fh.metadata()?.len().saturating_sub(self.offset)) / BLOCK_SIZE as u64) as u32;
+        self.disk_blocks = disk_blocks;
+        let cyl_volume = layout.cyl_volume as u32;
+        let geometry = Geometry::new(
+            shdd_cylinders(disk_blocks, cyl_volume),
+            layout.heads(),
+            layout.sectors as u16,
+        );
+        let starts: Vec<u32> = layout
+            .part_cylinders
+            .iter()
+            .take_while(|&&cyl| cyl != 0 && cyl != 0xffff)
+            .map(|&cyl| cyl as u32 * cyl_volume)
+            .collect();
+        if starts.is_empty() {
+            return Err(SHDDError::NoPartitions);
+        }
+
+        let mut partitions = Vec::with_capacity(starts.len());
+        let mut block = [0u8; BLOCK_SIZE];
+        for (n, &lba) in starts.iter().enumerate() {
+            if n > 0 && lba <= starts[n - 1] {
+                return Err(SHDDError::PartitionsOrder(n));
+            }
+            let mut part = Partition {
+                lba,
+                start_cylinder: (lba / cyl_volume) as u16,
+                start_head: 0,
+                start_sector: 1,
+                ..Default::default()
+            };
+            // до следующего раздела или до конца диска
+            let next = starts.get(n + 1).copied().unwrap_or(disk_blocks);
+            part.length = next.saturating_sub(lba);
+
+            // блок параметров в первом блоке раздела
+            let _pos = fh.seek(SeekFrom::Start(
+                self.offset + lba as u64 * BLOCK_SIZE as u64,
+            ))?;
+            let size = fh.read(&mut block[..])?;
+            let beyond_image = size != BLOCK_SIZE;
+            if !beyond_image {
+                let params = SHDDParamBlock::from_block(&block)?;
+                // размер из блока параметров главнее, если он есть и не
+                // залезает на следующий раздел (за концом диска проверим ниже)
+                let length = params.length as u32;
+                if length > part.length && n + 1 < starts.len() {
+                    if self.parse_mode == ParseMode::Strict {
+                        return Err(SHDDError::PartitionsOverlap(n, n + 1));
+                    }
+                    let issue = CheckIssue::Overlap {
+                        first: n,
+                        second: n + 1,
+                    };
+                    warn!("{}: length {} in parameter block", issue, length);
+                    self.diagnostics.push(issue);
+                } else if length != 0 {
+                    part.length = length;
+                }
+                part.shdd_params = Some(params);
+            } else {
+                warn!("Partition {} starts beyond end of image", n);
+            }
+
+            let end = lba + part.length;
+            part.end_block = end;
+            (part.end_cylinder, part.end_head, part.end_sector) =
+                geometry.lba_to_chs(end.saturating_sub(1).max(lba));
+            if beyond_image {
+                self.diagnostics.push(CheckIssue::BeyondImage {
+                    partition: n,
+                    end,
+                    image: disk_blocks as u64,
+                });
+            }
+            partitions.push(part);
+        }
+        self.partitions = partitions;
+
+        for (n, part) in self.partitions.iter().enumerate() {
+            if part.end_block > disk_blocks {
+                if self.parse_mode == ParseMode::Strict {
+                    return Err(SHDDError::PartitionOutOfDisk(
+                        n,
+                        part.end_block,
+                        disk_blocks,
+                    ));
+                }
+                let issue = CheckIssue::BeyondDisk {
+                    partition: n,
+                    end: part.end_block,
+                    disk: disk_blocks,
+                };
+                warn!("{}", issue);
+                self.diagnostics.push(issue);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn partitions(&self) -> &Vec<Partition> {
+        &self.partitions
+    }
+
+    /// Problems of the table found by last read (not errors in current parse mode)
+    pub fn diagnostics(&self) -> &[CheckIssue] {
+        &self.diagnostics
+    }
+
+    /// Store partition starts and parameter blocks back to disk
+    pub fn write_back(&mut self) -> Result<(), SHDDError> {
+        if self.read_only {
+            return Err(SHDDError::ReadOnly);
+        }
+        let cyl_volume = self.layout.cyl_volume as u32;
+        let max = self.layout.part_cylinders.len();
+        if self.partitions.len() > max {
+            return Err(SHDDError::BadPartition(max));
+        }
+        let mut cylinders = vec![0u16; max];
+        for (n, part) in self.partitions.iter().enumerate() {
+            // разделы начинаются только с границы цилиндра
+            if cyl_volume == 0 || part.lba % cyl_volume != 0 || part.lba / cyl_volume > 0xfffe {
+                return Err(SHDDError::BadPartition(n));
+            }
+            // длина в блоке параметров 16-битная
+            if part.shdd_params.is_some() && part.length > 0xffff {
+                return Err(SHDDError::BadPartition(n));
+            }
+            cylinders[n] = (part.lba / cyl_volume) as u16;
+        }
+        for (n, cyl) in cylinders.iter().enumerate() {
+            let pos = (SHDD_PART_W + n) * 2;
+            self.raw[pos..pos + 2].copy_from_slice(&cyl.to_le_bytes());
+        }
+        let pos = SHDD_BOOT_W * 2;
+        self.raw[pos..pos + 2].copy_from_slice(&self.layout.boot.to_le_bytes());
+        self.layout.part_cylinders = cylinders;
+
+        if self.fh.is_none() {
+            self.open()?
+        }
+        let fh = self.fh.as_mut().ok_or(SHDDError::FhMut)?;
+        fh.seek(SeekFrom::Start(
+            self.offset + (SHDD_PT_SEC * BLOCK_SIZE) as u64,
+        ))?;
+        fh.write_all(&self.raw)?;
+        for part in self.partitions.iter() {
+            if let Some(mut params) = part.shdd_params {
+                params.length = part.length as u16;
+                let raw: Vec<u8> = params
+                    .to_words()
+                    .iter()
+                    .flat_map(|w| w.to_le_bytes())
+                    .collect();
+                fh.seek(SeekFrom::Start(
+                    self.offset + part.lba as u64 * BLOCK_SIZE as u64,
+                ))?;
+                fh.write_all(&raw)?;
+            }
+        }
+        fh.sync_data()?;
+
+        Ok(())
+    }
+}
 
 pub const HDI_MAGIC_OFFSET: usize = 510;
 pub const HDI_MAGIC: u8 = 0xa5;
@@ -432,6 +1203,64 @@ impl std::fmt::Debug for HDILayout {
     }
 }
 
+/// Только осмысленные поля заголовка HDI, строки ATA (байты в словах
+/// переставлены) как текст
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "HDILayout")]
+struct HDIFields {
+    cylinders: u16,
+    heads: u16,
+    sectors: u16,
+    serial_number: String,
+    fw_version: String,
+    model_name: String,
+    capacity_in_sectors: u32,
+    total_used_sectors: u32,
+    #[serde(default)]
+    checksum: u8,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for HDILayout {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let text = |bytes: &[u8]| {
+            String::from_utf8_lossy(&swap_pairs(bytes))
+                .trim_end()
+                .to_string()
+        };
+        HDIFields {
+            cylinders: self.cylinders,
+            heads: self.heads,
+            sectors: self.sectors,
+            serial_number: text(&self.serial_number),
+            fw_version: text(&self.fw_version),
+            model_name: text(&self.model_name),
+            capacity_in_sectors: self.capacity_in_sectors,
+            total_used_sectors: self.total_used_sectors,
+            checksum: self.checksum,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Остальные поля как у `HDILayout::new()`, контрольную сумму блока
+/// все равно пересчитывает `to_block()`
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for HDILayout {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = HDIFields::deserialize(deserializer)?;
+        let mut layout = HDILayout::new(fields.cylinders, fields.heads, fields.sectors)
+            .with_serial_number(&fields.serial_number)
+            .with_fw_version(&fields.fw_version)
+            .with_model_name(&fields.model_name);
+        layout.capacity_in_sectors = fields.capacity_in_sectors;
+        layout.total_used_sectors = fields.total_used_sectors;
+        layout.checksum = fields.checksum;
+        Ok(layout)
+    }
+}
+
 impl Default for HDILayout {
     fn default() -> Self {
         Self {
@@ -464,6 +1293,187 @@ impl Default for HDILayout {
     }
 }
 
+impl HDILayout {
+    /// Header of disk with given geometry (capacity stops at `u32::MAX`)
+    pub fn new(cylinders: u16, heads: u16, sectors: u16) -> Self {
+        let capacity = Geometry::new(cylinders, heads, sectors).blocks();
+        Self {
+            cylinders,
+            heads,
+            sectors,
+            raw_bytes_per_sector: BLOCK_SIZE as u16,
+            raw_bytes_per_track: (sectors as u32 * BLOCK_SIZE as u32).min(0xffff) as u16,
+            capacity_in_sectors: capacity,
+            total_used_sectors: capacity,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_model_name(mut self, name: &str) -> Self {
+        ata_string(&mut self.model_name, name);
+        self
+    }
+
+    pub fn with_serial_number(mut self, serial: &str) -> Self {
+        ata_string(&mut self.serial_number, serial);
+        self
+    }
+
+    pub fn with_fw_version(mut self, version: &str) -> Self {
+        ata_string(&mut self.fw_version, version);
+        self
+    }
+
+    /// Header block with magic and checksum
+    pub fn to_block(&self) -> [u8; BLOCK_SIZE] {
+        let mut words: Vec<u16> = vec![
+            self.main_config,
+            self.cylinders,
+            self.word2,
+            self.heads,
+            self.raw_bytes_per_track,
+            self.raw_bytes_per_sector,
+            self.sectors,
+        ];
+        words.extend_from_slice(&self.reserved7);
+        let mut raw: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        raw.extend_from_slice(&self.serial_number);
+        for w in [
+            self.buffer_type,
+            self.buffer_size_in_sectors,
+            self.ecc_bytes_num,
+        ] {
+            raw.extend_from_slice(&w.to_le_bytes());
+        }
+        raw.extend_from_slice(&self.fw_version);
+        raw.extend_from_slice(&self.model_name);
+        for w in [
+            self.word47,
+            self.word48,
+            self.capabilities1,
+            self.capabilities2,
+        ]
+        .iter()
+        .chain(self.reserved51.iter())
+        {
+            raw.extend_from_slice(&w.to_le_bytes());
+        }
+        raw.extend_from_slice(&self.capacity_in_sectors.to_le_bytes());
+        raw.extend_from_slice(&self.reserved59.to_le_bytes());
+        raw.extend_from_slice(&self.total_used_sectors.to_le_bytes());
+        for w in self.reserved62.iter() {
+            raw.extend_from_slice(&w.to_le_bytes());
+        }
+
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..HDI_MAGIC_OFFSET].copy_from_slice(&raw[..HDI_MAGIC_OFFSET]);
+        block[HDI_MAGIC_OFFSET] = HDI_MAGIC;
+        block[BLOCK_SIZE - 1] = hdi_checksum(&block);
+        block
+    }
+}
+
+/// Строка в паспорте ATA: дополнена пробелами, байты в словах переставлены
+fn ata_string(field: &mut [u8], s: &str) {
+    let mut buf = vec![b' '; field.len()];
+    for (dst, src) in buf.iter_mut().zip(s.bytes().filter(u8::is_ascii)) {
+        *dst = src;
+    }
+    field.copy_from_slice(&swap_pairs(&buf));
+}
+
+/// Контрольная сумма блока HDI: сумма всех 512 байт должна быть 0
+fn hdi_checksum(block: &[u8]) -> u8 {
+    block[..(BLOCK_SIZE - 1)]
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_add(b))
+        .wrapping_neg()
+}
+
+/// `out` - тот же файл, что и `input`, в том числе под другим путем или
+/// жесткой ссылкой: создание `out` обрезало бы исходный образ
+fn is_same_file(input: &Path, out: &Path) -> std::io::Result<bool> {
+    let Ok(out_meta) = fs::metadata(out) else {
+        return Ok(false);
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let in_meta = fs::metadata(input)?;
+        Ok(in_meta.dev() == out_meta.dev() && in_meta.ino() == out_meta.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = out_meta;
+        Ok(fs::canonicalize(input)? == fs::canonicalize(out)?)
+    }
+}
+
+/// Prepend HDI header to raw disk image `raw`, result is written to `out`.
+/// Returns number of data blocks
+pub fn create_hdi<P: AsRef<Path>>(raw: P, out: P, layout: &HDILayout) -> Result<u64, HDIError> {
+    create_hdi_with_progress(raw, out, layout, &|_| {})
+}
+
+/// `create_hdi()` reporting copied blocks to `progress`
+pub fn create_hdi_with_progress<P: AsRef<Path>>(
+    raw: P,
+    out: P,
+    layout: &HDILayout,
+    progress: &dyn Fn(Progress),
+) -> Result<u64, HDIError> {
+    if is_same_file(raw.as_ref(), out.as_ref())? {
+        return Err(HDIError::SameFile);
+    }
+    let mut input = fs::File::open(raw)?;
+    let total = input.metadata()?.len().div_ceil(BLOCK_SIZE as u64);
+    let mut output = std::io::BufWriter::new(fs::File::create(out)?);
+    output.write_all(&layout.to_block())?;
+    let size = copy_with_progress(&mut input, &mut output, Operation::Convert, total, progress)?;
+    // образ дополняем до целого блока
+    let tail = (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64;
+    output.write_all(&vec![0u8; tail as usize])?;
+    output.flush()?;
+    Ok((size + tail) / BLOCK_SIZE as u64)
+}
+
+/// Strip HDI header from `hdi`, raw disk data is written to `out`.
+/// Returns number of data blocks
+pub fn strip_hdi<P: AsRef<Path>>(hdi: P, out: P) -> Result<u64, HDIError> {
+    strip_hdi_with_progress(hdi, out, &|_| {})
+}
+
+/// `strip_hdi()` reporting copied blocks to `progress`
+pub fn strip_hdi_with_progress<P: AsRef<Path>>(
+    hdi: P,
+    out: P,
+    progress: &dyn Fn(Progress),
+) -> Result<u64, HDIError> {
+    let hdi = hdi.as_ref();
+    if !HDI::probe(&hdi.to_string_lossy())? {
+        return Err(HDIError::Magic);
+    }
+    if is_same_file(hdi, out.as_ref())? {
+        return Err(HDIError::SameFile);
+    }
+    let mut input = fs::File::open(hdi)?;
+    let total = input.metadata()?.len().saturating_sub(BLOCK_SIZE as u64) / BLOCK_SIZE as u64;
+    input.seek(SeekFrom::Start(BLOCK_SIZE as u64))?;
+    let mut output = std::io::BufWriter::new(fs::File::create(out)?);
+    let size = copy_with_progress(&mut input, &mut output, Operation::Convert, total, progress)?;
+    output.flush()?;
+    Ok(size / BLOCK_SIZE as u64)
+}
+
+/// Geometry C/H/S for disk of `blocks` blocks when partition table
+/// doesn't tell it: 16 heads, 63 sectors (as CF cards report in LBA mode)
+pub fn guess_geometry(blocks: u64) -> Geometry {
+    const HEADS: u16 = 16;
+    const SECTORS: u16 = 63;
+    let cylinders = (blocks / (HEADS as u64 * SECTORS as u64)).clamp(1, u16::MAX as u64);
+    Geometry::new(cylinders as u16, HEADS, SECTORS)
+}
+
 /// Main HDI Struct
 pub struct HDI {
     file_name: String,
@@ -471,7 +1481,8 @@ pub struct HDI {
     read_only: bool,
     meta: HDILayout,
     pub is_hdi: bool,
-    ahdd: AHDD,
+    parse_mode: ParseMode,
+    table: Option<Box<dyn PartitionTable>>,
     pub is_ahdd: bool,
     pub is_shdd: bool,
     raw: [u8; BLOCK_SIZE],
@@ -485,7 +1496,8 @@ impl Default for HDI {
             read_only: true,
             meta: HDILayout::default(),
             is_hdi: false,
-            ahdd: AHDD::default(),
+            parse_mode: ParseMode::default(),
+            table: None,
             is_ahdd: false,
             is_shdd: false,
             raw: [0u8; BLOCK_SIZE],
@@ -493,7 +1505,23 @@ impl Default for HDI {
     }
 }
 
+/// Сколько блоков копируем за раз при извлечении и записи раздела
+const COPY_BLOCKS: usize = 64;
+
+/// Partition position in image file, see [`HDI::partition_location()`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PartitionLocation {
+    /// blocks from start of image file
+    pub offset: u64,
+    /// size in blocks
+    pub size: u64,
+    /// data must be read with inverted reader
+    pub inverted: bool,
+}
+
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HDIInfo {
     pub cylinders: u16,
     pub heads: u16,
@@ -519,6 +1547,16 @@ pub enum HDIError {
     Magic,
     #[error("Unknown format")]
     UnknownFormat,
+    #[error("Partition {0} not found")]
+    NoPartition(usize),
+    #[error("Image of {size} bytes doesn't fit in partition of {blocks} blocks")]
+    TooBig { size: u64, blocks: u64 },
+    #[error("Boot code of {0} bytes doesn't fit in {1} bytes before partition table")]
+    BootCodeSize(usize, usize),
+    #[error("Output file is the input file")]
+    SameFile,
+    #[error("Opened read only")]
+    ReadOnly,
     #[error("Io Error")] //
     Io {
         #[from]
@@ -529,6 +1567,11 @@ pub enum HDIError {
         #[from]
         source: AHDDError,
     },
+    #[error("SHDD Error")] //
+    SHDD {
+        #[from]
+        source: SHDDError,
+    },
     #[error("BinRW Error")]
     BinRW {
         #[from]
@@ -543,11 +1586,25 @@ impl HDI {
         Self {
             file_name: String::from(fname),
             read_only: true,
-            ahdd: AHDD::new(fname),
             ..Default::default()
         }
     }
 
+    /// Set parse mode of partition table readers (used on next open)
+    pub fn set_parse_mode(&mut self, mode: ParseMode) {
+        self.parse_mode = mode;
+    }
+
+    /// Open image writable, so partition table can be written back (used on next open)
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// HDI header as is, see also `info()`
+    pub fn layout(&self) -> &HDILayout {
+        &self.meta
+    }
+
     pub fn info(&self) -> HDIInfo {
         let meta = &self.meta;
         HDIInfo {
@@ -581,52 +1638,155 @@ impl HDI {
         Ok(())
     }
 
-    fn read_header(&mut self) -> Result<(), HDIError> {
-        if let Some(reader) = self.reader.as_mut() {
-            reader.seek(SeekFrom::Start(0))?;
-            let size = reader.read(&mut self.raw[..])?;
-            if size != BLOCK_SIZE {
-                return Err(HDIError::ReadHeaderSize(size));
-            }
-            if self.raw[HDI_MAGIC_OFFSET] == HDI_MAGIC {
-                let mut c = Cursor::new(&self.raw[..]);
-                self.meta = HDILayout::read(&mut c)?;
-                if self.checksum() == self.meta.checksum {
-                    self.is_hdi = true;
-                }
-            }
-            if self.is_hdi {
-                self.ahdd.set_offset(BLOCK_SIZE as u64);
-            }
-            let res = self.ahdd.read_header();
-            match res {
-                Err(AHDDError::Io { .. }) => res?,
-                Err(_) => self.is_ahdd = false,
-                _ => self.is_ahdd = true,
-            }
-            if !self.is_ahdd && !self.is_shdd {
-                return Err(HDIError::UnknownFormat);
+    /// Check for HDI header only (partition table is not read)
+    pub fn probe(fname: &str) -> Result<bool, HDIError> {
+        let mut hdi = Self::new(fname);
+        hdi.reader = Some(fs::File::open(fname)?);
+        hdi.read_hdi_header()?;
+        Ok(hdi.is_hdi)
+    }
+
+    /// Заголовок HDI (его может и не быть - тогда это просто образ диска)
+    fn read_hdi_header(&mut self) -> Result<(), HDIError> {
+        let reader = self.reader.as_mut().ok_or(HDIError::FhMut)?;
+        reader.seek(SeekFrom::Start(0))?;
+        let size = reader.read(&mut self.raw[..])?;
+        if size != BLOCK_SIZE {
+            return Err(HDIError::ReadHeaderSize(size));
+        }
+        self.is_hdi = false;
+        if self.raw[HDI_MAGIC_OFFSET] == HDI_MAGIC {
+            let mut c = Cursor::new(&self.raw[..]);
+            self.meta = HDILayout::read(&mut c)?;
+            if self.checksum() == self.meta.checksum {
+                self.is_hdi = true;
             }
+        }
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<(), HDIError> {
+        if self.reader.is_some() {
+            self.read_hdi_header()?;
+            let offset = if self.is_hdi { BLOCK_SIZE as u64 } else { 0 };
+            let table =
+                detect_partition_table(&self.file_name, offset, self.parse_mode, self.read_only)?
+                    .ok_or(HDIError::UnknownFormat)?;
+            self.is_ahdd = table.controller() == Controller::AltPro;
+            self.is_shdd = table.controller() == Controller::Samara;
+            self.table = Some(table);
         } else {
             // need to reopen
             return Err(HDIError::FhMut);
         }
-
         Ok(())
     }
 
     pub fn partitions(&self) -> Vec<&Partition> {
-        if self.is_ahdd {
-            self.ahdd.partitions.iter().collect()
+        match self.table.as_ref() {
+            Some(table) => table.partitions().iter().collect(),
+            None => Vec::with_capacity(0),
+        }
+    }
+
+    /// Detected partition table
+    pub fn table(&self) -> Option<&dyn PartitionTable> {
+        self.table.as_deref()
+    }
+
+    pub fn table_mut(&mut self) -> Option<&mut (dyn PartitionTable + 'static)> {
+        self.table.as_deref_mut()
+    }
+
+    pub fn controller(&self) -> Option<Controller> {
+        self.table.as_ref().map(|t| t.controller())
+    }
+
+    /// Start of disk data in blocks (HDI header is skipped)
+    pub fn data_offset(&self) -> u64 {
+        if self.is_hdi {
+            1
         } else {
-            Vec::with_capacity(0)
+            0
+        }
+    }
+
+    /// Copy partition `n` to `out`, AltPro data is de-inverted unless `raw` is set.
+    /// Returns number of blocks copied
+    pub fn extract_partition<W: Write>(
+        &mut self,
+        n: usize,
+        out: &mut W,
+        raw: bool,
+    ) -> Result<u64, HDIError> {
+        let loc = self.partition_location(n).ok_or(HDIError::NoPartition(n))?;
+        let reader = self.reader.as_mut().ok_or(HDIError::FhMut)?;
+        reader.seek(SeekFrom::Start(loc.offset * BLOCK_SIZE as u64))?;
+        let mut buf = vec![0u8; COPY_BLOCKS * BLOCK_SIZE];
+        let mut left = loc.size;
+        while left > 0 {
+            let blocks = left.min(COPY_BLOCKS as u64);
+            let chunk = &mut buf[..blocks as usize * BLOCK_SIZE];
+            reader.read_exact(chunk)?;
+            if loc.inverted && !raw {
+                chunk.iter_mut().for_each(|b| *b = !*b);
+            }
+            out.write_all(chunk)?;
+            left -= blocks;
         }
+        out.flush()?;
+        Ok(loc.size)
+    }
+
+    /// Write `size` bytes from `input` over partition `n`, AltPro data is inverted
+    /// unless `raw` is set. Rest of partition is left as is
+    pub fn inject_partition<R: Read>(
+        &mut self,
+        n: usize,
+        input: &mut R,
+        size: u64,
+        raw: bool,
+    ) -> Result<(), HDIError> {
+        if self.read_only {
+            return Err(HDIError::ReadOnly);
+        }
+        let loc = self.partition_location(n).ok_or(HDIError::NoPartition(n))?;
+        if size > loc.size * BLOCK_SIZE as u64 {
+            return Err(HDIError::TooBig {
+                size,
+                blocks: loc.size,
+            });
+        }
+        let writer = self.reader.as_mut().ok_or(HDIError::FhMut)?;
+        writer.seek(SeekFrom::Start(loc.offset * BLOCK_SIZE as u64))?;
+        let mut buf = vec![0u8; COPY_BLOCKS * BLOCK_SIZE];
+        let mut left = size;
+        while left > 0 {
+            let len = left.min(buf.len() as u64) as usize;
+            let chunk = &mut buf[..len];
+            input.read_exact(chunk)?;
+            if loc.inverted && !raw {
+                chunk.iter_mut().for_each(|b| *b = !*b);
+            }
+            writer.write_all(chunk)?;
+            left -= len as u64;
+        }
+        writer.sync_data()?;
+        Ok(())
+    }
+
+    /// Where partition `n` lies in image file
+    pub fn partition_location(&self, n: usize) -> Option<PartitionLocation> {
+        let part = self.partitions().into_iter().nth(n)?;
+        Some(PartitionLocation {
+            offset: self.data_offset() + part.lba as u64,
+            size: part.length as u64,
+            // АльтПро хранит все данные инвертированными
+            inverted: self.is_ahdd,
+        })
     }
 
     pub fn checksum(&self) -> u8 {
-        let cs = self.raw[..(BLOCK_SIZE - 1)]
-            .iter()
-            .fold(0u8, |sum, &b| sum.wrapping_add(b));
-        -(cs as i8) as u8
+        hdi_checksum(&self.raw)
     }
 }
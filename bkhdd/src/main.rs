@@ -1,9 +1,27 @@
-use clap::{crate_authors, crate_name, crate_version, App, AppSettings, Arg};
-use color_eyre::eyre::Result;
-// use tracing::info;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::TcpListener,
+};
+
+use clap::{crate_authors, crate_name, crate_version, App, AppSettings, Arg, ArgGroup, ArgMatches};
+use color_eyre::eyre::{eyre, Result};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use tracing::info;
 use tracing_subscriber::EnvFilter;
 
-use bkhdd::HDI;
+use bkhdd::{
+    boot::{boot_area_size, find_bootloader, write_boot_code},
+    check_image, create_hdi_with_progress, detect_partition_table, guess_geometry,
+    nbd::{self, PartitionExport},
+    output::{Format, FORMATS},
+    progress::{term_progress, ProgressFn},
+    strip_hdi_with_progress, Geometry, HDIError, HDILayout, ParseMode, Partition, AHDD, BLOCK_SIZE,
+    HDI,
+};
+#[cfg(feature = "serde")]
+use bkhdd::{output, Controller};
 
 fn main() -> Result<()> {
     setup_logging()?;
@@ -17,6 +35,42 @@ fn main() -> Result<()> {
             App::new("info")
                 .alias("show")
                 .about("Disk image information")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(
+                    Arg::new("fix-checksum")
+                        .long("fix-checksum")
+                        .help("Repair AltPro partition table checksum before reading"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            App::new("list")
+                .alias("ls")
+                .about("Partitions list")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            App::new("check")
+                .about("Validate partition table: checksum, geometry, C/H/S, overlaps")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            App::new("fdisk")
+                .about("Edit AltPro partition table interactively (written only on 'w')")
                 .arg(
                     Arg::new("IMAGE_NAME")
                         .required(true)
@@ -24,11 +78,235 @@ fn main() -> Result<()> {
                 ),
         )
         .subcommand(
-            App::new("list").alias("ls").about("Partitions list").arg(
-                Arg::new("IMAGE_NAME")
-                    .required(true)
-                    .help("Disk image file path"),
-            ),
+            App::new("protect")
+                .about("Write protect AltPro partition (table checksum is updated)")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(
+                    Arg::new("PART_IDX")
+                        .required(true)
+                        .validator(parse_int::<usize>)
+                        .help("Partition number"),
+                ),
+        )
+        .subcommand(
+            App::new("unprotect")
+                .about("Remove write protection of AltPro partition")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(
+                    Arg::new("PART_IDX")
+                        .required(true)
+                        .validator(parse_int::<usize>)
+                        .help("Partition number"),
+                ),
+        )
+        .subcommand(
+            App::new("make-bootable")
+                .about("Set boot partition and install bootloader before partition table")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(
+                    Arg::new("drive")
+                        .long("drive")
+                        .short('d')
+                        .takes_value(true)
+                        .required(true)
+                        .validator(parse_int::<usize>)
+                        .value_name("N")
+                        .help("Partition to boot from"),
+                )
+                .arg(
+                    Arg::new("loader")
+                        .long("loader")
+                        .short('l')
+                        .takes_value(true)
+                        .value_name("NAME|FILE")
+                        .help("Bootloader file or name in $BKTOOLS_BOOT_DIR (boot area is kept if not set)"),
+                ),
+        )
+        .subcommand(
+            App::new("extract")
+                .about("Copy partition to a plain disk image file")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(
+                    Arg::new("PART_IDX")
+                        .required(true)
+                        .validator(parse_int::<usize>)
+                        .help("Partition number"),
+                )
+                .arg(Arg::new("OUT").required(true).help("Output file path"))
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .help("Keep data as is (AltPro partitions stay inverted)"),
+                ),
+        )
+        .subcommand(
+            App::new("inject")
+                .about("Write partition image file back to disk image")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(
+                    Arg::new("PART_IDX")
+                        .required(true)
+                        .validator(parse_int::<usize>)
+                        .help("Partition number"),
+                )
+                .arg(
+                    Arg::new("FILE")
+                        .required(true)
+                        .help("Partition image file path"),
+                )
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .help("Write data as is (file is already inverted for AltPro)"),
+                ),
+        )
+        .subcommand(
+            App::new("nbd")
+                .about("Serve partition (de-inverted, read only) over NBD protocol")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(
+                    Arg::new("PART_IDX")
+                        .required(true)
+                        .validator(parse_int::<usize>)
+                        .help("Partition number"),
+                )
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .short('l')
+                        .takes_value(true)
+                        .default_value("127.0.0.1:10809")
+                        .value_name("ADDR")
+                        .help("Address and port to listen on"),
+                )
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Export name [default: partN]"),
+                ),
+        )
+        .subcommand(
+            App::new("hdi-create")
+                .about("Make HDI image from raw disk image (header is prepended)")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Raw disk image file path"),
+                )
+                .arg(Arg::new("OUT").required(true).help("Output HDI file path"))
+                .arg(
+                    Arg::new("cylinders")
+                        .long("cylinders")
+                        .short('c')
+                        .takes_value(true)
+                        .requires_all(&["heads", "sectors"])
+                        .validator(parse_int::<u16>)
+                        .value_name("C")
+                        .help("Cylinders (geometry is taken from partition table if not set)"),
+                )
+                .arg(
+                    Arg::new("heads")
+                        .long("heads")
+                        .short('H')
+                        .takes_value(true)
+                        .requires_all(&["cylinders", "sectors"])
+                        .validator(parse_int::<u16>)
+                        .value_name("H")
+                        .help("Heads"),
+                )
+                .arg(
+                    Arg::new("sectors")
+                        .long("sectors")
+                        .short('s')
+                        .takes_value(true)
+                        .requires_all(&["cylinders", "heads"])
+                        .validator(parse_int::<u16>)
+                        .value_name("S")
+                        .help("Sectors per track"),
+                )
+                .arg(
+                    Arg::new("model")
+                        .long("model")
+                        .takes_value(true)
+                        .default_value("BK HDD")
+                        .value_name("NAME")
+                        .help("Model name (40 chars max)"),
+                )
+                .arg(
+                    Arg::new("serial")
+                        .long("serial")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("SERIAL")
+                        .help("Serial number (20 chars max)"),
+                ),
+        )
+        .subcommand(
+            App::new("convert")
+                .about("Convert between HDI and raw disk images")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Disk image file path"),
+                )
+                .arg(Arg::new("OUT").required(true).help("Output file path"))
+                .arg(Arg::new("to-raw").long("to-raw").help("Strip HDI header"))
+                .arg(Arg::new("to-hdi").long("to-hdi").help("Prepend HDI header"))
+                .group(
+                    ArgGroup::new("direction")
+                        .args(&["to-raw", "to-hdi"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("chs")
+                        .long("chs")
+                        .takes_value(true)
+                        .requires("to-hdi")
+                        .value_name("C/H/S")
+                        .help("Geometry (default: from partition table or image size)"),
+                )
+                .arg(
+                    Arg::new("model")
+                        .long("model")
+                        .takes_value(true)
+                        .default_value("BK HDD")
+                        .value_name("NAME")
+                        .help("Model name (40 chars max)"),
+                )
+                .arg(
+                    Arg::new("serial")
+                        .long("serial")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("SERIAL")
+                        .help("Serial number (20 chars max)"),
+                ),
         )
         .get_matches();
     // dbg!(&matches);
@@ -42,32 +320,597 @@ fn main() -> Result<()> {
 
     // dbg!(&cmd, &image_name);
 
+    if cmd == "hdi-create" {
+        let sub = matches.subcommand_matches(cmd).unwrap();
+        return hdi_create(image_name, sub);
+    }
+    if cmd == "convert" {
+        let sub = matches.subcommand_matches(cmd).unwrap();
+        return convert(image_name, sub);
+    }
+    if cmd == "fdisk" {
+        return fdisk(image_name);
+    }
+    if cmd == "make-bootable" {
+        let sub = matches.subcommand_matches(cmd).unwrap();
+        return make_bootable(image_name, sub);
+    }
+    if cmd == "protect" || cmd == "unprotect" {
+        let sub = matches.subcommand_matches(cmd).unwrap();
+        let n = sub.value_of("PART_IDX").unwrap().parse::<usize>()?;
+        return set_protected(image_name, n, cmd == "protect");
+    }
+    // таблицу с ошибками HDI::try_open() не откроет
+    if cmd == "check" {
+        let sub = matches.subcommand_matches(cmd).unwrap();
+        return check(image_name, sub.value_of_t("format")?);
+    }
+
+    if cmd == "info"
+        && matches
+            .subcommand_matches(cmd)
+            .unwrap()
+            .is_present("fix-checksum")
+    {
+        fix_checksum(image_name)?;
+    }
+
     let mut hdi = HDI::new(image_name);
+    if cmd == "inject" {
+        hdi.set_read_only(false);
+    }
     hdi.try_open()?;
 
     match cmd {
         "info" => {
-            if hdi.is_hdi {
-                println!("HDI Info:");
-                let info = hdi.info();
-                println!(
-                    "\tC/H/S: {}/{}/{} Version: {}",
-                    info.cylinders, info.heads, info.sectors, info.fw_version
-                );
+            let sub = matches.subcommand_matches(cmd).unwrap();
+            let parts = hdi.partitions();
+            match sub.value_of_t::<Format>("format")? {
+                #[cfg(feature = "serde")]
+                Format::Json => output::write_json(
+                    &mut io::stdout().lock(),
+                    &DiskInfo {
+                        hdi: hdi.is_hdi.then(|| hdi.layout()),
+                        controller: hdi.controller(),
+                        checksum_ok: hdi.table().map(|table| table.checksum_ok()),
+                        boot_drive: hdi.table().and_then(|table| table.boot_drive()),
+                        partitions: partition_rows(&parts),
+                    },
+                )?,
+                #[cfg(feature = "serde")]
+                Format::Csv => {
+                    output::write_csv(&mut io::stdout().lock(), &partition_rows(&parts))?
+                }
+                Format::Table => print_info(&hdi, &parts),
+            }
+        }
+        "list" => {
+            let sub = matches.subcommand_matches(cmd).unwrap();
+            let rows = partition_rows(&hdi.partitions());
+            match sub.value_of_t::<Format>("format")? {
+                #[cfg(feature = "serde")]
+                Format::Json => output::write_json(&mut io::stdout().lock(), &rows)?,
+                #[cfg(feature = "serde")]
+                Format::Csv => output::write_csv(&mut io::stdout().lock(), &rows)?,
+                Format::Table => print_partitions(&rows),
+            }
+        }
+        "extract" => {
+            let sub = matches.subcommand_matches(cmd).unwrap();
+            let n = sub.value_of("PART_IDX").unwrap().parse::<usize>()?;
+            let out_name = sub.value_of("OUT").unwrap();
+            let mut out = BufWriter::new(File::create(out_name)?);
+            let blocks = hdi.extract_partition(n, &mut out, sub.is_present("raw"))?;
+            println!(
+                "Partition {} extracted to {:?}: {} blocks",
+                n, out_name, blocks
+            );
+        }
+        "inject" => {
+            let sub = matches.subcommand_matches(cmd).unwrap();
+            let n = sub.value_of("PART_IDX").unwrap().parse::<usize>()?;
+            let in_name = sub.value_of("FILE").unwrap();
+            let file = File::open(in_name)?;
+            let size = file.metadata()?.len();
+            let mut input = BufReader::new(file);
+            hdi.inject_partition(n, &mut input, size, sub.is_present("raw"))?;
+            println!("Partition {} written from {:?}: {} bytes", n, in_name, size);
+        }
+        "nbd" => {
+            let sub = matches.subcommand_matches(cmd).unwrap();
+            let n = sub.value_of("PART_IDX").unwrap().parse::<usize>()?;
+            let loc = hdi.partition_location(n).ok_or(HDIError::NoPartition(n))?;
+            let name = sub
+                .value_of("name")
+                .map_or_else(|| format!("part{}", n), str::to_string);
+            let export = PartitionExport::new(File::open(image_name)?, &loc);
+            let listener = TcpListener::bind(sub.value_of("listen").unwrap())?;
+            info!(
+                "Serving partition {} ({} blocks) as {:?} on {}",
+                n,
+                loc.size,
+                name,
+                listener.local_addr()?
+            );
+            nbd::serve(&listener, &export, &name)?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn format_arg() -> Arg<'static> {
+    Arg::new("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(FORMATS)
+        .default_value("table")
+        .help("Output format")
+}
+
+/// Раздел с номером, под которым его знают extract/inject/nbd
+#[cfg_attr(feature = "serde", derive(Serialize))]
+struct PartitionRow<'a> {
+    index: usize,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    partition: &'a Partition,
+}
+
+fn partition_rows<'a>(parts: &[&'a Partition]) -> Vec<PartitionRow<'a>> {
+    parts
+        .iter()
+        .enumerate()
+        .map(|(index, &partition)| PartitionRow { index, partition })
+        .collect()
+}
+
+/// `info --format json`
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct DiskInfo<'a> {
+    hdi: Option<&'a HDILayout>,
+    controller: Option<Controller>,
+    checksum_ok: Option<bool>,
+    boot_drive: Option<usize>,
+    partitions: Vec<PartitionRow<'a>>,
+}
+
+fn print_info(hdi: &HDI, parts: &[&Partition]) {
+    if hdi.is_hdi {
+        println!("HDI Info:");
+        let info = hdi.info();
+        println!(
+            "\tC/H/S: {}/{}/{} Version: {}",
+            info.cylinders, info.heads, info.sectors, info.fw_version
+        );
+        println!(
+            "\tName: \"{}\" Serial: \"{}\"",
+            info.model_name, info.serial_number
+        );
+    }
+    if let Some(table) = hdi.table() {
+        println!(
+            "Controller: {}{}. Info:",
+            table.controller(),
+            if table.checksum_ok() {
+                ""
+            } else {
+                " (bad checksum)"
+            }
+        );
+        if let Some(n) = table.boot_drive() {
+            println!("\tBoot partition: {}", n);
+        }
+    }
+    for (n, part) in parts.iter().enumerate() {
+        if let Some(params) = part.shdd_params {
+            println!(
+                "\tLD {}: number: {} length: {} flags: {:06o} boot: {:06o} params: {:06o} page: {:06o}{}",
+                n,
+                params.ld_number,
+                params.length,
+                params.flags,
+                params.boot_address,
+                params.params_address,
+                params.page,
+                if params.is_bootable() { " (bootable)" } else { "" }
+            );
+        }
+    }
+    print_partitions(&partition_rows(parts));
+}
+
+fn print_partitions(rows: &[PartitionRow<'_>]) {
+    println!(
+        "{:>3} {:>14} {:>14} {:>8} {:>8}",
+        "N", "Start C/H/S", "End C/H/S", "LBA", "Blocks"
+    );
+    for row in rows {
+        let part = row.partition;
+        println!(
+            "{:>3} {:>14} {:>14} {:>8} {:>8}{}",
+            row.index,
+            format!(
+                "{}/{}/{}",
+                part.start_cylinder, part.start_head, part.start_sector
+            ),
+            format!(
+                "{}/{}/{}",
+                part.end_cylinder, part.end_head, part.end_sector
+            ),
+            part.lba,
+            part.length,
+            partition_flags(part)
+        );
+    }
+}
+
+/// Хвост строки раздела: защита, номер и загрузочность лог. диска Самары
+fn partition_flags(part: &Partition) -> String {
+    let mut flags = String::new();
+    if part.protected {
+        flags.push_str(" protected");
+    }
+    if let Some(params) = part.shdd_params {
+        flags.push_str(&format!(" LD {}", params.ld_number));
+        if params.is_bootable() {
+            flags.push_str(" bootable");
+        }
+    }
+    flags
+}
+
+fn check(image_name: &str, format: Format) -> Result<()> {
+    let report = check_image(image_name)?;
+    match format {
+        #[cfg(feature = "serde")]
+        Format::Json => output::write_json(&mut io::stdout().lock(), &report)?,
+        #[cfg(feature = "serde")]
+        Format::Csv => output::write_csv(&mut io::stdout().lock(), &report.issues)?,
+        Format::Table => {
+            for issue in report.issues.iter() {
+                println!("{}", issue);
+            }
+            if let (Some(controller), Some(geometry)) = (report.controller, report.geometry) {
                 println!(
-                    "\tName: \"{}\" Serial: \"{}\"",
-                    info.model_name, info.serial_number
+                    "{} table, C/H/S {}, {} partitions checked, {} problems",
+                    controller,
+                    geometry,
+                    report.partitions,
+                    report.issues.len()
                 );
             }
-            print!("Controller: ");
-            if hdi.is_ahdd {
-                println!("AltPro. Info:");
+        }
+    }
+    if !report.is_clean() {
+        return Err(eyre!(
+            "Partition table has {} problems",
+            report.issues.len()
+        ));
+    }
+
+    Ok(())
+}
+
+const FDISK_HELP: &str = "\
+Commands:
+  p                     print partition table
+  n START BLOCKS [p]    add partition at C/H or at LBA (track start), p - protected
+  d N                   delete partition N (following ones are renumbered)
+  r N BLOCKS            resize partition N, start stays in place
+  c LBA | C/H/S         convert between LBA and C/H/S
+  w                     write table to disk and quit
+  q                     quit without saving
+  m                     this help";
+
+/// Таблица АльтПро для правки, с неверной контрольной суммой не открываем
+fn open_altpro(image_name: &str) -> Result<AHDD> {
+    let mut ahdd = AHDD::new(image_name);
+    if HDI::probe(image_name)? {
+        ahdd.set_offset(BLOCK_SIZE as u64);
+    }
+    ahdd.set_read_only(false);
+    ahdd.read_header().map_err(|e| match e {
+        e @ bkhdd::AHDDError::CheckSum(..) => {
+            eyre!("{}, repair it with 'info --fix-checksum' first", e)
+        }
+        e => eyre!("No AltPro partition table: {}", e),
+    })?;
+    Ok(ahdd)
+}
+
+fn set_protected(image_name: &str, n: usize, protected: bool) -> Result<()> {
+    let mut ahdd = open_altpro(image_name)?;
+    let count = ahdd.partitions().len();
+    if n >= count {
+        return Err(eyre!(
+            "No partition {}, disk has {} partitions numbered from 0",
+            n,
+            count
+        ));
+    }
+    let state = if protected {
+        "protected"
+    } else {
+        "unprotected"
+    };
+    if ahdd.set_protected(n, protected)? {
+        println!("Partition {} is {} now", n, state);
+    } else {
+        println!("Partition {} is already {}", n, state);
+    }
+
+    Ok(())
+}
+
+/// Загрузочный раздел в таблице и загрузчик в блоки перед ней
+fn make_bootable(image_name: &str, sub: &ArgMatches) -> Result<()> {
+    let offset = if HDI::probe(image_name)? {
+        BLOCK_SIZE as u64
+    } else {
+        0
+    };
+    let n = sub.value_of("drive").unwrap().parse::<usize>()?;
+    let mut table = detect_partition_table(image_name, offset, ParseMode::default(), false)?
+        .ok_or_else(|| eyre!("No partition table found"))?;
+    let controller = table.controller();
+    // загрузчик проверяем до того, как что-то писать
+    let code = sub.value_of("loader").map(find_bootloader).transpose()?;
+    if let Some(code) = code.as_ref() {
+        let size = boot_area_size(controller);
+        if code.len() > size {
+            return Err(HDIError::BootCodeSize(code.len(), size).into());
+        }
+    }
+    table.set_boot_drive(n)?;
+    table.write_back()?;
+    println!("{} table: boot from partition {}", controller, n);
+    if let Some(code) = code {
+        write_boot_code(image_name, controller, offset, &code)?;
+        println!(
+            "Bootloader {} ({} bytes) installed",
+            sub.value_of("loader").unwrap(),
+            code.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Редактор таблицы АльтПро: все правки в памяти, на диск только по `w`
+fn fdisk(image_name: &str) -> Result<()> {
+    let mut ahdd = open_altpro(image_name)?;
+    let geometry = ahdd.geometry();
+    println!(
+        "AltPro disk {:?}: C/H/S {}, {} blocks",
+        image_name,
+        geometry,
+        geometry.blocks()
+    );
+    println!("{}", FDISK_HELP);
+
+    let mut changed = false;
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("Command (m for help): ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => {
+                println!();
+                break;
             }
-            let parts = hdi.partitions();
-            dbg!(parts);
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&cmd, args)) = args.split_first() else {
+            continue;
+        };
+        match cmd {
+            "w" => {
+                if changed {
+                    ahdd.write_header()?;
+                    println!("Partition table written");
+                } else {
+                    println!("No changes");
+                }
+                return Ok(());
+            }
+            "q" => break,
+            cmd => match fdisk_command(&mut ahdd, cmd, args) {
+                Ok(edited) => changed |= edited,
+                Err(e) => println!("Error: {}", e),
+            },
         }
-        _ => unreachable!(),
     }
+    if changed {
+        println!("Changes discarded");
+    }
+
+    Ok(())
+}
+
+/// Одна команда редактора, `true` если таблица изменилась
+fn fdisk_command(ahdd: &mut AHDD, cmd: &str, args: &[&str]) -> Result<bool> {
+    let geometry = ahdd.geometry();
+    let number = |idx: usize| -> Result<u64> {
+        let arg = args
+            .get(idx)
+            .ok_or_else(|| eyre!("Missing argument, see 'm'"))?;
+        Ok(arg.parse::<u64>()?)
+    };
+    match cmd {
+        "p" => {
+            let parts: Vec<&Partition> = ahdd.partitions().iter().collect();
+            print_partitions(&partition_rows(&parts));
+            Ok(false)
+        }
+        "n" => {
+            let start = args
+                .first()
+                .ok_or_else(|| eyre!("Missing start, see 'm'"))?;
+            let (cylinder, head) = match start.split_once(['/', ',']) {
+                Some((c, h)) => (c.trim().parse::<u16>()?, h.trim().parse::<u16>()?),
+                None => start_of_lba(start.parse::<u32>()?, &geometry)?,
+            };
+            let blocks = u16::try_from(number(1)?)?;
+            let protected = args.get(2) == Some(&"p");
+            let n = ahdd.add_partition(cylinder, head, blocks, protected)?;
+            let part = &ahdd.partitions()[n];
+            println!(
+                "Partition {} added: LBA {}, {} blocks",
+                n, part.lba, part.length
+            );
+            Ok(true)
+        }
+        "d" => {
+            let n = number(0)? as usize;
+            let part = ahdd.delete_partition(n)?;
+            println!(
+                "Partition {} deleted: LBA {}, {} blocks",
+                n, part.lba, part.length
+            );
+            Ok(true)
+        }
+        "r" => {
+            let n = number(0)? as usize;
+            ahdd.resize_partition(n, u16::try_from(number(1)?)?)?;
+            println!(
+                "Partition {} resized to {} blocks",
+                n,
+                ahdd.partitions()[n].length
+            );
+            Ok(true)
+        }
+        "c" => {
+            let value = args.first().ok_or_else(|| eyre!("Missing value"))?;
+            if let Ok((c, h, s)) = parse_chs(value) {
+                let lba = geometry
+                    .chs_to_lba(c, h, s)
+                    .ok_or_else(|| eyre!("{} is outside of disk geometry", value))?;
+                println!("C/H/S {}/{}/{} = LBA {}", c, h, s, lba);
+            } else {
+                let lba = value.parse::<u32>()?;
+                let (c, h, s) = geometry.lba_to_wide_chs(lba);
+                println!("LBA {} = C/H/S {}/{}/{}", lba, c, h, s);
+            }
+            Ok(false)
+        }
+        "m" | "h" | "?" => {
+            println!("{}", FDISK_HELP);
+            Ok(false)
+        }
+        _ => Err(eyre!("Unknown command {:?}, see 'm'", cmd)),
+    }
+}
+
+/// Цилиндр и головка для раздела с `lba`: разделы АльтПро начинаются
+/// только с начала дорожки
+fn start_of_lba(lba: u32, geometry: &Geometry) -> Result<(u16, u16)> {
+    if !geometry.is_track_aligned(lba) {
+        return Err(eyre!(
+            "LBA {} is not a track start, nearest are {} and {}",
+            lba,
+            geometry.align_down_to_track(lba),
+            geometry.align_up_to_track(lba)
+        ));
+    }
+    let (cylinder, head, _) = geometry.lba_to_chs(lba);
+    Ok((cylinder, head))
+}
+
+fn fix_checksum(image_name: &str) -> Result<()> {
+    let mut ahdd = AHDD::new(image_name);
+    if HDI::probe(image_name)? {
+        ahdd.set_offset(BLOCK_SIZE as u64);
+    }
+    ahdd.set_read_only(false);
+    ahdd.open_unchecked()?;
+    match ahdd.repair_checksum()? {
+        Some((stored, fixed)) => println!("AltPro checksum fixed: {:06o} -> {:06o}", stored, fixed),
+        None => println!("AltPro checksum is correct"),
+    }
+
+    Ok(())
+}
+
+fn hdi_create(image_name: &str, sub: &ArgMatches) -> Result<()> {
+    let geometry = if sub.is_present("cylinders") {
+        Geometry::new(
+            sub.value_of("cylinders").unwrap().parse::<u16>()?,
+            sub.value_of("heads").unwrap().parse::<u16>()?,
+            sub.value_of("sectors").unwrap().parse::<u16>()?,
+        )
+    } else {
+        detect_partition_table(image_name, 0, ParseMode::default(), true)?
+            .ok_or_else(|| eyre!("No partition table found, set geometry with -c/-H/-s"))?
+            .geometry()
+    };
+    write_hdi(image_name, sub, geometry)
+}
+
+fn convert(image_name: &str, sub: &ArgMatches) -> Result<()> {
+    let out_name = sub.value_of("OUT").unwrap();
+    if sub.is_present("to-raw") {
+        let blocks = strip_hdi_with_progress(image_name, out_name, &progress_line())?;
+        println!("Raw image {:?} created: {} blocks", out_name, blocks);
+        return Ok(());
+    }
+
+    // геометрия: явно, из таблицы разделов или по размеру образа
+    let geometry = if let Some(chs) = sub.value_of("chs") {
+        let geometry = Geometry::from(parse_chs(chs)?);
+        if !geometry.is_valid() {
+            return Err(eyre!("Geometry can't have zero values, got {:?}", chs));
+        }
+        geometry
+    } else if let Some(table) = detect_partition_table(image_name, 0, ParseMode::default(), true)? {
+        table.geometry()
+    } else {
+        let blocks = std::fs::metadata(image_name)?.len() / BLOCK_SIZE as u64;
+        guess_geometry(blocks)
+    };
+    write_hdi(image_name, sub, geometry)
+}
+
+/// Строка хода на терминале, иначе ничего
+fn progress_line() -> ProgressFn {
+    term_progress().unwrap_or_else(|| Box::new(|_| {}))
+}
+
+/// Validator for integer arguments: `.validator(parse_int::<u64>)`
+fn parse_int<T>(s: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    s.parse::<T>()
+        .map_err(|e| format!("value must be an integer: {}", e))
+}
+
+/// "C/H/S" (или через запятую)
+fn parse_chs(s: &str) -> Result<(u16, u16, u16)> {
+    let v = s
+        .split(['/', ','])
+        .map(|n| n.trim().parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match v[..] {
+        [c, h, s] => Ok((c, h, s)),
+        _ => Err(eyre!("Expected C/H/S, got {:?}", s)),
+    }
+}
+
+fn write_hdi(image_name: &str, sub: &ArgMatches, geometry: Geometry) -> Result<()> {
+    let layout = HDILayout::new(geometry.cylinders, geometry.heads, geometry.sectors)
+        .with_model_name(sub.value_of("model").unwrap())
+        .with_serial_number(sub.value_of("serial").unwrap());
+    let out_name = sub.value_of("OUT").unwrap();
+    let blocks = create_hdi_with_progress(image_name, out_name, &layout, &progress_line())?;
+    println!(
+        "HDI {:?} created: C/H/S {}, {} blocks",
+        out_name, geometry, blocks
+    );
 
     Ok(())
 }
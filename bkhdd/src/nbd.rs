@@ -0,0 +1,316 @@
+//! NBD сервер: раздел или том как сетевой блочный диск
+//!
+//! `nbd-client HOST -N NAME /dev/nbd0` или qemu (`nbd://HOST/NAME`) видят
+//! содержимое уже без инверсии АльтПро, так что его можно смотреть любыми
+//! утилитами и отдавать эмуляторам там, где нет FUSE. Только fixed
+//! newstyle протокол и только чтение. Клиенты обслуживаются по очереди.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use tracing::{info, warn};
+
+use crate::{PartitionLocation, BLOCK_SIZE};
+
+/// Default NBD port
+pub const NBD_PORT: u16 = 10809;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const OPTS_MAGIC: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_HAS_FLAGS: u16 = 1 << 0;
+const FLAG_READ_ONLY: u16 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+const INFO_EXPORT: u16 = 0;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+/// Max size of one read request, bigger ones are refused
+const MAX_REQUEST: u32 = 32 * 1024 * 1024;
+/// Max size of option data, longer options are garbage
+const MAX_OPTION: u32 = 4096;
+
+/// Read only disk served by `serve()`
+pub trait Export {
+    /// Size of the disk in bytes
+    fn size(&self) -> u64;
+
+    /// Read whole `buf` at `offset`, `offset + buf.len()` is within `size()`
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+}
+
+/// Partition of disk image file, AltPro data is de-inverted
+#[derive(Debug)]
+pub struct PartitionExport {
+    file: Mutex<File>,
+    /// bytes from start of the file
+    offset: u64,
+    size: u64,
+    inverted: bool,
+}
+
+impl PartitionExport {
+    /// Serve partition at `loc` of `file` (see `HDI::partition_location()`)
+    pub fn new(file: File, loc: &PartitionLocation) -> Self {
+        Self {
+            file: Mutex::new(file),
+            offset: loc.offset * BLOCK_SIZE as u64,
+            size: loc.size * BLOCK_SIZE as u64,
+            inverted: loc.inverted,
+        }
+    }
+}
+
+impl Export for PartitionExport {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.seek(SeekFrom::Start(self.offset + offset))?;
+        file.read_exact(buf)?;
+        if self.inverted {
+            buf.iter_mut().for_each(|b| *b = !*b);
+        }
+        Ok(())
+    }
+}
+
+fn read_u16(stream: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn protocol_error(desc: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, desc)
+}
+
+/// Ответ на опцию: magic, опция, тип ответа, длина, данные
+fn option_reply(stream: &mut impl Write, option: u32, reply: u32, data: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(20 + data.len());
+    buf.extend_from_slice(&REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&reply.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)
+}
+
+fn simple_reply(stream: &mut impl Write, error: u32, handle: u64, data: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(16 + data.len());
+    buf.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&error.to_be_bytes());
+    buf.extend_from_slice(&handle.to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)
+}
+
+/// Имя экспорта из данных NBD_OPT_INFO/NBD_OPT_GO (длина, имя, запросы info)
+fn info_name(data: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    data.get(4..4 + len)
+}
+
+/// Один клиент: согласование опций, потом команды до NBD_CMD_DISC
+struct Session<'a, E> {
+    export: &'a E,
+    name: &'a str,
+    stream: TcpStream,
+}
+
+impl<E: Export> Session<'_, E> {
+    /// Клиент просит экспорт по имени, пустое имя - экспорт по умолчанию
+    fn is_our(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.name.as_bytes()
+    }
+
+    fn export_info(&self) -> [u8; 10] {
+        let mut info = [0u8; 10];
+        info[..8].copy_from_slice(&self.export.size().to_be_bytes());
+        info[8..].copy_from_slice(&(FLAG_HAS_FLAGS | FLAG_READ_ONLY).to_be_bytes());
+        info
+    }
+
+    /// Согласование, `true` - переходим к командам
+    fn handshake(&mut self) -> io::Result<bool> {
+        let mut hello = Vec::with_capacity(18);
+        hello.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        hello.extend_from_slice(&OPTS_MAGIC.to_be_bytes());
+        hello.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+        self.stream.write_all(&hello)?;
+        let client_flags = read_u32(&mut self.stream)?;
+        let no_zeroes = client_flags & u32::from(FLAG_NO_ZEROES) != 0;
+
+        loop {
+            if read_u64(&mut self.stream)? != OPTS_MAGIC {
+                return Err(protocol_error("bad option magic"));
+            }
+            let option = read_u32(&mut self.stream)?;
+            let len = read_u32(&mut self.stream)?;
+            if len > MAX_OPTION {
+                return Err(protocol_error("option is too long"));
+            }
+            let mut data = vec![0u8; len as usize];
+            self.stream.read_exact(&mut data)?;
+
+            match option {
+                // старый способ: ответа на опцию нет, сразу размер и флаги
+                OPT_EXPORT_NAME => {
+                    if !self.is_our(&data) {
+                        return Ok(false);
+                    }
+                    let mut reply = self.export_info().to_vec();
+                    if !no_zeroes {
+                        reply.resize(reply.len() + 124, 0);
+                    }
+                    self.stream.write_all(&reply)?;
+                    return Ok(true);
+                }
+                OPT_ABORT => {
+                    option_reply(&mut self.stream, option, REP_ACK, &[])?;
+                    return Ok(false);
+                }
+                OPT_LIST => {
+                    let mut server = (self.name.len() as u32).to_be_bytes().to_vec();
+                    server.extend_from_slice(self.name.as_bytes());
+                    option_reply(&mut self.stream, option, REP_SERVER, &server)?;
+                    option_reply(&mut self.stream, option, REP_ACK, &[])?;
+                }
+                OPT_INFO | OPT_GO => {
+                    let name = match info_name(&data) {
+                        Some(name) => name,
+                        None => {
+                            option_reply(&mut self.stream, option, REP_ERR_INVALID, &[])?;
+                            continue;
+                        }
+                    };
+                    if !self.is_our(name) {
+                        option_reply(&mut self.stream, option, REP_ERR_UNKNOWN, &[])?;
+                        continue;
+                    }
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&self.export_info());
+                    option_reply(&mut self.stream, option, REP_INFO, &info)?;
+                    option_reply(&mut self.stream, option, REP_ACK, &[])?;
+                    if option == OPT_GO {
+                        return Ok(true);
+                    }
+                }
+                _ => option_reply(&mut self.stream, option, REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    /// Команды до NBD_CMD_DISC или закрытия соединения
+    fn transmission(&mut self) -> io::Result<()> {
+        let size = self.export.size();
+        let mut buf = Vec::new();
+        loop {
+            if read_u32(&mut self.stream)? != REQUEST_MAGIC {
+                return Err(protocol_error("bad request magic"));
+            }
+            let _flags = read_u16(&mut self.stream)?;
+            let command = read_u16(&mut self.stream)?;
+            let handle = read_u64(&mut self.stream)?;
+            let offset = read_u64(&mut self.stream)?;
+            let len = read_u32(&mut self.stream)?;
+
+            match command {
+                CMD_READ => {
+                    if len > MAX_REQUEST || offset.saturating_add(len.into()) > size {
+                        simple_reply(&mut self.stream, EINVAL, handle, &[])?;
+                        continue;
+                    }
+                    buf.resize(len as usize, 0);
+                    match self.export.read_exact_at(&mut buf, offset) {
+                        Ok(()) => simple_reply(&mut self.stream, 0, handle, &buf)?,
+                        Err(e) => {
+                            warn!("Can't read {}+{}: {}", offset, len, e);
+                            simple_reply(&mut self.stream, EIO, handle, &[])?;
+                        }
+                    }
+                }
+                // диск только для чтения, данные записи пропускаем
+                CMD_WRITE => {
+                    io::copy(&mut (&mut self.stream).take(len.into()), &mut io::sink())?;
+                    simple_reply(&mut self.stream, EPERM, handle, &[])?;
+                }
+                CMD_DISC => return Ok(()),
+                CMD_FLUSH => simple_reply(&mut self.stream, 0, handle, &[])?,
+                _ => simple_reply(&mut self.stream, EINVAL, handle, &[])?,
+            }
+        }
+    }
+}
+
+/// Serve `export` as NBD export `name` to clients of `listener` one by one,
+/// returns only on error of `listener`
+pub fn serve<E: Export>(listener: &TcpListener, export: &E, name: &str) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        info!(%peer, "NBD client connected");
+        let _ = stream.set_nodelay(true);
+        let mut session = Session {
+            export,
+            name,
+            stream,
+        };
+        // ошибка клиента сервер не останавливает
+        let result = match session.handshake() {
+            Ok(true) => session.transmission(),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => info!(%peer, "NBD client disconnected"),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                info!(%peer, "NBD client disconnected")
+            }
+            Err(e) => warn!(%peer, "NBD client error: {}", e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! Адреса и флаги восьмеричными строками, как их привыкли видеть на БК
+//!
+//! Для полей `u8`/`u16`/`u32`: `#[serde(with = "bkhdd::octal")]` пишет
+//! `"001000"` вместо `512`. Читает и строку (`"001000"`, `"0o1000"`),
+//! и обычное число, так что старые снимки метаданных тоже подходят.
+
+use std::fmt;
+
+use serde::{de, Deserializer, Serializer};
+
+/// Serialize integer as octal string padded to 6 digits
+pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Copy + Into<u64>,
+    S: Serializer,
+{
+    serializer.collect_str(&format_args!("{:06o}", (*value).into()))
+}
+
+struct OctalVisitor;
+
+impl de::Visitor<'_> for OctalVisitor {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("octal string or integer")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<u64, E> {
+        Ok(v)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<u64, E> {
+        u64::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<u64, E> {
+        let digits = v.trim();
+        let digits = digits.strip_prefix("0o").unwrap_or(digits);
+        u64::from_str_radix(digits, 8).map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+}
+
+/// Deserialize integer from octal string or plain number
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: TryFrom<u64>,
+    D: Deserializer<'de>,
+{
+    let value = deserializer.deserialize_any(OctalVisitor)?;
+    T::try_from(value).map_err(|_| {
+        de::Error::invalid_value(de::Unexpected::Unsigned(value), &"integer in field range")
+    })
+}
@@ -0,0 +1,139 @@
+//! Вывод списков и метаданных: таблица для людей, JSON и CSV для скриптов
+//!
+//! Все идет через serde: JSON как есть, CSV - по строке на элемент, вложенные
+//! структуры разворачиваются в колонки `parent.field`. Колонки собираются
+//! по всем строкам, так что `Option` со структурой внутри не ломает таблицу.
+//! Без feature `serde` остается только таблица.
+
+use std::{fmt, str::FromStr};
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use serde_json::{Map, Value};
+#[cfg(feature = "serde")]
+use std::io;
+
+/// Possible values of `--format`
+#[cfg(feature = "serde")]
+pub const FORMATS: [&str; 3] = ["table", "json", "csv"];
+/// Possible values of `--format`
+#[cfg(not(feature = "serde"))]
+pub const FORMATS: [&str; 1] = ["table"];
+
+/// Output format of listings
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Table,
+    #[cfg(feature = "serde")]
+    Json,
+    #[cfg(feature = "serde")]
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            #[cfg(feature = "serde")]
+            "json" => Ok(Self::Json),
+            #[cfg(feature = "serde")]
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Table => write!(f, "table"),
+            #[cfg(feature = "serde")]
+            Self::Json => write!(f, "json"),
+            #[cfg(feature = "serde")]
+            Self::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+/// Write `value` as pretty JSON and newline
+pub fn write_json<W, T>(out: &mut W, value: &T) -> io::Result<()>
+where
+    W: io::Write,
+    T: Serialize + ?Sized,
+{
+    serde_json::to_writer_pretty(&mut *out, value)?;
+    writeln!(out)
+}
+
+#[cfg(feature = "serde")]
+/// Вложенные объекты в плоские колонки `parent.field`
+fn flatten(prefix: &str, value: Value, row: &mut Map<String, Value>) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields {
+                let name = if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}.{}", prefix, name)
+                };
+                flatten(&name, value, row);
+            }
+        }
+        value => {
+            row.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn csv_field(value: Option<&Value>) -> String {
+    let text = match value {
+        None | Some(Value::Null) => return String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+#[cfg(feature = "serde")]
+/// Write `rows` as CSV with header, nested structures become `parent.field` columns
+pub fn write_csv<W, T>(out: &mut W, rows: &[T]) -> io::Result<()>
+where
+    W: io::Write,
+    T: Serialize,
+{
+    let mut table = Vec::with_capacity(rows.len());
+    let mut columns: Vec<String> = Vec::new();
+    for row in rows {
+        let mut flat = Map::new();
+        flatten("", serde_json::to_value(row)?, &mut flat);
+        for name in flat.keys() {
+            if !columns.contains(name) {
+                columns.push(name.clone());
+            }
+        }
+        table.push(flat);
+    }
+    let header = columns
+        .iter()
+        .map(|name| csv_field(Some(&Value::String(name.clone()))))
+        .collect::<Vec<_>>();
+    writeln!(out, "{}", header.join(","))?;
+    for row in table {
+        let fields = columns
+            .iter()
+            .map(|name| csv_field(row.get(name)))
+            .collect::<Vec<_>>();
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
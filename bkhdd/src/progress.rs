@@ -0,0 +1,131 @@
+//! Ход долгих операций
+//!
+//! Операции над всем образом (извлечение, сборка, squeeze, преобразование
+//! HDI) на образах винчестера в сотни мегабайт идут заметно долго. Им можно
+//! дать обратный вызов, который получает `Progress` после каждого файла или
+//! куска данных: утилиты рисуют по нему строку хода (`term_progress()`),
+//! GUI - свой индикатор.
+
+use std::{
+    fmt,
+    io::{self, IsTerminal, Read, Write},
+};
+
+/// Long operation reporting progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    ExtractAll,
+    Squeeze,
+    Pack,
+    Format,
+    Convert,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ExtractAll => write!(f, "Extracting"),
+            Self::Squeeze => write!(f, "Squeezing"),
+            Self::Pack => write!(f, "Packing"),
+            Self::Format => write!(f, "Formatting"),
+            Self::Convert => write!(f, "Converting"),
+        }
+    }
+}
+
+/// `done` of `total` units of operation: entries for extract, pack and
+/// squeeze, blocks for format and convert
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub operation: Operation,
+    pub done: u64,
+    pub total: u64,
+    /// entry being processed, if any
+    pub item: Option<String>,
+}
+
+impl Progress {
+    pub fn new(operation: Operation, done: u64, total: u64) -> Self {
+        Self {
+            operation,
+            done,
+            total,
+            item: None,
+        }
+    }
+
+    pub fn with_item(mut self, item: &str) -> Self {
+        self.item = Some(item.to_string());
+        self
+    }
+
+    pub fn percent(&self) -> u64 {
+        match self.total {
+            0 => 100,
+            total => self.done.min(total) * 100 / total,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.done >= self.total
+    }
+}
+
+/// Progress callback
+pub type ProgressFn = Box<dyn Fn(Progress) + Send + Sync>;
+
+/// Progress line on stderr for command line tools, `None` if stderr is not a terminal
+pub fn term_progress() -> Option<ProgressFn> {
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+    Some(Box::new(|progress: Progress| {
+        let mut err = io::stderr().lock();
+        // \x1b[K стирает хвост предыдущей, более длинной строки
+        let _ = write!(
+            err,
+            "\r{} {}/{} {}%\x1b[K",
+            progress.operation,
+            progress.done,
+            progress.total,
+            progress.percent()
+        );
+        if progress.is_finished() {
+            let _ = writeln!(err);
+        }
+    }))
+}
+
+/// Блоки для копирования за раз, между ними сообщаем о ходе
+const COPY_CHUNK_BLOCKS: usize = 2048;
+
+/// Like `io::copy()` but reports progress in blocks every chunk,
+/// `total` is expected size in blocks
+pub fn copy_with_progress<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    operation: Operation,
+    total: u64,
+    progress: &dyn Fn(Progress),
+) -> io::Result<u64> {
+    let mut buf = vec![0u8; COPY_CHUNK_BLOCKS * crate::BLOCK_SIZE];
+    let mut copied = 0u64;
+    progress(Progress::new(operation, 0, total));
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+        let done = copied / crate::BLOCK_SIZE as u64;
+        // образ может оказаться больше ожидаемого, конец сообщаем ниже
+        if done < total {
+            progress(Progress::new(operation, done, total));
+        }
+    }
+    progress(Progress::new(operation, total, total));
+    Ok(copied)
+}
@@ -0,0 +1,194 @@
+//! Таблицы разделов контроллеров
+//!
+//! Контроллер определяем по самой таблице: АльтПро держит ее в блоке 7
+//! (инвертированной, с контрольной суммой), Самара - в блоке 1 (как есть,
+//! геометрия должна сходиться). Кто первым прочитался, тот и наш.
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AHDDError, CheckIssue, Geometry, HDIError, ParseMode, Partition, SHDDError, AHDD, SHDD,
+    SHDD_FIRST_LD_DEVICE,
+};
+
+/// HDD controller (partition table format)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Controller {
+    AltPro,
+    Samara,
+}
+
+impl fmt::Display for Controller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AltPro => write!(f, "AltPro"),
+            Self::Samara => write!(f, "Samara"),
+        }
+    }
+}
+
+/// Partition table of some HDD controller
+pub trait PartitionTable {
+    fn controller(&self) -> Controller;
+    /// Read the table, `Ok(false)` if image has no table of this controller
+    fn detect(&mut self) -> Result<bool, HDIError>;
+    fn partitions(&self) -> &[Partition];
+    /// Disk geometry C/H/S as controller sees it
+    fn geometry(&self) -> Geometry;
+    fn partitions_mut(&mut self) -> &mut Vec<Partition>;
+    /// Always `true` for tables without checksum
+    fn checksum_ok(&self) -> bool;
+    /// Stored and computed checksum if they differ
+    fn checksum_mismatch(&self) -> Option<(u16, u16)> {
+        None
+    }
+    /// Problems found while reading the table, not errors in its parse mode
+    fn diagnostics(&self) -> &[CheckIssue];
+    /// Partition the controller boots from, `None` if it's not a partition
+    fn boot_drive(&self) -> Option<usize>;
+    /// Boot from partition `n` (stored by `write_back()`)
+    fn set_boot_drive(&mut self, n: usize) -> Result<(), HDIError>;
+    /// Store (changed) partitions back to disk
+    fn write_back(&mut self) -> Result<(), HDIError>;
+}
+
+impl PartitionTable for AHDD {
+    fn controller(&self) -> Controller {
+        Controller::AltPro
+    }
+
+    fn detect(&mut self) -> Result<bool, HDIError> {
+        match self.read_header() {
+            Ok(()) => Ok(true),
+            Err(e @ AHDDError::Io { .. }) => Err(e.into()),
+            // таблица найдена, но не прошла проверку в ParseMode::Strict
+            Err(e @ (AHDDError::PartitionOutOfDisk(..) | AHDDError::PartitionsOverlap(..))) => {
+                Err(e.into())
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
+    fn geometry(&self) -> Geometry {
+        Self::geometry(self)
+    }
+
+    fn partitions_mut(&mut self) -> &mut Vec<Partition> {
+        &mut self.partitions
+    }
+
+    fn checksum_ok(&self) -> bool {
+        self.checksum().is_ok()
+    }
+
+    fn checksum_mismatch(&self) -> Option<(u16, u16)> {
+        match self.checksum() {
+            Err(AHDDError::CheckSum(stored, computed)) => Some((stored, computed)),
+            _ => None,
+        }
+    }
+
+    fn diagnostics(&self) -> &[CheckIssue] {
+        Self::diagnostics(self)
+    }
+
+    fn boot_drive(&self) -> Option<usize> {
+        Some(Self::boot_drive(self) as usize).filter(|&n| n < self.partitions.len())
+    }
+
+    fn set_boot_drive(&mut self, n: usize) -> Result<(), HDIError> {
+        Ok(Self::set_boot_drive(self, n)?)
+    }
+
+    fn write_back(&mut self) -> Result<(), HDIError> {
+        Ok(self.write_header()?)
+    }
+}
+
+impl PartitionTable for SHDD {
+    fn controller(&self) -> Controller {
+        Controller::Samara
+    }
+
+    fn detect(&mut self) -> Result<bool, HDIError> {
+        match self.read_header() {
+            Ok(()) => Ok(true),
+            Err(e @ (SHDDError::Io { .. } | SHDDError::PartitionOutOfDisk(..))) => Err(e.into()),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
+    fn geometry(&self) -> Geometry {
+        Self::geometry(self)
+    }
+
+    fn partitions_mut(&mut self) -> &mut Vec<Partition> {
+        &mut self.partitions
+    }
+
+    fn checksum_ok(&self) -> bool {
+        true
+    }
+
+    fn diagnostics(&self) -> &[CheckIssue] {
+        Self::diagnostics(self)
+    }
+
+    // устройства 0 и 1 - дисководы, лог. диски винчестера с 2 (C)
+    fn boot_drive(&self) -> Option<usize> {
+        self.boot_device()
+            .checked_sub(SHDD_FIRST_LD_DEVICE)
+            .map(|n| n as usize)
+            .filter(|&n| n < self.partitions.len())
+    }
+
+    fn set_boot_drive(&mut self, n: usize) -> Result<(), HDIError> {
+        if n >= self.partitions.len() {
+            return Err(HDIError::NoPartition(n));
+        }
+        self.set_boot_device(n as u16 + SHDD_FIRST_LD_DEVICE);
+        Ok(())
+    }
+
+    fn write_back(&mut self) -> Result<(), HDIError> {
+        Ok(SHDD::write_back(self)?)
+    }
+}
+
+/// Probe AltPro (block 7) and Samara (block 1) tables, `offset` is the start of disk data
+pub fn detect_partition_table(
+    fname: &str,
+    offset: u64,
+    mode: ParseMode,
+    read_only: bool,
+) -> Result<Option<Box<dyn PartitionTable>>, HDIError> {
+    let mut ahdd = AHDD::new(fname);
+    ahdd.set_offset(offset);
+    ahdd.set_parse_mode(mode);
+    ahdd.set_read_only(read_only);
+    if ahdd.detect()? {
+        return Ok(Some(Box::new(ahdd)));
+    }
+
+    let mut shdd = SHDD::new(fname);
+    shdd.set_offset(offset);
+    shdd.set_parse_mode(mode);
+    shdd.set_read_only(read_only);
+    if shdd.detect()? {
+        return Ok(Some(Box::new(shdd)));
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,595 @@
+//! Dokan драйвер для томов любого формата БК (Windows)
+//!
+//! То же, что fuse-mkdosfs, только через Dokan: `DokanFs` работает с
+//! `bkfs::BkFileSystem`, а пути Windows (`\DIR\FILE`) переводит в иноды
+//! поиском от корня. Имена сравниваются без учета регистра, как принято
+//! в Windows. Запись есть, только если ее умеет сам том.
+//!
+//! Собирается только на Windows с feature `dokan`, иначе крейт пустой.
+#![cfg(all(windows, feature = "dokan"))]
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::SystemTime,
+};
+
+use bkfs::{BkFileSystem, Entry, ErrorKind, ROOT_INODE};
+use dokan::{
+    CreateFileInfo, DiskSpaceInfo, FileInfo, FileSystemHandler, FillDataError, FillDataResult,
+    FindData, OperationInfo, OperationResult, VolumeInfo,
+};
+use dokan_sys::{
+    win32::{
+        FILE_CREATE, FILE_DIRECTORY_FILE, FILE_NON_DIRECTORY_FILE, FILE_OPEN_IF, FILE_OVERWRITE,
+        FILE_OVERWRITE_IF, FILE_SUPERSEDE,
+    },
+    DOKAN_IO_SECURITY_CONTEXT,
+};
+use tracing::{info, warn};
+use widestring::{U16CStr, U16CString};
+use winapi::{
+    shared::{ntdef::NTSTATUS, ntstatus::*},
+    um::winnt::{
+        ACCESS_MASK, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_NORMAL, FILE_ATTRIBUTE_READONLY,
+        FILE_CASE_PRESERVED_NAMES, FILE_READ_ONLY_VOLUME, FILE_UNICODE_ON_DISK,
+    },
+};
+
+/// Map `bkfs::Error` to NTSTATUS for Dokan reply
+pub fn ntstatus_from_error(err: &bkfs::Error) -> NTSTATUS {
+    match err.kind() {
+        ErrorKind::NotFound => STATUS_OBJECT_NAME_NOT_FOUND,
+        ErrorKind::ReadOnly => STATUS_MEDIA_WRITE_PROTECTED,
+        ErrorKind::Exists => STATUS_OBJECT_NAME_COLLISION,
+        ErrorKind::NameTooLong => STATUS_NAME_TOO_LONG,
+        ErrorKind::BadName => STATUS_OBJECT_NAME_INVALID,
+        ErrorKind::NoSpace => STATUS_DISK_FULL,
+        ErrorKind::Protected => STATUS_ACCESS_DENIED,
+        ErrorKind::IsDirectory => STATUS_FILE_IS_A_DIRECTORY,
+        ErrorKind::NotDirectory => STATUS_NOT_A_DIRECTORY,
+        ErrorKind::Unsupported => STATUS_NOT_IMPLEMENTED,
+        ErrorKind::Unavailable | ErrorKind::Io => STATUS_IO_DEVICE_ERROR,
+    }
+}
+
+fn status(err: bkfs::Error) -> NTSTATUS {
+    ntstatus_from_error(&err)
+}
+
+/// Части пути Windows без корня: `\DIR\FILE` -> [DIR, FILE]
+fn split_path(file_name: &U16CStr) -> OperationResult<Vec<String>> {
+    let path = file_name
+        .to_string()
+        .map_err(|_| STATUS_OBJECT_NAME_INVALID)?;
+    Ok(path
+        .split('\\')
+        .filter(|part| !part.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Поиск `name` в каталоге: сначала точное имя, потом без учета регистра
+fn lookup<'a, B: BkFileSystem>(fs: &'a B, parent: u64, name: &str) -> Option<Entry<'a>> {
+    fs.lookup(parent, name).or_else(|| {
+        let name = name.to_lowercase();
+        fs.list(parent)
+            .ok()?
+            .into_iter()
+            .find(|entry| entry.name.to_lowercase() == name)
+    })
+}
+
+/// Инод по пути: нет последней части - NAME_NOT_FOUND, промежуточной - PATH_NOT_FOUND
+fn resolve<B: BkFileSystem>(fs: &B, path: &[String]) -> OperationResult<u64> {
+    let mut inode = ROOT_INODE;
+    for (i, name) in path.iter().enumerate() {
+        match lookup(fs, inode, name) {
+            Some(entry) if i + 1 == path.len() || entry.is_dir() => inode = entry.inode,
+            Some(_) => return Err(STATUS_OBJECT_PATH_NOT_FOUND),
+            None if i + 1 == path.len() => return Err(STATUS_OBJECT_NAME_NOT_FOUND),
+            None => return Err(STATUS_OBJECT_PATH_NOT_FOUND),
+        }
+    }
+    Ok(inode)
+}
+
+fn ignore_name_too_long(err: FillDataError) -> OperationResult<()> {
+    match err {
+        // имя не влезло в короткий буфер Dokan - просто пропускаем файл
+        FillDataError::NameTooLong => Ok(()),
+        FillDataError::BufferFull => Err(STATUS_BUFFER_OVERFLOW),
+    }
+}
+
+/// Opened file or directory, Dokan context of a handle
+#[derive(Debug)]
+pub struct Handle {
+    inode: u64,
+    is_dir: bool,
+    /// generation of the volume at open, see `BkFileSystem::generation()`
+    generation: u64,
+    written: AtomicBool,
+}
+
+impl Handle {
+    fn new(inode: u64, is_dir: bool, generation: u64) -> Self {
+        Self {
+            inode,
+            is_dir,
+            generation,
+            written: AtomicBool::new(false),
+        }
+    }
+
+    /// Файл для чтения и записи: не каталог и том не перечитывался
+    fn check_file<B: BkFileSystem>(&self, fs: &B) -> OperationResult<()> {
+        if self.is_dir {
+            return Err(STATUS_INVALID_DEVICE_REQUEST);
+        }
+        if self.generation != fs.generation() {
+            return Err(STATUS_FILE_INVALID);
+        }
+        Ok(())
+    }
+}
+
+/// Dokan driver for any `BkFileSystem` volume
+#[derive(Debug)]
+pub struct DokanFs<B> {
+    fs: RwLock<B>,
+    /// strip .bin header from written files on close
+    parse_bin: bool,
+    /// time of all entries instead of the real one
+    fake_date: Option<SystemTime>,
+    /// volume label shown by Explorer
+    volume_name: String,
+    /// filesystem name shown by Explorer
+    fs_name: String,
+}
+
+impl<B: BkFileSystem> DokanFs<B> {
+    /// Mount opened volume `fs`
+    pub fn new(fs: B) -> Self {
+        Self {
+            fs: RwLock::new(fs),
+            parse_bin: false,
+            fake_date: None,
+            volume_name: "BK".to_string(),
+            fs_name: "BKFS".to_string(),
+        }
+    }
+
+    /// Strip BK .bin header from written files, see `BkFileSystem::strip_bin_header()`
+    pub fn set_parse_bin(&mut self, parse_bin: bool) {
+        self.parse_bin = parse_bin;
+    }
+
+    /// Show `date` for all entries instead of the image time
+    pub fn set_fake_date(&mut self, date: Option<SystemTime>) {
+        self.fake_date = date;
+    }
+
+    /// Volume label and filesystem name shown by Explorer
+    pub fn set_names(&mut self, volume_name: &str, fs_name: &str) {
+        self.volume_name = volume_name.to_string();
+        self.fs_name = fs_name.to_string();
+    }
+
+    fn fs_read(&self) -> RwLockReadGuard<'_, B> {
+        self.fs.read().expect("Fs lock poisoned")
+    }
+
+    fn fs_write(&self) -> RwLockWriteGuard<'_, B> {
+        self.fs.write().expect("Fs lock poisoned")
+    }
+
+    /// Read access to volume, it is reread first if it was changed,
+    /// как в fuse-mkdosfs
+    fn fs(&self) -> OperationResult<RwLockReadGuard<'_, B>> {
+        let modified = self.fs_read().is_modified();
+        if modified {
+            if let Err(e) = self.fs_write().refresh() {
+                warn!("Can't reopen image: {}", e);
+            }
+        }
+        let fs = self.fs_read();
+        if fs.is_degraded() {
+            return Err(STATUS_IO_DEVICE_ERROR);
+        }
+        Ok(fs)
+    }
+
+    fn entry_time(&self, fs: &B, entry: Option<&Entry<'_>>) -> SystemTime {
+        self.fake_date
+            .or_else(|| entry.and_then(|entry| entry.mtime))
+            .unwrap_or_else(|| fs.last_modified())
+    }
+
+    fn attributes(fs: &B, entry: Option<&Entry<'_>>) -> u32 {
+        let mut attributes = match entry {
+            Some(entry) if !entry.is_dir() => FILE_ATTRIBUTE_NORMAL,
+            _ => FILE_ATTRIBUTE_DIRECTORY,
+        };
+        let writable = entry.map_or(true, |entry| entry.mode & 0o200 != 0);
+        if fs.is_read_only() || !writable {
+            attributes |= FILE_ATTRIBUTE_READONLY;
+        }
+        attributes
+    }
+
+    /// Сведения о иноде, корень не запись тома и может не иметь `metadata()`
+    fn file_info(&self, fs: &B, inode: u64) -> OperationResult<FileInfo> {
+        let entry = fs.metadata(inode);
+        if entry.is_none() && inode != ROOT_INODE {
+            return Err(STATUS_OBJECT_NAME_NOT_FOUND);
+        }
+        let time = self.entry_time(fs, entry.as_ref());
+        Ok(FileInfo {
+            attributes: Self::attributes(fs, entry.as_ref()),
+            creation_time: time,
+            last_access_time: time,
+            last_write_time: time,
+            file_size: entry
+                .filter(|entry| !entry.is_dir())
+                .map_or(0, |entry| entry.size),
+            number_of_links: 1,
+            file_index: inode,
+        })
+    }
+}
+
+impl<'c, 'h: 'c, B> FileSystemHandler<'c, 'h> for DokanFs<B>
+where
+    B: BkFileSystem + Send + Sync + 'h,
+{
+    type Context = Handle;
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_file(
+        &'h self,
+        file_name: &U16CStr,
+        _security_context: &DOKAN_IO_SECURITY_CONTEXT,
+        _desired_access: ACCESS_MASK,
+        _file_attributes: u32,
+        _share_access: u32,
+        create_disposition: u32,
+        create_options: u32,
+        _info: &mut OperationInfo<'c, 'h, Self>,
+    ) -> OperationResult<CreateFileInfo<Self::Context>> {
+        let path = split_path(file_name)?;
+        let fs = self.fs()?;
+        let generation = fs.generation();
+        match resolve(&*fs, &path) {
+            Ok(inode) => {
+                let is_dir = inode == ROOT_INODE || fs.metadata(inode).is_some_and(|e| e.is_dir());
+                if create_disposition == FILE_CREATE {
+                    return Err(STATUS_OBJECT_NAME_COLLISION);
+                }
+                if is_dir && create_options & FILE_NON_DIRECTORY_FILE != 0 {
+                    return Err(STATUS_FILE_IS_A_DIRECTORY);
+                }
+                if !is_dir && create_options & FILE_DIRECTORY_FILE != 0 {
+                    return Err(STATUS_NOT_A_DIRECTORY);
+                }
+                drop(fs);
+                if matches!(
+                    create_disposition,
+                    FILE_OVERWRITE | FILE_OVERWRITE_IF | FILE_SUPERSEDE
+                ) {
+                    if is_dir {
+                        return Err(STATUS_FILE_IS_A_DIRECTORY);
+                    }
+                    self.fs_write().truncate(inode, 0).map_err(status)?;
+                }
+                Ok(CreateFileInfo {
+                    context: Handle::new(inode, is_dir, generation),
+                    is_dir,
+                    new_file_created: false,
+                })
+            }
+            Err(STATUS_OBJECT_NAME_NOT_FOUND)
+                if matches!(
+                    create_disposition,
+                    FILE_CREATE | FILE_OPEN_IF | FILE_OVERWRITE_IF | FILE_SUPERSEDE
+                ) =>
+            {
+                // каталогов трейт не создает
+                if create_options & FILE_DIRECTORY_FILE != 0 {
+                    return Err(STATUS_NOT_IMPLEMENTED);
+                }
+                let (name, parent_path) = path.split_last().ok_or(STATUS_OBJECT_NAME_INVALID)?;
+                let parent = resolve(&*fs, parent_path)?;
+                drop(fs);
+                let mut fs = self.fs_write();
+                let inode = fs.create(parent, name).map_err(status)?;
+                info!(inode, name = name.as_str(), "Created");
+                Ok(CreateFileInfo {
+                    context: Handle::new(inode, false, fs.generation()),
+                    is_dir: false,
+                    new_file_created: true,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn cleanup(
+        &'h self,
+        file_name: &U16CStr,
+        info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) {
+        if info.delete_on_close() {
+            // удаление только помечается в delete_file, делается на закрытии
+            let result = split_path(file_name).and_then(|path| {
+                let (name, parent_path) = path.split_last().ok_or(STATUS_ACCESS_DENIED)?;
+                let mut fs = self.fs_write();
+                let parent = resolve(&*fs, parent_path)?;
+                fs.unlink(parent, name).map_err(status)
+            });
+            if let Err(e) = result {
+                warn!("Can't delete {}: {:#x}", file_name.to_string_lossy(), e);
+            }
+            return;
+        }
+        // заголовок видно только в файле целиком, поэтому на закрытии
+        if self.parse_bin && context.written.load(Ordering::Relaxed) {
+            match self.fs_write().strip_bin_header(context.inode) {
+                Ok(Some(header)) => info!(inode = context.inode, ?header, ".bin header stripped"),
+                Ok(None) => {}
+                Err(e) => warn!("Can't strip .bin header of inode {}: {}", context.inode, e),
+            }
+        }
+    }
+
+    fn read_file(
+        &'h self,
+        _file_name: &U16CStr,
+        offset: i64,
+        buffer: &mut [u8],
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<u32> {
+        let offset = u64::try_from(offset).map_err(|_| STATUS_INVALID_PARAMETER)?;
+        let fs = self.fs()?;
+        context.check_file(&*fs)?;
+        let size = fs
+            .read_into(context.inode, offset, buffer)
+            .map_err(status)?;
+        Ok(size as u32)
+    }
+
+    fn write_file(
+        &'h self,
+        _file_name: &U16CStr,
+        offset: i64,
+        buffer: &[u8],
+        info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<u32> {
+        let offset = {
+            let fs = self.fs()?;
+            context.check_file(&*fs)?;
+            if info.write_to_end_of_file() {
+                fs.metadata(context.inode).map_or(0, |entry| entry.size)
+            } else {
+                u64::try_from(offset).map_err(|_| STATUS_INVALID_PARAMETER)?
+            }
+        };
+        // запись меняет каталог, поэтому идет под блокировкой на запись
+        let written = self
+            .fs_write()
+            .write(context.inode, offset, buffer)
+            .map_err(status)?;
+        context.written.store(true, Ordering::Relaxed);
+        Ok(written as u32)
+    }
+
+    fn flush_file_buffers(
+        &'h self,
+        _file_name: &U16CStr,
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<()> {
+        // если через этот handle не писали, блокировку на запись не берем
+        if !context.written.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.fs_write().flush().map_err(status)
+    }
+
+    fn get_file_information(
+        &'h self,
+        _file_name: &U16CStr,
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<FileInfo> {
+        let fs = self.fs()?;
+        self.file_info(&*fs, context.inode)
+    }
+
+    fn find_files(
+        &'h self,
+        _file_name: &U16CStr,
+        mut fill_find_data: impl FnMut(&FindData) -> FillDataResult,
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<()> {
+        if !context.is_dir {
+            return Err(STATUS_NOT_A_DIRECTORY);
+        }
+        let fs = self.fs()?;
+        for entry in fs.list(context.inode).map_err(status)? {
+            let time = self.entry_time(&*fs, Some(&entry));
+            let file_name = match U16CString::from_str(entry.name) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            fill_find_data(&FindData {
+                attributes: Self::attributes(&*fs, Some(&entry)),
+                creation_time: time,
+                last_access_time: time,
+                last_write_time: time,
+                file_size: if entry.is_dir() { 0 } else { entry.size },
+                file_name,
+            })
+            .or_else(ignore_name_too_long)?;
+        }
+        Ok(())
+    }
+
+    fn set_end_of_file(
+        &'h self,
+        _file_name: &U16CStr,
+        offset: i64,
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<()> {
+        let size = u64::try_from(offset).map_err(|_| STATUS_INVALID_PARAMETER)?;
+        context.check_file(&*self.fs()?)?;
+        self.fs_write()
+            .truncate(context.inode, size)
+            .map_err(status)?;
+        context.written.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_allocation_size(
+        &'h self,
+        _file_name: &U16CStr,
+        alloc_size: i64,
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<()> {
+        let alloc_size = u64::try_from(alloc_size).map_err(|_| STATUS_INVALID_PARAMETER)?;
+        let size = {
+            let fs = self.fs()?;
+            context.check_file(&*fs)?;
+            fs.metadata(context.inode).map_or(0, |entry| entry.size)
+        };
+        // место заранее не выделяем, только обрезаем файл
+        if alloc_size < size {
+            self.fs_write()
+                .truncate(context.inode, alloc_size)
+                .map_err(status)?;
+            context.written.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn delete_file(
+        &'h self,
+        _file_name: &U16CStr,
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<()> {
+        let fs = self.fs()?;
+        if fs.is_read_only() {
+            return Err(STATUS_MEDIA_WRITE_PROTECTED);
+        }
+        context.check_file(&*fs)
+    }
+
+    fn delete_directory(
+        &'h self,
+        _file_name: &U16CStr,
+        _info: &OperationInfo<'c, 'h, Self>,
+        context: &'c Self::Context,
+    ) -> OperationResult<()> {
+        let fs = self.fs()?;
+        if fs.is_read_only() {
+            return Err(STATUS_MEDIA_WRITE_PROTECTED);
+        }
+        if context.inode == ROOT_INODE {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+        if !fs.list(context.inode).map_err(status)?.is_empty() {
+            return Err(STATUS_DIRECTORY_NOT_EMPTY);
+        }
+        Ok(())
+    }
+
+    fn move_file(
+        &'h self,
+        file_name: &U16CStr,
+        new_file_name: &U16CStr,
+        replace_if_existing: bool,
+        _info: &OperationInfo<'c, 'h, Self>,
+        _context: &'c Self::Context,
+    ) -> OperationResult<()> {
+        let path = split_path(file_name)?;
+        let new_path = split_path(new_file_name)?;
+        let ((name, parent_path), (new_name, new_parent_path)) =
+            match (path.split_last(), new_path.split_last()) {
+                (Some(old), Some(new)) => (old, new),
+                _ => return Err(STATUS_ACCESS_DENIED),
+            };
+        let mut fs = self.fs_write();
+        let parent = resolve(&*fs, parent_path)?;
+        // перенос между каталогами не делаем, Explorer сам скопирует и удалит
+        if resolve(&*fs, new_parent_path)? != parent {
+            return Err(STATUS_NOT_SAME_DEVICE);
+        }
+        // имя файла на томе, а не как его набрал пользователь
+        let name = lookup(&*fs, parent, name)
+            .ok_or(STATUS_OBJECT_NAME_NOT_FOUND)?
+            .name
+            .to_string();
+        fs.rename(parent, &name, new_name, replace_if_existing)
+            .map_err(status)
+    }
+
+    fn get_disk_free_space(
+        &'h self,
+        _info: &OperationInfo<'c, 'h, Self>,
+    ) -> OperationResult<DiskSpaceInfo> {
+        let fs = self.fs()?;
+        let stats = fs.stats();
+        let block_size = u64::from(fs.block_size());
+        Ok(DiskSpaceInfo {
+            byte_count: stats.blocks * block_size,
+            free_byte_count: stats.free * block_size,
+            available_byte_count: stats.free * block_size,
+        })
+    }
+
+    fn get_volume_information(
+        &'h self,
+        _info: &OperationInfo<'c, 'h, Self>,
+    ) -> OperationResult<VolumeInfo> {
+        let fs = self.fs()?;
+        let mut fs_flags = FILE_CASE_PRESERVED_NAMES | FILE_UNICODE_ON_DISK;
+        if fs.is_read_only() {
+            fs_flags |= FILE_READ_ONLY_VOLUME;
+        }
+        Ok(VolumeInfo {
+            name: U16CString::from_str(&self.volume_name).map_err(|_| STATUS_INVALID_PARAMETER)?,
+            serial_number: 0,
+            max_component_length: fs.stats().namelen,
+            fs_flags,
+            fs_name: U16CString::from_str(&self.fs_name).map_err(|_| STATUS_INVALID_PARAMETER)?,
+        })
+    }
+
+    fn mounted(
+        &'h self,
+        mount_point: &U16CStr,
+        _info: &OperationInfo<'c, 'h, Self>,
+    ) -> OperationResult<()> {
+        info!("Mounted at {}", mount_point.to_string_lossy());
+        Ok(())
+    }
+
+    fn unmounted(&'h self, _info: &OperationInfo<'c, 'h, Self>) -> OperationResult<()> {
+        info!("Unmounted");
+        let mut fs = self.fs_write();
+        if let Err(e) = fs.flush() {
+            warn!("Can't flush volume: {}", e);
+        }
+        fs.destroy();
+        Ok(())
+    }
+}
@@ -0,0 +1,449 @@
+#[cfg(all(windows, feature = "dokan"))]
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(all(windows, feature = "dokan"))]
+use bkfs::BkFileSystem;
+#[cfg(all(windows, feature = "dokan"))]
+use clap::{crate_authors, crate_name, crate_version, App, Arg};
+#[cfg(all(windows, feature = "dokan"))]
+use color_eyre::eyre::{eyre, Result};
+#[cfg(all(windows, feature = "dokan"))]
+use dokan::{FileSystemMounter, MountFlags, MountOptions};
+#[cfg(all(windows, feature = "dokan"))]
+use tracing::{info, warn};
+#[cfg(all(windows, feature = "dokan"))]
+use tracing_subscriber::EnvFilter;
+#[cfg(all(windows, feature = "dokan"))]
+use widestring::U16CString;
+
+#[cfg(all(windows, feature = "dokan"))]
+use dokan_mkdosfs::DokanFs;
+#[cfg(all(windows, feature = "dokan"))]
+use mkdosfs::{
+    container::{self, InputFormat, INPUT_FORMATS},
+    AndosFs, Encoding, Fs, FsError, FsKind, GeometryMapper, Rt11Fs,
+};
+#[cfg(all(windows, feature = "dokan"))]
+use time::{
+    format_description::{well_known::Rfc3339, FormatItem},
+    macros::format_description,
+    Date, OffsetDateTime,
+};
+
+#[cfg(all(windows, feature = "dokan"))]
+const DATE_FORMAT: &[FormatItem<'_>] = format_description!("[year]-[month]-[day]");
+
+/// Settings of `DokanFs` from command line
+#[derive(Debug)]
+#[cfg(all(windows, feature = "dokan"))]
+struct MountSettings {
+    parse_bin: bool,
+    fake_date: Option<SystemTime>,
+    label: Option<String>,
+}
+
+#[cfg(all(windows, feature = "dokan"))]
+fn main() -> Result<()> {
+    let matches = App::new(crate_name!())
+        .version(crate_version!())
+        .author(crate_authors!())
+        .arg(
+            Arg::new("IMAGE_NAME")
+                .required(true)
+                .index(1)
+                .help("MKDOS disk image file path"),
+        )
+        .arg(
+            Arg::new("MOUNT_POINT")
+                .required(true)
+                .index(2)
+                .help("Mount image at drive letter (M:) or empty NTFS directory"),
+        )
+        .arg(
+            Arg::new("removable")
+                .long("removable")
+                .help("Show volume as removable drive"),
+        )
+        .arg(
+            Arg::new("single-thread")
+                .long("single-thread")
+                .help("Serve requests in one thread"),
+        )
+        .arg(
+            Arg::new("label")
+                .long("label")
+                .takes_value(true)
+                .value_name("LABEL")
+                .help("Volume label shown by Explorer [default: BK]"),
+        )
+        .arg(
+            Arg::new("rw")
+                .long("rw")
+                .help("Mount image read-write (changes are written to the image)"),
+        )
+        .arg(
+            Arg::new("show-bad")
+                .long("show-bad")
+                .help("Enable show bad files (areas marked as bad blocks)"),
+        )
+        .arg(
+            Arg::new("show-deleted")
+                .long("show-deleted")
+                .help("Show deleted files in virtual directory .deleted"),
+        )
+        .arg(
+            Arg::new("logical-dirs")
+                .long("logical-dirs")
+                .help("Show logical disks as directories with their files (read only)"),
+        )
+        .arg(
+            Arg::new("offset")
+                .long("offset")
+                .alias("base")
+                .short('o')
+                .takes_value(true)
+                .requires("size")
+                .validator(parse_int::<u64>)
+                .value_name("OFFSET")
+                .help("Offset from start of image in blocks (HDD images: see --partition)"),
+        )
+        .arg(
+            Arg::new("size")
+                .long("size")
+                .short('s')
+                .requires("offset")
+                .takes_value(true)
+                .validator(parse_int::<u64>)
+                .value_name("SIZE")
+                .help("Size of image in blocks"),
+        )
+        .arg(
+            Arg::new("partition")
+                .long("partition")
+                .short('p')
+                .takes_value(true)
+                .conflicts_with_all(&["offset", "size", "inverted"])
+                .validator(parse_int::<usize>)
+                .value_name("N")
+                .help("Mount partition N of HDD image (raw or HDI, AltPro or Samara)"),
+        )
+        .arg(
+            Arg::new("andos")
+                .long("andos")
+                .conflicts_with_all(&["rw", "logical-dirs"])
+                .help("Mount ANDOS (FAT12) disk image (read only, detected by default)"),
+        )
+        .arg(
+            Arg::new("rt11")
+                .long("rt11")
+                .conflicts_with_all(&["andos", "rw", "logical-dirs"])
+                .help("Mount RT-11 disk image or HDD partition (read only, detected by default)"),
+        )
+        .arg(
+            Arg::new("parse-bin")
+                .long("parse-bin")
+                .requires("rw")
+                .help("Strip BK .bin header from written files, load address goes to catalog"),
+        )
+        .arg(
+            Arg::new("fake-date")
+                .long("fake-date")
+                .takes_value(true)
+                .validator(|s| parse_date(s).map_err(|e| e.to_string()))
+                .value_name("DATE")
+                .help(
+                    "Show this time (1979-01-29 or RFC 3339) for all files instead of image time",
+                ),
+        )
+        .arg(
+            Arg::new("scan-full-catalog")
+                .long("scan-full-catalog")
+                .conflicts_with("rw")
+                .help("Read catalog past blank slots up to the start block (damaged images)"),
+        )
+        .arg(
+            Arg::new("inverted")
+                .long("use-inverted")
+                .short('i')
+                .help("Force inverted reader (detected by signatures by default)"),
+        )
+        .arg(
+            Arg::new("byte-swap")
+                .long("byte-swap")
+                .conflicts_with("partition")
+                .help("Swap bytes in every 16-bit word (dumps of some HDD controllers)"),
+        )
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .short('e')
+                .takes_value(true)
+                .possible_values(["koi8r", "cp866", "translit"])
+                .default_value("koi8r")
+                .value_name("ENCODING")
+                .help("File names encoding (translit shows cyrillic names in ASCII)"),
+        )
+        .arg(
+            Arg::new("input-format")
+                .long("input-format")
+                .takes_value(true)
+                .possible_values(INPUT_FORMATS)
+                .default_value("auto")
+                .value_name("FORMAT")
+                .help("Image container: raw sectors, Teledisk, ImageDisk or HxC (read only)"),
+        )
+        .arg(
+            Arg::new("geometry")
+                .long("geometry")
+                .takes_value(true)
+                .conflicts_with("partition")
+                .validator(|s| s.parse::<GeometryMapper>().map(|_| ()))
+                .value_name("GEOMETRY")
+                .help("Remap blocks of side by side image: TRACKS,SIDES,SECTORS[,INTERLEAVE]"),
+        )
+        .arg(
+            Arg::new("cache-blocks")
+                .long("cache-blocks")
+                .takes_value(true)
+                .validator(parse_int::<usize>)
+                .value_name("BLOCKS")
+                .help("Image block cache size in blocks, 0 disables the cache"),
+        )
+        .arg(
+            Arg::new("check-interval")
+                .long("check-interval")
+                .takes_value(true)
+                .validator(parse_int::<u64>)
+                .value_name("MS")
+                .help("Check image for changes not more often than once per MS milliseconds"),
+        )
+        .arg(
+            Arg::new("no-lock")
+                .long("no-lock")
+                .help("Don't lock image file (shared lock read only, exclusive read-write)"),
+        )
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("With --rw write changes to FILE, image stays untouched"),
+        )
+        .get_matches();
+
+    setup_logging()?;
+
+    let imagename = matches.value_of("IMAGE_NAME").unwrap();
+    let mountpoint = matches.value_of("MOUNT_POINT").unwrap();
+    let read_only = !matches.is_present("rw");
+    let mut flags = MountFlags::MOUNT_MANAGER;
+    if read_only {
+        flags |= MountFlags::WRITE_PROTECT;
+    }
+    if matches.is_present("removable") {
+        flags |= MountFlags::REMOVABLE;
+    }
+    let options = MountOptions {
+        single_thread: matches.is_present("single-thread"),
+        flags,
+        ..Default::default()
+    };
+    let encoding = matches.value_of("encoding").unwrap().parse::<Encoding>()?;
+
+    let settings = MountSettings {
+        parse_bin: matches.is_present("parse-bin"),
+        fake_date: match matches.value_of("fake-date") {
+            Some(date) => Some(parse_date(date)?),
+            None => None,
+        },
+        label: matches.value_of("label").map(str::to_string),
+    };
+
+    // сжатый образ, TD0, IMD и HFE монтируем из копии, только на чтение
+    let input_format = matches.value_of_t::<InputFormat>("input-format")?;
+    let unpacked = container::prepared_image(imagename, input_format)?;
+    if unpacked.is_some() && !read_only {
+        return Err(eyre!(
+            "{} is not a raw image, it can be mounted only read only",
+            imagename
+        ));
+    }
+    let unpacked = unpacked.map(|path| path.to_string_lossy().into_owned());
+    let imagename = unpacked.as_deref().unwrap_or(imagename);
+
+    let mut fs = Fs::new(imagename);
+
+    if !read_only {
+        fs.set_read_only(false);
+    }
+    if matches.is_present("show-bad") {
+        fs.set_read_bad(true);
+    }
+    if matches.is_present("show-deleted") {
+        fs.set_read_deleted(true);
+    }
+    if matches.is_present("inverted") {
+        fs.set_inverted(true);
+    }
+    if matches.is_present("byte-swap") {
+        fs.set_swapped(true);
+    }
+    if matches.is_present("geometry") {
+        fs.set_geometry(Some(matches.value_of_t::<GeometryMapper>("geometry")?));
+    }
+    if matches.is_present("logical-dirs") {
+        fs.set_open_logical(true);
+    }
+    if matches.is_present("scan-full-catalog") {
+        fs.set_scan_full_catalog(true);
+    }
+    fs.set_encoding(encoding);
+    if matches.is_present("cache-blocks") {
+        let blocks = matches.value_of("cache-blocks").unwrap().parse::<usize>()?;
+        fs.set_cache_blocks(blocks);
+    }
+    fs.set_lock(!matches.is_present("no-lock"));
+    fs.set_overlay(matches.value_of("overlay").map(PathBuf::from));
+    if matches.is_present("check-interval") {
+        let ms = matches.value_of("check-interval").unwrap().parse::<u64>()?;
+        fs.set_check_interval(Duration::from_millis(ms));
+    }
+
+    if matches.is_present("offset") {
+        let offset = matches.value_of("offset").unwrap().parse::<u64>()?;
+        fs.set_offset_blocks(offset);
+        let size = matches.value_of("size").unwrap().parse::<u64>()?;
+        fs.set_size_blocks(size);
+    }
+    if matches.is_present("partition") {
+        let partition = matches.value_of("partition").unwrap().parse::<usize>()?;
+        fs.set_partition(partition)?;
+    } else if fs.skip_hdi_header()? {
+        info!("HDI header skipped");
+    }
+
+    // формат не указан - смотрим на сигнатуры
+    let kind = match () {
+        _ if matches.is_present("andos") => FsKind::Andos,
+        _ if matches.is_present("rt11") => FsKind::Rt11,
+        // инверсию раздела знает таблица, флаг главнее сигнатур
+        _ if matches.is_present("partition") || matches.is_present("inverted") => fs.detect()?,
+        _ => fs.detect_inverted()?,
+    };
+    info!(%kind, "Filesystem");
+    match kind {
+        FsKind::Andos | FsKind::Rt11 if !read_only => {
+            Err(eyre!("{} can be mounted only read only", kind))
+        }
+        FsKind::Andos => {
+            let mut andos = AndosFs::new(imagename);
+            andos.set_encoding(encoding);
+            andos.set_offset_blocks(fs.offset_blocks());
+            andos.set_inverted(fs.is_inverted());
+            andos.set_swapped(fs.is_swapped());
+            andos.set_geometry(fs.geometry().cloned());
+            info!("Starting");
+            andos.try_open()?;
+            mount(andos, kind, &settings, mountpoint, &options)
+        }
+        FsKind::Rt11 => {
+            let mut rt11 = Rt11Fs::new(imagename);
+            rt11.set_offset_blocks(fs.offset_blocks());
+            rt11.set_size_blocks(fs.size_blocks());
+            rt11.set_inverted(fs.is_inverted());
+            rt11.set_swapped(fs.is_swapped());
+            rt11.set_geometry(fs.geometry().cloned());
+            info!("Starting");
+            rt11.try_open()?;
+            mount(rt11, kind, &settings, mountpoint, &options)
+        }
+        FsKind::CsiDos => Err(FsError::Unsupported(kind).into()),
+        // неизвестное открываем как MK-DOS, ошибка будет понятнее
+        FsKind::MkDos | FsKind::Unknown => {
+            info!("Starting");
+            fs.try_open()?;
+            if let Err(e) = fs.watch() {
+                warn!("Can't watch image, polling it: {}", e);
+            }
+            mount(fs, FsKind::MkDos, &settings, mountpoint, &options)
+        }
+    }
+}
+
+/// Date of `--fake-date`: RFC 3339 or just a date (midnight UTC)
+#[cfg(all(windows, feature = "dokan"))]
+fn parse_date(s: &str) -> Result<SystemTime, time::error::Parse> {
+    let date = match OffsetDateTime::parse(s, &Rfc3339) {
+        Ok(date) => date,
+        Err(_) => Date::parse(s, DATE_FORMAT)?.midnight().assume_utc(),
+    };
+    Ok(date.into())
+}
+
+/// Validator for integer arguments: `.validator(parse_int::<u64>)`
+#[cfg(all(windows, feature = "dokan"))]
+fn parse_int<T>(s: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    s.parse::<T>()
+        .map_err(|e| format!("value must be an integer: {}", e))
+}
+
+/// Mount volume `fs` with generic Dokan driver, returns after unmount
+/// (`dokanctl /u M:` or Explorer)
+#[cfg(all(windows, feature = "dokan"))]
+fn mount<B>(
+    fs: B,
+    kind: FsKind,
+    settings: &MountSettings,
+    mountpoint: &str,
+    options: &MountOptions,
+) -> Result<()>
+where
+    B: BkFileSystem + Send + Sync,
+{
+    let mut fs = DokanFs::new(fs);
+    fs.set_parse_bin(settings.parse_bin);
+    fs.set_fake_date(settings.fake_date);
+    fs.set_names(settings.label.as_deref().unwrap_or("BK"), &kind.to_string());
+    let mountpoint = U16CString::from_str(mountpoint).map_err(|e| eyre!(e))?;
+
+    dokan::init();
+    let result = FileSystemMounter::new(&fs, &mountpoint, options)
+        .mount()
+        // drop ждет, пока том не размонтируют
+        .map(drop);
+    dokan::shutdown();
+    result.map_err(|e| eyre!("Dokan mount error: {}", e))
+}
+
+#[cfg(all(windows, feature = "dokan"))]
+fn setup_logging() -> Result<()> {
+    if std::env::var("RUST_LIB_BACKTRACE").is_err() {
+        std::env::set_var("RUST_LIB_BACKTRACE", "full");
+    }
+    color_eyre::install()?;
+
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    tracing_subscriber::fmt::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    Ok(())
+}
+
+#[cfg(not(all(windows, feature = "dokan")))]
+fn main() {
+    eprintln!(
+        "{} needs Dokan: build it on Windows with feature dokan",
+        env!("CARGO_PKG_NAME")
+    );
+    std::process::exit(1);
+}
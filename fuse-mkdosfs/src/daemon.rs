@@ -0,0 +1,164 @@
+//! Уход в фон, как у обычного mount хелпера
+//!
+//! fork делаем до открытия образа: потоки (пул, слежение за образом)
+//! после fork не переживут. Родитель ждет по pipe, пока потомок
+//! смонтирует том, и выходит с 0 или 1 - mount(8) и fstab видят результат.
+//! Пока монтирования нет, ошибки потомка идут в тот же терминал.
+
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::PathBuf,
+};
+
+use tracing::{warn, Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Background process waiting for mount, see `daemonize()`
+#[derive(Debug)]
+pub struct Daemon {
+    /// write end of pipe to the parent, `None` after `ready()`
+    notify: Option<File>,
+    pidfile: Option<PathBuf>,
+}
+
+fn last_error<T>(ret: T) -> io::Result<T>
+where
+    T: PartialOrd + Default,
+{
+    if ret < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Fork to background. Returns in the child only, the parent exits
+/// when the child calls `Daemon::ready()` (status 0) or dies (status 1)
+pub fn daemonize(pidfile: Option<PathBuf>) -> io::Result<Daemon> {
+    let mut fds = [0; 2];
+    last_error(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (mut rx, tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    if last_error(unsafe { libc::fork() })? > 0 {
+        drop(tx);
+        // потомок пишет один байт после монтирования, EOF - он умер раньше
+        let mut status = [0u8; 1];
+        let code = match rx.read(&mut status) {
+            Ok(1) if status[0] == 0 => 0,
+            _ => 1,
+        };
+        std::process::exit(code);
+    }
+
+    drop(rx);
+    last_error(unsafe { libc::setsid() })?;
+    Ok(Daemon {
+        notify: Some(tx),
+        pidfile,
+    })
+}
+
+impl Daemon {
+    /// Volume is mounted: write pidfile, let the parent exit and detach
+    /// from the terminal
+    pub fn ready(&mut self) -> io::Result<()> {
+        if let Some(pidfile) = self.pidfile.as_ref() {
+            fs::write(pidfile, format!("{}\n", std::process::id()))?;
+        }
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            last_error(unsafe { libc::dup2(null.as_raw_fd(), fd) })?;
+        }
+        std::env::set_current_dir("/")?;
+        if let Some(mut notify) = self.notify.take() {
+            notify.write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if self.notify.is_some() {
+            return;
+        }
+        if let Some(pidfile) = self.pidfile.as_ref() {
+            if let Err(e) = fs::remove_file(pidfile) {
+                warn!("Can't remove pidfile {:?}: {}", pidfile, e);
+            }
+        }
+    }
+}
+
+/// `MakeWriter` for tracing_subscriber sending every event to syslog
+#[derive(Debug)]
+pub struct Syslog;
+
+impl Syslog {
+    /// Open syslog with ident `fuse-mkdosfs`
+    pub fn open() -> Self {
+        unsafe { libc::openlog(c"fuse-mkdosfs".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+        Self
+    }
+}
+
+/// Одно событие tracing, уходит в syslog целиком при drop
+#[derive(Debug)]
+pub struct SyslogWriter {
+    priority: libc::c_int,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogWriter {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.buf).replace('\0', " ");
+        let text = text.trim_end();
+        if text.is_empty() {
+            return;
+        }
+        if let Ok(msg) = CString::new(text) {
+            unsafe { libc::syslog(self.priority, c"%s".as_ptr(), msg.as_ptr()) };
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogWriter {
+            priority: libc::LOG_INFO,
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let priority = match *meta.level() {
+            Level::ERROR => libc::LOG_ERR,
+            Level::WARN => libc::LOG_WARNING,
+            Level::INFO => libc::LOG_INFO,
+            Level::DEBUG | Level::TRACE => libc::LOG_DEBUG,
+        };
+        SyslogWriter {
+            priority,
+            buf: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Таблица открытых файлов `FuseFs`
+//!
+//! fh выдается на каждый open/create и живет до release. В записи
+//! поколение тома на момент открытия: после перечитывания образа инод мог
+//! достаться другому файлу, такой fh протух (ESTALE).
+
+use std::collections::HashMap;
+
+/// File opened by open/create
+#[derive(Debug, Clone, Copy)]
+pub struct OpenFile {
+    pub inode: u64,
+    /// open(2) flags
+    pub flags: i32,
+    /// generation of inode's volume at open, see `BkFileSystem::inode_generation()`
+    pub generation: u64,
+    /// end of last read or write
+    pub pos: u64,
+    /// file was written through this handle
+    pub written: bool,
+}
+
+#[derive(Debug)]
+pub struct FileHandles {
+    /// 0 не выдаем: его получают каталоги
+    next_fh: u64,
+    files: HashMap<u64, OpenFile>,
+}
+
+impl Default for FileHandles {
+    fn default() -> Self {
+        Self {
+            next_fh: 1,
+            files: HashMap::new(),
+        }
+    }
+}
+
+impl FileHandles {
+    /// Register opened file, returns its fh
+    pub fn open(&mut self, inode: u64, flags: i32, generation: u64) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.files.insert(
+            fh,
+            OpenFile {
+                inode,
+                flags,
+                generation,
+                pos: 0,
+                written: false,
+            },
+        );
+        fh
+    }
+
+    /// Open file `fh`, `None` if it is not opened or belongs to other inode
+    pub fn get_mut(&mut self, fh: u64, inode: u64) -> Option<&mut OpenFile> {
+        self.files.get_mut(&fh).filter(|f| f.inode == inode)
+    }
+
+    /// Forget file `fh`, returns its last state
+    pub fn release(&mut self, fh: u64) -> Option<OpenFile> {
+        self.files.remove(&fh)
+    }
+
+    /// Number of opened files
+    pub fn count(&self) -> usize {
+        self.files.len()
+    }
+}
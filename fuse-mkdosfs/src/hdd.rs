@@ -0,0 +1,255 @@
+//! Весь жесткий диск одним томом: каталог `partN` на каждый раздел MK-DOS
+//!
+//! Каждый раздел открывается своим `Fs`. Чтобы иноды разных разделов
+//! не пересекались, в старших 32 битах лежит номер открытого раздела + 1,
+//! в младших - инод внутри раздела. Корень раздела (`ROOT_INODE` в его `Fs`)
+//! и есть каталог `partN`. Только для чтения.
+
+use std::{path::Path, time::SystemTime};
+
+use bkfs::{BinHeader, BkFileSystem, Entry, ErrorKind, FileKind, VolumeStats, ROOT_INODE};
+use bkhdd::HDI;
+use mkdosfs::{Encoding, Fs, FsError, InodeStrategy, LookupPolicy};
+use tracing::{info, warn};
+
+/// Раздел диска с MK-DOS
+#[derive(Debug)]
+struct HddPart {
+    name: String,
+    fs: Fs,
+}
+
+#[derive(Debug)]
+pub struct HddFs {
+    /// path to image
+    file_path: String,
+    /// Enable show bad files
+    show_bad: bool,
+    /// Enable show deleted
+    show_deleted: bool,
+    encoding: Encoding,
+    lookup_policy: LookupPolicy,
+    inode_strategy: InodeStrategy,
+    /// shared lock of image file
+    lock: bool,
+    /// opened MK-DOS partitions
+    parts: Vec<HddPart>,
+    _tracing_span: tracing::Span,
+}
+
+impl Default for HddFs {
+    fn default() -> Self {
+        Self {
+            file_path: String::default(),
+            show_bad: false,
+            show_deleted: false,
+            encoding: Encoding::default(),
+            lookup_policy: LookupPolicy::default(),
+            inode_strategy: InodeStrategy::default(),
+            lock: true,
+            parts: Vec::new(),
+            _tracing_span: tracing::span!(tracing::Level::TRACE, "HddFs"),
+        }
+    }
+}
+
+impl HddFs {
+    pub fn new(fname: &str) -> Self {
+        Self {
+            file_path: fname.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Open all MK-DOS partitions of HDD image (others are skipped)
+    pub fn try_open(&mut self) -> Result<(), FsError> {
+        let mut hdi = HDI::new(&self.file_path);
+        hdi.try_open()?;
+        info!(controller = ?hdi.controller(), is_hdi = hdi.is_hdi, "HDD image");
+
+        self.parts = Vec::new();
+        for n in 0..hdi.partitions().len() {
+            let loc = match hdi.partition_location(n) {
+                Some(loc) => loc,
+                None => continue,
+            };
+            let mut fs = Fs::new(&self.file_path);
+            fs.set_offset_blocks(loc.offset);
+            fs.set_size_blocks(loc.size);
+            fs.set_inverted(loc.inverted);
+            fs.set_encoding(self.encoding);
+            fs.set_lookup_policy(self.lookup_policy);
+            fs.set_inode_strategy(self.inode_strategy);
+            fs.set_read_deleted(self.show_deleted);
+            fs.set_read_bad(self.show_bad);
+            fs.set_lock(self.lock);
+            match fs.try_open() {
+                Ok(()) => self.parts.push(HddPart {
+                    name: format!("part{}", n),
+                    fs,
+                }),
+                Err(e) => info!("Partition {} skipped (not MK-DOS?): {}", n, e),
+            }
+        }
+        if self.parts.is_empty() {
+            warn!("No MK-DOS partitions found");
+        }
+
+        Ok(())
+    }
+
+    pub fn show_bad(&mut self, arg: bool) {
+        self.show_bad = arg;
+    }
+
+    pub fn show_deleted(&mut self, arg: bool) {
+        self.show_deleted = arg;
+    }
+
+    /// File names encoding (used on next open)
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// How names are matched in lookup, see `Fs::set_lookup_policy()`
+    /// (used on next open)
+    pub fn set_lookup_policy(&mut self, policy: LookupPolicy) {
+        self.lookup_policy = policy;
+    }
+
+    /// How file inodes are assigned, see `Fs::set_inode_strategy()`
+    /// (used on next open)
+    pub fn set_inode_strategy(&mut self, strategy: InodeStrategy) {
+        self.inode_strategy = strategy;
+    }
+
+    /// Shared lock of image file, see `Fs::set_lock()` (used on next open)
+    pub fn set_lock(&mut self, lock: bool) {
+        self.lock = lock;
+    }
+
+    /// Names of opened partitions (mount directories)
+    pub fn partitions(&self) -> Vec<&str> {
+        self.parts.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    fn global_inode(idx: usize, inode: u64) -> u64 {
+        (idx as u64 + 1) << 32 | inode
+    }
+
+    /// Раздел и инод внутри него, `None` для корня монтирования
+    fn part_inode(&self, ino: u64) -> Option<(usize, u64)> {
+        let idx = (ino >> 32) as usize;
+        if idx == 0 || idx > self.parts.len() {
+            return None;
+        }
+        Some((idx - 1, ino & 0xffff_ffff))
+    }
+
+    /// Каталог `partN`
+    fn part_dir(&self, idx: usize) -> Entry<'_> {
+        Entry {
+            inode: Self::global_inode(idx, ROOT_INODE),
+            parent_inode: ROOT_INODE,
+            name: &self.parts[idx].name,
+            kind: FileKind::Directory,
+            size: 0,
+            blocks: 0,
+            mode: 0o555,
+            mtime: Some(self.parts[idx].fs.last_modified()),
+        }
+    }
+
+    /// Запись раздела с глобальными инодами, запись запрещена
+    fn part_entry<'a>(&self, idx: usize, entry: Entry<'a>) -> Entry<'a> {
+        Entry {
+            inode: Self::global_inode(idx, entry.inode),
+            parent_inode: Self::global_inode(idx, entry.parent_inode),
+            mode: entry.mode & !0o222,
+            mtime: Some(self.parts[idx].fs.last_modified()),
+            ..entry
+        }
+    }
+}
+
+impl BkFileSystem for HddFs {
+    fn lookup(&self, parent_inode: u64, name: &str) -> Option<Entry<'_>> {
+        if parent_inode == ROOT_INODE {
+            let idx = self.parts.iter().position(|p| p.name == name)?;
+            return Some(self.part_dir(idx));
+        }
+        let (idx, inode) = self.part_inode(parent_inode)?;
+        let entry = self.parts[idx].fs.lookup(inode, name)?;
+        Some(self.part_entry(idx, entry))
+    }
+
+    fn metadata(&self, inode: u64) -> Option<Entry<'_>> {
+        let (idx, inode) = self.part_inode(inode)?;
+        if inode == ROOT_INODE {
+            return Some(self.part_dir(idx));
+        }
+        let entry = self.parts[idx].fs.metadata(inode)?;
+        Some(self.part_entry(idx, entry))
+    }
+
+    fn list(&self, inode: u64) -> bkfs::Result<Vec<Entry<'_>>> {
+        if inode == ROOT_INODE {
+            return Ok((0..self.parts.len())
+                .map(|idx| self.part_dir(idx))
+                .collect());
+        }
+        let (idx, inode) = self.part_inode(inode).ok_or(ErrorKind::NotFound)?;
+        Ok(self.parts[idx]
+            .fs
+            .list(inode)?
+            .into_iter()
+            .map(|e| self.part_entry(idx, e))
+            .collect())
+    }
+
+    fn read(&self, inode: u64, offset: u64, size: usize) -> bkfs::Result<Vec<u8>> {
+        let (idx, inode) = self.part_inode(inode).ok_or(ErrorKind::NotFound)?;
+        self.parts[idx].fs.read(inode, offset, size)
+    }
+
+    fn xattrs(&self, inode: u64) -> Vec<(String, Vec<u8>)> {
+        match self.part_inode(inode) {
+            Some((idx, inode)) if inode != ROOT_INODE => self.parts[idx].fs.xattrs(inode),
+            _ => Vec::new(),
+        }
+    }
+
+    fn bin_header(&self, inode: u64) -> Option<BinHeader> {
+        let (idx, inode) = self.part_inode(inode)?;
+        self.parts[idx].fs.bin_header(inode)
+    }
+
+    /// Whole disk image: every partition reads the whole file at its offset
+    fn snapshot(&self, dest: &Path) -> bkfs::Result<u64> {
+        let part = self.parts.first().ok_or(ErrorKind::Unavailable)?;
+        Ok(part.fs.snapshot(dest)?)
+    }
+
+    /// Sum of all partitions
+    fn stats(&self) -> VolumeStats {
+        self.parts.iter().map(|p| BkFileSystem::stats(&p.fs)).fold(
+            VolumeStats::default(),
+            |acc, s| VolumeStats {
+                blocks: acc.blocks + s.blocks,
+                free: acc.free + s.free,
+                files: acc.files + s.files,
+                free_files: acc.free_files + s.free_files,
+                namelen: s.namelen,
+            },
+        )
+    }
+
+    /// Latest of partitions
+    fn last_modified(&self) -> SystemTime {
+        self.parts
+            .iter()
+            .map(|p| p.fs.last_modified())
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+}
@@ -1,34 +1,96 @@
+//! Fuse драйвер для томов любого формата БК
+//!
+//! `FuseFs` работает только через `bkfs::BkFileSystem`: MK-DOS, ANDOS,
+//! RT-11 и весь жесткий диск (`HddFs`) монтируются одним и тем же кодом.
+//! Запись есть, только если ее умеет сам том.
+//!
+//! Только для unix: на других системах крейт пустой, а бинарник
+//! сообщает, что монтировать нечем.
+#![cfg(unix)]
+
 use libc::{ENOENT, ENOSYS};
 use std::{
+    borrow::Cow,
     ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
     time::{Duration as StdDuration, SystemTime as StdSystemTime, UNIX_EPOCH as STD_UNIX_EPOCH},
 };
-use time::macros::datetime;
 
+use bkfs::{BkFileSystem, Entry, ErrorKind, FileKind, BIN_HEADER_SIZE, ROOT_INODE};
 use fuser::{
+    consts::{FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO},
     FileType, Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData,
     ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek,
     ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use mkdosfs::{DirEntryStatus, Fs, FsError};
 
-use tracing::instrument;
+use tracing::{debug, info, instrument, trace, warn};
+
+use handles::FileHandles;
+use names::{escape_name, unescape_name};
+use pool::{BufferPool, ThreadPool};
+
+pub mod daemon;
+mod handles;
+pub mod hdd;
+pub mod mount_helper;
+pub mod multi;
+mod names;
+pub mod pool;
 
-const ED_UNIX_TIME: u64 = 286405200;
+pub use hdd::HddFs;
+pub use multi::MultiFs;
 
-pub fn from_direntry_status(status: DirEntryStatus) -> FileType {
-    use DirEntryStatus::*;
+const TTL: StdDuration = StdDuration::from_secs(10);
 
-    match status {
-        Normal | Protected | LogicalDisk => FileType::RegularFile,
-        Directory => FileType::Directory,
-        BadFile => FileType::RegularFile,
-        Deleted => FileType::RegularFile,
+/// Inode of the virtual file with raw volume contents, see `set_disk_file()`
+// с инодами томов не пересекается: они считаются от корня
+pub const DISK_INODE: u64 = u64::MAX - 1;
+/// Name of the raw volume file in the root directory
+pub const DISK_FILE_NAME: &str = ".disk";
+/// Inode of the control directory, see `set_control()`
+pub const CONTROL_INODE: u64 = u64::MAX - 2;
+/// Name of the control directory in the root directory
+pub const CONTROL_DIR_NAME: &str = ".control";
+/// Inode of the control file, a path written to it gets a snapshot of the image
+pub const SNAPSHOT_INODE: u64 = u64::MAX - 3;
+pub const SNAPSHOT_FILE_NAME: &str = "snapshot";
+
+/// Имя `name` из запроса ядра, каким его знает том: экранированное
+/// имя разворачивается, если записи с таким именем как есть нет
+fn volume_name<'n, B: BkFileSystem + ?Sized>(fs: &B, parent: u64, name: &'n str) -> Cow<'n, str> {
+    if fs.lookup(parent, name).is_some() {
+        return Cow::Borrowed(name);
+    }
+    match unescape_name(name) {
+        Some(unescaped) if fs.lookup(parent, &unescaped).is_some() => Cow::Owned(unescaped),
+        _ => Cow::Borrowed(name),
     }
 }
 
-fn systime_from_secs(secs: u64) -> StdSystemTime {
-    STD_UNIX_EPOCH + StdDuration::from_secs(secs)
+pub fn file_type(kind: FileKind) -> FileType {
+    match kind {
+        FileKind::File => FileType::RegularFile,
+        FileKind::Directory => FileType::Directory,
+    }
+}
+
+/// Map `bkfs::Error` to errno for fuse reply
+pub fn errno_from_error(err: &bkfs::Error) -> i32 {
+    match err.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::ReadOnly => libc::EROFS,
+        ErrorKind::Exists => libc::EEXIST,
+        ErrorKind::NameTooLong => libc::ENAMETOOLONG,
+        ErrorKind::BadName => libc::EINVAL,
+        ErrorKind::NoSpace => libc::ENOSPC,
+        ErrorKind::Protected => libc::EPERM,
+        ErrorKind::IsDirectory => libc::EISDIR,
+        ErrorKind::NotDirectory => libc::ENOTDIR,
+        ErrorKind::Unsupported => ENOSYS,
+        ErrorKind::Unavailable | ErrorKind::Io => libc::EIO,
+    }
 }
 
 const ROOT_DIR_ATTR: fuser::FileAttr = fuser::FileAttr {
@@ -49,129 +111,519 @@ const ROOT_DIR_ATTR: fuser::FileAttr = fuser::FileAttr {
     blksize: 512,
 };
 
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct FuseFs {
-    /// path to image
-    file_path: String,
-    /// need demonize
-    demonize: bool,
-    /// read only mode
+/// Атрибуты записи для ответа ядру (без своего времени берем время тома)
+fn entry_attr(entry: &Entry<'_>, last_modified: StdSystemTime, blksize: u32) -> fuser::FileAttr {
+    let mtime = entry.mtime.unwrap_or(last_modified);
+    fuser::FileAttr {
+        ino: entry.inode,
+        size: entry.size,
+        blocks: entry.blocks,
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind: file_type(entry.kind),
+        perm: entry.mode,
+        nlink: 1,
+        uid: 1000,
+        gid: 1000,
+        rdev: 0,
+        blksize,
+        flags: 0,
+    }
+}
+
+/// Проверка access(2) по битам режима: владелец, группа, остальные.
+/// Root проходит все, кроме записи в защищенный (sticky) файл
+/// и исполнения файла без единого бита x
+fn check_access(
+    fattr: &fuser::FileAttr,
+    uid: u32,
+    gid: u32,
+    mask: i32,
     read_only: bool,
-    /// Enable show bad files
-    show_bad: bool,
-    /// Enable show deleted
-    show_deleted: bool,
-    /// Use binary inverted reader
-    inverted: bool,
-    /// Offset from start of image in blocks
+) -> Result<(), i32> {
+    if mask == libc::F_OK {
+        return Ok(());
+    }
+    if mask & libc::W_OK != 0 {
+        if read_only {
+            return Err(libc::EROFS);
+        }
+        if fattr.kind == FileType::RegularFile && fattr.perm & 0o1000 != 0 {
+            return Err(libc::EACCES);
+        }
+    }
+    let perm = i32::from(fattr.perm);
+    let granted = if uid == 0 {
+        let exec = if fattr.kind == FileType::Directory || perm & 0o111 != 0 {
+            libc::X_OK
+        } else {
+            0
+        };
+        libc::R_OK | libc::W_OK | exec
+    } else if uid == fattr.uid {
+        (perm >> 6) & 0o7
+    } else if gid == fattr.gid {
+        (perm >> 3) & 0o7
+    } else {
+        perm & 0o7
+    };
+    if mask & !granted == 0 {
+        Ok(())
+    } else {
+        Err(libc::EACCES)
+    }
+}
+
+/// Чтение файла в `buf`, с `bin_headers` перед данными файла идет
+/// заголовок .bin и смещения сдвинуты на его размер
+fn read_file<B: BkFileSystem>(
+    fs: &B,
+    ino: u64,
     offset: u64,
-    /// Size of image in blocks
-    size: u64,
-    ///
-    fs: Fs,
+    buf: &mut [u8],
+    bin_headers: bool,
+) -> bkfs::Result<usize> {
+    let header = match fs.bin_header(ino) {
+        Some(header) if bin_headers => header.to_bytes(),
+        _ => return fs.read_into(ino, offset, buf),
+    };
+    let mut size = 0;
+    if offset < BIN_HEADER_SIZE {
+        let end = std::cmp::min(BIN_HEADER_SIZE, offset + buf.len() as u64);
+        size = (end - offset) as usize;
+        buf[..size].copy_from_slice(&header[offset as usize..end as usize]);
+    }
+    // читаем и при пустом остатке: ошибки файла важнее заголовка
+    let body = fs.read_into(
+        ino,
+        offset.saturating_sub(BIN_HEADER_SIZE),
+        &mut buf[size..],
+    )?;
+    Ok(size + body)
+}
+
+/// Сырые байты тома в `buf` (виртуальный `/.disk`)
+fn read_disk<B: BkFileSystem>(fs: &B, offset: u64, buf: &mut [u8]) -> bkfs::Result<usize> {
+    let data = fs.read_raw(offset, buf.len())?;
+    buf[..data.len()].copy_from_slice(&data);
+    Ok(data.len())
+}
+
+/// Путь из записи в `/.control/snapshot`: одна строка, только абсолютный
+/// (у демона свой текущий каталог)
+fn snapshot_path(data: &[u8]) -> Result<PathBuf, i32> {
+    let path = std::str::from_utf8(data).map_err(|_| libc::EINVAL)?;
+    let path = Path::new(path.trim_end_matches(['\n', '\r']));
+    if !path.is_absolute() {
+        return Err(libc::EINVAL);
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Ответ на getxattr/listxattr: при `size == 0` ядро спрашивает только длину
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// Fuse driver of any `BkFileSystem` volume
+#[derive(Debug)]
+pub struct FuseFs<B> {
+    /// shared between fuse session and worker threads
+    fs: Arc<RwLock<B>>,
+    /// workers for slow requests (read)
+    pool: ThreadPool,
+    /// reused buffers of read replies
+    buffers: Arc<BufferPool>,
+    /// prepend .bin header to files with load address (read only)
+    bin_headers: bool,
+    /// strip .bin header from written files on close
+    parse_bin: bool,
+    /// show raw volume as read only `/.disk`
+    disk_file: bool,
+    /// show `/.control` with control files
+    control: bool,
+    /// snapshots are not saved under it
+    mountpoint: Option<PathBuf>,
+    /// opened files by fh
+    handles: FileHandles,
+    /// time of all entries instead of the real one
+    fake_date: Option<StdSystemTime>,
+    /// owner of all entries
+    uid: u32,
+    gid: u32,
+    /// permission bits cleared on files and directories
+    fmask: u16,
+    dmask: u16,
     _tracing_span: tracing::Span,
 }
 
-impl Default for FuseFs {
-    fn default() -> Self {
+impl<B: BkFileSystem> FuseFs<B> {
+    /// Mount opened volume `fs`
+    pub fn new(fs: B) -> Self {
         Self {
-            file_path: String::default(),
+            fs: Arc::new(RwLock::new(fs)),
+            pool: ThreadPool::default(),
+            buffers: Arc::default(),
+            bin_headers: false,
+            parse_bin: false,
+            disk_file: false,
+            control: false,
+            mountpoint: None,
+            handles: FileHandles::default(),
+            fake_date: None,
+            uid: 1000,
+            gid: 1000,
+            fmask: 0,
+            dmask: 0,
             _tracing_span: tracing::span!(tracing::Level::TRACE, "FuseFs"),
-            demonize: false,
-            read_only: true,
-            show_bad: false,
-            show_deleted: false,
-            inverted: false,
-            offset: 0,
-            size: 0,
-            fs: Fs::default(),
         }
     }
-}
 
-impl FuseFs {
-    pub fn new(fname: &str) -> Self {
-        let fs = Fs::new(fname);
-        Self {
-            file_path: fname.into(),
-            demonize: false,
-            fs,
-            ..Default::default()
+    /// Show time `date` for all entries instead of image modification time
+    pub fn set_fake_date(&mut self, date: Option<StdSystemTime>) {
+        self.fake_date = date;
+    }
+
+    /// Set owner of all files and directories (1000:1000 by default)
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    /// Clear permission bits `fmask` of files and `dmask` of directories
+    /// (like umask, only `0o777` bits are used)
+    pub fn set_masks(&mut self, fmask: u16, dmask: u16) {
+        self.fmask = fmask & 0o777;
+        self.dmask = dmask & 0o777;
+    }
+
+    /// Set number of worker threads used for reads
+    pub fn set_threads(&mut self, threads: usize) {
+        self.pool = ThreadPool::new(threads);
+    }
+
+    /// Expose files with BK `.bin` header (load address and length)
+    /// prepended, so copied files can be loaded by emulators.
+    /// Such files can't be opened for writing
+    pub fn set_bin_headers(&mut self, bin_headers: bool) {
+        self.bin_headers = bin_headers;
+    }
+
+    /// Strip BK `.bin` header from written files when they are closed,
+    /// load address goes to the catalog (like MK-DOS does on load)
+    pub fn set_parse_bin(&mut self, parse_bin: bool) {
+        self.parse_bin = parse_bin;
+    }
+
+    /// Show read only file `/.disk` with raw (not inverted) contents
+    /// of the volume, if its format allows raw access
+    pub fn set_disk_file(&mut self, disk_file: bool) {
+        self.disk_file = disk_file;
+    }
+
+    /// Show directory `/.control`, a path written to `/.control/snapshot`
+    /// gets consistent copy of the image (see `BkFileSystem::snapshot()`)
+    pub fn set_control(&mut self, control: bool) {
+        self.control = control;
+    }
+
+    /// Where the volume is mounted: snapshot into the mount itself would
+    /// wait for the volume forever
+    pub fn set_mountpoint(&mut self, mountpoint: Option<PathBuf>) {
+        self.mountpoint = mountpoint;
+    }
+
+    fn fs_read(&self) -> RwLockReadGuard<'_, B> {
+        self.fs.read().expect("Fs lock poisoned")
+    }
+
+    fn fs_write(&self) -> RwLockWriteGuard<'_, B> {
+        self.fs.write().expect("Fs lock poisoned")
+    }
+
+    /// Read access to volume, it is reread first if it was changed
+    /// (write lock is taken only in that case).
+    /// EIO while volume can't be reread, next call tries again
+    fn fs(&self) -> Result<RwLockReadGuard<'_, B>, i32> {
+        let modified = self.fs_read().is_modified();
+        if modified {
+            if let Err(e) = self.fs_write().refresh() {
+                warn!("Can't reopen image: {}", e);
+            }
         }
+        let fs = self.fs_read();
+        if fs.is_degraded() {
+            return Err(libc::EIO);
+        }
+        Ok(fs)
     }
 
-    pub fn try_open(&mut self) -> Result<(), FsError> {
-        self.fs.try_open()
+    /// Запись виртуального `/.disk`, `None` если он выключен
+    fn disk_entry(&self, fs: &B) -> Option<Entry<'static>> {
+        let size = fs.raw_size().filter(|_| self.disk_file)?;
+        Some(Entry {
+            inode: DISK_INODE,
+            parent_inode: ROOT_INODE,
+            name: DISK_FILE_NAME,
+            kind: FileKind::File,
+            size,
+            blocks: size.div_ceil(u64::from(fs.block_size())),
+            mode: 0o444,
+            mtime: None,
+        })
     }
 
-    pub fn show_bad(&mut self, arg: bool) {
-        self.show_bad = arg;
+    /// Каталог `/.control` и файлы в нем, `None` если он выключен
+    fn control_entry(&self, ino: u64) -> Option<Entry<'static>> {
+        if !self.control {
+            return None;
+        }
+        let (parent_inode, name, kind, mode) = match ino {
+            CONTROL_INODE => (ROOT_INODE, CONTROL_DIR_NAME, FileKind::Directory, 0o555),
+            SNAPSHOT_INODE => (CONTROL_INODE, SNAPSHOT_FILE_NAME, FileKind::File, 0o200),
+            _ => return None,
+        };
+        Some(Entry {
+            inode: ino,
+            parent_inode,
+            name,
+            kind,
+            size: 0,
+            blocks: 0,
+            mode,
+            mtime: None,
+        })
+    }
+
+    /// `name` в каталоге `parent` - это `/.disk`
+    fn is_disk_file(&self, parent: u64, name: &str) -> bool {
+        self.disk_file && parent == ROOT_INODE && name == DISK_FILE_NAME
+    }
+
+    fn lookup_entry<'a>(&self, fs: &'a B, parent: u64, name: &str) -> Option<Entry<'a>> {
+        if self.is_disk_file(parent, name) {
+            return self.disk_entry(fs);
+        }
+        match (parent, name) {
+            (ROOT_INODE, CONTROL_DIR_NAME) if self.control => self.control_entry(CONTROL_INODE),
+            (CONTROL_INODE, SNAPSHOT_FILE_NAME) => self.control_entry(SNAPSHOT_INODE),
+            (CONTROL_INODE, _) => None,
+            _ => fs.lookup(parent, &volume_name(fs, parent, name)),
+        }
+    }
+
+    /// Содержимое каталога `ino` для readdir: "." и "..", затем записи
+    /// Имена записей тома экранированы (`names.rs`)
+    fn dir_entries<'a>(
+        &self,
+        fs: &'a B,
+        ino: u64,
+    ) -> Result<Vec<(u64, FileType, Cow<'a, str>)>, i32> {
+        if ino == CONTROL_INODE {
+            return Ok(vec![
+                (ino, FileType::Directory, ".".into()),
+                (ROOT_INODE, FileType::Directory, "..".into()),
+                (
+                    SNAPSHOT_INODE,
+                    FileType::RegularFile,
+                    SNAPSHOT_FILE_NAME.into(),
+                ),
+            ]);
+        }
+        // после перечитывания тома инода может уже не быть
+        let parent = if ino == ROOT_INODE {
+            ROOT_INODE
+        } else {
+            fs.metadata(ino).ok_or(ENOENT)?.parent_inode
+        };
+        let children = fs.list(ino).map_err(|e| errno_from_error(&e))?;
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".into()),
+            (parent, FileType::Directory, "..".into()),
+        ];
+        entries.extend(
+            children
+                .into_iter()
+                .map(|e| (e.inode, file_type(e.kind), escape_name(e.name))),
+        );
+        if ino == ROOT_INODE {
+            entries.extend(
+                self.disk_entry(fs)
+                    .into_iter()
+                    .chain(self.control_entry(CONTROL_INODE))
+                    .map(|e| (e.inode, file_type(e.kind), e.name.into())),
+            );
+        }
+        Ok(entries)
     }
 
-    pub fn show_deleted(&mut self, arg: bool) {
-        self.show_deleted = arg;
+    /// Атрибуты корня или записи `ino`, с `bin_headers` файлы с адресом
+    /// загрузки длиннее на заголовок .bin, с `fake_date` у всех одно время
+    fn attr(&self, fs: &B, ino: u64) -> Option<fuser::FileAttr> {
+        let mut attr = self.volume_attr(fs, ino)?;
+        attr.uid = self.uid;
+        attr.gid = self.gid;
+        attr.perm &= match attr.kind {
+            FileType::Directory => !self.dmask,
+            _ => !self.fmask,
+        };
+        Some(attr)
     }
 
-    /// Set the fuse fs's inverted.
-    pub fn set_inverted(&mut self, inverted: bool) {
-        self.inverted = inverted;
-        self.fs.set_inverted(inverted);
+    fn volume_attr(&self, fs: &B, ino: u64) -> Option<fuser::FileAttr> {
+        let last_modified = self.fake_date.unwrap_or_else(|| fs.last_modified());
+        if ino == ROOT_INODE {
+            let mut attr = ROOT_DIR_ATTR;
+            if fs.is_read_only() {
+                attr.perm = 0o555;
+            }
+            attr.atime = last_modified;
+            attr.mtime = last_modified;
+            attr.ctime = last_modified;
+            attr.crtime = last_modified;
+            attr.blksize = fs.block_size();
+            return Some(attr);
+        }
+        let mut entry = match ino {
+            DISK_INODE => self.disk_entry(fs)?,
+            CONTROL_INODE | SNAPSHOT_INODE => self.control_entry(ino)?,
+            _ => fs.metadata(ino)?,
+        };
+        if self.fake_date.is_some() {
+            entry.mtime = None;
+        }
+        let mut attr = entry_attr(&entry, last_modified, fs.block_size());
+        if self.bin_headers && attr.kind == FileType::RegularFile && fs.bin_header(ino).is_some() {
+            attr.size += BIN_HEADER_SIZE;
+        }
+        Some(attr)
     }
 
-    /// Set the fuse fs's offset.
-    pub fn set_offset(&mut self, offset: u64) {
-        self.offset = offset;
-        self.fs.set_offset_blocks(offset);
+    /// Запись пути в `/.control/snapshot`: копия образа делается в пуле
+    /// под блокировкой тома на чтение, записи ждут ее окончания
+    fn write_snapshot(&mut self, fh: u64, data: &[u8], reply: ReplyWrite)
+    where
+        B: Send + Sync + 'static,
+    {
+        if self.handles.get_mut(fh, SNAPSHOT_INODE).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
+        let dest = match snapshot_path(data) {
+            Ok(dest) => dest,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        let fs = Arc::clone(&self.fs);
+        let mountpoint = self.mountpoint.clone();
+        let written = data.len() as u32;
+        self.pool.execute(move || {
+            // lookup-и внутри точки монтирования отвечает поток сессии, он свободен
+            let inside_mount = mountpoint.is_some_and(|mountpoint| {
+                dest.parent()
+                    .and_then(|dir| dir.canonicalize().ok())
+                    .is_some_and(|dir| dir.starts_with(mountpoint))
+            });
+            if inside_mount {
+                warn!("Snapshot {:?} inside of the mount point", dest);
+                reply.error(libc::EINVAL);
+                return;
+            }
+            let fs = match fs.read() {
+                Ok(fs) => fs,
+                Err(_) => {
+                    reply.error(libc::EIO);
+                    return;
+                }
+            };
+            match fs.snapshot(&dest) {
+                Ok(size) => {
+                    info!(?dest, size, "Snapshot saved");
+                    reply.written(written);
+                }
+                Err(e) => {
+                    warn!("Can't save snapshot {:?}: {}", dest, e);
+                    reply.error(errno_from_error(&e));
+                }
+            }
+        });
     }
 
-    /// Set the fuse fs's offset.
-    pub fn set_size(&mut self, size: u64) {
-        self.size = size;
-        self.fs.set_size_blocks(size);
+    /// Права на открытие по флагам, запись только если том ее умеет
+    fn access_mask(&self, flags: i32) -> Result<i32, i32> {
+        let read_only = self.bin_headers || self.fs_read().is_read_only();
+        match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => {
+                // Behavior is undefined, but most filesystems return EACCES
+                if flags & libc::O_TRUNC != 0 {
+                    return Err(libc::EACCES);
+                }
+                Ok(libc::R_OK)
+            }
+            libc::O_WRONLY if read_only => Err(libc::EACCES),
+            libc::O_WRONLY => Ok(libc::W_OK),
+            libc::O_RDWR if read_only => Err(libc::EACCES),
+            libc::O_RDWR => Ok(libc::R_OK | libc::W_OK),
+            // Exactly one access mode flag must be specified
+            _ => Err(libc::EINVAL),
+        }
     }
 }
 
-impl Filesystem for FuseFs {
-    #[instrument(level = "trace")]
+impl<B> Filesystem for FuseFs<B>
+where
+    B: BkFileSystem + Send + Sync + 'static,
+{
+    #[instrument(level = "trace", skip(self))]
     fn init(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut KernelConfig,
+        config: &mut KernelConfig,
     ) -> std::result::Result<(), i32> {
+        // атрибуты вместе с именами: ls -l без lookup на каждый файл
+        if let Err(unsupported) =
+            config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO)
+        {
+            debug!("Kernel has no readdirplus ({:#x})", unsupported);
+        }
         Ok(())
     }
 
-    fn destroy(&mut self) {}
+    fn destroy(&mut self) {
+        if self.handles.count() > 0 {
+            debug!(
+                count = self.handles.count(),
+                "Files still opened on unmount"
+            );
+        }
+        self.fs_write().destroy();
+    }
 
     #[instrument(level = "trace", skip(self, _req, reply))]
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        use fuser::FileAttr;
-
-        // dbg!("LOOKUP: ", parent, name);
-        let last_modified = self.fs.last_modified();
-        // dbg!("LOOKUP: ", &last_modified);
-        if let Some(entry) = self.fs.find_entrie(name.to_str().unwrap(), parent) {
-            let fattr = FileAttr {
-                ino: entry.inode,
-                size: entry.size as u64,
-                blocks: entry.blocks,
-                atime: last_modified,  // datetime!(1979-01-29 03:00 UTC).into(),
-                mtime: last_modified,  // datetime!(1979-01-29 03:00 UTC).into(),
-                ctime: last_modified,  // datetime!(1979-01-29 03:00 UTC).into(),
-                crtime: last_modified, // datetime!(1979-01-29 03:00 UTC).into(),
-                kind: from_direntry_status(entry.status),
-                perm: entry.mode,
-                nlink: 1,
-                uid: 1000,
-                gid: 1000,
-                rdev: 0,
-                blksize: self.fs.block_size() as u32,
-                flags: 0,
-            };
-            reply.entry(&StdDuration::from_secs(10), &fattr, 0);
-        } else {
-            reply.error(ENOENT);
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        let entry = name
+            .to_str()
+            .and_then(|name| self.lookup_entry(&*fs, parent, name));
+        match entry.and_then(|e| self.attr(&*fs, e.inode)) {
+            Some(fattr) => reply.entry(&TTL, &fattr, 0),
+            None => reply.error(ENOENT),
         }
     }
 
@@ -179,51 +631,28 @@ impl Filesystem for FuseFs {
 
     #[instrument(level = "trace", skip(self, _req, reply))]
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        use fuser::FileAttr;
-        // 1 => _
-        let last_modified = self.fs.last_modified();
-        if ino == 1 {
-            let mut dattr = ROOT_DIR_ATTR;
-            dattr.atime = datetime!(1979-01-29 03:00 UTC).into(); //systime_from_secs(ED_UNIX_TIME);
-            dattr.ctime = systime_from_secs(ED_UNIX_TIME);
-            dattr.mtime = systime_from_secs(ED_UNIX_TIME);
-            dattr.crtime = systime_from_secs(ED_UNIX_TIME);
-            reply.attr(&StdDuration::from_secs(10), &dattr);
-        }
-        // 2 => _
-        else if let Some(entry) = self.fs.entrie_by_inode(ino) {
-            let fattr = FileAttr {
-                ino,
-                size: entry.size as u64,
-                blocks: entry.blocks,
-                atime: last_modified,  // datetime!(1979-01-29 03:00 UTC).into(),
-                mtime: last_modified,  // datetime!(1979-01-29 03:00 UTC).into(),
-                ctime: last_modified,  // datetime!(1979-01-29 03:00 UTC).into(),
-                crtime: last_modified, // datetime!(1979-01-29 03:00 UTC).into(),
-                kind: from_direntry_status(entry.status),
-                perm: entry.mode,
-                nlink: 1,
-                uid: 1000,
-                gid: 1000,
-                rdev: 0,
-                blksize: self.fs.block_size() as u32,
-                flags: 0,
-            };
-            reply.attr(&StdDuration::from_secs(10), &fattr)
-        } else {
-            reply.error(ENOENT);
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        match self.attr(&*fs, ino) {
+            Some(fattr) => reply.attr(&TTL, &fattr),
+            None => reply.error(ENOENT),
         }
-        // reply.error(ENOENT);
     }
 
+    #[instrument(level = "trace", skip(self, _req, reply))]
     fn setattr(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _mode: Option<u32>,
         _uid: Option<u32>,
         _gid: Option<u32>,
-        _size: Option<u64>,
+        size: Option<u64>,
         _atime: Option<TimeOrNow>,
         _mtime: Option<TimeOrNow>,
         _ctime: Option<StdSystemTime>,
@@ -234,7 +663,24 @@ impl Filesystem for FuseFs {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        reply.error(ENOSYS);
+        // из атрибутов поменять можно только размер
+        if size.is_some() && (self.bin_headers || ino == DISK_INODE) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let mut fs = self.fs_write();
+        // `echo path > snapshot` сначала обрезает файл, а обрезать нечего
+        let is_control = matches!(ino, CONTROL_INODE | SNAPSHOT_INODE);
+        if let Some(size) = size.filter(|_| !is_control) {
+            if let Err(e) = fs.truncate(ino, size) {
+                reply.error(errno_from_error(&e));
+                return;
+            }
+        }
+        match self.attr(&*fs, ino) {
+            Some(fattr) => reply.attr(&TTL, &fattr),
+            None => reply.error(ENOENT),
+        }
     }
 
     fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
@@ -266,8 +712,25 @@ impl Filesystem for FuseFs {
         reply.error(ENOSYS);
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    #[instrument(level = "trace", skip(self, _req, reply))]
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        if self.is_disk_file(parent, name) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        let mut fs = self.fs_write();
+        let name = volume_name(&*fs, parent, name);
+        match fs.unlink(parent, &name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno_from_error(&e)),
+        }
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
@@ -285,17 +748,46 @@ impl Filesystem for FuseFs {
         reply.error(ENOSYS);
     }
 
+    #[instrument(level = "trace", skip(self, _req, reply))]
     fn rename(
         &mut self,
         _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _newparent: u64,
-        _newname: &OsStr,
-        _flags: u32,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        reply.error(ENOSYS);
+        let (name, newname) = match (name.to_str(), newname.to_str()) {
+            (Some(name), Some(newname)) => (name, newname),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        // RENAME_EXCHANGE и прочее не умеем: обмен как замена потерял бы второй файл
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        // перенос между каталогами не делаем, mv сам скопирует и удалит
+        if parent != newparent {
+            reply.error(libc::EXDEV);
+            return;
+        }
+        if self.is_disk_file(parent, name) || self.is_disk_file(newparent, newname) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        let replace = flags & libc::RENAME_NOREPLACE == 0;
+        let mut fs = self.fs_write();
+        let name = volume_name(&*fs, parent, name);
+        let newname = volume_name(&*fs, parent, newname);
+        match fs.rename(parent, &name, &newname, replace) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno_from_error(&e)),
+        }
     }
 
     fn link(
@@ -311,39 +803,33 @@ impl Filesystem for FuseFs {
 
     #[instrument(level = "trace", skip(self, _req, reply))]
     fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        // dbg!(ino, flags);
-        let access_mask = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => {
-                // Behavior is undefined, but most filesystems return EACCES
-                if flags & libc::O_TRUNC != 0 {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-                libc::R_OK
-            }
-            libc::O_WRONLY => {
-                if self.read_only {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-                libc::W_OK
+        // снимок можно сделать и с тома только для чтения
+        let access_mask = match self.access_mask(flags) {
+            _ if ino == SNAPSHOT_INODE && flags & libc::O_ACCMODE != libc::O_WRONLY => {
+                reply.error(libc::EACCES);
+                return;
             }
-            libc::O_RDWR => {
-                if self.read_only {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-                libc::R_OK | libc::W_OK
+            _ if ino == SNAPSHOT_INODE => libc::W_OK,
+            Ok(mask) if ino == DISK_INODE && mask & libc::W_OK != 0 => {
+                reply.error(libc::EACCES);
+                return;
             }
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
+            Ok(mask) => mask,
+            Err(errno) => {
+                reply.error(errno);
                 return;
             }
         };
 
-        reply.opened(0, access_mask as u32);
-        // reply.opened(0, 0);
+        let generation = match self.fs() {
+            Ok(fs) => fs.inode_generation(ino),
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        let fh = self.handles.open(ino, flags, generation);
+        reply.opened(fh, access_mask as u32);
     }
 
     #[instrument(level = "trace", skip(self, _req, reply))]
@@ -358,64 +844,167 @@ impl Filesystem for FuseFs {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        // dbg!(ino, fh, offset, size, flags);
-
-        if let Some(entry) = self.fs.entrie_by_inode(ino) {
-            let file_size = entry.size as u64;
-            // Could underflow if file length is less than local_start
-            let read_size = std::cmp::min(size, file_size.saturating_sub(offset as u64) as u32);
-            // Move this to mkfdosfs::Fs
-            let real_offset = offset as u64 + entry.start_block * self.fs.block_size();
-            let mut buf = vec![0; read_size as usize];
-            // ^
-            if self.fs.read_exact_at(&mut buf, real_offset).is_ok() {
-                reply.data(&buf);
-            } else {
-                reply.error(libc::EIO);
-            }
-        } else {
-            reply.error(ENOENT);
+        // перечитываем том здесь, в потоке сессии, а само чтение
+        // уходит в пул и не держит остальные запросы
+        if let Err(errno) = self.fs() {
+            reply.error(errno);
+            return;
         }
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let generation = match self.handles.get_mut(fh, ino) {
+            Some(file) => {
+                trace!(sequential = file.pos == offset as u64, "read");
+                file.pos = offset as u64 + size as u64;
+                file.generation
+            }
+            None => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        };
+        let fs = Arc::clone(&self.fs);
+        let buffers = Arc::clone(&self.buffers);
+        let bin_headers = self.bin_headers;
+        self.pool.execute(move || {
+            let fs = match fs.read() {
+                Ok(fs) => fs,
+                Err(_) => {
+                    reply.error(libc::EIO);
+                    return;
+                }
+            };
+            // том перечитан после open, инод мог достаться другому файлу
+            if generation != fs.inode_generation(ino) {
+                reply.error(libc::ESTALE);
+                return;
+            }
+            let mut buf = buffers.get(size as usize);
+            let data = &mut buf[..size as usize];
+            let read = match ino {
+                DISK_INODE => read_disk(&*fs, offset as u64, data),
+                _ => read_file(&*fs, ino, offset as u64, data, bin_headers),
+            };
+            match read {
+                Ok(read) => reply.data(&data[..read]),
+                Err(e) => {
+                    if e.kind() != ErrorKind::NotFound {
+                        warn!("Can't read inode {}: {}", ino, e);
+                    }
+                    reply.error(errno_from_error(&e));
+                }
+            }
+            buffers.put(buf);
+        });
     }
 
+    #[instrument(level = "trace", skip(self, _req, data, reply))]
     fn write(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _offset: i64,
-        _data: &[u8],
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
         _write_flags: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        reply.error(ENOSYS);
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if ino == SNAPSHOT_INODE {
+            self.write_snapshot(fh, data, reply);
+            return;
+        }
+        let generation = match self.fs() {
+            Ok(fs) => fs.inode_generation(ino),
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        match self.handles.get_mut(fh, ino) {
+            Some(file) if file.flags & libc::O_ACCMODE == libc::O_RDONLY => {
+                reply.error(libc::EBADF);
+                return;
+            }
+            Some(file) if file.generation == generation => {}
+            Some(_) => {
+                reply.error(libc::ESTALE);
+                return;
+            }
+            None => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        }
+        // запись меняет каталог, поэтому идет под блокировкой на запись,
+        // а не через пул
+        let result = self.fs_write().write(ino, offset as u64, data);
+        match result {
+            Ok(written) => {
+                if let Some(file) = self.handles.get_mut(fh, ino) {
+                    file.written = true;
+                    file.pos = offset as u64 + written as u64;
+                }
+                reply.written(written as u32)
+            }
+            Err(e) => {
+                warn!("Can't write inode {}: {}", ino, e);
+                reply.error(errno_from_error(&e));
+            }
+        }
     }
 
     fn flush(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _lock_owner: u64,
         reply: ReplyEmpty,
     ) {
-        reply.error(ENOSYS);
+        // flush приходит на каждый close: если через этот дескриптор
+        // не писали, блокировку на запись не берем и чтения не ждут
+        if !self
+            .handles
+            .get_mut(fh, ino)
+            .is_some_and(|file| file.written)
+        {
+            reply.ok();
+            return;
+        }
+        match self.fs_write().flush() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno_from_error(&e)),
+        }
     }
 
     #[instrument(level = "trace", skip(self, _req, reply))]
     fn release(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        // dbg!(&_ino, &_fh);
+        let written = self.handles.release(fh).is_some_and(|file| file.written);
+        // заголовок видно только в файле целиком, поэтому на закрытии
+        if written && self.parse_bin {
+            match self.fs_write().strip_bin_header(ino) {
+                Ok(Some(header)) => info!(ino, ?header, ".bin header stripped"),
+                Ok(None) => {}
+                Err(e) => warn!("Can't strip .bin header of inode {}: {}", ino, e),
+            }
+        }
         reply.ok();
     }
 
@@ -427,43 +1016,22 @@ impl Filesystem for FuseFs {
         _datasync: bool,
         reply: ReplyEmpty,
     ) {
-        reply.error(ENOSYS);
+        if self.fs_read().is_read_only() {
+            reply.ok();
+            return;
+        }
+        match self.fs_write().flush() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno_from_error(&e)),
+        }
     }
 
     #[instrument(level = "trace", skip(self, _req, reply))]
     fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        let access_mask = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => {
-                // Behavior is undefined, but most filesystems return EACCES
-                if flags & libc::O_TRUNC != 0 {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-                libc::R_OK
-            }
-            libc::O_WRONLY => {
-                if self.read_only {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-                libc::W_OK
-            }
-            libc::O_RDWR => {
-                if self.read_only {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-                libc::R_OK | libc::W_OK
-            }
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
-            }
-        };
-
-        reply.opened(0, access_mask as u32);
-        // reply.opened(0, 0);
+        match self.access_mask(flags) {
+            Ok(mask) => reply.opened(0, mask as u32),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     #[instrument(level = "trace", skip(self, _req, reply))]
@@ -472,81 +1040,67 @@ impl Filesystem for FuseFs {
         _req: &Request<'_>,
         ino: u64,
         _fh: u64,
-        mut offset: i64,
+        offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        // dbg!("Readdir", ino, offset);
-        // эту порнуху надо исправить
-        if offset == 0 || offset == 1 {
-            if ino == 1 {
-                if offset == 0 {
-                    offset += 1;
-                    if reply.add(1, offset, FileType::Directory, ".") {
-                        return;
-                    }
-                }
-                if offset == 1 {
-                    offset += 1;
-                    if reply.add(1, offset, FileType::Directory, "..") {
-                        return;
-                    }
-                }
-            } else {
-                if offset == 0 {
-                    offset += 1;
-                    if reply.add(ino, offset, FileType::Directory, ".") {
-                        return;
-                    }
-                }
-                if offset == 1 {
-                    let entry = self.fs.entrie_by_inode(ino);
-                    assert!(entry.is_some());
-                    offset += 1;
-                    if reply.add(
-                        entry.unwrap().parent_inode,
-                        offset,
-                        FileType::Directory,
-                        "..",
-                    ) {
-                        return;
-                    }
-                }
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
             }
-        }
-
-        // фильтр надо перести в mkdosfs
-        for (i, entry) in self
-            .fs
-            .entries_by_parent_inode(ino)
-            .iter()
-            .filter(|&e| (!e.is_deleted || self.show_deleted) && (!e.is_bad || self.show_bad))
-            .skip((offset - 2) as usize)
-            .enumerate()
-        {
-            if reply.add(
-                entry.inode,
-                // i + 1 means the index of the next entry
-                offset + 1 + i as i64,
-                from_direntry_status(entry.status),
-                &entry.name,
-            ) {
+        };
+        let entries = match self.dir_entries(&*fs, ino) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // i + 1 means the index of the next entry
+            if reply.add(ino, i as i64 + 1, kind, &*name) {
                 break;
             }
         }
-
         reply.ok();
-        // reply.error(ENOSYS);
     }
 
+    #[instrument(level = "trace", skip(self, _req, reply))]
     fn readdirplus(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _offset: i64,
-        reply: ReplyDirectoryPlus,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
     ) {
-        reply.error(ENOSYS);
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        let entries = match self.dir_entries(&*fs, ino) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        for (i, (ino, _, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // атрибуты те же, что отдает lookup
+            let fattr = match self.attr(&*fs, ino) {
+                Some(fattr) => fattr,
+                None => continue,
+            };
+            if reply.add(ino, i as i64 + 1, &*name, &TTL, &fattr, 0) {
+                break;
+            }
+        }
+        reply.ok();
     }
 
     fn releasedir(
@@ -571,22 +1125,25 @@ impl Filesystem for FuseFs {
         reply.error(ENOSYS);
     }
 
-    /// Returns image fs info
+    /// Returns volume info
     #[instrument(level = "trace", skip(self, _req, reply))]
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
-        // let _ffree = (self.meta.start_block as u64 * BLOCK_SIZE as u64
-        //     - MetaOffset::DirEntriesStart as u64)
-        //     / DIR_ENTRY_SIZE as u64
-        //     - self.meta.files as u64;
-        // dbg!(_ffree);
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        let stats = fs.stats();
         reply.statfs(
-            self.fs.disk_size(),
-            self.fs.disk_size() - self.fs.blocks(),
-            self.fs.disk_size() - self.fs.blocks() as u64,
-            self.fs.files(),
-            0,
-            self.fs.block_size() as u32,
-            14,
+            stats.blocks,
+            stats.free,
+            stats.free,
+            stats.files,
+            stats.free_files,
+            fs.block_size(),
+            stats.namelen,
             0,
         );
     }
@@ -604,40 +1161,121 @@ impl Filesystem for FuseFs {
         reply.error(ENOSYS);
     }
 
+    #[instrument(level = "trace", skip(self, _req, reply))]
     fn getxattr(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _name: &OsStr,
-        _size: u32,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
         reply: ReplyXattr,
     ) {
-        reply.error(ENOSYS);
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        if self.attr(&*fs, ino).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+        let value = fs
+            .xattrs(ino)
+            .into_iter()
+            .find(|(n, _)| name.to_str() == Some(n.as_str()))
+            .map(|(_, value)| value);
+        match value {
+            Some(value) => reply_xattr(reply, size, &value),
+            None => reply.error(libc::ENODATA),
+        }
     }
 
-    fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
-        reply.error(ENOSYS);
+    #[instrument(level = "trace", skip(self, _req, reply))]
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        if self.attr(&*fs, ino).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+        // имена через \0, после последнего тоже \0
+        let mut names = Vec::new();
+        for (name, _) in fs.xattrs(ino) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(reply, size, &names);
     }
 
     fn removexattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
-    fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: i32, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    #[instrument(level = "trace", skip(self, req, reply))]
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let fs = match self.fs() {
+            Ok(fs) => fs,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        let fattr = match self.attr(&*fs, ino) {
+            Some(fattr) => fattr,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let read_only = self.bin_headers || ino == DISK_INODE || fs.is_read_only();
+        match check_access(&fattr, req.uid(), req.gid(), mask, read_only) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
     }
 
+    #[instrument(level = "trace", skip(self, _req, reply))]
     fn create(
         &mut self,
         _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
+        parent: u64,
+        name: &OsStr,
         _mode: u32,
         _umask: u32,
-        _flags: i32,
+        flags: i32,
         reply: ReplyCreate,
     ) {
-        reply.error(ENOSYS);
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        if self.is_disk_file(parent, name) {
+            reply.error(libc::EEXIST);
+            return;
+        }
+        let created = {
+            let mut fs = self.fs_write();
+            fs.create(parent, name)
+                .map(|inode| (inode, self.attr(&*fs, inode), fs.inode_generation(inode)))
+        };
+        match created {
+            Ok((inode, Some(fattr), generation)) => {
+                let fh = self.handles.open(inode, flags, generation);
+                reply.created(&TTL, &fattr, 0, fh, 0)
+            }
+            Ok((_, None, _)) => reply.error(libc::EIO),
+            Err(e) => reply.error(errno_from_error(&e)),
+        }
     }
 
     fn getlk(
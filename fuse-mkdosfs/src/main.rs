@@ -1,15 +1,74 @@
 //#![feature(destructuring_assignment)]
 
+#[cfg(unix)]
+use std::{
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(unix)]
+use bkfs::BkFileSystem;
+#[cfg(unix)]
 use clap::{crate_authors, crate_name, crate_version, App, Arg};
-use color_eyre::eyre::Result;
+#[cfg(unix)]
+use color_eyre::eyre::{eyre, Result};
+#[cfg(unix)]
 use fuser::MountOption;
-use tracing::info;
+#[cfg(unix)]
+use tracing::{info, warn};
+#[cfg(unix)]
 use tracing_subscriber::EnvFilter;
 
-use fuse_mkdosfs::FuseFs;
+#[cfg(unix)]
+use fuse_mkdosfs::{
+    daemon::{daemonize, Daemon, Syslog},
+    mount_helper::{helper_args, is_mount_helper},
+    multi::{image_files, Volume},
+    FuseFs, HddFs, MultiFs,
+};
+#[cfg(unix)]
+use mkdosfs::{
+    container::{self, InputFormat, INPUT_FORMATS},
+    AndosFs, Encoding, Fs, FsError, FsKind, GeometryMapper, InodeStrategy, LookupPolicy, Rt11Fs,
+};
+#[cfg(unix)]
+use time::{
+    format_description::{well_known::Rfc3339, FormatItem},
+    macros::format_description,
+    Date, OffsetDateTime,
+};
+
+#[cfg(unix)]
+const DATE_FORMAT: &[FormatItem<'_>] = format_description!("[year]-[month]-[day]");
 
+/// Settings of `FuseFs` from command line
+#[derive(Debug)]
+#[cfg(unix)]
+struct MountSettings {
+    threads: Option<usize>,
+    bin_headers: bool,
+    parse_bin: bool,
+    disk_file: bool,
+    control: bool,
+    fake_date: Option<SystemTime>,
+    uid: u32,
+    gid: u32,
+    fmask: u16,
+    dmask: u16,
+}
+
+#[cfg(unix)]
 fn main() -> Result<()> {
-    setup_logging()?;
+    let mut args: Vec<String> = std::env::args().collect();
+    // mount -t mkdosfs и fstab: опции одной строкой через -o
+    if args
+        .first()
+        .is_some_and(|argv0| is_mount_helper(argv0.as_ref()))
+    {
+        args = helper_args(args).map_err(|e| eyre!(e))?;
+    }
 
     let matches = App::new(crate_name!())
         .version(crate_version!())
@@ -18,7 +77,11 @@ fn main() -> Result<()> {
             Arg::new("IMAGE_NAME")
                 .required(true)
                 .index(1)
-                .help("MKDOS disk image file path"),
+                .multiple_values(true)
+                .help(
+                    "MKDOS disk image file path. Several images or a directory of images \
+                     are mounted read only, each as a directory with its file name",
+                ),
         )
         .arg(
             Arg::new("MOUNT_POINT")
@@ -36,6 +99,75 @@ fn main() -> Result<()> {
                 .long("allow-root")
                 .help("Allow root user to access filesystem"),
         )
+        .arg(
+            Arg::new("foreground")
+                .long("foreground")
+                .short('f')
+                .help("Stay in foreground, do not fork to background after mount"),
+        )
+        .arg(
+            Arg::new("pidfile")
+                .long("pidfile")
+                .takes_value(true)
+                .conflicts_with("foreground")
+                .value_name("FILE")
+                .help("Write pid of background process to FILE"),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write log to FILE instead of stderr"),
+        )
+        .arg(
+            Arg::new("syslog")
+                .long("syslog")
+                .conflicts_with("log-file")
+                .help("Write log to syslog instead of stderr"),
+        )
+        .arg(
+            Arg::new("default-permissions")
+                .long("default-permissions")
+                .help("Let the kernel check permissions by file mode instead of the driver"),
+        )
+        .arg(
+            Arg::new("uid")
+                .long("uid")
+                .takes_value(true)
+                .validator(parse_int::<u32>)
+                .value_name("UID")
+                .help("Owner of all files [default: 1000]"),
+        )
+        .arg(
+            Arg::new("gid")
+                .long("gid")
+                .takes_value(true)
+                .validator(parse_int::<u32>)
+                .value_name("GID")
+                .help("Group of all files [default: 1000]"),
+        )
+        .arg(
+            Arg::new("fmask")
+                .long("fmask")
+                .takes_value(true)
+                .validator(|s| parse_mask(s).map(|_| ()))
+                .value_name("MASK")
+                .help("Octal mask of permissions cleared on files (like umask)"),
+        )
+        .arg(
+            Arg::new("dmask")
+                .long("dmask")
+                .takes_value(true)
+                .validator(|s| parse_mask(s).map(|_| ()))
+                .value_name("MASK")
+                .help("Octal mask of permissions cleared on directories (like umask)"),
+        )
+        .arg(
+            Arg::new("rw")
+                .long("rw")
+                .help("Mount image read-write (changes are written to the image)"),
+        )
         .arg(
             Arg::new("show-bad")
                 .long("show-bad")
@@ -44,7 +176,12 @@ fn main() -> Result<()> {
         .arg(
             Arg::new("show-deleted")
                 .long("show-deleted")
-                .help("Enable show deleted files (files marked as deleted)"),
+                .help("Show deleted files in virtual directory .deleted"),
+        )
+        .arg(
+            Arg::new("logical-dirs")
+                .long("logical-dirs")
+                .help("Show logical disks as directories with their files (read only)"),
         )
         .arg(
             Arg::new("offset")
@@ -53,12 +190,9 @@ fn main() -> Result<()> {
                 .short('o')
                 .takes_value(true)
                 .requires("size")
-                .validator(|s| match s.parse::<u64>() {
-                    Ok(_n) => Ok(()),
-                    Err(e) => Err(format!("valuse must an integer: {}", e)),
-                })
+                .validator(parse_int::<u64>)
                 .value_name("OFFSET")
-                .help("Offset from start of image in blocks"),
+                .help("Offset from start of image in blocks (HDD images: see --partition)"),
         )
         .arg(
             Arg::new("size")
@@ -66,55 +200,512 @@ fn main() -> Result<()> {
                 .short('s')
                 .requires("offset")
                 .takes_value(true)
-                .validator(|s| match s.parse::<u64>() {
-                    Ok(_n) => Ok(()),
-                    Err(e) => Err(format!("valuse must an integer: {}", e)),
-                })
+                .validator(parse_int::<u64>)
                 .value_name("SIZE")
                 .help("Size of image in blocks"),
         )
+        .arg(
+            Arg::new("partition")
+                .long("partition")
+                .short('p')
+                .takes_value(true)
+                .conflicts_with_all(&["offset", "size", "inverted"])
+                .validator(parse_int::<usize>)
+                .value_name("N")
+                .help("Mount partition N of HDD image (raw or HDI, AltPro or Samara)"),
+        )
+        .arg(
+            Arg::new("hdd")
+                .long("hdd")
+                .conflicts_with_all(&["partition", "offset", "size", "inverted", "rw"])
+                .help("Mount all MK-DOS partitions of HDD image as part0, part1, ... (read only)"),
+        )
+        .arg(
+            Arg::new("andos")
+                .long("andos")
+                .conflicts_with_all(&["hdd", "rw", "logical-dirs"])
+                .help("Mount ANDOS (FAT12) disk image (read only, detected by default)"),
+        )
+        .arg(
+            Arg::new("rt11")
+                .long("rt11")
+                .conflicts_with_all(&["andos", "hdd", "rw", "logical-dirs"])
+                .help("Mount RT-11 disk image or HDD partition (read only, detected by default)"),
+        )
+        .arg(
+            Arg::new("parse-bin")
+                .long("parse-bin")
+                .requires("rw")
+                .help("Strip BK .bin header from written files, load address goes to catalog"),
+        )
+        .arg(
+            Arg::new("bin-headers")
+                .long("bin-headers")
+                .conflicts_with("rw")
+                .help("Prepend BK .bin header (load address and length) to files"),
+        )
+        .arg(
+            Arg::new("disk-file")
+                .long("disk-file")
+                .conflicts_with("hdd")
+                .help("Show raw contents of MK-DOS volume (not inverted) as read only /.disk"),
+        )
+        .arg(Arg::new("control").long("control").help(
+            "Show /.control, write absolute path to /.control/snapshot to save copy of image",
+        ))
+        .arg(
+            Arg::new("fake-date")
+                .long("fake-date")
+                .takes_value(true)
+                .validator(|s| parse_date(s).map_err(|e| e.to_string()))
+                .value_name("DATE")
+                .help(
+                    "Show this time (1979-01-29 or RFC 3339) for all files instead of image time",
+                ),
+        )
+        .arg(
+            Arg::new("scan-full-catalog")
+                .long("scan-full-catalog")
+                .conflicts_with("rw")
+                .help("Read catalog past blank slots up to the start block (damaged images)"),
+        )
         .arg(
             Arg::new("inverted")
                 .long("use-inverted")
                 .short('i')
-                .help("Use inverted reader (used to read hdd images images)"),
+                .help("Force inverted reader (detected by signatures by default)"),
+        )
+        .arg(
+            Arg::new("byte-swap")
+                .long("byte-swap")
+                .conflicts_with_all(&["partition", "hdd"])
+                .help("Swap bytes in every 16-bit word (dumps of some HDD controllers)"),
+        )
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .short('e')
+                .takes_value(true)
+                .possible_values(["koi8r", "cp866", "translit"])
+                .default_value("koi8r")
+                .value_name("ENCODING")
+                .help("File names encoding (translit shows cyrillic names in ASCII)"),
+        )
+        .arg(
+            Arg::new("ignore-case").long("ignore-case").help(
+                "Find files regardless of letter case and trailing spaces (cat mnt/game.bin)",
+            ),
+        )
+        .arg(
+            Arg::new("stable-inodes")
+                .long("stable-inodes")
+                .help("Keep inodes of files when the image is reread (open files stay valid)"),
+        )
+        .arg(
+            Arg::new("input-format")
+                .long("input-format")
+                .takes_value(true)
+                .possible_values(INPUT_FORMATS)
+                .default_value("auto")
+                .value_name("FORMAT")
+                .help("Image container: raw sectors, Teledisk, ImageDisk or HxC (read only)"),
+        )
+        .arg(
+            Arg::new("geometry")
+                .long("geometry")
+                .takes_value(true)
+                .conflicts_with_all(&["partition", "hdd"])
+                .validator(|s| s.parse::<GeometryMapper>().map(|_| ()))
+                .value_name("GEOMETRY")
+                .help("Remap blocks of side by side image: TRACKS,SIDES,SECTORS[,INTERLEAVE]"),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .short('t')
+                .takes_value(true)
+                .validator(|s| match parse_int::<usize>(s)? {
+                    0 => Err("value must be greater than zero".to_string()),
+                    _ => Ok(()),
+                })
+                .value_name("THREADS")
+                .help("Number of worker threads for read requests"),
+        )
+        .arg(
+            Arg::new("cache-blocks")
+                .long("cache-blocks")
+                .takes_value(true)
+                .validator(parse_int::<usize>)
+                .value_name("BLOCKS")
+                .help("Image block cache size in blocks, 0 disables the cache"),
+        )
+        .arg(
+            Arg::new("check-interval")
+                .long("check-interval")
+                .takes_value(true)
+                .validator(parse_int::<u64>)
+                .value_name("MS")
+                .help("Check image for changes not more often than once per MS milliseconds"),
+        )
+        .arg(
+            Arg::new("no-lock")
+                .long("no-lock")
+                .help("Don't lock image file (shared lock read only, exclusive read-write)"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("With --rw write changes to FILE, image stays untouched"),
+        )
+        .get_matches_from(args);
+
+    setup_logging(matches.value_of("log-file"), matches.is_present("syslog"))?;
 
     let imagename = matches.value_of("IMAGE_NAME").unwrap();
     let mountpoint = matches.value_of("MOUNT_POINT").unwrap();
-    let mut options = vec![MountOption::RO, MountOption::FSName("mkdosfs".to_string())];
+    let read_only = !matches.is_present("rw");
+    let mut options = vec![
+        if read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
+        MountOption::FSName("mkdosfs".to_string()),
+    ];
     if matches.is_present("auto-unmount") {
         options.push(MountOption::AutoUnmount);
     }
     if matches.is_present("allow-root") {
         options.push(MountOption::AllowRoot);
     }
+    if matches.is_present("default-permissions") {
+        options.push(MountOption::DefaultPermissions);
+    }
 
     // fuser::mount2(Fs, mountpoint, &options).wrap_err("fuser::mount error")?;
     info!(?options, "Mount options: ");
-    let mut fs = FuseFs::new(imagename);
+    let encoding = matches.value_of("encoding").unwrap().parse::<Encoding>()?;
+    let lookup_policy = match matches.is_present("ignore-case") {
+        true => LookupPolicy::CaseInsensitive,
+        false => LookupPolicy::Exact,
+    };
+    let inode_strategy = match matches.is_present("stable-inodes") {
+        true => InodeStrategy::Stable,
+        false => InodeStrategy::Sequential,
+    };
+
+    let settings = MountSettings {
+        threads: match matches.value_of("threads") {
+            Some(threads) => Some(threads.parse::<usize>()?),
+            None => None,
+        },
+        bin_headers: matches.is_present("bin-headers"),
+        parse_bin: matches.is_present("parse-bin"),
+        disk_file: matches.is_present("disk-file"),
+        control: matches.is_present("control"),
+        fake_date: match matches.value_of("fake-date") {
+            Some(date) => Some(parse_date(date)?),
+            None => None,
+        },
+        uid: matches.value_of("uid").map_or(Ok(1000), str::parse)?,
+        gid: matches.value_of("gid").map_or(Ok(1000), str::parse)?,
+        fmask: matches
+            .value_of("fmask")
+            .map_or(Ok(0), parse_mask)
+            .map_err(|e| eyre!(e))?,
+        dmask: matches
+            .value_of("dmask")
+            .map_or(Ok(0), parse_mask)
+            .map_err(|e| eyre!(e))?,
+    };
+
+    // несколько образов или каталог с ними - каждый своим каталогом
+    let images: Vec<&str> = matches.values_of("IMAGE_NAME").unwrap().collect();
+    let multi = images.len() > 1 || Path::new(imagename).is_dir();
+    if multi && !read_only {
+        return Err(eyre!("Several images can be mounted only read only"));
+    }
+    // разделы и смещение - для одного образа, каждый из нескольких открывается целиком
+    let single_only = ["hdd", "partition", "offset", "size"];
+    if let Some(arg) = single_only
+        .iter()
+        .find(|&&arg| multi && matches.is_present(arg))
+    {
+        return Err(eyre!("--{} can't be used with several images", arg));
+    }
 
+    // сжатый образ, TD0, IMD и HFE монтируем из копии, только на чтение
+    let input_format = matches.value_of_t::<InputFormat>("input-format")?;
+    let unpacked = match multi {
+        true => None,
+        false => container::prepared_image(imagename, input_format)?,
+    };
+    if unpacked.is_some() && !read_only {
+        return Err(eyre!(
+            "{} is not a raw image, it can be mounted only read only",
+            imagename
+        ));
+    }
+    let unpacked = unpacked.map(|path| path.to_string_lossy().into_owned());
+    let imagename = unpacked.as_deref().unwrap_or(imagename);
+
+    // в фон до открытия образа: потоки fork не переживут
+    let mut daemon = if matches.is_present("foreground") {
+        None
+    } else {
+        Some(daemonize(matches.value_of("pidfile").map(PathBuf::from))?)
+    };
+
+    if multi {
+        let paths = match images.as_slice() {
+            [dir] => image_files(Path::new(dir))?,
+            _ => images.iter().map(PathBuf::from).collect(),
+        };
+        let image_options = ImageOptions {
+            input_format,
+            encoding,
+            lookup_policy,
+            inode_strategy,
+            show_bad: matches.is_present("show-bad"),
+            show_deleted: matches.is_present("show-deleted"),
+            lock: !matches.is_present("no-lock"),
+        };
+        let fs = MultiFs::new(
+            paths,
+            Box::new(move |path: &Path| open_image(path, &image_options)),
+        );
+        info!(images = fs.names().len(), "Starting");
+        return mount(fs, &settings, daemon.as_mut(), mountpoint, &options);
+    }
+
+    if matches.is_present("hdd") {
+        let mut fs = HddFs::new(imagename);
+        fs.show_bad(matches.is_present("show-bad"));
+        fs.show_deleted(matches.is_present("show-deleted"));
+        fs.set_encoding(encoding);
+        fs.set_lookup_policy(lookup_policy);
+        fs.set_inode_strategy(inode_strategy);
+        fs.set_lock(!matches.is_present("no-lock"));
+        info!("Starting");
+        fs.try_open()?;
+        info!(partitions = ?fs.partitions(), "Partitions");
+        return mount(fs, &settings, daemon.as_mut(), mountpoint, &options);
+    }
+
+    let mut fs = Fs::new(imagename);
+
+    if !read_only {
+        fs.set_read_only(false);
+    }
     if matches.is_present("show-bad") {
-        fs.show_bad(true);
+        fs.set_read_bad(true);
     }
     if matches.is_present("show-deleted") {
-        fs.show_deleted(true);
+        fs.set_read_deleted(true);
     }
     if matches.is_present("inverted") {
         fs.set_inverted(true);
     }
+    if matches.is_present("byte-swap") {
+        fs.set_swapped(true);
+    }
+    if matches.is_present("geometry") {
+        fs.set_geometry(Some(matches.value_of_t::<GeometryMapper>("geometry")?));
+    }
+    if matches.is_present("logical-dirs") {
+        fs.set_open_logical(true);
+    }
+    if matches.is_present("scan-full-catalog") {
+        fs.set_scan_full_catalog(true);
+    }
+    fs.set_encoding(encoding);
+    fs.set_lookup_policy(lookup_policy);
+    fs.set_inode_strategy(inode_strategy);
+    if matches.is_present("cache-blocks") {
+        let blocks = matches.value_of("cache-blocks").unwrap().parse::<usize>()?;
+        fs.set_cache_blocks(blocks);
+    }
+    fs.set_lock(!matches.is_present("no-lock"));
+    fs.set_overlay(matches.value_of("overlay").map(PathBuf::from));
+    if matches.is_present("check-interval") {
+        let ms = matches.value_of("check-interval").unwrap().parse::<u64>()?;
+        fs.set_check_interval(Duration::from_millis(ms));
+    }
 
     if matches.is_present("offset") {
         let offset = matches.value_of("offset").unwrap().parse::<u64>()?;
-        fs.set_offset(offset);
+        fs.set_offset_blocks(offset);
         let size = matches.value_of("size").unwrap().parse::<u64>()?;
-        fs.set_size(size);
+        fs.set_size_blocks(size);
+    }
+    if matches.is_present("partition") {
+        let partition = matches.value_of("partition").unwrap().parse::<usize>()?;
+        fs.set_partition(partition)?;
+    } else if fs.skip_hdi_header()? {
+        info!("HDI header skipped");
+    }
+
+    // формат не указан - смотрим на сигнатуры
+    let kind = match () {
+        _ if matches.is_present("andos") => FsKind::Andos,
+        _ if matches.is_present("rt11") => FsKind::Rt11,
+        // инверсию раздела знает таблица, флаг главнее сигнатур
+        _ if matches.is_present("partition") || matches.is_present("inverted") => fs.detect()?,
+        _ => fs.detect_inverted()?,
+    };
+    info!(%kind, "Filesystem");
+    match kind {
+        FsKind::Andos | FsKind::Rt11 if !read_only => {
+            Err(eyre!("{} can be mounted only read only", kind))
+        }
+        FsKind::Andos => {
+            let mut andos = AndosFs::new(imagename);
+            andos.set_encoding(encoding);
+            andos.set_offset_blocks(fs.offset_blocks());
+            andos.set_inverted(fs.is_inverted());
+            andos.set_swapped(fs.is_swapped());
+            andos.set_geometry(fs.geometry().cloned());
+            info!("Starting");
+            andos.try_open()?;
+            mount(andos, &settings, daemon.as_mut(), mountpoint, &options)
+        }
+        FsKind::Rt11 => {
+            let mut rt11 = Rt11Fs::new(imagename);
+            rt11.set_offset_blocks(fs.offset_blocks());
+            rt11.set_size_blocks(fs.size_blocks());
+            rt11.set_inverted(fs.is_inverted());
+            rt11.set_swapped(fs.is_swapped());
+            rt11.set_geometry(fs.geometry().cloned());
+            info!("Starting");
+            rt11.try_open()?;
+            mount(rt11, &settings, daemon.as_mut(), mountpoint, &options)
+        }
+        FsKind::CsiDos => Err(FsError::Unsupported(kind).into()),
+        // неизвестное открываем как MK-DOS, ошибка будет понятнее
+        FsKind::MkDos | FsKind::Unknown => {
+            info!("Starting");
+            fs.try_open()?;
+            if let Err(e) = fs.watch() {
+                warn!("Can't watch image, polling it: {}", e);
+            }
+            mount(fs, &settings, daemon.as_mut(), mountpoint, &options)
+        }
+    }
+}
+
+/// Options of images mounted together, see `open_image()`
+#[cfg(unix)]
+struct ImageOptions {
+    input_format: InputFormat,
+    encoding: Encoding,
+    lookup_policy: LookupPolicy,
+    inode_strategy: InodeStrategy,
+    show_bad: bool,
+    show_deleted: bool,
+    lock: bool,
+}
+
+/// Том образа `path` для `MultiFs`: формат по сигнатурам, только чтение
+#[cfg(unix)]
+fn open_image(path: &Path, options: &ImageOptions) -> Result<Box<Volume>, FsError> {
+    let unpacked = container::prepared_image(path, options.input_format)?;
+    let imagename = unpacked.as_deref().unwrap_or(path).to_string_lossy();
+    let mut fs = Fs::new(&imagename);
+    fs.set_read_bad(options.show_bad);
+    fs.set_read_deleted(options.show_deleted);
+    fs.set_encoding(options.encoding);
+    fs.set_lookup_policy(options.lookup_policy);
+    fs.set_inode_strategy(options.inode_strategy);
+    fs.set_lock(options.lock);
+    fs.skip_hdi_header()?;
+    let kind = fs.detect_inverted()?;
+    match kind {
+        FsKind::Andos => {
+            let mut andos = AndosFs::new(&imagename);
+            andos.set_encoding(options.encoding);
+            andos.set_offset_blocks(fs.offset_blocks());
+            andos.set_inverted(fs.is_inverted());
+            andos.try_open()?;
+            Ok(Box::new(andos))
+        }
+        FsKind::Rt11 => {
+            let mut rt11 = Rt11Fs::new(&imagename);
+            rt11.set_offset_blocks(fs.offset_blocks());
+            rt11.set_inverted(fs.is_inverted());
+            rt11.try_open()?;
+            Ok(Box::new(rt11))
+        }
+        FsKind::CsiDos => Err(FsError::Unsupported(kind)),
+        FsKind::MkDos | FsKind::Unknown => {
+            fs.try_open()?;
+            Ok(Box::new(fs))
+        }
+    }
+}
+
+/// Date of `--fake-date`: RFC 3339 or just a date (midnight UTC)
+#[cfg(unix)]
+fn parse_date(s: &str) -> Result<SystemTime, time::error::Parse> {
+    let date = match OffsetDateTime::parse(s, &Rfc3339) {
+        Ok(date) => date,
+        Err(_) => Date::parse(s, DATE_FORMAT)?.midnight().assume_utc(),
+    };
+    Ok(date.into())
+}
+
+/// Validator for integer arguments: `.validator(parse_int::<u64>)`
+#[cfg(unix)]
+fn parse_int<T>(s: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    s.parse::<T>()
+        .map_err(|e| format!("value must be an integer: {}", e))
+}
+
+/// Octal permission mask of `--fmask` and `--dmask`
+#[cfg(unix)]
+fn parse_mask(s: &str) -> Result<u16, String> {
+    match u16::from_str_radix(s, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        Ok(_) => Err("mask must be at most 777".to_string()),
+        Err(e) => Err(format!("mask must be an octal number: {}", e)),
     }
+}
 
-    info!("Starting");
-    fs.try_open()?;
-    fuser::mount2(fs, mountpoint, &options).map_or_else(
+/// Mount volume `fs` with generic fuse driver
+#[cfg(unix)]
+fn mount<B>(
+    fs: B,
+    settings: &MountSettings,
+    daemon: Option<&mut Daemon>,
+    mountpoint: &str,
+    options: &[MountOption],
+) -> Result<()>
+where
+    B: BkFileSystem + Send + Sync + 'static,
+{
+    let mut fs = FuseFs::new(fs);
+    if let Some(threads) = settings.threads {
+        fs.set_threads(threads);
+    }
+    fs.set_bin_headers(settings.bin_headers);
+    fs.set_parse_bin(settings.parse_bin);
+    fs.set_disk_file(settings.disk_file);
+    fs.set_control(settings.control);
+    fs.set_mountpoint(Path::new(mountpoint).canonicalize().ok());
+    fs.set_fake_date(settings.fake_date);
+    fs.set_owner(settings.uid, settings.gid);
+    fs.set_masks(settings.fmask, settings.dmask);
+    let mut session = fuser::Session::new(fs, Path::new(mountpoint), options)?;
+    // смонтировано: теперь можно отпустить родителя
+    if let Some(daemon) = daemon {
+        daemon.ready()?;
+    }
+    session.run().map_or_else(
         |e| match e.raw_os_error() {
             Some(0) => Ok(()),
             _ => Err(e),
@@ -125,7 +716,9 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-pub fn setup_logging() -> Result<()> {
+/// Log to stderr, `log_file` or syslog
+#[cfg(unix)]
+pub fn setup_logging(log_file: Option<&str>, syslog: bool) -> Result<()> {
     if std::env::var("RUST_LIB_BACKTRACE").is_err() {
         std::env::set_var("RUST_LIB_BACKTRACE", "full");
     }
@@ -134,13 +727,35 @@ pub fn setup_logging() -> Result<()> {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
-    tracing_subscriber::fmt::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+    let builder = tracing_subscriber::fmt::fmt().with_env_filter(EnvFilter::from_default_env());
+    if let Some(path) = log_file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        builder
+            .with_ansi(false)
+            .with_writer(Mutex::new(file))
+            .init();
+    } else if syslog {
+        builder
+            .with_ansi(false)
+            .without_time()
+            .with_writer(Syslog::open())
+            .init();
+    } else {
+        builder.init();
+    }
 
     Ok(())
 }
 
+#[cfg(not(unix))]
+fn main() {
+    eprintln!(
+        "{} needs FUSE and works only on unix",
+        env!("CARGO_PKG_NAME")
+    );
+    std::process::exit(1);
+}
+
 #[cfg(test)]
 mod tests {
     // use std::mem::size_of;
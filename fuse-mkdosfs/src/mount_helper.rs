@@ -0,0 +1,81 @@
+//! Режим mount хелпера: `mount.mkdosfs IMAGE MOUNTPOINT -o opts`
+//!
+//! mount(8) для `-t mkdosfs` и строк fstab запускает `/sbin/mount.mkdosfs`
+//! (ссылка на fuse-mkdosfs) и передает опции одной строкой через запятую.
+//! Переводим их в обычные аргументы: `key` в `--key`, `key=value` в
+//! `--key value`, дальше все проверяет clap. Общие опции mount(8),
+//! которые драйверу ничего не говорят, пропускаем.
+
+use std::{ffi::OsStr, path::Path};
+
+/// Options of mount(8) and fstab which mean nothing to the driver
+const IGNORED_OPTIONS: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "nouser", "users", "owner", "group", "nofail", "_netdev",
+    "exec", "noexec", "suid", "nosuid", "dev", "nodev", "atime", "noatime", "relatime", "sync",
+    "async", "fsname", "subtype",
+];
+
+/// Program is called as mount helper (`mount.mkdosfs`, `mount.fuse.mkdosfs`)
+pub fn is_mount_helper(argv0: &OsStr) -> bool {
+    Path::new(argv0)
+        .file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| name.starts_with("mount."))
+}
+
+/// Long option of the driver for mount option `name`
+/// (`show_deleted` and `show-deleted` are the same)
+fn long_option(name: &str) -> Option<String> {
+    match name {
+        // только чтение и так по умолчанию
+        "ro" => None,
+        "inverted" => Some("use-inverted".to_string()),
+        _ if IGNORED_OPTIONS.contains(&name) => None,
+        _ => Some(name.replace('_', "-")),
+    }
+}
+
+/// Convert comma separated `opts` to long options
+fn push_options(args: &mut Vec<String>, opts: &str) {
+    for opt in opts.split(',').filter(|opt| !opt.is_empty()) {
+        let (name, value) = match opt.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (opt, None),
+        };
+        if let Some(long) = long_option(name) {
+            args.push(format!("--{}", long));
+            args.extend(value.map(str::to_string));
+        }
+    }
+}
+
+/// Convert arguments of `mount.mkdosfs IMAGE MOUNTPOINT [-o opts] [-nsv]`
+/// (first one is program name) to the usual command line
+pub fn helper_args<I>(args: I) -> Result<Vec<String>, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    let mut result = vec![args.next().unwrap_or_default()];
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                let opts = args.next().ok_or("option -o requires a value")?;
+                push_options(&mut options, &opts);
+            }
+            // без mtab, sloppy, verbose: к драйверу отношения не имеют
+            "-n" | "-s" | "-v" => {}
+            _ if arg.starts_with("-o") => push_options(&mut options, &arg[2..]),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        return Err("usage: mount.mkdosfs IMAGE MOUNTPOINT [-o options]".to_string());
+    }
+    result.append(&mut positional);
+    result.append(&mut options);
+    Ok(result)
+}
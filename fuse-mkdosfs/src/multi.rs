@@ -0,0 +1,292 @@
+//! Несколько образов одним томом: каталог на каждый образ
+//!
+//! Коллекцию дампов дискет удобнее смотреть одним монтированием, а не
+//! сотней. Образ - каталог в корне с именем его файла, повторы имен
+//! получают суффиксы `~1`, `~2`, ... Том образа открывается при первом
+//! обращении к его каталогу (`OpenImage` решает, каким форматом) и живет до
+//! размонтирования. Образ, который не открылся, остается пустым каталогом,
+//! причина в логе.
+//!
+//! Иноды как в `HddFs`: в старших 32 битах номер образа + 1, в младших -
+//! инод внутри тома. Только для чтения. Образы перечитываются по отдельности,
+//! и открытые файлы протухают только при перечитывании своего образа.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use bkfs::{BinHeader, BkFileSystem, Entry, ErrorKind, FileKind, VolumeStats, ROOT_INODE};
+use mkdosfs::{compressed::IMAGE_EXTENSIONS, FsError};
+use tracing::{info, warn};
+
+/// Volume of one image
+pub type Volume = dyn BkFileSystem + Send + Sync;
+/// Opens image at path as a volume of any format
+pub type OpenImage = Box<dyn Fn(&Path) -> Result<Box<Volume>, FsError> + Send + Sync>;
+
+/// Контейнеры, которые разбираются сами (см. `mkdosfs::container`)
+const CONTAINER_EXTENSIONS: [&str; 5] = ["td0", "imd", "hfe", "gz", "zip"];
+
+/// Образ с ленивым томом
+struct Image {
+    name: String,
+    path: PathBuf,
+    mtime: Option<SystemTime>,
+    /// `None` внутри - образ не открылся
+    fs: OnceLock<Option<Box<Volume>>>,
+}
+
+pub struct MultiFs {
+    images: Vec<Image>,
+    open: OpenImage,
+}
+
+impl std::fmt::Debug for MultiFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MultiFs")
+            .field("images", &self.names())
+            .finish()
+    }
+}
+
+/// Image file by extension: raw image or container
+pub fn is_image_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .is_some_and(|ext| {
+            IMAGE_EXTENSIONS.contains(&ext.as_str()) || CONTAINER_EXTENSIONS.contains(&ext.as_str())
+        })
+}
+
+/// Image files in directory `dir` (not recursive), sorted by name
+pub fn image_files(dir: &Path) -> Result<Vec<PathBuf>, FsError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && is_image_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+impl MultiFs {
+    /// Images `paths` opened by `open` on first access
+    pub fn new(paths: Vec<PathBuf>, open: OpenImage) -> Self {
+        let mut taken = HashSet::new();
+        let images = paths
+            .into_iter()
+            .map(|path| {
+                let base = path
+                    .file_name()
+                    .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+                    .into_owned();
+                let name = match taken.contains(&base) {
+                    true => (1..)
+                        .map(|n| format!("{}~{}", base, n))
+                        .find(|alias| !taken.contains(alias))
+                        .unwrap_or_default(),
+                    false => base,
+                };
+                taken.insert(name.clone());
+                Image {
+                    name,
+                    mtime: fs::metadata(&path).and_then(|m| m.modified()).ok(),
+                    path,
+                    fs: OnceLock::new(),
+                }
+            })
+            .collect();
+        Self { images, open }
+    }
+
+    /// Names of image directories
+    pub fn names(&self) -> Vec<&str> {
+        self.images.iter().map(|i| i.name.as_str()).collect()
+    }
+
+    fn global_inode(idx: usize, inode: u64) -> u64 {
+        (idx as u64 + 1) << 32 | inode
+    }
+
+    /// Образ и инод внутри его тома, `None` для корня монтирования
+    fn image_inode(&self, ino: u64) -> Option<(usize, u64)> {
+        let idx = (ino >> 32) as usize;
+        if idx == 0 || idx > self.images.len() {
+            return None;
+        }
+        Some((idx - 1, ino & 0xffff_ffff))
+    }
+
+    /// Том образа `idx`, открывается при первом обращении
+    fn volume(&self, idx: usize) -> Option<&Volume> {
+        let image = &self.images[idx];
+        image
+            .fs
+            .get_or_init(|| match (self.open)(&image.path) {
+                Ok(fs) => {
+                    info!("Image {} opened", image.path.display());
+                    Some(fs)
+                }
+                Err(e) => {
+                    warn!("Can't open image {}: {}", image.path.display(), e);
+                    None
+                }
+            })
+            .as_deref()
+    }
+
+    /// Уже открытые тома
+    fn opened(&self) -> impl Iterator<Item = &Volume> {
+        self.images
+            .iter()
+            .filter_map(|image| image.fs.get()?.as_deref())
+    }
+
+    /// Каталог образа
+    fn image_dir(&self, idx: usize) -> Entry<'_> {
+        Entry {
+            inode: Self::global_inode(idx, ROOT_INODE),
+            parent_inode: ROOT_INODE,
+            name: &self.images[idx].name,
+            kind: FileKind::Directory,
+            size: 0,
+            blocks: 0,
+            mode: 0o555,
+            mtime: self.images[idx].mtime,
+        }
+    }
+
+    /// Запись тома с глобальными инодами, запись запрещена
+    fn image_entry<'a>(&self, idx: usize, entry: Entry<'a>) -> Entry<'a> {
+        Entry {
+            inode: Self::global_inode(idx, entry.inode),
+            parent_inode: Self::global_inode(idx, entry.parent_inode),
+            mode: entry.mode & !0o222,
+            mtime: entry.mtime.or(self.images[idx].mtime),
+            ..entry
+        }
+    }
+}
+
+impl BkFileSystem for MultiFs {
+    fn lookup(&self, parent_inode: u64, name: &str) -> Option<Entry<'_>> {
+        if parent_inode == ROOT_INODE {
+            let idx = self.images.iter().position(|i| i.name == name)?;
+            return Some(self.image_dir(idx));
+        }
+        let (idx, inode) = self.image_inode(parent_inode)?;
+        let entry = self.volume(idx)?.lookup(inode, name)?;
+        Some(self.image_entry(idx, entry))
+    }
+
+    fn metadata(&self, inode: u64) -> Option<Entry<'_>> {
+        let (idx, inode) = self.image_inode(inode)?;
+        if inode == ROOT_INODE {
+            return Some(self.image_dir(idx));
+        }
+        let entry = self.volume(idx)?.metadata(inode)?;
+        Some(self.image_entry(idx, entry))
+    }
+
+    fn list(&self, inode: u64) -> bkfs::Result<Vec<Entry<'_>>> {
+        if inode == ROOT_INODE {
+            return Ok((0..self.images.len())
+                .map(|idx| self.image_dir(idx))
+                .collect());
+        }
+        let (idx, inode) = self.image_inode(inode).ok_or(ErrorKind::NotFound)?;
+        // не открылся - пустой каталог
+        let Some(fs) = self.volume(idx) else {
+            return Ok(Vec::new());
+        };
+        Ok(fs
+            .list(inode)?
+            .into_iter()
+            .map(|e| self.image_entry(idx, e))
+            .collect())
+    }
+
+    fn read(&self, inode: u64, offset: u64, size: usize) -> bkfs::Result<Vec<u8>> {
+        let (idx, inode) = self.image_inode(inode).ok_or(ErrorKind::NotFound)?;
+        let fs = self.volume(idx).ok_or(ErrorKind::NotFound)?;
+        fs.read(inode, offset, size)
+    }
+
+    fn xattrs(&self, inode: u64) -> Vec<(String, Vec<u8>)> {
+        match self.image_inode(inode) {
+            Some((idx, inode)) if inode != ROOT_INODE => self
+                .volume(idx)
+                .map(|fs| fs.xattrs(inode))
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn bin_header(&self, inode: u64) -> Option<BinHeader> {
+        let (idx, inode) = self.image_inode(inode)?;
+        self.volume(idx)?.bin_header(inode)
+    }
+
+    /// Sum of opened images, others are not opened for statfs
+    fn stats(&self) -> VolumeStats {
+        self.opened()
+            .map(|fs| fs.stats())
+            .fold(VolumeStats::default(), |acc, s| VolumeStats {
+                blocks: acc.blocks + s.blocks,
+                free: acc.free + s.free,
+                files: acc.files + s.files,
+                free_files: acc.free_files + s.free_files,
+                namelen: s.namelen,
+            })
+    }
+
+    /// Latest of image files
+    fn last_modified(&self) -> SystemTime {
+        self.images
+            .iter()
+            .filter_map(|image| image.mtime)
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    fn is_modified(&self) -> bool {
+        self.opened().any(|fs| fs.is_modified())
+    }
+
+    /// Reread changed images, one that can't be reread doesn't stop others
+    fn refresh(&mut self) -> bkfs::Result<()> {
+        for image in self.images.iter_mut() {
+            let Some(Some(fs)) = image.fs.get_mut() else {
+                continue;
+            };
+            if fs.is_modified() {
+                if let Err(e) = fs.refresh() {
+                    warn!("Can't reopen image {}: {}", image.path.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sum of images generations: a reread of any of them makes it grow
+    fn generation(&self) -> u64 {
+        self.opened().map(|fs| fs.generation()).sum()
+    }
+
+    /// Generation of the image holding `inode`
+    fn inode_generation(&self, inode: u64) -> u64 {
+        self.image_inode(inode)
+            .and_then(|(idx, inode)| {
+                let fs = self.images[idx].fs.get()?.as_deref()?;
+                Some(fs.inode_generation(inode))
+            })
+            .unwrap_or(0)
+    }
+}
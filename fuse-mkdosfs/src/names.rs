@@ -0,0 +1,75 @@
+//! Имена записей на границе FUSE
+//!
+//! В каталоге БК имя может содержать '/', управляющие символы или быть
+//! пустым, "." или "..". Ядро такое имя не примет, и readdir ломается на
+//! всем каталоге. Такие имена показываем экранированными: неудобный символ
+//! заменяется на %XX (байты UTF-8), а '%' в таком имени - на %25, чтобы
+//! экранирование можно было развернуть однозначно. Пустое имя - "%".
+//! Обычные имена, и с '%' тоже, остаются как есть.
+//!
+//! Обратное отображение - `unescape_name()`, таблица имен не нужна: путь
+//! из ls работает и до readdir, и после перемонтирования.
+
+use std::borrow::Cow;
+
+/// Как показываем пустое имя, после экранирования '%' один не остается
+const EMPTY_NAME: &str = "%";
+
+/// Символ нельзя оставлять в имени для хоста
+fn is_hostile(c: char) -> bool {
+    c == '/' || c.is_control()
+}
+
+fn needs_escape(name: &str) -> bool {
+    name.is_empty() || name == "." || name == ".." || name.chars().any(is_hostile)
+}
+
+/// Name as shown to the host, see module docs
+pub fn escape_name(name: &str) -> Cow<'_, str> {
+    if !needs_escape(name) {
+        return Cow::Borrowed(name);
+    }
+    if name.is_empty() {
+        return Cow::Borrowed(EMPTY_NAME);
+    }
+    // точки экранируем только в самих "." и ".."
+    let dots = name == "." || name == "..";
+    let mut out = String::with_capacity(name.len() * 3);
+    for c in name.chars() {
+        if is_hostile(c) || c == '%' || (dots && c == '.') {
+            let mut buf = [0u8; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Volume name for `name` shown by `escape_name()`, `None` if `name` is
+/// not an escaped name
+pub fn unescape_name(name: &str) -> Option<String> {
+    if name == EMPTY_NAME {
+        return Some(String::new());
+    }
+    if !name.contains('%') {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b != b'%' {
+            bytes.push(b);
+            rest = tail;
+            continue;
+        }
+        let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &tail[2..];
+    }
+    let unescaped = String::from_utf8(bytes).ok()?;
+    // только то, что сами и показали бы: "%41" - обычное имя, не "A"
+    (escape_name(&unescaped) == name).then_some(unescaped)
+}
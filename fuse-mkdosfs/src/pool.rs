@@ -0,0 +1,131 @@
+use std::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+};
+
+use tracing::{trace, warn};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Простой пул потоков для обработки медленных fuse запросов (read)
+/// Ответ (`Reply*`) в fuser можно отдать из любого потока, поэтому
+/// основной цикл сессии только раздает задания и не ждет чтения
+pub struct ThreadPool {
+    tx: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("workers", &self.workers.len())
+            .finish()
+    }
+}
+
+impl Default for ThreadPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_THREADS)
+    }
+}
+
+/// Количество рабочих потоков по умолчанию
+pub const DEFAULT_THREADS: usize = 4;
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..size)
+            .map(|n| {
+                let rx = Arc::clone(&rx);
+                thread::Builder::new()
+                    .name(format!("fuse-worker-{}", n))
+                    .spawn(move || worker(n, rx))
+                    .expect("Can't spawn fuse worker thread")
+            })
+            .collect();
+
+        Self {
+            tx: Some(tx),
+            workers,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(tx) = self.tx.as_ref() {
+            if tx.send(Box::new(job)).is_err() {
+                warn!("All fuse workers are gone, job dropped");
+            }
+        }
+    }
+}
+
+fn worker(n: usize, rx: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match rx.lock() {
+            Ok(rx) => rx.recv(),
+            Err(_) => break,
+        };
+        match job {
+            Ok(job) => job(),
+            // канал закрыт, пул уничтожается
+            Err(_) => break,
+        }
+    }
+    trace!("fuse worker {} exited", n);
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // закрываем канал, чтобы воркеры вышли из recv()
+        drop(self.tx.take());
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Сколько свободных буферов держим, остальные освобождаются
+const MAX_FREE_BUFFERS: usize = 16;
+
+/// Буферы для ответов на read: память не выделяется на каждый запрос
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Buffer of at least `size` bytes (contents are left from previous use)
+    pub fn get(&self, size: usize) -> Vec<u8> {
+        let mut buf = self
+            .free
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop()
+            .unwrap_or_default();
+        if buf.len() < size {
+            buf.resize(size, 0);
+        }
+        buf
+    }
+
+    /// Return buffer taken by `get()`
+    pub fn put(&self, buf: Vec<u8>) {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        if free.len() < MAX_FREE_BUFFERS {
+            free.push(buf);
+        }
+    }
+}
@@ -0,0 +1,564 @@
+//! ANDOS: MS-DOS FAT12 на дисках БК
+//!
+//! Загрузочный сектор с BPB как у MS-DOS, одна или две копии FAT12,
+//! корневой каталог фиксированного размера и область данных кластерами.
+//! Имена 8.3 в KOI8-R (или в выбранной кодировке), подкаталоги - цепочки
+//! кластеров, как обычные файлы. Только чтение.
+//!
+//! Цепочки кластеров всех файлов разбираются при открытии: диски маленькие,
+//! а битая цепочка (петля, свободный или сбойный кластер, выход за диск)
+//! сразу помечает запись `is_corrupt`, читать ее нельзя.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{Read, Seek},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use crate::{
+    inode::ROOT_INODE,
+    io::{Reader, ReaderBuilder},
+    Encoding, FsError, GeometryMapper, BLOCK_SIZE,
+};
+
+pub const FAT_DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// длинные имена Windows, на БК их нет, пропускаем
+const ATTR_LFN: u8 = 0x0f;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+
+/// FAT12: 0xff8.. - конец цепочки, 0xff7 - сбойный кластер
+const FAT12_BAD: u16 = 0xff7;
+const FAT12_EOC: u16 = 0xff8;
+
+/// Параметры диска из загрузочного сектора (BPB)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Bpb {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    pub media: u8,
+    pub sectors_per_fat: u16,
+}
+
+impl Bpb {
+    /// Parse and validate BPB of boot sector
+    pub fn parse(boot: &[u8]) -> Result<Self, FsError> {
+        if boot.len() < BLOCK_SIZE {
+            return Err(FsError::BadBootSector("short boot sector".into()));
+        }
+        let word = |off: usize| u16::from_le_bytes([boot[off], boot[off + 1]]);
+        let mut bpb = Self {
+            bytes_per_sector: word(0x0b),
+            sectors_per_cluster: boot[0x0d],
+            reserved_sectors: word(0x0e),
+            fats: boot[0x10],
+            root_entries: word(0x11),
+            total_sectors: word(0x13) as u32,
+            media: boot[0x15],
+            sectors_per_fat: word(0x16),
+        };
+        if bpb.total_sectors == 0 {
+            bpb.total_sectors =
+                u32::from_le_bytes([boot[0x20], boot[0x21], boot[0x22], boot[0x23]]);
+        }
+
+        if bpb.bytes_per_sector as usize != BLOCK_SIZE {
+            return Err(FsError::BadBootSector(format!(
+                "sector size {}",
+                bpb.bytes_per_sector
+            )));
+        }
+        if !bpb.sectors_per_cluster.is_power_of_two() {
+            return Err(FsError::BadBootSector(format!(
+                "{} sectors per cluster",
+                bpb.sectors_per_cluster
+            )));
+        }
+        if !(1..=2).contains(&bpb.fats) || bpb.sectors_per_fat == 0 {
+            return Err(FsError::BadBootSector(format!(
+                "{} FATs of {} sectors",
+                bpb.fats, bpb.sectors_per_fat
+            )));
+        }
+        if bpb.reserved_sectors == 0 || bpb.root_entries == 0 {
+            return Err(FsError::BadBootSector(
+                "no reserved sectors or root entries".into(),
+            ));
+        }
+        if bpb.data_start() >= bpb.total_sectors as u64 {
+            return Err(FsError::BadBootSector(format!(
+                "disk of {} sectors has no data area",
+                bpb.total_sectors
+            )));
+        }
+        // больше кластеров FAT12 не адресует, это уже FAT16
+        if bpb.clusters() > FAT12_BAD as u64 - 2 {
+            return Err(FsError::BadBootSector(format!(
+                "{} clusters is too many for FAT12",
+                bpb.clusters()
+            )));
+        }
+        Ok(bpb)
+    }
+
+    pub fn fat_start(&self) -> u64 {
+        self.reserved_sectors as u64
+    }
+
+    pub fn root_start(&self) -> u64 {
+        self.fat_start() + self.fats as u64 * self.sectors_per_fat as u64
+    }
+
+    pub fn root_sectors(&self) -> u64 {
+        (self.root_entries as u64 * FAT_DIR_ENTRY_SIZE as u64).div_ceil(BLOCK_SIZE as u64)
+    }
+
+    pub fn data_start(&self) -> u64 {
+        self.root_start() + self.root_sectors()
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster as u64 * BLOCK_SIZE as u64
+    }
+
+    /// Data clusters on disk (numbered from 2)
+    pub fn clusters(&self) -> u64 {
+        (self.total_sectors as u64).saturating_sub(self.data_start())
+            / self.sectors_per_cluster as u64
+    }
+
+    /// Byte offset of cluster `cluster` from start of disk
+    pub fn cluster_offset(&self, cluster: u16) -> u64 {
+        (self.data_start() + (cluster as u64 - 2) * self.sectors_per_cluster as u64)
+            * BLOCK_SIZE as u64
+    }
+}
+
+/// ANDOS catalog entry
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AndosEntry {
+    /// `NAME.EXT`
+    pub name: String,
+    /// virtual inode, root is `ROOT_INODE`, others are numbered in catalog order
+    pub inode: u64,
+    pub parent_inode: u64,
+    pub is_dir: bool,
+    pub is_read_only: bool,
+    /// broken cluster chain, entry can't be read
+    pub is_corrupt: bool,
+    /// FAT attributes byte
+    pub attr: u8,
+    pub first_cluster: u16,
+    pub size: u32,
+    /// unix mode
+    pub mode: u16,
+    /// кластеры файла по порядку
+    #[cfg_attr(feature = "serde", serde(skip))]
+    clusters: Vec<u16>,
+}
+
+impl AndosEntry {
+    /// Disk blocks occupied by entry
+    pub fn blocks(&self, bpb: &Bpb) -> u64 {
+        self.clusters.len() as u64 * bpb.sectors_per_cluster as u64
+    }
+}
+
+/// ANDOS volume over image backend `R` (file by default)
+pub struct AndosFs<R = File> {
+    /// path to image
+    file_path: String,
+    reader: Option<Reader<R>>,
+    /// offset from start of image in bytes
+    offset: u64,
+    inverted: bool,
+    /// bytes in 16-bit words are swapped
+    swapped: bool,
+    /// block remapping of side by side dumps
+    geometry: Option<GeometryMapper>,
+    /// file names encoding
+    encoding: Encoding,
+    last_modified: SystemTime,
+    bpb: Bpb,
+    /// первая копия FAT, по элементу на кластер
+    fat: Vec<u16>,
+    entries: Vec<AndosEntry>,
+    /// inode -> позиция в entries
+    by_inode: HashMap<u64, usize>,
+    _tracing_span: tracing::Span,
+}
+
+impl<R> Debug for AndosFs<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AndosFs")
+            .field("file_name", &self.file_path)
+            .field("offset", &self.offset)
+            .field("inverted", &self.inverted)
+            .field("swapped", &self.swapped)
+            .field("geometry", &self.geometry)
+            .field("encoding", &self.encoding)
+            .field("bpb", &self.bpb)
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+impl<R> Default for AndosFs<R> {
+    fn default() -> Self {
+        Self {
+            file_path: String::default(),
+            reader: None,
+            offset: 0,
+            inverted: false,
+            swapped: false,
+            geometry: None,
+            encoding: Encoding::default(),
+            last_modified: SystemTime::UNIX_EPOCH,
+            bpb: Bpb::default(),
+            fat: Vec::new(),
+            entries: Vec::new(),
+            by_inode: HashMap::new(),
+            _tracing_span: tracing::span!(tracing::Level::TRACE, "AndosFs"),
+        }
+    }
+}
+
+impl AndosFs {
+    pub fn new(fname: &str) -> Self {
+        Self {
+            file_path: fname.into(),
+            ..Default::default()
+        }
+    }
+
+    #[instrument(level = "trace", skip(self), fields(file_path, ?self.file_path))]
+    pub fn try_open(&mut self) -> Result<(), FsError> {
+        let fname = PathBuf::new().join(&self.file_path);
+        let h = OpenOptions::new()
+            .read(true)
+            .open(&fname)
+            .map_err(|e| FsError::CustomIo {
+                desc: format!("Can't open {:?}", &fname),
+                source: e,
+            })?;
+        let reader = ReaderBuilder::with_flags(self.inverted, self.swapped)
+            .with_geometry(self.geometry.as_ref())
+            .build_file(h)?;
+        self.open_reader(reader)
+    }
+}
+
+impl<R> AndosFs<R>
+where
+    R: Read + Seek,
+{
+    /// Open ANDOS volume from any `Read + Seek` source with default settings
+    pub fn from_reader(reader: R) -> Result<Self, FsError> {
+        let mut fs = Self::default();
+        fs.try_open_reader(reader)?;
+        Ok(fs)
+    }
+
+    /// Like `try_open()`, but reads image from `reader`
+    pub fn try_open_reader(&mut self, reader: R) -> Result<(), FsError> {
+        let reader = ReaderBuilder::with_flags(self.inverted, self.swapped)
+            .with_geometry(self.geometry.as_ref())
+            .build(reader);
+        self.open_reader(reader)
+    }
+
+    fn open_reader(&mut self, reader: Reader<R>) -> Result<(), FsError> {
+        if let Some(mt) = reader.modified() {
+            self.last_modified = mt;
+        }
+        self.reader = Some(reader);
+        self.entries = Vec::new();
+        self.by_inode = HashMap::new();
+
+        let mut boot = vec![0u8; BLOCK_SIZE];
+        self.read_full(&mut boot, 0)?;
+        self.bpb = Bpb::parse(&boot)?;
+        self.read_fat()?;
+        self.read_dir(ROOT_INODE, None, &mut HashSet::new())?;
+
+        Ok(())
+    }
+
+    fn read_full(&self, buf: &mut [u8], offset: u64) -> Result<(), FsError> {
+        let size = self.read_exact_at(buf, offset)?;
+        if size != buf.len() {
+            return Err(FsError::CustomIo {
+                desc: format!("Short read at {}", offset),
+                source: std::io::ErrorKind::UnexpectedEof.into(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Позиционное чтение от начала тома
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        match self.reader.as_ref() {
+            Some(reader) => reader.read_at(buf, self.offset + offset),
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn read_fat(&mut self) -> Result<(), FsError> {
+        let mut raw = vec![0u8; self.bpb.sectors_per_fat as usize * BLOCK_SIZE];
+        self.read_full(&mut raw, self.bpb.fat_start() * BLOCK_SIZE as u64)?;
+        let count = std::cmp::min(self.bpb.clusters() as usize + 2, raw.len() * 2 / 3);
+        self.fat = (0..count)
+            .map(|n| {
+                // 12 бит на элемент, два элемента в трех байтах
+                let off = n * 3 / 2;
+                let pair = u16::from_le_bytes([raw[off], raw[off + 1]]);
+                if n % 2 == 0 {
+                    pair & 0x0fff
+                } else {
+                    pair >> 4
+                }
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Кластеры цепочки с `first`, `None` если цепочка битая
+    fn chain(&self, first: u16) -> Option<Vec<u16>> {
+        let mut clusters = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cluster = first;
+        loop {
+            if cluster < 2 || cluster as usize >= self.fat.len() || !seen.insert(cluster) {
+                return None;
+            }
+            clusters.push(cluster);
+            match self.fat[cluster as usize] {
+                next if next >= FAT12_EOC => return Some(clusters),
+                0 | FAT12_BAD => return None,
+                next => cluster = next,
+            }
+        }
+    }
+
+    /// Читает каталог (`clusters` = `None` - корневой) и рекурсивно подкаталоги.
+    /// `visited` - первые кластеры уже прочитанных каталогов, от петель
+    fn read_dir(
+        &mut self,
+        parent_inode: u64,
+        clusters: Option<&[u16]>,
+        visited: &mut HashSet<u16>,
+    ) -> Result<(), FsError> {
+        let raw = match clusters {
+            None => {
+                let mut raw = vec![0u8; self.bpb.root_entries as usize * FAT_DIR_ENTRY_SIZE];
+                self.read_full(&mut raw, self.bpb.root_start() * BLOCK_SIZE as u64)?;
+                raw
+            }
+            Some(clusters) => {
+                let size = self.bpb.cluster_size() as usize;
+                let mut raw = vec![0u8; clusters.len() * size];
+                for (chunk, &cluster) in raw.chunks_mut(size).zip(clusters) {
+                    self.read_full(chunk, self.bpb.cluster_offset(cluster))?;
+                }
+                raw
+            }
+        };
+
+        let mut subdirs = Vec::new();
+        for raw in raw.chunks_exact(FAT_DIR_ENTRY_SIZE) {
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_DELETED | b'.' => continue,
+                _ => {}
+            }
+            let attr = raw[11];
+            if attr == ATTR_LFN || attr & ATTR_VOLUME != 0 {
+                continue;
+            }
+
+            let mut entry = AndosEntry {
+                name: self.decode_name(&raw[0..8], &raw[8..11]),
+                inode: ROOT_INODE + 1 + self.entries.len() as u64,
+                parent_inode,
+                is_dir: attr & ATTR_DIRECTORY != 0,
+                is_read_only: attr & ATTR_READ_ONLY != 0,
+                attr,
+                first_cluster: u16::from_le_bytes([raw[26], raw[27]]),
+                size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+                ..Default::default()
+            };
+            entry.mode = match (entry.is_dir, entry.is_read_only) {
+                (true, _) => 0o555,
+                (false, true) => 0o444,
+                (false, false) => 0o644,
+            };
+
+            // пустой файл кластеров не занимает
+            if entry.first_cluster != 0 || entry.is_dir {
+                match self.chain(entry.first_cluster) {
+                    Some(clusters) => entry.clusters = clusters,
+                    None => entry.is_corrupt = true,
+                }
+            }
+            if !entry.is_dir
+                && entry.clusters.len() as u64 * self.bpb.cluster_size() < entry.size as u64
+            {
+                entry.is_corrupt = true;
+            }
+            if entry.is_corrupt {
+                warn!(parent: &self._tracing_span, "Broken cluster chain of {:?}", entry.name);
+            }
+            if entry.is_dir && !entry.is_corrupt && visited.insert(entry.first_cluster) {
+                subdirs.push(self.entries.len());
+            }
+            self.by_inode.insert(entry.inode, self.entries.len());
+            self.entries.push(entry);
+        }
+
+        for idx in subdirs {
+            let (inode, clusters) = {
+                let e = &self.entries[idx];
+                (e.inode, e.clusters.clone())
+            };
+            self.read_dir(inode, Some(&clusters), visited)?;
+        }
+        Ok(())
+    }
+
+    fn decode_name(&self, name: &[u8], ext: &[u8]) -> String {
+        let trim = |raw: &[u8]| {
+            let len = raw.iter().rposition(|&b| b != b' ').map_or(0, |p| p + 1);
+            self.encoding.decode(&raw[..len]).0.into_owned()
+        };
+        let (name, ext) = (trim(name), trim(ext));
+        if ext.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, ext)
+        }
+    }
+
+    /// Read up to `size` bytes of file `inode` from `offset`,
+    /// result is short (or empty) at end of file
+    pub fn read_file(&self, inode: u64, offset: u64, size: usize) -> Result<Vec<u8>, FsError> {
+        let entry = self.entrie_by_inode(inode).ok_or(FsError::NotFound)?;
+        if entry.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        if entry.is_corrupt {
+            return Err(FsError::CorruptEntry(entry.name.clone()));
+        }
+
+        let size = std::cmp::min(size as u64, (entry.size as u64).saturating_sub(offset)) as usize;
+        let mut buf = vec![0u8; size];
+        let cluster_size = self.bpb.cluster_size();
+        let mut done = 0;
+        while done < size {
+            let pos = offset + done as u64;
+            let cluster = entry.clusters[(pos / cluster_size) as usize];
+            let skip = pos % cluster_size;
+            let n = std::cmp::min((cluster_size - skip) as usize, size - done);
+            self.read_full(
+                &mut buf[done..done + n],
+                self.bpb.cluster_offset(cluster) + skip,
+            )?;
+            done += n;
+        }
+        Ok(buf)
+    }
+}
+
+impl<R> AndosFs<R> {
+    /// All entries of the volume
+    pub fn iter_all(&self) -> impl Iterator<Item = &AndosEntry> {
+        self.entries.iter()
+    }
+
+    /// Entries of directory `parent_inode` in catalog order
+    pub fn iter_dir(&self, parent_inode: u64) -> impl Iterator<Item = &AndosEntry> {
+        self.entries
+            .iter()
+            .filter(move |e| e.parent_inode == parent_inode)
+    }
+
+    pub fn entrie_by_inode(&self, inode: u64) -> Option<&AndosEntry> {
+        self.entries.get(*self.by_inode.get(&inode)?)
+    }
+
+    /// ищет `name` в каталоге с `parent_inode`, как и в MS-DOS без учета регистра
+    pub fn find_entrie(&self, name: &str, parent_inode: u64) -> Option<&AndosEntry> {
+        let name = name.to_uppercase();
+        self.iter_dir(parent_inode)
+            .find(|e| e.name.to_uppercase() == name)
+    }
+
+    pub fn bpb(&self) -> &Bpb {
+        &self.bpb
+    }
+
+    pub fn block_size(&self) -> u64 {
+        BLOCK_SIZE as u64
+    }
+
+    /// Disk size in blocks
+    pub fn disk_size(&self) -> u64 {
+        self.bpb.total_sectors as u64
+    }
+
+    /// Free blocks (free clusters)
+    pub fn free_blocks(&self) -> u64 {
+        let free = self.fat.iter().skip(2).filter(|&&c| c == 0).count() as u64;
+        free * self.bpb.sectors_per_cluster as u64
+    }
+
+    /// Files (directories are not counted)
+    pub fn files(&self) -> u64 {
+        self.entries.iter().filter(|e| !e.is_dir).count() as u64
+    }
+
+    pub fn last_modified(&self) -> SystemTime {
+        self.last_modified
+    }
+
+    /// Offset from start of image in blocks (HDD partitions)
+    pub fn set_offset_blocks(&mut self, offset: u64) {
+        self.offset = offset * BLOCK_SIZE as u64;
+    }
+
+    pub fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
+    }
+
+    /// Swap bytes in every 16-bit word of the image (used on next open)
+    pub fn set_swapped(&mut self, swapped: bool) {
+        self.swapped = swapped;
+    }
+
+    /// Remap blocks of side by side, sector interleaved image
+    /// (used on next open)
+    pub fn set_geometry(&mut self, geometry: Option<GeometryMapper>) {
+        self.geometry = geometry;
+    }
+
+    /// File names encoding (used on next open)
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+}
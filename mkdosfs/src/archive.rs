@@ -0,0 +1,544 @@
+//! Распаковка тома в каталог хоста и обратная сборка образа
+//!
+//! `extract_all()` раскладывает живые записи по каталогам хоста
+//! (логические диски - тоже каталоги) и рядом с каждой кладет sidecar с
+//! тем, что на хосте не сохранить: адрес загрузки, статус, исходное имя.
+//! Sidecar бывает JSON (`NAME.json`) или однострочный `.inf`, как у других
+//! ретро утилит: исходное имя байтами образа (KOI8-R), адрес, длина и
+//! статус восьмеричными числами.
+//!
+//! В корень пишется `manifest.json`: геометрия тома, кодировка имен и все
+//! записи в порядке каталога. По нему `pack()` собирает равноценный образ:
+//! те же каталоги, файлы, адреса и статусы, в том же порядке (номера
+//! блоков могут отличаться, дыр от удаленных файлов нет).
+//!
+//! Без манифеста `scan_tree()` строит его по любому дереву хоста: каталоги
+//! становятся каталогами MK-DOS, адреса берутся из sidecar, если они есть.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub use crate::host_name;
+use crate::{
+    inode::ROOT_INODE, progress::Operation, DirEntry, DirEntryStatus, Encoding, Fs, FsError,
+    MetaOffset, Progress, BLOCK_SIZE,
+};
+
+/// Name of manifest file in the root of extracted tree
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Format of per-entry metadata files written next to extracted entries
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Sidecar {
+    #[default]
+    Json,
+    Inf,
+    None,
+}
+
+impl FromStr for Sidecar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "inf" => Ok(Self::Inf),
+            "none" => Ok(Self::None),
+            _ => Err(format!("unknown sidecar format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Sidecar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Inf => write!(f, "inf"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Volume size and first data block, as in meta block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Geometry {
+    pub disk_size: u16,
+    pub start_block: u16,
+}
+
+/// Entry metadata host file system can't keep (sidecar contents)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntryMeta {
+    /// decoded name
+    pub name: String,
+    /// name bytes in image encoding as hex, trailing spaces are trimmed
+    pub raw_name: String,
+    pub status: DirEntryStatus,
+    #[serde(with = "bkhdd::octal")]
+    pub start_address: u32,
+    pub size: u32,
+    pub blocks: u64,
+    /// geometry of logical disk extracted as directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<Geometry>,
+}
+
+impl EntryMeta {
+    fn is_dir(&self) -> bool {
+        matches!(self.status, DirEntryStatus::Directory)
+    }
+}
+
+/// Entry of manifest: path of extracted file or directory and its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// relative to manifest, `/`-separated
+    pub path: String,
+    #[serde(flatten)]
+    pub meta: EntryMeta,
+}
+
+/// Everything `pack()` needs to rebuild image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub volume: Geometry,
+    /// file names encoding (see `Encoding`)
+    pub encoding: String,
+    /// in catalog order, entries of logical disks follow outer volume
+    pub entries: Vec<ManifestEntry>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn trim_raw_name(raw: &[u8]) -> &[u8] {
+    let len = raw
+        .iter()
+        .rposition(|&b| b != b' ')
+        .map_or(0, |pos| pos + 1);
+    &raw[..len]
+}
+
+/// Путь записи на хосте от корня тома по цепочке каталогов
+fn host_path(fs: &Fs, entry: &DirEntry, dirs: &HashMap<u64, &DirEntry>) -> String {
+    let mut parts = vec![host_name(fs.entry_name(entry))];
+    let mut parent = entry.parent_inode;
+    // петли порваны еще на чтении, ограничение - просто страховка
+    for _ in 0..dirs.len() {
+        match dirs.get(&parent) {
+            Some(dir) => {
+                parts.push(host_name(fs.entry_name(dir)));
+                parent = dir.parent_inode;
+            }
+            None => break,
+        }
+    }
+    parts.reverse();
+    parts.join("/")
+}
+
+/// Геометрия вложенного тома из его мета блока
+fn logical_geometry(fs: &Fs, entry: &DirEntry) -> Result<Geometry, FsError> {
+    let mut buf = [0u8; 4];
+    let offset = entry.start_block * BLOCK_SIZE as u64 + MetaOffset::DiskSize as u64;
+    fs.read_exact_at(&mut buf, offset)?;
+    Ok(Geometry {
+        disk_size: u16::from_le_bytes([buf[0], buf[1]]),
+        start_block: u16::from_le_bytes([buf[2], buf[3]]),
+    })
+}
+
+fn sidecar_path(host: &Path, ext: &str) -> PathBuf {
+    let mut name = OsString::from(host.as_os_str());
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+fn write_sidecar(
+    host: &Path,
+    entry: &DirEntry,
+    meta: &EntryMeta,
+    sidecar: Sidecar,
+) -> Result<(), FsError> {
+    match sidecar {
+        Sidecar::Json => {
+            let mut out = BufWriter::new(File::create(sidecar_path(host, "json"))?);
+            serde_json::to_writer_pretty(&mut out, meta).map_err(std::io::Error::from)?;
+            writeln!(out)?;
+            out.flush()?;
+        }
+        Sidecar::Inf => {
+            let mut line = trim_raw_name(entry.raw_name()).to_vec();
+            line.extend_from_slice(
+                format!(
+                    " {:06o} {:06o} {:03o}\n",
+                    meta.start_address,
+                    meta.size,
+                    u8::from(meta.status)
+                )
+                .as_bytes(),
+            );
+            fs::write(sidecar_path(host, "inf"), line)?;
+        }
+        Sidecar::None => {}
+    }
+    Ok(())
+}
+
+/// Copy live entries of `fs` into directory `dest` keeping directories and
+/// logical disks (open `fs` with `set_open_logical(true)` to get them as
+/// directories), write sidecars and manifest. Unreadable files are skipped
+pub fn extract_all(fs: &Fs, dest: &Path, sidecar: Sidecar) -> Result<Manifest, FsError> {
+    fs::create_dir_all(dest)?;
+    let live: Vec<&DirEntry> = fs
+        .iter_all()
+        .filter(|e| !e.is_deleted && !e.is_bad && !e.is_garbage)
+        .collect();
+    let dirs: HashMap<u64, &DirEntry> = live
+        .iter()
+        .filter(|e| e.is_dir || e.is_volume)
+        .map(|e| (e.inode, *e))
+        .collect();
+
+    let mut manifest = Manifest {
+        volume: Geometry {
+            disk_size: fs.meta.disk_size,
+            start_block: fs.meta.start_block,
+        },
+        encoding: fs.encoding().to_string(),
+        entries: Vec::with_capacity(live.len()),
+    };
+    let total = live.len() as u64;
+    for (n, entry) in live.into_iter().enumerate() {
+        fs.report_progress(
+            Progress::new(Operation::ExtractAll, n as u64, total).with_item(&entry.name),
+        );
+        let path = host_path(fs, entry, &dirs);
+        let host = dest.join(&path);
+        let mut meta = EntryMeta {
+            name: entry.name.clone(),
+            raw_name: hex(trim_raw_name(entry.raw_name())),
+            status: entry.status,
+            start_address: entry.start_address,
+            size: entry.size,
+            blocks: entry.blocks,
+            volume: None,
+        };
+        if entry.is_dir || entry.is_volume {
+            fs::create_dir_all(&host)?;
+            if entry.is_volume {
+                meta.volume = Some(logical_geometry(fs, entry)?);
+            }
+        } else {
+            if let Some(parent) = host.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match fs.read_entry_data(entry) {
+                Ok(data) => fs::write(&host, data)?,
+                Err(e) => {
+                    warn!("Can't extract {:?}: {}", path, e);
+                    continue;
+                }
+            }
+        }
+        write_sidecar(&host, entry, &meta, sidecar)?;
+        manifest.entries.push(ManifestEntry { path, meta });
+    }
+    fs.report_progress(Progress::new(Operation::ExtractAll, total, total));
+
+    let mut out = BufWriter::new(File::create(dest.join(MANIFEST_NAME))?);
+    serde_json::to_writer_pretty(&mut out, &manifest).map_err(std::io::Error::from)?;
+    writeln!(out)?;
+    out.flush()?;
+
+    Ok(manifest)
+}
+
+/// Manifest of directory made by `extract_all()`, `None` if there is no one
+pub fn read_manifest(src: &Path) -> Result<Option<Manifest>, FsError> {
+    let file = match File::open(src.join(MANIFEST_NAME)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_reader(BufReader::new(file))
+        .map(Some)
+        .map_err(|e| FsError::BadManifest(e.to_string()))
+}
+
+/// Метаданные из `.inf`: имя байтами образа, адрес, длина, статус
+fn parse_inf(line: &[u8], encoding: Encoding) -> Option<EntryMeta> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    // в имени бывают пробелы, поэтому поля разбираем с конца
+    let mut fields = line.rsplitn(4, |&b| b == b' ');
+    let mut octal = || u32::from_str_radix(std::str::from_utf8(fields.next()?).ok()?, 8).ok();
+    let status = octal()?;
+    let size = octal()?;
+    let start_address = octal()?;
+    let raw = fields.next()?;
+    let status = match status {
+        0 => DirEntryStatus::Normal,
+        1 => DirEntryStatus::Protected,
+        2 => DirEntryStatus::LogicalDisk,
+        4 => DirEntryStatus::Directory,
+        _ => return None,
+    };
+    Some(EntryMeta {
+        name: encoding.decode(raw).0.into_owned(),
+        raw_name: hex(raw),
+        status,
+        start_address,
+        size,
+        blocks: 0,
+        volume: None,
+    })
+}
+
+/// Sidecar записи `host`, если он есть и читается
+fn read_sidecar(host: &Path, encoding: Encoding) -> Option<EntryMeta> {
+    let json = sidecar_path(host, "json");
+    if json.is_file() {
+        match fs::read(&json).map(|data| serde_json::from_slice(&data)) {
+            Ok(Ok(meta)) => return Some(meta),
+            Ok(Err(e)) => warn!("Bad sidecar {:?}: {}", json, e),
+            Err(e) => warn!("Can't read {:?}: {}", json, e),
+        }
+    }
+    let inf = sidecar_path(host, "inf");
+    if inf.is_file() {
+        let meta = fs::read(&inf)
+            .ok()
+            .and_then(|line| parse_inf(&line, encoding));
+        if meta.is_none() {
+            warn!("Bad sidecar {:?}", inf);
+        }
+        return meta;
+    }
+    None
+}
+
+/// `NAME.json` и `NAME.inf` рядом с `NAME` - это sidecar, а не файлы
+fn is_sidecar(host: &Path) -> bool {
+    match host.extension().and_then(|ext| ext.to_str()) {
+        Some("json" | "inf") => host.with_extension("").exists(),
+        _ => false,
+    }
+}
+
+fn scan_dir(
+    src: &Path,
+    prefix: &str,
+    encoding: Encoding,
+    entries: &mut Vec<ManifestEntry>,
+) -> Result<(), FsError> {
+    let mut names = fs::read_dir(src.join(prefix))?
+        .map(|dirent| Ok(dirent?.file_name()))
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+    names.sort();
+    for name in names {
+        let name = name.to_string_lossy();
+        let path = format!("{}{}", prefix, name);
+        let host = src.join(&path);
+        if (prefix.is_empty() && name == MANIFEST_NAME) || is_sidecar(&host) {
+            continue;
+        }
+        let is_dir = host.is_dir();
+        let size = if is_dir { 0 } else { host.metadata()?.len() };
+        let mut meta = read_sidecar(&host, encoding).unwrap_or_else(|| EntryMeta {
+            name: crate::truncate_name(&name, is_dir),
+            raw_name: String::new(),
+            status: if is_dir {
+                DirEntryStatus::Directory
+            } else {
+                DirEntryStatus::Normal
+            },
+            start_address: crate::DEFAULT_START_ADDRESS,
+            size: 0,
+            blocks: 0,
+            volume: None,
+        });
+        // каталог хоста - каталог MK-DOS или логический диск, если sidecar так
+        // говорит (в `.inf` геометрии нет, размер диска берем из длины)
+        if is_dir && matches!(meta.status, DirEntryStatus::LogicalDisk) && meta.volume.is_none() {
+            meta.volume = u16::try_from(meta.size as usize / BLOCK_SIZE)
+                .ok()
+                .filter(|&disk_size| disk_size > crate::DEFAULT_START_BLOCK)
+                .map(|disk_size| Geometry {
+                    disk_size,
+                    start_block: crate::DEFAULT_START_BLOCK,
+                });
+        }
+        if is_dir && meta.volume.is_none() {
+            meta.status = DirEntryStatus::Directory;
+        }
+        if !is_dir {
+            meta.size = size as u32;
+            meta.blocks = size.div_ceil(BLOCK_SIZE as u64);
+            meta.volume = None;
+            if meta.is_dir() {
+                meta.status = DirEntryStatus::Normal;
+            }
+        }
+        entries.push(ManifestEntry { path, meta });
+        if is_dir {
+            scan_dir(src, &format!("{}{}/", prefix, name), encoding, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Manifest for any host directory tree: subdirectories become MK-DOS
+/// directories, load addresses, statuses and names are taken from sidecars
+/// if there are ones (other files get `DEFAULT_START_ADDRESS`)
+pub fn scan_tree(src: &Path, volume: Geometry, encoding: Encoding) -> Result<Manifest, FsError> {
+    let mut entries = Vec::new();
+    scan_dir(src, "", encoding, &mut entries)?;
+    Ok(Manifest {
+        volume,
+        encoding: encoding.to_string(),
+        entries,
+    })
+}
+
+/// Build new image `image` from files of directory `src` listed in
+/// `manifest` (see `read_manifest()`, `scan_tree()`). Existing file is overwritten
+pub fn pack(src: &Path, image: &Path, manifest: &Manifest) -> Result<(), FsError> {
+    pack_with_progress(src, image, manifest, &|_| {})
+}
+
+/// `pack()` reporting manifest entries written to `progress`
+pub fn pack_with_progress(
+    src: &Path,
+    image: &Path,
+    manifest: &Manifest,
+    progress: &dyn Fn(Progress),
+) -> Result<(), FsError> {
+    let encoding = manifest.encoding.parse::<Encoding>()?;
+    let mut counter = PackProgress {
+        done: 0,
+        total: manifest.entries.len() as u64,
+        report: progress,
+    };
+    pack_volume(
+        src,
+        image,
+        manifest.volume,
+        encoding,
+        &manifest.entries,
+        "",
+        &mut counter,
+    )?;
+    progress(Progress::new(Operation::Pack, counter.total, counter.total));
+    Ok(())
+}
+
+/// Счетчик записей манифеста на все вложенные тома
+struct PackProgress<'a> {
+    done: u64,
+    total: u64,
+    report: &'a dyn Fn(Progress),
+}
+
+impl PackProgress<'_> {
+    fn step(&mut self, name: &str) {
+        (self.report)(Progress::new(Operation::Pack, self.done, self.total).with_item(name));
+        self.done += 1;
+    }
+}
+
+fn parent_inode(dirs: &HashMap<&str, u64>, rel: &str) -> Result<u64, FsError> {
+    let parent = rel.rsplit_once('/').map_or("", |(parent, _)| parent);
+    dirs.get(parent)
+        .copied()
+        .ok_or_else(|| FsError::BadManifest(format!("no directory for {:?}", rel)))
+}
+
+/// Том из записей манифеста под `prefix`, вложенные логические диски
+/// собираются рядом с образом во временных файлах
+fn pack_volume(
+    src: &Path,
+    image: &Path,
+    geometry: Geometry,
+    encoding: Encoding,
+    entries: &[ManifestEntry],
+    prefix: &str,
+    progress: &mut PackProgress,
+) -> Result<(), FsError> {
+    Fs::format(image, geometry.disk_size, geometry.start_block)?;
+    let mut fs = Fs::new(&image.to_string_lossy());
+    fs.set_read_only(false);
+    fs.set_encoding(encoding);
+    fs.try_open()?;
+
+    // свои записи: под prefix, но не внутри вложенных дисков
+    let under: Vec<(&str, &ManifestEntry)> = entries
+        .iter()
+        .filter_map(|e| Some((e.path.strip_prefix(prefix)?, e)))
+        .filter(|(rel, _)| !rel.is_empty())
+        .collect();
+    let volumes: Vec<&str> = under
+        .iter()
+        .filter(|(_, e)| e.meta.volume.is_some())
+        .map(|(rel, _)| *rel)
+        .collect();
+    let own: Vec<(&str, &ManifestEntry)> = under
+        .into_iter()
+        .filter(|(rel, _)| {
+            !volumes
+                .iter()
+                .any(|v| rel.strip_prefix(v).is_some_and(|r| r.starts_with('/')))
+        })
+        .collect();
+
+    // каталоги раньше файлов, родители раньше детей
+    let mut dirs: HashMap<&str, u64> = HashMap::from([("", ROOT_INODE)]);
+    let mut new_dirs: Vec<&(&str, &ManifestEntry)> =
+        own.iter().filter(|(_, e)| e.meta.is_dir()).collect();
+    new_dirs.sort_by_key(|(rel, _)| rel.matches('/').count());
+    for &&(rel, entry) in new_dirs.iter() {
+        progress.step(&entry.meta.name);
+        let parent = parent_inode(&dirs, rel)?;
+        let dir = fs.create_dir(parent, &entry.meta.name)?;
+        dirs.insert(rel, dir.inode);
+    }
+
+    for (n, &(rel, entry)) in own.iter().enumerate() {
+        if entry.meta.is_dir() {
+            continue;
+        }
+        let data = match entry.meta.volume {
+            Some(volume) => {
+                let tmp = sidecar_path(image, &format!("ld{}", n));
+                let nested = format!("{}{}/", prefix, rel);
+                let packed = pack_volume(src, &tmp, volume, encoding, entries, &nested, progress)
+                    .and_then(|()| Ok(fs::read(&tmp)?));
+                let _ = fs::remove_file(&tmp);
+                packed?
+            }
+            None => fs::read(src.join(&entry.path))?,
+        };
+        progress.step(&entry.meta.name);
+        let parent = parent_inode(&dirs, rel)?;
+        let file = fs.create_entry(parent, &entry.meta.name)?;
+        if !data.is_empty() {
+            fs.write_entry(file.inode, 0, &data)?;
+        }
+        fs.set_start_address(file.inode, entry.meta.start_address as u16)?;
+        if !matches!(entry.meta.status, DirEntryStatus::Normal) {
+            fs.set_status(file.inode, entry.meta.status)?;
+        }
+    }
+    fs.flush()
+}
@@ -0,0 +1,104 @@
+//! Асинхронное чтение тома MK-DOS (feature `async`)
+//!
+//! К рантайму не привязано: источник - любой `AsyncRead + AsyncSeek`
+//! из futures-io (async-std, smol, tokio через compat). Системная область
+//! читается в память целиком и разбирается тем же кодом, что и образ
+//! с диска, а данные файлов читаются из источника по запросу, так что
+//! потоки executor'а не блокируются на чтении образа.
+
+use std::{future::poll_fn, io::SeekFrom, pin::Pin};
+
+use futures_io::{AsyncRead, AsyncSeek};
+
+use crate::{
+    io::{MemImage, Reader},
+    short_read, Fs, FsError, MetaOffset, BLOCK_SIZE,
+};
+
+async fn seek<R: AsyncSeek + Unpin>(reader: &mut R, pos: SeekFrom) -> std::io::Result<u64> {
+    poll_fn(|cx| Pin::new(&mut *reader).poll_seek(cx, pos)).await
+}
+
+/// Читает `buf` целиком, меньше только на EOF
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        let rest = &mut buf[size..];
+        match poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, rest)).await {
+            Ok(0) => break,
+            Ok(n) => size += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+fn meta_word(meta: &[u8], offset: MetaOffset) -> u16 {
+    let offset = usize::from(offset);
+    u16::from_le_bytes([meta[offset], meta[offset + 1]])
+}
+
+impl Fs<MemImage> {
+    /// Open MK-DOS volume from async `reader` with default settings,
+    /// see `try_open_async()`
+    pub async fn read_entries_async<R>(reader: &mut R) -> Result<Self, FsError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+    {
+        let mut fs = Self::default();
+        fs.try_open_async(reader).await?;
+        Ok(fs)
+    }
+
+    /// Read meta block and catalog from async `reader`. Volume starts at
+    /// position 0 of `reader` and its bytes are taken as is (wrap partitions
+    /// in `SubReader` and inverted images in `BinInvertedReader`), offset
+    /// and inverted settings are not used. Only the system area stays in
+    /// memory, file data is read by `read_file_async()` from the same source
+    pub async fn try_open_async<R>(&mut self, reader: &mut R) -> Result<(), FsError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+    {
+        let mut system = vec![0u8; BLOCK_SIZE];
+        seek(reader, SeekFrom::Start(0)).await?;
+        let size = read_full(reader, &mut system).await?;
+        if size < BLOCK_SIZE {
+            return Err(FsError::BadMetaSize(size));
+        }
+        let disk_size = meta_word(&system, MetaOffset::DiskSize);
+        let start_block = meta_word(&system, MetaOffset::StartBlock);
+        // битый мета блок разберет open_reader, лишнего не читаем
+        let blocks = std::cmp::min(start_block, disk_size).max(1) as usize;
+        system.resize(blocks * BLOCK_SIZE, 0);
+        let size = read_full(reader, &mut system[BLOCK_SIZE..]).await?;
+        system.truncate(BLOCK_SIZE + size);
+
+        self.offset = 0;
+        if self.size == 0 {
+            self.size = u64::from(disk_size) * BLOCK_SIZE as u64;
+        }
+        self.open_reader(Reader::new(MemImage::from(system)))
+    }
+
+    /// Async `read_file()`: up to `size` bytes of file `inode` from `offset`
+    /// read from `source` (the same volume `try_open_async()` was given)
+    pub async fn read_file_async<R>(
+        &self,
+        source: &mut R,
+        inode: u64,
+        offset: u64,
+        size: usize,
+    ) -> Result<Vec<u8>, FsError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+    {
+        let (start, size) = self.file_extent(inode, offset, size)?;
+        let mut buf = vec![0u8; size];
+        seek(source, SeekFrom::Start(start)).await?;
+        if read_full(source, &mut buf).await? != size {
+            return Err(short_read(self.entrie_by_inode(inode)));
+        }
+        Ok(buf)
+    }
+}
@@ -0,0 +1,1420 @@
+//! Работа с образами MK-DOS без FUSE: ls, cat, extract, extract-all, pack,
+//! format, put, import-tape, undelete, squeeze, carve, info, fsck, nbd,
+//! serve, diff, hash (ANDOS и RT-11 определяются по сигнатурам, для них
+//! только ls, cat, serve, diff и hash). Образы в gzip и zip, Teledisk,
+//! ImageDisk и HFE разбираются сами и открываются только на чтение
+
+use std::{
+    fs,
+    io::{self, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process,
+};
+
+use bkfs::{BinHeader, BkFileSystem, BIN_HEADER_SIZE};
+#[cfg(feature = "serde")]
+use bkhdd::output;
+use bkhdd::{
+    boot::find_bootloader,
+    nbd::{self, Export},
+    output::{Format, FORMATS},
+};
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgMatches};
+use color_eyre::eyre::{eyre, Result};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[cfg(feature = "serde")]
+use mkdosfs::{
+    archive::{self, Geometry},
+    DISK_800K_BLOCKS,
+};
+use mkdosfs::{
+    boot_code_fits,
+    carve::{self, CarvedKind},
+    container::{self, InputFormat, INPUT_FORMATS},
+    diff, hash, host_name, http,
+    inode::ROOT_INODE,
+    progress,
+    tape::{self, TapeFormat},
+    truncate_name, AndosFs, DirEntry, Encoding, Fs, FsError, FsKind, GeometryMapper, LookupPolicy,
+    Overlay, ProgressFn, Rt11Fs, Warning, DEFAULT_START_BLOCK, MICRODOS_LABEL, MKDOS_LABEL,
+};
+
+fn image_args() -> [Arg<'static>; 11] {
+    [
+        Arg::new("IMAGE_NAME")
+            .required(true)
+            .help("MKDOS disk image file path"),
+        Arg::new("inverted")
+            .long("use-inverted")
+            .short('i')
+            .help("Force inverted reader (detected by signatures by default)"),
+        Arg::new("byte-swap")
+            .long("byte-swap")
+            .conflicts_with("partition")
+            .help("Swap bytes in every 16-bit word (dumps of some HDD controllers)"),
+        Arg::new("partition")
+            .long("partition")
+            .short('p')
+            .takes_value(true)
+            .conflicts_with("inverted")
+            .validator(parse_int::<usize>)
+            .value_name("N")
+            .help("Use partition N of HDD image (raw or HDI)"),
+        Arg::new("encoding")
+            .long("encoding")
+            .short('e')
+            .takes_value(true)
+            .possible_values(["koi8r", "cp866", "translit"])
+            .default_value("koi8r")
+            .value_name("ENCODING")
+            .help("File names encoding"),
+        Arg::new("scan-full-catalog")
+            .long("scan-full-catalog")
+            .help("Read catalog past blank slots up to the start block (read only)"),
+        Arg::new("input-format")
+            .long("input-format")
+            .takes_value(true)
+            .possible_values(INPUT_FORMATS)
+            .default_value("auto")
+            .value_name("FORMAT")
+            .help("Image container: raw sectors, Teledisk, ImageDisk or HxC (read only)"),
+        Arg::new("geometry")
+            .long("geometry")
+            .takes_value(true)
+            .conflicts_with("partition")
+            .validator(|s| s.parse::<GeometryMapper>().map(|_| ()))
+            .value_name("GEOMETRY")
+            .help("Remap blocks of side by side image: TRACKS,SIDES,SECTORS[,INTERLEAVE]"),
+        Arg::new("no-lock")
+            .long("no-lock")
+            .help("Don't lock image file (shared lock to read, exclusive to write)"),
+        Arg::new("overlay")
+            .long("overlay")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write changes to FILE, image stays untouched (see commit, discard)"),
+        Arg::new("ignore-case")
+            .long("ignore-case")
+            .help("Find files regardless of letter case and trailing spaces"),
+    ]
+}
+
+/// extract-all и pack: манифест в JSON, только с feature `serde`
+#[cfg(feature = "serde")]
+fn archive_commands() -> Vec<App<'static>> {
+    vec![
+        App::new("extract-all")
+            .about("Extract all files with metadata sidecars and manifest for pack")
+            .args(image_args())
+            .arg(
+                Arg::new("DEST")
+                    .required(true)
+                    .help("Destination directory (logical disks become directories)"),
+            )
+            .arg(
+                Arg::new("sidecar")
+                    .long("sidecar")
+                    .takes_value(true)
+                    .possible_values(["json", "inf", "none"])
+                    .default_value("json")
+                    .help("Format of per-file metadata (NAME.json or NAME.inf)"),
+            ),
+        App::new("pack")
+            .about("Build new image from host directory (by manifest of extract-all if any)")
+            .arg(
+                Arg::new("SOURCE_DIR")
+                    .required(true)
+                    .help("Directory to copy, subdirectories become MK-DOS directories"),
+            )
+            .arg(
+                Arg::new("IMAGE_NAME")
+                    .required(true)
+                    .help("Image to create (overwritten if exists)"),
+            )
+            .arg(
+                Arg::new("size")
+                    .long("size")
+                    .short('s')
+                    .takes_value(true)
+                    .validator(parse_size)
+                    .value_name("SIZE")
+                    .help(
+                        "Disk size: 800K, 400K or blocks (800K or size from manifest by default)",
+                    ),
+            )
+            .arg(
+                Arg::new("encoding")
+                    .long("encoding")
+                    .short('e')
+                    .takes_value(true)
+                    .possible_values(["koi8r", "cp866", "translit"])
+                    .default_value("koi8r")
+                    .value_name("ENCODING")
+                    .help("File names encoding (without manifest)"),
+            ),
+    ]
+}
+
+#[cfg(not(feature = "serde"))]
+fn archive_commands() -> Vec<App<'static>> {
+    Vec::new()
+}
+
+/// `hash --manifest`, только с feature `serde`
+#[cfg(feature = "serde")]
+fn manifest_arg() -> Option<Arg<'static>> {
+    Some(
+        Arg::new("manifest")
+            .long("manifest")
+            .short('m')
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write checksums to FILE as JSON manifest"),
+    )
+}
+
+#[cfg(not(feature = "serde"))]
+fn manifest_arg() -> Option<Arg<'static>> {
+    None
+}
+
+fn main() -> Result<()> {
+    setup_logging()?;
+
+    let matches = App::new("mkdos")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Read MKDOS disk images without FUSE")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::DeriveDisplayOrder)
+        .subcommand(
+            App::new("ls")
+                .about("List directory")
+                .args(image_args())
+                .arg(Arg::new("PATH").help("Directory in image (root by default)"))
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .short('a')
+                        .help("Show deleted and bad files too"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(FORMATS)
+                        .default_value("table")
+                        .help("Output format"),
+                ),
+        )
+        .subcommand(
+            App::new("cat")
+                .about("Write file contents to stdout")
+                .args(image_args())
+                .arg(Arg::new("FILE").required(true).help("File path in image")),
+        )
+        .subcommand(
+            App::new("extract")
+                .about("Copy files from image to host")
+                .args(image_args())
+                .arg(
+                    Arg::new("FILE")
+                        .required_unless_present("all")
+                        .help("File path in image"),
+                )
+                .arg(
+                    Arg::new("DEST")
+                        .required_unless_present("all")
+                        .help("Destination file or directory"),
+                )
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .takes_value(true)
+                        .conflicts_with_all(&["FILE", "DEST"])
+                        .value_name("DEST")
+                        .help("Extract all files to DEST keeping directories"),
+                ),
+        )
+        .subcommands(archive_commands())
+        .subcommand(
+            App::new("format")
+                .about("Create empty MK-DOS image (like MK-DOS INIT)")
+                .arg(
+                    Arg::new("IMAGE_NAME")
+                        .required(true)
+                        .help("Image to create (overwritten if exists)"),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .short('s')
+                        .takes_value(true)
+                        .default_value("800K")
+                        .validator(parse_size)
+                        .value_name("SIZE")
+                        .help("Disk size: 800K, 400K or blocks"),
+                )
+                .arg(
+                    Arg::new("start-block")
+                        .long("start-block")
+                        .takes_value(true)
+                        .validator(parse_int::<u16>)
+                        .value_name("N")
+                        .help("First block of files [default: 20]"),
+                )
+                .arg(
+                    Arg::new("bootable")
+                        .long("bootable")
+                        .short('b')
+                        .takes_value(true)
+                        .value_name("NAME|FILE")
+                        .help("Install bootloader: file or name in $BKTOOLS_BOOT_DIR"),
+                ),
+        )
+        .subcommand(
+            App::new("commit")
+                .about("Write changes of overlay file into image and remove it")
+                .arg(Arg::new("IMAGE_NAME").required(true).help("Image file path"))
+                .arg(
+                    Arg::new("OVERLAY")
+                        .required(true)
+                        .help("Overlay file made with --overlay"),
+                ),
+        )
+        .subcommand(
+            App::new("discard")
+                .about("Drop changes of overlay file (the file is removed)")
+                .arg(
+                    Arg::new("OVERLAY")
+                        .required(true)
+                        .help("Overlay file made with --overlay"),
+                ),
+        )
+        .subcommand(
+            App::new("put")
+                .about("Copy host file into image")
+                .args(image_args())
+                .arg(Arg::new("HOST_FILE").required(true).help("File to copy"))
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .short('n')
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Name (or DIR/NAME) in image, host file name by default"),
+                )
+                .arg(
+                    Arg::new("addr")
+                        .long("addr")
+                        .short('a')
+                        .takes_value(true)
+                        .validator(|s| match u16::from_str_radix(s, 8) {
+                            Ok(_n) => Ok(()),
+                            Err(e) => Err(format!("value must be an octal word: {}", e)),
+                        })
+                        .value_name("OCTAL")
+                        .help("Load address (octal), 1000 by default"),
+                )
+                .arg(
+                    Arg::new("parse-bin")
+                        .long("parse-bin")
+                        .short('b')
+                        .help("Strip BK .bin header, load address is taken from it"),
+                ),
+        )
+        .subcommand(
+            App::new("import-tape")
+                .about("Copy files from BK tape files (.bin with header, .tap) with their addresses")
+                .args(image_args())
+                .arg(
+                    Arg::new("TAPE_FILE")
+                        .required(true)
+                        .multiple_values(true)
+                        .help("Tape files, sound (.wav) must be converted to .tap first"),
+                )
+                .arg(
+                    Arg::new("tape-format")
+                        .long("tape-format")
+                        .takes_value(true)
+                        .possible_values(["bin", "tap"])
+                        .value_name("FORMAT")
+                        .help("Format of tape files, by extension by default"),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .short('d')
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Directory in image, root by default"),
+                ),
+        )
+        .subcommand(
+            App::new("undelete")
+                .about("Restore deleted file (without FILE lists deleted files)")
+                .args(image_args())
+                .arg(
+                    Arg::new("FILE")
+                        .help("Deleted file name, NAME~1, NAME~2, ... for repeated names"),
+                ),
+        )
+        .subcommand(
+            App::new("squeeze")
+                .about("Move files down to close holes left by deleted ones (MK-DOS SQUEEZE)")
+                .args(image_args()),
+        )
+        .subcommand(
+            App::new("carve")
+                .about("Find lost .bin files and BASIC texts in blocks not referenced by catalog")
+                .args(image_args())
+                .arg(
+                    Arg::new("extract")
+                        .long("extract")
+                        .short('x')
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Save found files to DIR"),
+                ),
+        )
+        .subcommand(
+            App::new("info")
+                .about("Show meta block, catalog usage, free space and warnings")
+                .args(image_args()),
+        )
+        .subcommand(
+            App::new("fsck")
+                .about("Check image consistency")
+                .args(image_args())
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .help("Fix files and used blocks counters in meta block"),
+                ),
+        )
+        .subcommand(
+            App::new("nbd")
+                .about("Serve volume (not inverted, read only) over NBD protocol")
+                .args(image_args())
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .short('l')
+                        .takes_value(true)
+                        .default_value("127.0.0.1:10809")
+                        .value_name("ADDR")
+                        .help("Address and port to listen on"),
+                )
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .takes_value(true)
+                        .default_value("mkdos")
+                        .value_name("NAME")
+                        .help("Export name"),
+                ),
+        )
+        .subcommand(
+            App::new("serve")
+                .about("Publish catalog and files over HTTP (read only, JSON API at /api/)")
+                .args(image_args())
+                .arg(
+                    Arg::new("http")
+                        .long("http")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("[ADDR:]PORT")
+                        .help("Listen on PORT of all interfaces or on ADDR:PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("diff")
+                .about("Compare catalogs and file contents of two images (exit code 1 if differ)")
+                .args(image_args())
+                .arg(
+                    Arg::new("OTHER")
+                        .required(true)
+                        .help("Second image, opened with the same options"),
+                )
+                .arg(
+                    Arg::new("deleted")
+                        .long("deleted")
+                        .help("Compare deleted MK-DOS files too (as .deleted/NAME)"),
+                )
+                .arg(
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .help("Print nothing, only set exit code"),
+                ),
+        )
+        .subcommand(
+            App::new("hash")
+                .about("Checksums of files and of logical contents (free and deleted blocks don't count)")
+                .args(image_args())
+                .args(manifest_arg()),
+        )
+        .get_matches();
+
+    let (cmd, sub) = matches.subcommand().unwrap();
+    // как у diff(1): 0 - одинаковые, 1 - различаются, 2 - ошибка
+    if cmd == "diff" {
+        match compare(sub) {
+            Ok(true) => return Ok(()),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                process::exit(2);
+            }
+        }
+    }
+    if cmd == "hash" {
+        return hash(sub);
+    }
+    #[cfg(feature = "serde")]
+    if cmd == "pack" {
+        return pack(sub);
+    }
+    if cmd == "format" {
+        return format(sub);
+    }
+    if cmd == "commit" {
+        let image = sub.value_of("IMAGE_NAME").unwrap();
+        let overlay = sub.value_of("OVERLAY").unwrap();
+        let blocks = Overlay::open_existing(overlay)?.commit(image)?;
+        println!("{}: {} blocks written from {}", image, blocks, overlay);
+        return Ok(());
+    }
+    if cmd == "discard" {
+        let overlay = sub.value_of("OVERLAY").unwrap();
+        let blocks = Overlay::discard(overlay)?;
+        println!("{}: {} changed blocks dropped", overlay, blocks);
+        return Ok(());
+    }
+    let writable = cmd == "put"
+        || cmd == "import-tape"
+        || (cmd == "undelete" && sub.is_present("FILE"))
+        || cmd == "squeeze"
+        || (cmd == "fsck" && sub.is_present("repair"));
+    let mut fs = image(sub, writable)?;
+    // ANDOS и RT-11 только читаем
+    let kind = fs.detect()?;
+    if cmd == "serve" {
+        return serve(sub, kind, fs);
+    }
+    if !matches!(kind, FsKind::MkDos | FsKind::Unknown) {
+        return foreign(cmd, sub, kind, &fs);
+    }
+    // логические диски распаковываются как каталоги
+    fs.set_open_logical(cmd == "extract-all");
+    fs.try_open()?;
+
+    match cmd {
+        "ls" => ls(
+            &fs,
+            sub.value_of("PATH").unwrap_or(""),
+            sub.is_present("all"),
+            sub.value_of_t("format")?,
+        ),
+        "cat" => {
+            let entry = lookup_file(&fs, sub.value_of("FILE").unwrap())?;
+            let data = fs.read_entry_data(entry)?;
+            io::stdout().lock().write_all(&data)?;
+            Ok(())
+        }
+        "extract" => {
+            if let Some(dest) = sub.value_of("all") {
+                extract_all(&fs, Path::new(dest))
+            } else {
+                let dest = Path::new(sub.value_of("DEST").unwrap());
+                let entry = lookup_file(&fs, sub.value_of("FILE").unwrap())?;
+                let out = if dest.is_dir() {
+                    dest.join(host_name(&entry.name))
+                } else {
+                    dest.to_path_buf()
+                };
+                fs::write(&out, fs.read_entry_data(entry)?)?;
+                println!("{} -> {}", entry.name, out.display());
+                Ok(())
+            }
+        }
+        #[cfg(feature = "serde")]
+        "extract-all" => {
+            let dest = Path::new(sub.value_of("DEST").unwrap());
+            fs.set_progress(progress_line());
+            let manifest = archive::extract_all(&fs, dest, sub.value_of_t("sidecar")?)?;
+            for entry in &manifest.entries {
+                println!("{}", dest.join(&entry.path).display());
+            }
+            println!(
+                "{} entries, manifest: {}",
+                manifest.entries.len(),
+                dest.join(archive::MANIFEST_NAME).display()
+            );
+            Ok(())
+        }
+        "put" => put(&mut fs, sub),
+        "import-tape" => import_tape(&mut fs, sub),
+        "undelete" => undelete(&mut fs, sub.value_of("FILE")),
+        "squeeze" => {
+            fs.set_progress(progress_line());
+            let freed = fs.squeeze()?;
+            fs.flush()?;
+            println!(
+                "Squeezed: {} blocks reclaimed, {} blocks free",
+                freed,
+                fs.free_blocks()
+            );
+            Ok(())
+        }
+        "carve" => carve(&fs, sub.value_of("extract").map(Path::new)),
+        "info" => info(&fs),
+        "fsck" => fsck(&mut fs, sub.is_present("repair")),
+        "nbd" => serve_nbd(&fs, sub),
+        _ => unreachable!(),
+    }
+}
+
+/// Настроенный, но еще не открытый образ
+fn image(sub: &ArgMatches, writable: bool) -> Result<Fs> {
+    image_named(sub, sub.value_of("IMAGE_NAME").unwrap(), writable)
+}
+
+/// То же для другого файла с теми же опциями
+fn image_named(sub: &ArgMatches, name: &str, writable: bool) -> Result<Fs> {
+    // сжатый образ, TD0, IMD и HFE открываем из копии и только на чтение
+    let format = sub.value_of_t::<InputFormat>("input-format")?;
+    let mut fs = match container::prepared_image(name, format)? {
+        Some(_) if writable => {
+            return Err(eyre!("{} is not a raw image, it can't be changed", name))
+        }
+        Some(path) => Fs::new(&path.to_string_lossy()),
+        None => Fs::new(name),
+    };
+    fs.set_read_only(!writable);
+    fs.set_lock(!sub.is_present("no-lock"));
+    fs.set_overlay(sub.value_of("overlay").map(PathBuf::from));
+    if sub.is_present("inverted") {
+        fs.set_inverted(true);
+    }
+    fs.set_swapped(sub.is_present("byte-swap"));
+    if sub.is_present("geometry") {
+        fs.set_geometry(Some(sub.value_of_t::<GeometryMapper>("geometry")?));
+    }
+    if sub.is_present("partition") {
+        let partition = sub.value_of("partition").unwrap().parse::<usize>()?;
+        fs.set_partition(partition)?;
+    } else {
+        fs.skip_hdi_header()?;
+        // флаг главнее сигнатур
+        if !sub.is_present("inverted") {
+            fs.detect_inverted()?;
+        }
+    }
+    fs.set_encoding(sub.value_of("encoding").unwrap().parse::<Encoding>()?);
+    fs.set_scan_full_catalog(sub.is_present("scan-full-catalog"));
+    if sub.is_present("ignore-case") {
+        fs.set_lookup_policy(LookupPolicy::CaseInsensitive);
+    }
+    Ok(fs)
+}
+
+/// ls и cat для ANDOS и RT-11, `probe` дает файл, смещение раздела и инверсию
+fn foreign(cmd: &str, sub: &ArgMatches, kind: FsKind, probe: &Fs) -> Result<()> {
+    let path = match cmd {
+        "ls" => sub.value_of("PATH").unwrap_or(""),
+        "cat" => sub.value_of("FILE").unwrap(),
+        _ => return Err(eyre!("{} is not supported for {} images", cmd, kind)),
+    };
+    let format = if cmd == "ls" {
+        sub.value_of_t("format")?
+    } else {
+        Format::Table
+    };
+    let mut out = io::stdout().lock();
+    match kind {
+        FsKind::Andos => {
+            let fs = open_andos(probe)?;
+            let mut inode = ROOT_INODE;
+            for name in path.split('/').filter(|n| !n.is_empty()) {
+                inode = fs
+                    .find_entrie(name, inode)
+                    .ok_or_else(|| eyre!("{:?} not found", path))?
+                    .inode;
+            }
+            let entry = fs.entrie_by_inode(inode);
+            if cmd == "cat" {
+                let entry = entry.ok_or_else(|| eyre!("{:?} is a directory", path))?;
+                out.write_all(&fs.read_file(entry.inode, 0, entry.size as usize)?)?;
+                return Ok(());
+            }
+            if entry.is_some_and(|e| !e.is_dir) {
+                return Err(eyre!("{:?} is not a directory", path));
+            }
+            let entries = fs.iter_dir(inode).collect::<Vec<_>>();
+            if write_listing(
+                format,
+                VolumeMeta {
+                    files: fs.files(),
+                    free_blocks: fs.free_blocks(),
+                    disk_size: fs.disk_size(),
+                },
+                &entries,
+            )? {
+                return Ok(());
+            }
+            for e in entries {
+                writeln!(
+                    out,
+                    "{} {:<12}{} {:8}",
+                    if e.is_dir { 'd' } else { '-' },
+                    e.name,
+                    if e.is_dir { "/" } else { " " },
+                    e.size
+                )?;
+            }
+            writeln!(
+                out,
+                "Files: {} Blocks: {} free of {}",
+                fs.files(),
+                fs.free_blocks(),
+                fs.disk_size()
+            )?;
+        }
+        FsKind::Rt11 => {
+            let fs = open_rt11(probe)?;
+            let name = path.trim_matches('/');
+            if cmd == "cat" {
+                let entry = fs
+                    .find_entrie(name, ROOT_INODE)
+                    .ok_or_else(|| eyre!("{:?} not found", path))?;
+                out.write_all(&fs.read_file(entry.inode, 0, entry.size() as usize)?)?;
+                return Ok(());
+            }
+            if !name.is_empty() {
+                return Err(eyre!("RT-11 has no directories"));
+            }
+            let entries = fs.iter_all().collect::<Vec<_>>();
+            if write_listing(
+                format,
+                VolumeMeta {
+                    files: fs.files(),
+                    free_blocks: fs.free_blocks(),
+                    disk_size: fs.disk_size(),
+                },
+                &entries,
+            )? {
+                return Ok(());
+            }
+            for e in entries {
+                writeln!(
+                    out,
+                    "{} {:<10} {:5} {:5} {}",
+                    if e.is_protected { 'P' } else { '-' },
+                    e.name,
+                    e.blocks,
+                    e.start_block,
+                    e.date.map(|d| d.to_string()).unwrap_or_default()
+                )?;
+            }
+            writeln!(
+                out,
+                "Files: {} Blocks: {} free of {}",
+                fs.files(),
+                fs.free_blocks(),
+                fs.disk_size()
+            )?;
+        }
+        _ => return Err(FsError::Unsupported(kind).into()),
+    }
+
+    Ok(())
+}
+
+/// ANDOS том там же, где нашел `probe`
+fn open_andos(probe: &Fs) -> Result<AndosFs> {
+    let mut fs = AndosFs::new(probe.file_path());
+    fs.set_offset_blocks(probe.offset_blocks());
+    fs.set_inverted(probe.is_inverted());
+    fs.set_swapped(probe.is_swapped());
+    fs.set_geometry(probe.geometry().cloned());
+    fs.set_encoding(probe.encoding());
+    fs.try_open()?;
+    Ok(fs)
+}
+
+/// RT-11 том там же, где нашел `probe`
+fn open_rt11(probe: &Fs) -> Result<Rt11Fs> {
+    let mut fs = Rt11Fs::new(probe.file_path());
+    fs.set_offset_blocks(probe.offset_blocks());
+    fs.set_size_blocks(probe.size_blocks());
+    fs.set_inverted(probe.is_inverted());
+    fs.set_swapped(probe.is_swapped());
+    fs.set_geometry(probe.geometry().cloned());
+    fs.try_open()?;
+    Ok(fs)
+}
+
+/// HTTP сервер для тома любого формата
+fn serve(sub: &ArgMatches, kind: FsKind, mut probe: Fs) -> Result<()> {
+    let image = sub.value_of("IMAGE_NAME").unwrap();
+    let addr = sub.value_of("http").unwrap();
+    // один порт - слушаем на всех интерфейсах
+    let listener = match addr.parse::<u16>() {
+        Ok(port) => TcpListener::bind(("0.0.0.0", port))?,
+        Err(_) => TcpListener::bind(addr)?,
+    };
+    let title = Path::new(image)
+        .file_name()
+        .map_or_else(|| image.into(), |name| name.to_string_lossy());
+    info!(%kind, "Serving {} on http://{}/", image, listener.local_addr()?);
+    match kind {
+        FsKind::Andos => http::serve(&listener, &mut open_andos(&probe)?, &title)?,
+        FsKind::Rt11 => http::serve(&listener, &mut open_rt11(&probe)?, &title)?,
+        FsKind::MkDos | FsKind::Unknown => {
+            probe.try_open()?;
+            http::serve(&listener, &mut probe, &title)?
+        }
+        _ => return Err(FsError::Unsupported(kind).into()),
+    }
+    Ok(())
+}
+
+/// Том любого формата только для чтения, `deleted` - с удаленными файлами MK-DOS
+fn open_volume(sub: &ArgMatches, image: &str, deleted: bool) -> Result<Box<dyn BkFileSystem>> {
+    let mut probe = image_named(sub, image, false)?;
+    let kind = probe.detect()?;
+    Ok(match kind {
+        FsKind::Andos => Box::new(open_andos(&probe)?),
+        FsKind::Rt11 => Box::new(open_rt11(&probe)?),
+        FsKind::MkDos | FsKind::Unknown => {
+            probe.set_read_deleted(deleted);
+            probe.try_open()?;
+            Box::new(probe)
+        }
+        _ => return Err(FsError::Unsupported(kind).into()),
+    })
+}
+
+/// `true` если образы одинаковые
+fn compare(sub: &ArgMatches) -> Result<bool> {
+    let deleted = sub.is_present("deleted");
+    let old = open_volume(sub, sub.value_of("IMAGE_NAME").unwrap(), deleted)?;
+    let new = open_volume(sub, sub.value_of("OTHER").unwrap(), deleted)?;
+    let changes = diff::diff(&*old, &*new)?;
+    if !sub.is_present("quiet") {
+        let mut out = io::stdout().lock();
+        for change in changes.iter() {
+            writeln!(out, "{}", change)?;
+        }
+    }
+    Ok(changes.is_empty())
+}
+
+/// Суммы файлов построчно, как у crc32 и sha1sum, в конце суммы тома
+fn hash(sub: &ArgMatches) -> Result<()> {
+    // удаленные файлы в сумму не входят
+    let volume = open_volume(sub, sub.value_of("IMAGE_NAME").unwrap(), false)?;
+    let hashes = hash::hash_volume(&*volume)?;
+    let mut out = io::stdout().lock();
+    for file in hashes.files.iter() {
+        writeln!(out, "{} {}  {}", file.crc32, file.sha1, file.path)?;
+    }
+    writeln!(out, "content {}", hashes.content_sha1)?;
+    if let Some(sha1) = hashes.volume_sha1.as_ref() {
+        writeln!(out, "volume  {}", sha1)?;
+    }
+    #[cfg(feature = "serde")]
+    if let Some(manifest) = sub.value_of("manifest") {
+        hashes.write_manifest(Path::new(manifest))?;
+    }
+    Ok(())
+}
+
+fn lookup_file<'a>(fs: &'a Fs, path: &str) -> Result<&'a DirEntry> {
+    let entry = fs
+        .lookup_path(path)
+        .ok_or_else(|| eyre!("{:?} not found", path))?;
+    if entry.is_dir {
+        return Err(eyre!("{:?} is a directory", path));
+    }
+    Ok(entry)
+}
+
+/// `ls --format json`: заголовок тома и записи каталога
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct Listing<'a, M, E> {
+    meta: M,
+    entries: &'a [E],
+}
+
+/// Заголовок ANDOS и RT-11 тома для `ls --format json`
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+struct VolumeMeta {
+    files: u64,
+    free_blocks: u64,
+    disk_size: u64,
+}
+
+/// Вывод каталога в json/csv, `false` - печатать таблицу как обычно
+#[cfg(feature = "serde")]
+fn write_listing<M: Serialize, E: Serialize>(
+    format: Format,
+    meta: M,
+    entries: &[E],
+) -> Result<bool> {
+    let mut out = io::stdout().lock();
+    match format {
+        Format::Json => output::write_json(&mut out, &Listing { meta, entries })?,
+        Format::Csv => output::write_csv(&mut out, entries)?,
+        Format::Table => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Без serde есть только таблица
+#[cfg(not(feature = "serde"))]
+fn write_listing<M, E>(_format: Format, _meta: M, _entries: &[E]) -> Result<bool> {
+    Ok(false)
+}
+
+fn ls(fs: &Fs, path: &str, all: bool, format: Format) -> Result<()> {
+    let inode = if path.trim_matches('/').is_empty() {
+        ROOT_INODE
+    } else {
+        let entry = fs
+            .lookup_path(path)
+            .ok_or_else(|| eyre!("{:?} not found", path))?;
+        if !entry.is_dir && !entry.is_volume {
+            return Err(eyre!("{:?} is not a directory", path));
+        }
+        entry.inode
+    };
+
+    let entries = fs
+        .iter_dir(inode)
+        .filter(|e| all || (!e.is_deleted && !e.is_bad))
+        .collect::<Vec<_>>();
+    if write_listing(format, fs.meta(), &entries)? {
+        return Ok(());
+    }
+    for entry in entries {
+        let kind = match () {
+            _ if entry.is_deleted => 'D',
+            _ if entry.is_bad => 'B',
+            _ if entry.is_dir => 'd',
+            _ if entry.is_logical => 'L',
+            _ if entry.is_protected => 'P',
+            _ => '-',
+        };
+        println!(
+            "{} {:<14}{} {:06o} {:6} {:5} {:5}",
+            kind,
+            entry.name,
+            if entry.is_dir { "/" } else { " " },
+            entry.start_address,
+            entry.size,
+            entry.blocks,
+            entry.start_block,
+        );
+    }
+    println!(
+        "Files: {} Blocks: {} used of {}",
+        fs.files(),
+        fs.blocks(),
+        fs.disk_size()
+    );
+
+    Ok(())
+}
+
+fn put(fs: &mut Fs, sub: &ArgMatches) -> Result<()> {
+    let host_file = Path::new(sub.value_of("HOST_FILE").unwrap());
+    let data = fs::read(host_file)?;
+    let path = match sub.value_of("name") {
+        Some(name) => name.to_string(),
+        None => host_file
+            .file_name()
+            .ok_or_else(|| eyre!("{:?} has no file name", host_file))?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let (dir, name) = match path.trim_matches('/').rsplit_once('/') {
+        Some((dir, name)) => (dir, name),
+        None => ("", path.trim_matches('/')),
+    };
+    let parent = dir_inode(fs, dir)?;
+    let name = truncate_name(name, false);
+    // заголовок .bin в образ не пишем, адрес из него идет в каталог
+    let header = match sub.is_present("parse-bin") {
+        true => BinHeader::parse(&data),
+        false => None,
+    };
+    let data = match header {
+        Some(_) => &data[BIN_HEADER_SIZE as usize..],
+        None => &data[..],
+    };
+    let address = match sub.value_of("addr") {
+        Some(addr) => Some(u16::from_str_radix(addr, 8)?),
+        None => header.map(|h| h.start_address),
+    };
+
+    let entry = fs.create_entry(parent, &name)?;
+    // место ищется при записи, если не влезло - запись не оставляем
+    if let Err(e) = fs.write_entry(entry.inode, 0, data) {
+        fs.unlink_entry(parent, &name)?;
+        return Err(e.into());
+    }
+    let entry = match address {
+        Some(address) => fs.set_start_address(entry.inode, address)?,
+        None => fs.entrie_by_inode(entry.inode).cloned().unwrap_or(entry),
+    };
+    fs.flush()?;
+    println!(
+        "{} -> {} ({} bytes, {} blocks at {}, address {:06o})",
+        host_file.display(),
+        entry.name,
+        data.len(),
+        entry.blocks,
+        entry.start_block,
+        entry.start_address
+    );
+
+    Ok(())
+}
+
+/// Инод каталога по пути в образе, пустой путь - корень
+fn dir_inode(fs: &Fs, dir: &str) -> Result<u64> {
+    let dir = dir.trim_matches('/');
+    if dir.is_empty() {
+        return Ok(ROOT_INODE);
+    }
+    match fs.lookup_path(dir) {
+        Some(e) if e.is_dir => Ok(e.inode),
+        _ => Err(eyre!("Directory {:?} not found", dir)),
+    }
+}
+
+fn import_tape(fs: &mut Fs, sub: &ArgMatches) -> Result<()> {
+    let parent = dir_inode(fs, sub.value_of("dir").unwrap_or(""))?;
+    let format = match sub.value_of("tape-format") {
+        Some(format) => Some(format.parse::<TapeFormat>().map_err(|e| eyre!(e))?),
+        None => None,
+    };
+    for tape_file in sub.values_of("TAPE_FILE").unwrap() {
+        let path = Path::new(tape_file);
+        let files = tape::read_tape(path, format, fs.encoding())?;
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for (n, file) in files.iter().enumerate() {
+            // без имени на ленте называем по файлу
+            let name = match &file.name {
+                Some(name) => name.clone(),
+                None if files.len() == 1 => stem.clone(),
+                None => format!("{}.{}", stem, n + 1),
+            };
+            if let Some((stored, computed)) = file.checksum_mismatch {
+                eprintln!(
+                    "Warning: {} {:?}: checksum is {:06o} but {:06o} computed",
+                    path.display(),
+                    name,
+                    stored,
+                    computed
+                );
+            }
+            let entry = fs.import_tape_file(parent, &name, file)?;
+            println!(
+                "{} -> {} ({} bytes, {} blocks at {}, address {:06o})",
+                path.display(),
+                entry.name,
+                file.data.len(),
+                entry.blocks,
+                entry.start_block,
+                entry.start_address
+            );
+        }
+    }
+    fs.flush()?;
+    Ok(())
+}
+
+fn carve(fs: &Fs, dest: Option<&Path>) -> Result<()> {
+    let found = carve::carve(fs)?;
+    if let Some(dest) = dest {
+        fs::create_dir_all(dest)?;
+    }
+    for carved in found.iter() {
+        let address = match carved.kind {
+            CarvedKind::Bin { start_address } => format!("{:06o}", start_address),
+            CarvedKind::BasicText => "-".to_string(),
+        };
+        print!(
+            "{:5} {:<5} {:>6} {:6} {:5}",
+            carved.block,
+            carved.kind,
+            address,
+            carved.length,
+            carved.blocks()
+        );
+        match dest {
+            Some(dest) => {
+                let out = dest.join(carved.file_name());
+                fs::write(&out, carve::read_carved(fs, carved)?)?;
+                println!(" -> {}", out.display());
+            }
+            None => println!(),
+        }
+    }
+    let free: u64 = fs
+        .unreferenced_extents()
+        .iter()
+        .map(|extent| extent.end - extent.start)
+        .sum();
+    println!("Found: {} in {} unreferenced blocks", found.len(), free);
+    Ok(())
+}
+
+fn undelete(fs: &mut Fs, name: Option<&str>) -> Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            for (name, entry) in fs.iter_deleted() {
+                println!(
+                    "{:<16} {:06o} {:6} {:5} {:5}",
+                    name, entry.start_address, entry.size, entry.blocks, entry.start_block,
+                );
+            }
+            return Ok(());
+        }
+    };
+    let inode = fs
+        .find_deleted(name)
+        .map(|(_, e)| e.inode)
+        .ok_or_else(|| eyre!("Deleted file {:?} not found", name))?;
+    let entry = fs.undelete(inode)?;
+    fs.flush()?;
+    println!("{} restored", entry.name);
+
+    Ok(())
+}
+
+fn label_check(label: u16, expected: u16) -> &'static str {
+    match label == expected {
+        true => "ok",
+        false => "WRONG",
+    }
+}
+
+fn info(fs: &Fs) -> Result<()> {
+    let meta = fs.meta();
+    let mut flags = Vec::new();
+    if fs.is_inverted() {
+        flags.push("inverted");
+    }
+    if fs.is_swapped() {
+        flags.push("byte-swapped");
+    }
+    println!("Image:          {}", fs.file_path());
+    println!(
+        "Offset:         {} blocks{}",
+        fs.offset_blocks(),
+        match flags.is_empty() {
+            true => String::new(),
+            false => format!(" ({})", flags.join(", ")),
+        }
+    );
+
+    println!("Meta block:");
+    println!("  files         {}", meta.files());
+    println!("  blocks        {}", meta.blocks());
+    println!("  disk size     {} blocks", meta.disk_size());
+    println!("  start block   {}", meta.start_block());
+    println!(
+        "  label at 400  {:06o} {} (MicroDOS)",
+        meta.microdos_label(),
+        label_check(meta.microdos_label(), MICRODOS_LABEL)
+    );
+    println!(
+        "  label at 402  {:06o} {} (MK-DOS)",
+        meta.mkdos_label(),
+        label_check(meta.mkdos_label(), MKDOS_LABEL)
+    );
+
+    let entries: Vec<&DirEntry> = fs.iter_all().collect();
+    let count = |f: fn(&DirEntry) -> bool| entries.iter().filter(|&&e| f(e)).count();
+    let capacity = fs.catalog_capacity();
+    println!("Catalog:");
+    println!(
+        "  records       {} of {} ({} free)",
+        entries.len(),
+        capacity,
+        capacity.saturating_sub(entries.len())
+    );
+    println!(
+        "  files         {}",
+        count(|e| !e.is_dir && !e.is_logical && !e.is_deleted && !e.is_bad)
+    );
+    println!("  directories   {}", count(|e| e.is_dir && !e.is_deleted));
+    println!(
+        "  logical disks {}",
+        count(|e| e.is_logical && !e.is_deleted)
+    );
+    println!(
+        "  protected     {}",
+        count(|e| e.is_protected && !e.is_deleted)
+    );
+    println!("  deleted       {}", count(|e| e.is_deleted));
+    println!("  bad blocks    {}", count(|e| e.is_bad));
+    println!("  corrupt       {}", count(|e| e.is_corrupt));
+    println!("  garbage       {}", count(|e| e.is_garbage));
+
+    let extents = fs.unreferenced_extents();
+    let unreferenced: u64 = extents.iter().map(|e| e.end - e.start).sum();
+    let largest = extents.iter().map(|e| e.end - e.start).max().unwrap_or(0);
+    let deleted: u64 = entries
+        .iter()
+        .filter(|e| e.is_deleted)
+        .map(|e| e.extent_blocks())
+        .sum();
+    let tail = fs.free_blocks();
+    println!("Free space:");
+    println!("  after last record {} blocks", tail);
+    println!("  deleted files     {} blocks", deleted);
+    println!("  unreferenced      {} blocks", unreferenced);
+    println!("Fragmentation:");
+    println!(
+        "  {} free extents, largest {} blocks",
+        extents.len(),
+        largest
+    );
+    // дыры перед последним файлом сжатие вернет в хвост
+    println!(
+        "  {} blocks in holes between files",
+        (unreferenced + deleted).saturating_sub(tail)
+    );
+
+    let report = fs.check()?;
+    println!("Warnings:");
+    for warning in fs.diagnostics() {
+        println!("  {}", warning);
+    }
+    println!(
+        "  {} warnings while reading, fsck finds {} problems",
+        fs.diagnostics().len(),
+        report.issues.len()
+    );
+
+    Ok(())
+}
+
+fn fsck(fs: &mut Fs, repair: bool) -> Result<()> {
+    let mut report = fs.check()?;
+    for issue in report.issues.iter() {
+        println!("{}", issue);
+    }
+    // несоответствия метаданных check() найдет сам, остальное видно только при чтении
+    for warning in fs.diagnostics() {
+        if !matches!(warning, Warning::Inconsistency(_)) {
+            println!("Warning: {}", warning);
+        }
+    }
+    if repair && report.issues.iter().any(|i| i.is_counter()) {
+        fs.repair_counters()?;
+        println!("Meta block counters fixed");
+        report = fs.check()?;
+    }
+    println!(
+        "{} records checked, {} problems",
+        report.entries,
+        report.issues.len()
+    );
+    if !report.is_clean() {
+        return Err(eyre!("Image has {} problems", report.issues.len()));
+    }
+
+    Ok(())
+}
+
+/// Том как диск NBD: ровно `disk_size` блоков из мета блока
+struct Volume<'a>(&'a Fs);
+
+impl Export for Volume<'_> {
+    fn size(&self) -> u64 {
+        self.0.raw_size().unwrap_or(0)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self
+            .0
+            .read_raw(offset, buf.len())
+            .map_err(io::Error::other)?;
+        if data.len() < buf.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+}
+
+fn serve_nbd(fs: &Fs, sub: &ArgMatches) -> Result<()> {
+    let name = sub.value_of("name").unwrap();
+    let listener = TcpListener::bind(sub.value_of("listen").unwrap())?;
+    info!(
+        "Serving volume ({} blocks) as {:?} on {}",
+        fs.disk_size(),
+        name,
+        listener.local_addr()?
+    );
+    nbd::serve(&listener, &Volume(fs), name)?;
+    Ok(())
+}
+
+/// Validator for integer arguments: `.validator(parse_int::<u64>)`
+fn parse_int<T>(s: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    s.parse::<T>()
+        .map_err(|e| format!("value must be an integer: {}", e))
+}
+
+/// `800K`, `400K` или число блоков
+fn parse_size(s: &str) -> Result<u16, String> {
+    let blocks = match s.strip_suffix(['K', 'k']) {
+        Some(kb) => kb.parse::<u32>().map(|kb| kb * 2),
+        None => s.parse::<u32>(),
+    };
+    blocks
+        .ok()
+        .and_then(|blocks| u16::try_from(blocks).ok())
+        .filter(|&blocks| blocks > DEFAULT_START_BLOCK)
+        .ok_or_else(|| format!("bad disk size {:?}", s))
+}
+
+/// Чистый образ, загрузчик ставится в уже размеченный том
+fn format(sub: &ArgMatches) -> Result<()> {
+    let image = sub.value_of("IMAGE_NAME").unwrap();
+    let size = parse_size(sub.value_of("size").unwrap()).map_err(|e| eyre!(e))?;
+    let start_block = match sub.value_of("start-block") {
+        Some(n) => n.parse::<u16>()?,
+        None => DEFAULT_START_BLOCK,
+    };
+    // загрузчик ищем до того, как затереть образ
+    let code = sub.value_of("bootable").map(find_bootloader).transpose()?;
+    if let Some(code) = code.as_ref() {
+        if !boot_code_fits(code.len()) {
+            return Err(FsError::BootCodeSize(code.len()).into());
+        }
+    }
+    Fs::format_with_progress(image, size, start_block, &progress_line())?;
+    println!(
+        "{}: {} blocks, files from block {}",
+        image, size, start_block
+    );
+    if let Some(code) = code {
+        let mut fs = Fs::new(image);
+        fs.set_read_only(false);
+        fs.try_open()?;
+        fs.set_boot_code(&code)?;
+        println!(
+            "Bootloader {} ({} bytes) installed",
+            sub.value_of("bootable").unwrap(),
+            code.len()
+        );
+    }
+    Ok(())
+}
+
+/// Образ из каталога хоста, по манифесту extract-all, если он есть
+#[cfg(feature = "serde")]
+fn pack(sub: &ArgMatches) -> Result<()> {
+    let src = Path::new(sub.value_of("SOURCE_DIR").unwrap());
+    let image = Path::new(sub.value_of("IMAGE_NAME").unwrap());
+    let size = sub
+        .value_of("size")
+        .map(parse_size)
+        .transpose()
+        .map_err(|e| eyre!(e))?;
+    let mut manifest = match archive::read_manifest(src)? {
+        Some(manifest) => manifest,
+        None => archive::scan_tree(
+            src,
+            Geometry {
+                disk_size: DISK_800K_BLOCKS,
+                start_block: DEFAULT_START_BLOCK,
+            },
+            sub.value_of("encoding").unwrap().parse::<Encoding>()?,
+        )?,
+    };
+    if let Some(size) = size {
+        manifest.volume.disk_size = size;
+    }
+    archive::pack_with_progress(src, image, &manifest, &progress_line())?;
+    for entry in &manifest.entries {
+        println!("{} -> {}", src.join(&entry.path).display(), entry.meta.name);
+    }
+    println!(
+        "{}: {} entries, {} blocks",
+        image.display(),
+        manifest.entries.len(),
+        manifest.volume.disk_size
+    );
+    Ok(())
+}
+
+/// Строка хода на терминале, иначе ничего
+fn progress_line() -> ProgressFn {
+    progress::term_progress().unwrap_or_else(|| Box::new(|_| {}))
+}
+
+fn extract_all(fs: &Fs, dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    let mut dirs: Vec<PathBuf> = vec![dest.to_path_buf()];
+    for (depth, node) in fs.tree().walk().into_iter().skip(1) {
+        // путь родителя лежит на глубине depth - 1
+        dirs.truncate(depth);
+        let path = dirs[depth - 1].join(host_name(node.name()));
+        let entry = node.entry.as_ref().unwrap();
+        if node.is_dir() {
+            fs::create_dir_all(&path)?;
+            dirs.push(path);
+            continue;
+        }
+        match fs.read_entry_data(entry) {
+            Ok(data) => {
+                fs::write(&path, data)?;
+                println!("{}", path.display());
+            }
+            Err(e) => eprintln!("Can't extract {:?}: {}", entry.name, e),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn setup_logging() -> Result<()> {
+    if std::env::var("RUST_LIB_BACKTRACE").is_err() {
+        std::env::set_var("RUST_LIB_BACKTRACE", "full");
+    }
+    color_eyre::install()?;
+
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "warn");
+    }
+    tracing_subscriber::fmt::fmt()
+        .with_writer(io::stderr)
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Загрузчик тома MK-DOS в блоке 0
+//!
+//! Блок 0 общий для загрузчика и каталога: загрузчик занимает начало блока
+//! до меток (0400), а слова 030 и 032 в нем - счетчики каталога, которые
+//! загрузчик обходит. При установке счетчики остаются от тома. Двоичные
+//! файлы загрузчиков ищет `bkhdd::boot::find_bootloader()`.
+
+use std::{
+    io::{Read, Seek, Write},
+    ops::Range,
+};
+
+use crate::{Fs, FsError, MetaOffset, BLOCK_SIZE};
+
+/// Bytes of block 0 before MK-DOS labels available for boot code
+pub const BOOT_CODE_SIZE: usize = MetaOffset::MicrodosLabel as usize;
+/// счетчики файлов и блоков каталога внутри загрузчика
+const COUNTERS: Range<usize> = MetaOffset::Files as usize..MetaOffset::Blocks as usize + 2;
+
+/// Boot code of `len` bytes can be installed: up to `BOOT_CODE_SIZE` or
+/// whole block 0 of other disk
+pub fn boot_code_fits(len: usize) -> bool {
+    len <= BOOT_CODE_SIZE || len == BLOCK_SIZE
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek,
+{
+    /// Boot code of the volume: block 0 up to labels (with catalog counters)
+    pub fn boot_code(&self) -> &[u8] {
+        &self.meta.raw[..BOOT_CODE_SIZE]
+    }
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek + Write,
+{
+    /// Install boot code into block 0, catalog counters are kept.
+    /// Whole block 0 of other MK-DOS disk is accepted too, only its boot
+    /// code is taken
+    pub fn set_boot_code(&mut self, code: &[u8]) -> Result<(), FsError> {
+        self.check_writable()?;
+        if !boot_code_fits(code.len()) {
+            return Err(FsError::BootCodeSize(code.len()));
+        }
+        let code = &code[..code.len().min(BOOT_CODE_SIZE)];
+        let mut boot = [0u8; BOOT_CODE_SIZE];
+        boot[..code.len()].copy_from_slice(code);
+        boot[COUNTERS].copy_from_slice(&self.meta.raw[COUNTERS]);
+        self.write_image_at(&boot, 0)?;
+        self.meta.raw[..BOOT_CODE_SIZE].copy_from_slice(&boot);
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+//! LRU кэш блоков образа
+//!
+//! Через него идут позиционные чтения `Fs::read_exact_at` (FUSE, извлечение
+//! файлов). Чтение целиком из кэша - только если в нем есть все блоки, иначе
+//! читаем весь выровненный по блокам кусок одним pread и кладем его в кэш.
+//! Чтения больше самого кэша идут мимо, чтобы не вымывать горячие блоки.
+//! Запись через `Fs` выкидывает задетые блоки.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+};
+
+use crate::BLOCK_SIZE;
+
+/// Default block cache size (blocks)
+pub const DEFAULT_CACHE_BLOCKS: usize = 64;
+
+/// Block cache counters
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    /// cache size in blocks, 0 - disabled
+    pub capacity: usize,
+    /// blocks in cache now
+    pub blocks: usize,
+    /// blocks read from cache
+    pub hits: u64,
+    /// blocks read from image
+    pub misses: u64,
+}
+
+pub(crate) struct BlockCache {
+    capacity: usize,
+    /// счетчик обращений, у самого старого блока наименьший
+    tick: u64,
+    /// номер блока -> (последнее обращение, данные)
+    blocks: HashMap<u64, (u64, Vec<u8>)>,
+    /// последнее обращение -> номер блока
+    lru: BTreeMap<u64, u64>,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Чтение из `blocks` блоков стоит кэшировать
+    pub(crate) fn fits(&self, blocks: u64) -> bool {
+        blocks != 0 && blocks <= self.capacity as u64
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            blocks: self.blocks.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+        self.lru.clear();
+    }
+
+    fn touch(&mut self, block: u64) {
+        self.tick += 1;
+        if let Some((tick, _)) = self.blocks.get_mut(&block) {
+            self.lru.remove(tick);
+            *tick = self.tick;
+            self.lru.insert(self.tick, block);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.blocks.len() > self.capacity {
+            match self.lru.pop_first() {
+                Some((_, block)) => {
+                    self.blocks.remove(&block);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Читает `buf` с `offset` (от начала тома), если все блоки есть в кэше.
+    /// Короткий блок - конец образа, дальше него не читаем.
+    pub(crate) fn read(&mut self, buf: &mut [u8], offset: u64) -> Option<usize> {
+        let range = block_range(offset, buf.len());
+        let count = range.end - range.start;
+        if !range.clone().all(|b| self.blocks.contains_key(&b)) {
+            self.misses += count;
+            return None;
+        }
+        self.hits += count;
+
+        let mut done = 0;
+        for block in range {
+            self.touch(block);
+            let data = &self.blocks[&block].1;
+            let skip = (offset + done as u64 - block * BLOCK_SIZE as u64) as usize;
+            if skip >= data.len() {
+                break;
+            }
+            let n = std::cmp::min(data.len() - skip, buf.len() - done);
+            buf[done..done + n].copy_from_slice(&data[skip..skip + n]);
+            done += n;
+            if data.len() < BLOCK_SIZE {
+                break;
+            }
+        }
+        Some(done)
+    }
+
+    /// Кладет прочитанные с блока `first` данные, последний блок может быть короче
+    pub(crate) fn insert(&mut self, first: u64, data: &[u8]) {
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            let block = first + i as u64;
+            self.tick += 1;
+            if let Some((tick, _)) = self.blocks.remove(&block) {
+                self.lru.remove(&tick);
+            }
+            self.blocks.insert(block, (self.tick, chunk.to_vec()));
+            self.lru.insert(self.tick, block);
+        }
+        self.evict();
+    }
+
+    /// Выкидывает блоки, задетые записью `len` байт с `offset`
+    pub(crate) fn invalidate(&mut self, offset: u64, len: usize) {
+        for block in block_range(offset, len) {
+            if let Some((tick, _)) = self.blocks.remove(&block) {
+                self.lru.remove(&tick);
+            }
+        }
+    }
+}
+
+/// Блоки, которые задевают `len` байт с `offset`
+pub(crate) fn block_range(offset: u64, len: usize) -> Range<u64> {
+    let bs = BLOCK_SIZE as u64;
+    offset / bs..(offset + len as u64).div_ceil(bs)
+}
@@ -0,0 +1,158 @@
+//! Поиск потерянных файлов в свободных блоках (`mkdos carve`)
+//!
+//! Если каталог стерт, а данные уцелели, файлы можно найти по содержимому.
+//! Смотрим только блоки, на которые не ссылается ни одна запись каталога
+//! (`Fs::unreferenced_extents()`), и только начало каждого блока - файлы
+//! MK-DOS всегда начинаются с границы блока. Что узнаем:
+//!
+//! - `.bin` с заголовком: четный адрес загрузки в ОЗУ пользователя, длина,
+//!   которая умещается и в память, и в свободный участок;
+//! - текст программы на Бейсике: номер строки, пробел и оператор, дальше
+//!   печатные символы (KOI-8) до первого непечатного байта.
+//!
+//! Это эвристика: ложные находки возможны, поэтому сначала список, а
+//! извлечение - отдельным шагом. Блоки находки дальше не проверяются.
+
+use std::{fmt, ops::Range};
+
+use bkfs::{BinHeader, BIN_HEADER_SIZE};
+
+use crate::{Fs, FsError, BLOCK_SIZE};
+
+/// Адреса загрузки: ОЗУ пользователя БК-0010 за векторами и стеком
+const USER_RAM: Range<u32> = 0o1000..0o100000;
+/// Короче не считаем: слишком много случайных совпадений
+const MIN_LENGTH: usize = 16;
+/// Столько знаков в номере строки Бейсика
+const MAX_LINE_NUMBER_DIGITS: usize = 5;
+
+/// What was recognized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarvedKind {
+    /// `.bin` file, header included in data
+    Bin { start_address: u16 },
+    /// BASIC program as text
+    BasicText,
+}
+
+impl fmt::Display for CarvedKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bin { .. } => f.pad("bin"),
+            Self::BasicText => f.pad("basic"),
+        }
+    }
+}
+
+/// Lost file found by `carve()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Carved {
+    /// first block of the file in the volume
+    pub block: u64,
+    pub kind: CarvedKind,
+    /// bytes from the first block, `.bin` header included
+    pub length: u64,
+}
+
+impl Carved {
+    pub fn blocks(&self) -> u64 {
+        self.length.div_ceil(BLOCK_SIZE as u64)
+    }
+
+    /// Name for extracted file: first block and extension by kind
+    pub fn file_name(&self) -> String {
+        let ext = match self.kind {
+            CarvedKind::Bin { .. } => "bin",
+            CarvedKind::BasicText => "bas",
+        };
+        format!("carved-{:05}.{}", self.block, ext)
+    }
+}
+
+/// Заголовок .bin в начале `data` (остаток свободного участка)
+fn bin_at(data: &[u8]) -> Option<BinHeader> {
+    if data.len() < BIN_HEADER_SIZE as usize {
+        return None;
+    }
+    let header = BinHeader {
+        start_address: u16::from_le_bytes([data[0], data[1]]),
+        length: u16::from_le_bytes([data[2], data[3]]),
+    };
+    let address = u32::from(header.start_address);
+    let length = header.length as usize;
+    let fits = USER_RAM.contains(&address)
+        && address % 2 == 0
+        && length >= MIN_LENGTH
+        && address + length as u32 <= USER_RAM.end
+        && BIN_HEADER_SIZE as usize + length <= data.len();
+    fits.then_some(header)
+}
+
+/// Символы текста программы: печатные ASCII, русские буквы KOI-8 и концы строк
+fn is_text(b: u8) -> bool {
+    matches!(b, b'\n' | b'\r' | b'\t' | 0x20..=0x7e | 0xc0..=0xff)
+}
+
+/// Длина текста Бейсика в начале `data`, `None` если это не он
+fn basic_text_at(data: &[u8]) -> Option<usize> {
+    let digits = data.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 || digits > MAX_LINE_NUMBER_DIGITS {
+        return None;
+    }
+    // за номером строки пробел и оператор
+    match data.get(digits..digits + 2) {
+        Some([b' ', letter]) if letter.is_ascii_uppercase() => {}
+        _ => return None,
+    }
+    let length = data.iter().take_while(|&&b| is_text(b)).count();
+    let lines = data[..length]
+        .iter()
+        .filter(|&&b| b == b'\n' || b == b'\r')
+        .count();
+    (length >= MIN_LENGTH && lines > 0).then_some(length)
+}
+
+/// Находка в начале `data`
+fn carve_at(data: &[u8]) -> Option<(CarvedKind, usize)> {
+    if let Some(header) = bin_at(data) {
+        let kind = CarvedKind::Bin {
+            start_address: header.start_address,
+        };
+        return Some((kind, BIN_HEADER_SIZE as usize + header.length as usize));
+    }
+    basic_text_at(data).map(|length| (CarvedKind::BasicText, length))
+}
+
+/// Scan blocks not referenced by catalog for lost files, see module docs
+pub fn carve(fs: &Fs) -> Result<Vec<Carved>, FsError> {
+    let mut found = Vec::new();
+    for extent in fs.unreferenced_extents() {
+        let mut data = vec![0u8; (extent.end - extent.start) as usize * BLOCK_SIZE];
+        // образ бывает короче, чем записано в мета блоке
+        let size = fs.read_exact_at(&mut data, extent.start * BLOCK_SIZE as u64)?;
+        data.truncate(size);
+        let mut offset = 0;
+        while offset < data.len() {
+            match carve_at(&data[offset..]) {
+                Some((kind, length)) => {
+                    let carved = Carved {
+                        block: extent.start + (offset / BLOCK_SIZE) as u64,
+                        kind,
+                        length: length as u64,
+                    };
+                    offset += carved.blocks() as usize * BLOCK_SIZE;
+                    found.push(carved);
+                }
+                None => offset += BLOCK_SIZE,
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Contents of found file as it is on disk (`.bin` with header)
+pub fn read_carved(fs: &Fs, carved: &Carved) -> Result<Vec<u8>, FsError> {
+    let mut data = fs.read_blocks(carved.block, carved.blocks())?;
+    data.truncate(carved.length as usize);
+    Ok(data)
+}
@@ -0,0 +1,275 @@
+//! Проверка тома (fsck)
+//!
+//! При открытии несоответствия только логируются (или рвут открытие в
+//! ParseMode::Strict), а `Fs::check()` собирает их все в отчет, с которым
+//! уже можно что-то делать: показать, посчитать или починить счетчики.
+
+use std::{
+    fmt,
+    io::{Read, Seek, Write},
+    ops::Range,
+};
+
+use crate::{DirEntry, Fs, FsError, MetaOffset, BLOCK_SIZE, DIR_ENTRY_SIZE};
+
+/// Problem found by `Fs::check()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckIssue {
+    /// files counter in meta block differs from catalog
+    WrongFilesCount { meta: u64, found: u64 },
+    /// used blocks counter in meta block differs from catalog
+    WrongUsedBlocks { meta: u64, found: u64 },
+    /// disk size in meta block is bigger than image
+    WrongDiskSize { meta: u64, image: u64 },
+    /// file starts in system area (meta block and catalog)
+    ExtentInSystemArea {
+        name: String,
+        start: u64,
+        blocks: u64,
+    },
+    /// file ends past disk size
+    ExtentBeyondDisk {
+        name: String,
+        start: u64,
+        blocks: u64,
+    },
+    /// `name` shares `blocks` with `other`
+    Overlap {
+        name: String,
+        other: String,
+        blocks: Range<u64>,
+    },
+    /// entry with unknown status byte
+    UnknownStatus { name: String, status: u8 },
+    /// only `read` of `slots` catalog records were read
+    TruncatedCatalog { read: usize, slots: usize },
+}
+
+impl CheckIssue {
+    /// Fixed by `Fs::repair_counters()`
+    pub fn is_counter(&self) -> bool {
+        matches!(
+            self,
+            Self::WrongFilesCount { .. } | Self::WrongUsedBlocks { .. }
+        )
+    }
+}
+
+impl fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongFilesCount { meta, found } => {
+                write!(
+                    f,
+                    "Files count in meta block is {} but {} found",
+                    meta, found
+                )
+            }
+            Self::WrongUsedBlocks { meta, found } => {
+                write!(
+                    f,
+                    "Used blocks in meta block is {} but {} found",
+                    meta, found
+                )
+            }
+            Self::WrongDiskSize { meta, image } => write!(
+                f,
+                "Disk size in meta block is {} but image has {} blocks",
+                meta, image
+            ),
+            Self::ExtentInSystemArea {
+                name,
+                start,
+                blocks,
+            } => write!(
+                f,
+                "{:?}: extent {}+{} is in system area",
+                name, start, blocks
+            ),
+            Self::ExtentBeyondDisk {
+                name,
+                start,
+                blocks,
+            } => write!(
+                f,
+                "{:?}: extent {}+{} is beyond disk size",
+                name, start, blocks
+            ),
+            Self::Overlap {
+                name,
+                other,
+                blocks,
+            } => write!(
+                f,
+                "{:?} overlaps {:?} at blocks {}..{}",
+                name, other, blocks.start, blocks.end
+            ),
+            Self::UnknownStatus { name, status } => {
+                write!(f, "{:?}: unknown status 0{:o}", name, status)
+            }
+            Self::TruncatedCatalog { read, slots } => write!(
+                f,
+                "Catalog is truncated: {} of {} records read",
+                read, slots
+            ),
+        }
+    }
+}
+
+/// Result of `Fs::check()`
+#[derive(Debug, Default, Clone)]
+pub struct CheckReport {
+    /// catalog records checked
+    pub entries: usize,
+    pub issues: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Only meta block counters are wrong
+    pub fn counters_only(&self) -> bool {
+        !self.is_clean() && self.issues.iter().all(CheckIssue::is_counter)
+    }
+}
+
+/// Пересекающиеся записи: (индекс, индекс той, с кем пересеклась, общие блоки)
+pub(crate) fn find_overlaps(entries: &[DirEntry]) -> Vec<(usize, usize, Range<u64>)> {
+    let mut extents: Vec<(Range<u64>, usize)> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.occupies_disk() && e.extent_blocks() != 0)
+        .map(|(i, e)| {
+            let end = e.start_block.saturating_add(e.extent_blocks());
+            (e.start_block..end, i)
+        })
+        .collect();
+
+    // по порядку на диске каждая запись должна начинаться
+    // не раньше конца самой длинной из предыдущих
+    extents.sort_by_key(|(r, _)| (r.start, r.end));
+    let mut overlaps = Vec::new();
+    let mut last: Option<(u64, usize)> = None;
+    for (range, idx) in extents {
+        if let Some((end, other)) = last {
+            if range.start < end {
+                overlaps.push((idx, other, range.start..range.end.min(end)));
+            }
+            if end >= range.end {
+                continue;
+            }
+        }
+        last = Some((range.end, idx));
+    }
+    overlaps
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek,
+{
+    /// Check opened volume: meta counters, extents, overlaps, statuses
+    /// and catalog length. Logical disks contents are not checked.
+    pub fn check(&self) -> Result<CheckReport, FsError> {
+        let mut issues = Vec::new();
+        let layout = self.layout();
+
+        let (files, blocks) = self.catalog_counters();
+        if files != self.meta.files {
+            issues.push(CheckIssue::WrongFilesCount {
+                meta: self.meta.files as u64,
+                found: files as u64,
+            });
+        }
+        if blocks != self.meta.blocks {
+            issues.push(CheckIssue::WrongUsedBlocks {
+                meta: self.meta.blocks as u64,
+                found: blocks as u64,
+            });
+        }
+        let image = self.size / BLOCK_SIZE as u64;
+        if image < layout.disk_size {
+            issues.push(CheckIssue::WrongDiskSize {
+                meta: layout.disk_size,
+                image,
+            });
+        }
+
+        let slots = self.catalog_slots()?;
+        if self.entries.len() < slots {
+            issues.push(CheckIssue::TruncatedCatalog {
+                read: self.entries.len(),
+                slots,
+            });
+        }
+
+        for e in self.entries.iter() {
+            if e.is_unknown {
+                issues.push(CheckIssue::UnknownStatus {
+                    name: e.name.clone(),
+                    status: e.raw[0],
+                });
+            }
+            if !e.occupies_disk() || e.extent_blocks() == 0 {
+                continue;
+            }
+            match layout.check_extent(e.start_block, e.extent_blocks()) {
+                Err(FsError::ExtentInSystemArea { start, blocks, .. }) => {
+                    issues.push(CheckIssue::ExtentInSystemArea {
+                        name: e.name.clone(),
+                        start,
+                        blocks,
+                    })
+                }
+                Err(FsError::ExtentBeyondDisk { start, blocks, .. }) => {
+                    issues.push(CheckIssue::ExtentBeyondDisk {
+                        name: e.name.clone(),
+                        start,
+                        blocks,
+                    })
+                }
+                _ => {}
+            }
+        }
+        for (idx, other, blocks) in find_overlaps(&self.entries) {
+            issues.push(CheckIssue::Overlap {
+                name: self.entries[idx].name.clone(),
+                other: self.entries[other].name.clone(),
+                blocks,
+            });
+        }
+
+        Ok(CheckReport {
+            entries: self.entries.len(),
+            issues,
+        })
+    }
+
+    /// Непустые записи в каталоге до завершающей (по сырому образу)
+    fn catalog_slots(&self) -> Result<usize, FsError> {
+        let start = MetaOffset::DirEntriesStart as usize;
+        let size = (self.meta.start_block as usize * BLOCK_SIZE).saturating_sub(start);
+        let mut buf = vec![0u8; size];
+        let size = self.read_exact_at(&mut buf, start as u64)?;
+        let slots = buf[..size]
+            .chunks_exact(DIR_ENTRY_SIZE)
+            .take_while(|raw| raw[2] != 0)
+            .count();
+        Ok(slots)
+    }
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek + Write,
+{
+    /// Recompute files and used blocks counters of meta block from catalog
+    pub fn repair_counters(&mut self) -> Result<(), FsError> {
+        self.check_writable()?;
+        self.write_meta()?;
+        self.refresh_modified();
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//! Сжатые образы: `.img.gz` и zip с одним образом внутри
+//!
+//! Сжатие определяется по сигнатуре, а не по расширению. Для библиотеки
+//! `read_image()` распаковывает образ в память, и его можно открыть через
+//! `Fs::from_reader()` (только чтение). Утилитам и FUSE нужен файл:
+//! `cached_image()` распаковывает образ во временный каталог и при
+//! следующем запуске берет готовый, если исходник не менялся. TD0, IMD и
+//! HFE внутри архива (`container.rs`) разбираются уже из распакованной копии.
+//!
+//! В zip берется единственный файл, а если их несколько - единственный с
+//! расширением образа (`IMAGE_EXTENSIONS`).
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use flate2::read::MultiGzDecoder;
+use tracing::debug;
+use zip::ZipArchive;
+
+use crate::{container::cached_copy, io::MemImage, FsError};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+/// Extensions of images looked for in zip archives with several files
+pub const IMAGE_EXTENSIONS: [&str; 5] = ["img", "bkd", "dsk", "hdi", "raw"];
+
+/// Compression of image file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zip,
+}
+
+/// Compression by file signature, `None` for plain image
+pub fn detect(header: &[u8]) -> Option<Compression> {
+    if header.starts_with(&ZIP_MAGIC) {
+        Some(Compression::Zip)
+    } else if header.starts_with(&GZIP_MAGIC) {
+        Some(Compression::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Compression of file at `path`, `None` for plain image
+pub fn detect_file<P: AsRef<Path>>(path: P) -> Result<Option<Compression>, FsError> {
+    let mut header = [0u8; 4];
+    let mut file = File::open(path)?;
+    let mut len = 0;
+    // короткий файл - точно не архив
+    while len < header.len() {
+        match file.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(detect(&header[..len]))
+}
+
+fn zip_error(e: zip::result::ZipError) -> FsError {
+    match e {
+        zip::result::ZipError::Io(e) => e.into(),
+        e => FsError::Compressed(e.to_string()),
+    }
+}
+
+/// Номер файла образа в zip
+fn zip_image<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<usize, FsError> {
+    let mut files = Vec::new();
+    for idx in 0..archive.len() {
+        let file = archive.by_index_raw(idx).map_err(zip_error)?;
+        if file.is_file() {
+            files.push((idx, file.name().to_string()));
+        }
+    }
+    if let [(idx, _)] = files[..] {
+        return Ok(idx);
+    }
+    let images = files
+        .iter()
+        .filter(|(_, name)| {
+            Path::new(name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        })
+        .collect::<Vec<_>>();
+    match images[..] {
+        [(idx, _)] => Ok(*idx),
+        [] => Err(FsError::Compressed(format!(
+            "no image among {} files in zip",
+            files.len()
+        ))),
+        _ => Err(FsError::Compressed(format!(
+            "{} images in zip, can't choose",
+            images.len()
+        ))),
+    }
+}
+
+/// Распакованный образ из `reader` в `out`
+fn unpack<R, W>(mut reader: R, compression: Compression, out: &mut W) -> Result<u64, FsError>
+where
+    R: Read + Seek,
+    W: io::Write,
+{
+    reader.seek(SeekFrom::Start(0))?;
+    match compression {
+        Compression::Gzip => Ok(io::copy(&mut MultiGzDecoder::new(reader), out)?),
+        Compression::Zip => {
+            let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+            let idx = zip_image(&mut archive)?;
+            let mut file = archive.by_index(idx).map_err(zip_error)?;
+            debug!("Unpacking {:?} from zip", file.name());
+            Ok(io::copy(&mut file, out)?)
+        }
+    }
+}
+
+/// Read image at `path` into memory unpacking it if it's compressed.
+/// Open it with `Fs::from_reader()` (read only)
+pub fn read_image<P: AsRef<Path>>(path: P) -> Result<MemImage, FsError> {
+    let mut file = File::open(path.as_ref())?;
+    let mut header = [0u8; 4];
+    let len = file.read(&mut header)?;
+    let mut data = Vec::new();
+    match detect(&header[..len]) {
+        Some(compression) => {
+            unpack(file, compression, &mut data)?;
+        }
+        None => {
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut data)?;
+        }
+    }
+    Ok(MemImage::from(data))
+}
+
+/// Path of unpacked copy of compressed image `path` in temp dir (unpacked
+/// on first call, reused while `path` is not changed), `None` for plain image.
+/// Changes of the copy are not written back, so open it read only
+pub fn cached_image<P: AsRef<Path>>(path: P) -> Result<Option<PathBuf>, FsError> {
+    let path = path.as_ref();
+    let compression = match detect_file(path) {
+        Ok(Some(compression)) => compression,
+        // не читается - пусть об этом скажет открытие образа
+        Ok(None) | Err(_) => return Ok(None),
+    };
+    let cached = cached_copy(path, |out| unpack(File::open(path)?, compression, out))?;
+    Ok(Some(cached))
+}
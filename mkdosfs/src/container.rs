@@ -0,0 +1,315 @@
+//! Контейнеры образов дискет: Teledisk (`.td0`), ImageDisk (`.imd`) и HFE
+//! эмуляторов дисковода (HxC, Gotek)
+//!
+//! Все они хранят секторы вместе с их физическими адресами (дорожка,
+//! сторона, номер), сжатыми или как MFM поток. Декодеры (`td0.rs`,
+//! `imd.rs`, `hfe.rs`) собирают
+//! из них обычный линейный образ в памяти: дорожка за дорожкой, внутри
+//! дорожки стороны, внутри стороны секторы по номерам, как его пишут
+//! эмуляторы БК. Потерянные секторы заполняются нулями. Образы эмуляторов
+//! БК (`.bkd`, `.dsk`, `.img`) - это и есть такой линейный образ.
+//!
+//! Утилитам и FUSE нужен файл: `cached_image()` кладет собранный образ во
+//! временный каталог, туда же распаковываются сжатые образы.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+use tracing::{debug, warn};
+
+use crate::{hfe, imd, io::MemImage, td0, FsError};
+
+/// Possible values of `--input-format`
+pub const INPUT_FORMATS: [&str; 5] = ["auto", "raw", "td0", "imd", "hfe"];
+/// Subdirectory of temp dir for unpacked and decoded images
+const CACHE_DIR: &str = "bktools-images";
+
+/// Format of image file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// detect by signature
+    #[default]
+    Auto,
+    /// linear sector image (`.img`, `.bkd`, `.dsk` of BK emulators)
+    Raw,
+    /// Teledisk
+    Td0,
+    /// ImageDisk
+    Imd,
+    /// HxC floppy emulator (v1, MFM)
+    Hfe,
+}
+
+impl FromStr for InputFormat {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "raw" | "img" | "bkd" | "dsk" => Ok(Self::Raw),
+            "td0" => Ok(Self::Td0),
+            "imd" => Ok(Self::Imd),
+            "hfe" => Ok(Self::Hfe),
+            _ => Err(FsError::Container(format!("unknown input format {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Raw => write!(f, "raw"),
+            Self::Td0 => write!(f, "td0"),
+            Self::Imd => write!(f, "imd"),
+            Self::Hfe => write!(f, "hfe"),
+        }
+    }
+}
+
+/// Format by file signature (never `Auto`)
+pub fn detect(header: &[u8]) -> InputFormat {
+    if header.starts_with(imd::SIGNATURE) {
+        InputFormat::Imd
+    } else if header.starts_with(hfe::SIGNATURE) {
+        InputFormat::Hfe
+    } else if td0::is_signature(header) {
+        InputFormat::Td0
+    } else {
+        InputFormat::Raw
+    }
+}
+
+/// Linear sector image from contents of image file
+pub fn decode(data: &[u8], format: InputFormat) -> Result<Cow<'_, [u8]>, FsError> {
+    let format = match format {
+        InputFormat::Auto => detect(data),
+        format => format,
+    };
+    match format {
+        InputFormat::Auto | InputFormat::Raw => Ok(Cow::Borrowed(data)),
+        InputFormat::Td0 => Ok(Cow::Owned(td0::decode(data)?)),
+        InputFormat::Imd => Ok(Cow::Owned(imd::decode(data)?)),
+        InputFormat::Hfe => Ok(Cow::Owned(hfe::decode(data)?)),
+    }
+}
+
+/// Read image at `path` into memory decoding TD0, IMD and HFE containers.
+/// Open it with `Fs::from_reader()` (read only)
+pub fn read_image<P: AsRef<Path>>(path: P, format: InputFormat) -> Result<MemImage, FsError> {
+    let data = fs::read(path)?;
+    Ok(match decode(&data, format)? {
+        Cow::Borrowed(_) => MemImage::from(data),
+        Cow::Owned(image) => MemImage::from(image),
+    })
+}
+
+/// Path of decoded copy of TD0, IMD or HFE image `path` in temp dir (decoded on
+/// first call, reused while `path` is not changed), `None` for raw image.
+/// Open the copy read only
+pub fn cached_image<P: AsRef<Path>>(
+    path: P,
+    format: InputFormat,
+) -> Result<Option<PathBuf>, FsError> {
+    let path = path.as_ref();
+    let format = match format {
+        InputFormat::Auto => {
+            let mut header = [0u8; 16];
+            // не читается - пусть об этом скажет открытие образа
+            match File::open(path).and_then(|mut file| read_header(&mut file, &mut header)) {
+                Ok(len) => detect(&header[..len]),
+                Err(_) => return Ok(None),
+            }
+        }
+        format => format,
+    };
+    if format == InputFormat::Raw {
+        return Ok(None);
+    }
+    let image = cached_copy(path, |out| {
+        let data = fs::read(path)?;
+        let image = decode(&data, format)?;
+        out.write_all(&image)?;
+        Ok(image.len() as u64)
+    })?;
+    Ok(Some(image))
+}
+
+/// Path to open instead of `path`: decoded copy of TD0, IMD or HFE image
+/// (unpacked from gzip or zip first with `compress` feature), `None` if
+/// `path` is a raw image itself. Open the copy read only
+pub fn prepared_image<P: AsRef<Path>>(
+    path: P,
+    format: InputFormat,
+) -> Result<Option<PathBuf>, FsError> {
+    let path = path.as_ref();
+    #[cfg(feature = "compress")]
+    let unpacked = crate::compressed::cached_image(path)?;
+    #[cfg(not(feature = "compress"))]
+    let unpacked: Option<PathBuf> = None;
+    let source = unpacked.as_deref().unwrap_or(path);
+    Ok(cached_image(source, format)?.or(unpacked))
+}
+
+fn read_header(file: &mut File, header: &mut [u8]) -> std::io::Result<usize> {
+    use std::io::Read;
+
+    let mut len = 0;
+    while len < header.len() {
+        match file.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// Копия образа `path` во временном каталоге, которую пишет `fill`.
+/// Ключ кэша - полный путь, размер и время изменения исходника, так что
+/// при следующем запуске берется готовая копия, если исходник не менялся
+pub(crate) fn cached_copy<F>(path: &Path, fill: F) -> Result<PathBuf, FsError>
+where
+    F: FnOnce(&mut File) -> Result<u64, FsError>,
+{
+    let meta = fs::metadata(path)?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |mtime| mtime.as_nanos());
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(fs::canonicalize(path)?.to_string_lossy().as_bytes());
+    hasher.update(&meta.len().to_le_bytes());
+    hasher.update(&mtime.to_le_bytes());
+    let stem = path
+        .file_stem()
+        .map_or_else(|| "image".into(), |stem| stem.to_string_lossy());
+    let dir = std::env::temp_dir().join(CACHE_DIR);
+    let name = format!("{}-{:08x}", stem, hasher.finalize());
+    let cached = dir.join(&name);
+    if cached.is_file() {
+        debug!("Using cached {:?}", cached);
+        return Ok(cached);
+    }
+
+    fs::create_dir_all(&dir)?;
+    // сначала во временный файл: оборванная запись не попадет в кэш
+    let partial = dir.join(format!("{}.part{}", name, std::process::id()));
+    let written = File::create(&partial)
+        .map_err(FsError::from)
+        .and_then(|mut out| fill(&mut out));
+    match written {
+        Ok(size) => debug!("Cached {:?} ({} bytes) as {:?}", path, size, cached),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    }
+    fs::rename(&partial, &cached)?;
+    Ok(cached)
+}
+
+/// Разбор контейнера по порядку, нехватка данных - ошибка
+pub(crate) struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], FsError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| FsError::Container(format!("truncated at offset {}", self.pos)))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, FsError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn word(&mut self) -> Result<u16, FsError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+/// Секторы дискеты по физическим адресам (дорожка, сторона, номер)
+#[derive(Debug, Default)]
+pub(crate) struct SectorMap {
+    sectors: BTreeMap<(u8, u8, u8), Vec<u8>>,
+}
+
+impl SectorMap {
+    pub(crate) fn insert(&mut self, cylinder: u8, head: u8, sector: u8, data: Vec<u8>) {
+        if self
+            .sectors
+            .insert((cylinder, head, sector), data)
+            .is_some()
+        {
+            debug!(cylinder, head, sector, "Repeated sector, last copy is used");
+        }
+    }
+
+    /// Линейный образ: все дорожки и стороны с одинаковым числом секторов,
+    /// размер сектора - самый частый
+    pub(crate) fn into_image(self) -> Result<Vec<u8>, FsError> {
+        let mut sizes: BTreeMap<usize, usize> = BTreeMap::new();
+        for data in self.sectors.values() {
+            *sizes.entry(data.len()).or_default() += 1;
+        }
+        let size = sizes
+            .into_iter()
+            .max_by_key(|&(size, count)| (count, size))
+            .map(|(size, _)| size)
+            .filter(|&size| size > 0)
+            .ok_or_else(|| FsError::Container("no sectors with data".into()))?;
+        let keys = self.sectors.keys();
+        let cylinders = keys.clone().map(|k| k.0).max().unwrap_or(0) as usize + 1;
+        let heads = keys.clone().map(|k| k.1).max().unwrap_or(0) as usize + 1;
+        let first = keys.clone().map(|k| k.2).min().unwrap_or(0);
+        let last = keys.map(|k| k.2).max().unwrap_or(0);
+
+        let mut image = Vec::with_capacity(cylinders * heads * (last - first + 1) as usize * size);
+        let mut missing = 0;
+        for cylinder in 0..cylinders as u8 {
+            for head in 0..heads as u8 {
+                for sector in first..=last {
+                    let start = image.len();
+                    match self.sectors.get(&(cylinder, head, sector)) {
+                        Some(data) => image.extend_from_slice(&data[..data.len().min(size)]),
+                        None => missing += 1,
+                    }
+                    image.resize(start + size, 0);
+                }
+            }
+        }
+        if missing > 0 {
+            warn!("{} sectors are missing, filled with zeros", missing);
+        }
+        debug!(
+            cylinders,
+            heads,
+            sectors = last - first + 1,
+            size,
+            "Sector image"
+        );
+        Ok(image)
+    }
+}
@@ -0,0 +1,152 @@
+//! Определение файловой системы образа по сигнатурам
+//!
+//! Проверяем по порядку, от самой надежной метки к самой слабой:
+//! - MK-DOS: метки 123456 и 51414 в мета блоке (0400 и 0402 блока 0)
+//! - RT-11: "DECRT11A" в home block (0760 блока 1) или разумный
+//!   заголовок первого сегмента каталога
+//! - CSI-DOS: метка каталога в блоке 2
+//! - ANDOS: загрузочный сектор с корректным BPB FAT12
+//!
+//! BPB проверяется последним: у него нет метки, только правдоподобные числа.
+//!
+//! Тома с винчестера АльтПро лежат инвертированными. `Fs::detect_inverted()`
+//! ищет сигнатуры в обоих видах и сама выбирает, как читать образ.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use bkhdd::{detect_partition_table, ParseMode};
+use tracing::{info, warn};
+
+use crate::{
+    andos::Bpb, io::ReaderBuilder, Fs, FsError, MetaOffset, BLOCK_SIZE, MICRODOS_LABEL, MKDOS_LABEL,
+};
+
+/// Слово метки CSI-DOS в блоке каталога
+pub const CSIDOS_LABEL: u16 = 0o123123;
+const CSIDOS_LABEL_OFFSET: usize = 2 * BLOCK_SIZE + 4;
+
+const RT11_SYSTEM_ID: &[u8] = b"DECRT11A";
+const RT11_SYSTEM_ID_OFFSET: usize = BLOCK_SIZE + 0o760;
+const RT11_FIRST_SEGMENT_OFFSET: usize = BLOCK_SIZE + 0o724;
+
+/// Filesystem found by `detect_fs()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsKind {
+    MkDos,
+    Andos,
+    CsiDos,
+    Rt11,
+    Unknown,
+}
+
+impl fmt::Display for FsKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::MkDos => "MK-DOS",
+            Self::Andos => "ANDOS",
+            Self::CsiDos => "CSI-DOS",
+            Self::Rt11 => "RT-11",
+            Self::Unknown => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+fn word(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+/// Probe filesystem signatures of volume starting at `offset` bytes
+pub fn detect_fs<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<FsKind, FsError> {
+    // первых трех блоков хватает на все сигнатуры
+    let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut size = 0;
+    while size < buf.len() {
+        match reader.read(&mut buf[size..])? {
+            0 => break,
+            n => size += n,
+        }
+    }
+    // на хвост короткого образа сигнатуры не смотрим
+    buf[size..].iter_mut().for_each(|b| *b = 0);
+
+    if word(&buf, MetaOffset::MicrodosLabel as usize) == MICRODOS_LABEL
+        && word(&buf, MetaOffset::MkdosLabel as usize) == MKDOS_LABEL
+    {
+        return Ok(FsKind::MkDos);
+    }
+    if &buf[RT11_SYSTEM_ID_OFFSET..RT11_SYSTEM_ID_OFFSET + RT11_SYSTEM_ID.len()] == RT11_SYSTEM_ID {
+        return Ok(FsKind::Rt11);
+    }
+    if word(&buf, CSIDOS_LABEL_OFFSET) == CSIDOS_LABEL {
+        return Ok(FsKind::CsiDos);
+    }
+    if Bpb::parse(&buf[..BLOCK_SIZE]).is_ok() {
+        return Ok(FsKind::Andos);
+    }
+    // RT-11 без идентификатора системы: каталог почти всегда с блока 6,
+    // в заголовке 1..=31 сегментов и четное число доп. байт
+    if word(&buf, RT11_FIRST_SEGMENT_OFFSET) == 6 {
+        let mut segment = [0u8; 10];
+        reader.seek(SeekFrom::Start(offset + 6 * BLOCK_SIZE as u64))?;
+        if reader.read_exact(&mut segment).is_ok() {
+            let (total, next, extra) = (word(&segment, 0), word(&segment, 2), word(&segment, 6));
+            if (1..=31).contains(&total) && next <= total && extra & 1 == 0 {
+                return Ok(FsKind::Rt11);
+            }
+        }
+    }
+
+    Ok(FsKind::Unknown)
+}
+
+impl Fs {
+    /// Detect filesystem of the volume with current offset and
+    /// inverted, swapped and geometry settings (see `set_partition`,
+    /// `skip_hdi_header`)
+    pub fn detect(&self) -> Result<FsKind, FsError> {
+        self.detect_as(self.inverted)
+    }
+
+    fn detect_as(&self, inverted: bool) -> Result<FsKind, FsError> {
+        let file = File::open(&self.file_path).map_err(|e| FsError::CustomIo {
+            desc: format!("Can't open {:?}", &self.file_path),
+            source: e,
+        })?;
+        let mut reader = ReaderBuilder::with_flags(inverted, self.swapped)
+            .with_geometry(self.geometry.as_ref())
+            .build_file(file)?;
+        detect_fs(&mut reader, self.offset)
+    }
+
+    /// Choose plain or inverted reading by filesystem signatures of the
+    /// volume (plain is tried first). Setting is kept if neither way is
+    /// recognized. Returns filesystem found
+    pub fn detect_inverted(&mut self) -> Result<FsKind, FsError> {
+        for inverted in [false, true] {
+            let kind = self.detect_as(inverted)?;
+            if kind != FsKind::Unknown {
+                if inverted != self.inverted {
+                    info!(inverted, %kind, "Image inversion detected");
+                }
+                self.inverted = inverted;
+                return Ok(kind);
+            }
+        }
+        // весь диск вместо раздела - частая ошибка, подскажем
+        if let Ok(Some(table)) =
+            detect_partition_table(&self.file_path, self.offset, ParseMode::Permissive, true)
+        {
+            warn!(
+                "{} partition table found: this is an HDD image, open its partition (--partition N)",
+                table.controller()
+            );
+        }
+        Ok(FsKind::Unknown)
+    }
+}
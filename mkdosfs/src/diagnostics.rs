@@ -0,0 +1,145 @@
+//! Предупреждения при чтении образа
+//!
+//! В ParseMode::Permissive и Forensic несоответствия не рвут открытие и
+//! раньше попадали только в лог tracing, которого программа не видит. Теперь
+//! они копятся в `Diagnostics` тома (`Fs::diagnostics()`), откуда их берут
+//! GUI и fsck. Список собирается заново при каждом чтении каталога.
+
+use std::{fmt, sync::Arc};
+
+use crate::{ErrorCategory, FsError};
+
+/// Problem found while reading image, not fatal in current parse mode
+#[derive(Debug, Clone)]
+pub enum Warning {
+    /// metadata inconsistency, an error in `ParseMode::Strict`
+    Inconsistency(Arc<FsError>),
+    /// file name has bytes not valid in the encoding
+    BadName { raw: Vec<u8>, offset: u64 },
+    /// directory number is taken or no directory inodes left, `inode` assigned
+    DirectoryInode {
+        name: String,
+        number: u8,
+        inode: u64,
+    },
+    /// logical disk is not a readable MK-DOS volume
+    LogicalDisk { name: String, error: Arc<FsError> },
+    /// catalog reading stopped at entry that looks like garbage
+    GarbageEntry { status: u8, offset: u64 },
+    /// catalog runs into data area starting at block `start_block`
+    CatalogOverrun { offset: u64, start_block: u64 },
+    /// directory `dir_no` of file does not exist, file is moved to root
+    OrphanFile { name: String, dir_no: u8 },
+}
+
+impl Warning {
+    /// Offset in image file where the problem was found, if known
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Self::Inconsistency(e) => e.offset(),
+            Self::BadName { offset, .. }
+            | Self::GarbageEntry { offset, .. }
+            | Self::CatalogOverrun { offset, .. } => Some(*offset),
+            Self::DirectoryInode { .. } | Self::LogicalDisk { .. } | Self::OrphanFile { .. } => {
+                None
+            }
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Inconsistency(e) => e.category(),
+            _ => ErrorCategory::Parse,
+        }
+    }
+}
+
+impl From<FsError> for Warning {
+    fn from(e: FsError) -> Self {
+        Self::Inconsistency(Arc::new(e))
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inconsistency(e) => write!(f, "{}", e),
+            Self::BadName { raw, offset } => write!(
+                f,
+                "Error while recoding file name {:?} at image offset {:#o}",
+                raw, offset
+            ),
+            Self::DirectoryInode {
+                name,
+                number,
+                inode,
+            } => write!(
+                f,
+                "Directory {:?} number {} is not usable, inode {} assigned",
+                name, number, inode
+            ),
+            Self::LogicalDisk { name, error } => {
+                write!(f, "Can't open logical disk {:?}: {}", name, error)
+            }
+            Self::GarbageEntry { status, offset } => write!(
+                f,
+                "Catalog ends at garbage entry with status {:#o} at image offset {:#o}",
+                status, offset
+            ),
+            Self::CatalogOverrun {
+                offset,
+                start_block,
+            } => write!(
+                f,
+                "Catalog runs into data area (block {}) at image offset {:#o}",
+                start_block, offset
+            ),
+            Self::OrphanFile { name, dir_no } => write!(
+                f,
+                "Directory {} of {:?} does not exist, moved to root",
+                dir_no, name
+            ),
+        }
+    }
+}
+
+/// Warnings collected while reading image
+#[derive(Debug, Default, Clone)]
+pub struct Diagnostics {
+    warnings: Vec<Warning>,
+}
+
+impl Diagnostics {
+    pub(crate) fn push(&mut self, warning: impl Into<Warning>) {
+        self.warnings.push(warning.into());
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.warnings.clear();
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Warning> {
+        self.warnings.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.warnings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Warning;
+    type IntoIter = std::slice::Iter<'a, Warning>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
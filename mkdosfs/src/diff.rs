@@ -0,0 +1,155 @@
+//! Сравнение двух томов: каталоги и содержимое файлов
+//!
+//! Работает с любыми `BkFileSystem`, так что MK-DOS можно сравнить хоть с
+//! ANDOS. Файлы сравниваются по размеру и CRC32 содержимого, даты и
+//! адреса загрузки не учитываются. Файл, пропавший в одном месте и
+//! появившийся с тем же содержимым в другом, считается перемещенным.
+//! Файл, который не удалось прочитать, всегда считается измененным.
+
+use std::{collections::BTreeMap, fmt};
+
+use bkfs::{BkFileSystem, ROOT_INODE};
+use crc32fast::Hasher;
+use tracing::warn;
+
+/// Size of file chunks read to compute checksum
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Difference between two volumes, paths are `/`-separated without leading slash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// only in the second volume
+    Added(String),
+    /// only in the first volume
+    Removed(String),
+    /// in both volumes, but contents differ
+    Changed(String),
+    /// same contents under other path
+    Moved { from: String, to: String },
+}
+
+impl Change {
+    /// Path in the first volume (in the second one for added entries)
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added(path) | Change::Removed(path) | Change::Changed(path) => path,
+            Change::Moved { from, .. } => from,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(path) => write!(f, "A {}", path),
+            Change::Removed(path) => write!(f, "D {}", path),
+            Change::Changed(path) => write!(f, "M {}", path),
+            Change::Moved { from, to } => write!(f, "R {} -> {}", from, to),
+        }
+    }
+}
+
+/// Что известно о записи тома для сравнения
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Item {
+    Dir,
+    /// `crc` - `None`, если файл не читается
+    File {
+        size: u64,
+        crc: Option<u32>,
+    },
+}
+
+fn checksum<B: BkFileSystem + ?Sized>(fs: &B, inode: u64, size: u64) -> bkfs::Result<u32> {
+    let mut hasher = Hasher::new();
+    let mut offset = 0;
+    while offset < size {
+        let chunk = std::cmp::min(CHUNK_SIZE as u64, size - offset) as usize;
+        let data = fs.read(inode, offset, chunk)?;
+        if data.is_empty() {
+            break;
+        }
+        hasher.update(&data);
+        offset += data.len() as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+/// Все записи тома по путям
+fn catalog<B: BkFileSystem + ?Sized>(fs: &B) -> bkfs::Result<BTreeMap<String, Item>> {
+    let mut items = BTreeMap::new();
+    let mut dirs = vec![(ROOT_INODE, String::new())];
+    while let Some((inode, prefix)) = dirs.pop() {
+        for entry in fs.list(inode)? {
+            let path = format!("{}{}", prefix, entry.name);
+            if entry.is_dir() {
+                dirs.push((entry.inode, format!("{}/", path)));
+                items.insert(path, Item::Dir);
+                continue;
+            }
+            let crc = match checksum(fs, entry.inode, entry.size) {
+                Ok(crc) => Some(crc),
+                Err(e) => {
+                    warn!("Can't read {:?}: {}", path, e);
+                    None
+                }
+            };
+            items.insert(
+                path,
+                Item::File {
+                    size: entry.size,
+                    crc,
+                },
+            );
+        }
+    }
+    Ok(items)
+}
+
+/// Compare catalogs and file contents of two volumes, changes are sorted by path
+pub fn diff<A, B>(old: &A, new: &B) -> bkfs::Result<Vec<Change>>
+where
+    A: BkFileSystem + ?Sized,
+    B: BkFileSystem + ?Sized,
+{
+    let old = catalog(old)?;
+    let mut new = catalog(new)?;
+
+    let mut changes = Vec::new();
+    let mut removed = Vec::new();
+    for (path, item) in old {
+        match (item, new.remove(&path)) {
+            (Item::Dir, Some(Item::Dir)) => {}
+            (Item::File { .. }, Some(other @ Item::File { .. }))
+                if other == item && !matches!(item, Item::File { crc: None, .. }) => {}
+            (Item::File { .. }, Some(Item::File { .. })) => changes.push(Change::Changed(path)),
+            (item, Some(other)) => {
+                // файл стал каталогом или наоборот: удален и добавлен
+                new.insert(path.clone(), other);
+                removed.push((path, item));
+            }
+            (item, None) => removed.push((path, item)),
+        }
+    }
+
+    // в `new` остались только добавленные
+    for (path, item) in removed {
+        let to = match item {
+            Item::File { crc: Some(_), .. } => new
+                .iter()
+                .find(|(_, other)| **other == item)
+                .map(|(to, _)| to.clone()),
+            _ => None,
+        };
+        match to {
+            Some(to) => {
+                new.remove(&to);
+                changes.push(Change::Moved { from: path, to });
+            }
+            None => changes.push(Change::Removed(path)),
+        }
+    }
+    changes.extend(new.into_keys().map(Change::Added));
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
@@ -0,0 +1,121 @@
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use encoding_rs::{IBM866, KOI8_R};
+
+use crate::FsError;
+
+/// Кодировка имен файлов в каталоге
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Encoding {
+    /// родная для БК
+    #[default]
+    Koi8R,
+    /// альтернативная (образы, подготовленные на PC)
+    Cp866,
+    /// KOI8-R, но кириллица на чтении транслитерируется в ASCII
+    /// (для систем, где кириллица в путях - проблема).
+    /// На запись имена идут как в KOI8-R
+    Translit,
+}
+
+impl Encoding {
+    /// Decode raw name, `bool` is set if some bytes could not be decoded
+    pub fn decode<'a>(&self, raw: &'a [u8]) -> (Cow<'a, str>, bool) {
+        let (cow, _encoding_used, had_errors) = match self {
+            Self::Koi8R | Self::Translit => KOI8_R.decode(raw),
+            Self::Cp866 => IBM866.decode(raw),
+        };
+        if *self == Self::Translit {
+            return (Cow::Owned(transliterate(&cow)), had_errors);
+        }
+        (cow, had_errors)
+    }
+
+    /// Encode name, `None` if it has unmappable characters
+    pub fn encode<'a>(&self, name: &'a str) -> Option<Cow<'a, [u8]>> {
+        let (bytes, _encoding_used, had_unmappable) = match self {
+            Self::Koi8R | Self::Translit => KOI8_R.encode(name),
+            Self::Cp866 => IBM866.encode(name),
+        };
+        if had_unmappable {
+            None
+        } else {
+            Some(bytes)
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "koi8r" | "koi8-r" => Ok(Self::Koi8R),
+            "cp866" | "ibm866" => Ok(Self::Cp866),
+            "translit" | "ascii" => Ok(Self::Translit),
+            _ => Err(FsError::UnknownEncoding(s.into())),
+        }
+    }
+}
+
+/// Имя как его принимает `from_str()`
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Koi8R => write!(f, "koi8r"),
+            Self::Cp866 => write!(f, "cp866"),
+            Self::Translit => write!(f, "translit"),
+        }
+    }
+}
+
+/// Кириллица -> латиница, все остальное не ASCII (псевдографика) -> '_'
+fn transliterate(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii() {
+            out.push(c);
+            continue;
+        }
+        let lower = c.to_lowercase().next().unwrap_or(c);
+        let lat = match lower {
+            'а' => "a",
+            'б' => "b",
+            'в' => "v",
+            'г' => "g",
+            'д' => "d",
+            'е' | 'ё' | 'э' => "e",
+            'ж' => "zh",
+            'з' => "z",
+            'и' => "i",
+            'й' => "j",
+            'к' => "k",
+            'л' => "l",
+            'м' => "m",
+            'н' => "n",
+            'о' => "o",
+            'п' => "p",
+            'р' => "r",
+            'с' => "s",
+            'т' => "t",
+            'у' => "u",
+            'ф' => "f",
+            'х' => "h",
+            'ц' => "c",
+            'ч' => "ch",
+            'ш' => "sh",
+            'щ' => "sch",
+            'ъ' | 'ь' => "",
+            'ы' => "y",
+            'ю' => "ju",
+            'я' => "ja",
+            _ => "_",
+        };
+        if lower != c {
+            out.push_str(&lat.to_ascii_uppercase());
+        } else {
+            out.push_str(lat);
+        }
+    }
+    out
+}
@@ -0,0 +1,125 @@
+//! Пересчет номеров блоков для образов, снятых не подряд
+//!
+//! Обычный образ дискеты БК линейный: дорожка 0 сторона 0, дорожка 0
+//! сторона 1, дорожка 1 сторона 0 и так далее. Некоторые программы снятия
+//! пишут сначала все дорожки стороны 0, затем все дорожки стороны 1, а
+//! секторы внутри дорожки - в физическом порядке с чередованием
+//! (interleave). `GeometryMapper` переводит логический блок тома в блок
+//! такого образа, и парсер видит обычный линейный том. Сектор - один блок
+//! (512 байт). Образ, снятый подряд, но с чередованием секторов, задается
+//! одной стороной: `160,1,10,3`.
+
+use std::{fmt, ops::Range, str::FromStr};
+
+use crate::{FsError, BLOCK_SIZE};
+
+/// Block remapping of side by side, sector interleaved images
+/// (`tracks,sides,sectors[,interleave]`, e.g. `80,2,10,2`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeometryMapper {
+    tracks: u32,
+    sides: u32,
+    sectors: u32,
+    interleave: u32,
+    /// позиция в дорожке образа для каждого сектора по порядку
+    slots: Vec<u32>,
+}
+
+impl GeometryMapper {
+    pub fn new(tracks: u32, sides: u32, sectors: u32, interleave: u32) -> Result<Self, FsError> {
+        if tracks == 0 || sides == 0 || sectors == 0 {
+            return Err(FsError::BadMapping(
+                "tracks, sides and sectors can't be 0".into(),
+            ));
+        }
+        if interleave == 0 || interleave >= sectors.max(2) {
+            return Err(FsError::BadMapping(format!(
+                "interleave {} for {} sectors",
+                interleave, sectors
+            )));
+        }
+        // раскладка как при форматировании: каждый следующий сектор через
+        // interleave позиций, занятые позиции пропускаются
+        let mut slots = vec![0; sectors as usize];
+        let mut used = vec![false; sectors as usize];
+        let mut slot = 0;
+        for sector in slots.iter_mut() {
+            while used[slot] {
+                slot = (slot + 1) % used.len();
+            }
+            used[slot] = true;
+            *sector = slot as u32;
+            slot = (slot + interleave as usize) % used.len();
+        }
+        Ok(Self {
+            tracks,
+            sides,
+            sectors,
+            interleave,
+            slots,
+        })
+    }
+
+    /// Blocks covered by the geometry, blocks past them are not remapped
+    pub fn blocks(&self) -> u64 {
+        self.tracks as u64 * self.sides as u64 * self.sectors as u64
+    }
+
+    /// Block of the image holding logical block `block` of the volume
+    pub fn map_block(&self, block: u64) -> u64 {
+        if block >= self.blocks() {
+            return block;
+        }
+        let sectors = self.sectors as u64;
+        let sector = block % sectors;
+        let side = block / sectors % self.sides as u64;
+        let track = block / sectors / self.sides as u64;
+        (side * self.tracks as u64 + track) * sectors + self.slots[sector as usize] as u64
+    }
+
+    /// Куски `len` байт с логического `offset`: смещение в образе и
+    /// диапазон в буфере, по куску на блок
+    pub(crate) fn segments(&self, offset: u64, len: usize) -> Vec<(u64, Range<usize>)> {
+        let bs = BLOCK_SIZE as u64;
+        let mut segments = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let skip = pos % bs;
+            let size = std::cmp::min(len - done, (bs - skip) as usize);
+            segments.push((self.map_block(pos / bs) * bs + skip, done..done + size));
+            done += size;
+        }
+        segments
+    }
+}
+
+impl FromStr for GeometryMapper {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let numbers = s
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| FsError::BadMapping(format!("{:?}: {}", s, e)))?;
+        match numbers[..] {
+            [tracks, sides, sectors] => Self::new(tracks, sides, sectors, 1),
+            [tracks, sides, sectors, interleave] => Self::new(tracks, sides, sectors, interleave),
+            _ => Err(FsError::BadMapping(format!(
+                "{:?}, expected tracks,sides,sectors[,interleave]",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for GeometryMapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.tracks, self.sides, self.sectors, self.interleave
+        )
+    }
+}
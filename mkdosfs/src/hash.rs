@@ -0,0 +1,173 @@
+//! Контрольные суммы тома для архивов
+//!
+//! `hash_volume()` считает CRC32 и SHA-1 каждого файла и SHA-1 логического
+//! содержимого всего тома: пути каталогов и файлов, адреса загрузки,
+//! размеры и SHA-1 файлов, строками в порядке путей. Удаленные записи,
+//! свободные блоки и место файлов на диске в эту сумму не входят, так что
+//! образы, различающиеся только мусором в неиспользуемых блоках, дают одну
+//! сумму. Сумма сырых байтов тома (`volume_sha1`) такие образы различает.
+//!
+//! Работает с любым `BkFileSystem`, как и `diff`. Манифест в JSON - только
+//! с feature `serde`.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "serde")]
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use bkfs::{BkFileSystem, ROOT_INODE};
+use crc32fast::Hasher;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha1_smol::Sha1;
+
+#[cfg(feature = "serde")]
+use crate::FsError;
+
+/// Size of chunks read to compute checksums
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Checksums of file contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileHash {
+    /// `/`-separated without leading slash
+    pub path: String,
+    pub size: u64,
+    /// load address, if the format keeps it
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub start_address: Option<u16>,
+    /// hex
+    pub crc32: String,
+    /// hex
+    pub sha1: String,
+}
+
+/// Checksums of volume and its files (hash manifest)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VolumeHash {
+    /// SHA-1 of logical contents: directories, files and their checksums
+    pub content_sha1: String,
+    /// SHA-1 of raw volume bytes, if the format has raw access
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub volume_sha1: Option<String>,
+    pub directories: Vec<String>,
+    /// sorted by path
+    pub files: Vec<FileHash>,
+}
+
+#[cfg(feature = "serde")]
+impl VolumeHash {
+    /// Write manifest as JSON to `path`
+    pub fn write_manifest(&self, path: &Path) -> Result<(), FsError> {
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut out, self).map_err(std::io::Error::from)?;
+        writeln!(out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn file_hash<B: BkFileSystem + ?Sized>(
+    fs: &B,
+    inode: u64,
+    path: String,
+    size: u64,
+) -> bkfs::Result<FileHash> {
+    let mut crc = Hasher::new();
+    let mut sha1 = Sha1::new();
+    let mut offset = 0;
+    while offset < size {
+        let chunk = std::cmp::min(CHUNK_SIZE as u64, size - offset) as usize;
+        let data = fs.read(inode, offset, chunk)?;
+        if data.is_empty() {
+            break;
+        }
+        crc.update(&data);
+        sha1.update(&data);
+        offset += data.len() as u64;
+    }
+    Ok(FileHash {
+        path,
+        size: offset,
+        start_address: fs.bin_header(inode).map(|h| h.start_address),
+        crc32: format!("{:08x}", crc.finalize()),
+        sha1: sha1.digest().to_string(),
+    })
+}
+
+/// SHA-1 сырых байтов тома, `None` если формат их не отдает
+fn volume_sha1<B: BkFileSystem + ?Sized>(fs: &B) -> bkfs::Result<Option<String>> {
+    let Some(size) = fs.raw_size() else {
+        return Ok(None);
+    };
+    let mut sha1 = Sha1::new();
+    let mut offset = 0;
+    while offset < size {
+        let chunk = std::cmp::min(CHUNK_SIZE as u64, size - offset) as usize;
+        let data = fs.read_raw(offset, chunk)?;
+        if data.is_empty() {
+            break;
+        }
+        sha1.update(&data);
+        offset += data.len() as u64;
+    }
+    Ok(Some(sha1.digest().to_string()))
+}
+
+/// Checksums of all files and of the whole volume, see module docs.
+/// Unreadable file is an error: the sum would not describe the volume
+pub fn hash_volume<B: BkFileSystem + ?Sized>(fs: &B) -> bkfs::Result<VolumeHash> {
+    let mut directories = Vec::new();
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![(ROOT_INODE, String::new())];
+    while let Some((inode, prefix)) = dirs.pop() {
+        for entry in fs.list(inode)? {
+            let path = format!("{}{}", prefix, entry.name);
+            if entry.is_dir() {
+                dirs.push((entry.inode, format!("{}/", path)));
+                directories.push(path);
+            } else {
+                let hash = file_hash(fs, entry.inode, path.clone(), entry.size)?;
+                files.insert(path, hash);
+            }
+        }
+    }
+    directories.sort();
+
+    // каталоги и файлы вперемешку по путям: сумма не зависит от порядка в каталоге
+    let mut lines = BTreeMap::new();
+    for dir in directories.iter() {
+        lines.insert(dir.as_str(), format!("d {}\n", dir));
+    }
+    for (path, file) in files.iter() {
+        let address = file
+            .start_address
+            .map_or_else(|| "-".to_string(), |a| format!("{:06o}", a));
+        lines.insert(
+            path.as_str(),
+            format!("f {} {} {} {}\n", path, address, file.size, file.sha1),
+        );
+    }
+    let mut content = Sha1::new();
+    for line in lines.values() {
+        content.update(line.as_bytes());
+    }
+
+    Ok(VolumeHash {
+        content_sha1: content.digest().to_string(),
+        volume_sha1: volume_sha1(fs)?,
+        directories,
+        files: files.into_values().collect(),
+    })
+}
@@ -0,0 +1,59 @@
+//! Образы жестких дисков
+//!
+//! HDI - это образ диска с заголовком в один блок (паспорт IDE диска),
+//! смещения считаем от конца заголовка. Раздел ищем по таблице разделов
+//! контроллера (см. `bkhdd`), данные АльтПро читаем инвертированными.
+
+use bkhdd::{PartitionLocation, HDI};
+use tracing::{info, warn};
+
+use crate::{Fs, FsError, BLOCK_SIZE};
+
+/// Где лежит раздел `n` HDD образа `path` (для `set_partition` всех ФС)
+pub(crate) fn partition_location(path: &str, n: usize) -> Result<PartitionLocation, FsError> {
+    let mut hdi = HDI::new(path);
+    hdi.try_open()?;
+    let loc = hdi.partition_location(n).ok_or_else(|| {
+        // номер мимо - подскажем, сколько разделов нашлось
+        warn!(
+            controller = ?hdi.controller(),
+            "HDD image has {} partitions, numbered from 0",
+            hdi.partitions().len()
+        );
+        FsError::NoPartition(n)
+    })?;
+    info!(
+        partition = n,
+        controller = ?hdi.controller(),
+        offset = loc.offset,
+        size = loc.size,
+        "HDD partition"
+    );
+    Ok(loc)
+}
+
+impl Fs {
+    /// Skip HDI header if image has one (used on next open).
+    /// Offset set before stays relative to disk data.
+    pub fn skip_hdi_header(&mut self) -> Result<bool, FsError> {
+        if !HDI::probe(&self.file_path)? {
+            return Ok(false);
+        }
+        self.offset += BLOCK_SIZE as u64;
+        // без размера по смещению не открыть, берем все до конца образа
+        if self.size == 0 {
+            let len = std::fs::metadata(&self.file_path)?.len();
+            self.size = len.saturating_sub(self.offset);
+        }
+        Ok(true)
+    }
+
+    /// Open partition `n` of HDD image (raw or HDI) on next open
+    pub fn set_partition(&mut self, n: usize) -> Result<(), FsError> {
+        let loc = partition_location(&self.file_path, n)?;
+        self.set_offset_blocks(loc.offset);
+        self.set_size_blocks(loc.size);
+        self.set_inverted(loc.inverted);
+        Ok(())
+    }
+}
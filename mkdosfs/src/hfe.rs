@@ -0,0 +1,203 @@
+//! HFE (HxC, эмуляторы дисковода Gotek): дорожки как поток MFM ячеек
+//!
+//! Заголовок `HXCPICFE` (версия 1): число дорожек и сторон, кодирование и
+//! смещение таблицы дорожек в блоках по 512 байт. В таблице для каждой
+//! дорожки смещение (в блоках) и длина (байты обеих сторон). Данные
+//! дорожки идут блоками: 256 байт стороны 0, 256 байт стороны 1, биты в
+//! байте от младшего к старшему.
+//!
+//! Контроллер дисковода БК пишет обычный IBM MFM: синхромаркер A1 с
+//! пропущенным тактом (`0x4489`), за ним заголовок сектора (`FE`, цилиндр,
+//! сторона, номер, код размера, CRC) или данные (`FB`/`F8`, данные, CRC).
+//! Секторы берутся по адресам из заголовков. FM и HFE v3 не поддерживаются.
+
+use tracing::warn;
+
+use crate::{
+    container::{Bytes, SectorMap},
+    FsError,
+};
+
+pub(crate) const SIGNATURE: &[u8] = b"HXCPICFE";
+const HEADER_SIZE: usize = 26;
+const BLOCK: usize = 512;
+/// Track encoding: ISO/IBM MFM
+const ISOIBM_MFM: u8 = 0;
+/// A1 with missing clock bit
+const SYNC: u16 = 0x4489;
+const ID_MARK: u8 = 0xfe;
+const DATA_MARK: u8 = 0xfb;
+const DELETED_DATA_MARK: u8 = 0xf8;
+
+fn bad(what: impl std::fmt::Display) -> FsError {
+    FsError::Container(format!("bad HFE {}", what))
+}
+
+/// CRC-CCITT как у контроллера: от синхромаркеров до конца поля
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Поток MFM ячеек одной стороны дорожки
+struct Cells {
+    bits: Vec<bool>,
+    pos: usize,
+}
+
+impl Cells {
+    fn new(raw: &[u8]) -> Self {
+        let bits = raw
+            .iter()
+            .flat_map(|&byte| (0..8).map(move |bit| byte & (1 << bit) != 0))
+            .collect();
+        Self { bits, pos: 0 }
+    }
+
+    /// До следующего синхромаркера (`false` - дорожка кончилась)
+    fn find_sync(&mut self) -> bool {
+        let mut window = 0u16;
+        while let Some(&bit) = self.bits.get(self.pos) {
+            window = (window << 1) | bit as u16;
+            self.pos += 1;
+            if window == SYNC {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Следующие 16 ячеек - еще один синхромаркер
+    fn at_sync(&self) -> bool {
+        self.bits
+            .get(self.pos..self.pos + 16)
+            .is_some_and(|cells| cells.iter().fold(0u16, |w, &b| (w << 1) | b as u16) == SYNC)
+    }
+
+    /// Байт из 16 ячеек: такт, данные, такт, данные...
+    fn byte(&mut self) -> Option<u8> {
+        let cells = self.bits.get(self.pos..self.pos + 16)?;
+        self.pos += 16;
+        Some(
+            cells
+                .chunks(2)
+                .fold(0u8, |byte, cell| (byte << 1) | cell[1] as u8),
+        )
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<Vec<u8>> {
+        (0..len).map(|_| self.byte()).collect()
+    }
+}
+
+/// Счетчики сбойных полей для одного предупреждения в конце
+#[derive(Debug, Default)]
+struct Errors {
+    id: usize,
+    data: usize,
+}
+
+/// Секторы одной стороны дорожки
+fn decode_side(raw: &[u8], sectors: &mut SectorMap, errors: &mut Errors) {
+    let mut cells = Cells::new(raw);
+    // последний заголовок, ждущий своих данных
+    let mut id: Option<[u8; 4]> = None;
+    while cells.find_sync() {
+        // синхромаркеров обычно три, CRC считается по всем
+        let mut syncs = 1;
+        while cells.at_sync() {
+            cells.pos += 16;
+            syncs += 1;
+        }
+        let Some(mark) = cells.byte() else {
+            break;
+        };
+        let mut field = vec![0xa1; syncs];
+        field.push(mark);
+        match mark {
+            ID_MARK => {
+                let Some(header) = cells.bytes(6) else {
+                    break;
+                };
+                field.extend_from_slice(&header);
+                if crc16(&field) == 0 {
+                    id = Some([header[0], header[1], header[2], header[3]]);
+                } else {
+                    errors.id += 1;
+                    id = None;
+                }
+            }
+            DATA_MARK | DELETED_DATA_MARK => {
+                let Some([cylinder, head, number, size_code]) = id.take() else {
+                    continue;
+                };
+                let size = 128usize << (size_code & 7);
+                let Some(data) = cells.bytes(size + 2) else {
+                    break;
+                };
+                field.extend_from_slice(&data);
+                if crc16(&field) != 0 {
+                    errors.data += 1;
+                }
+                sectors.insert(cylinder, head, number, data[..size].to_vec());
+            }
+            _ => {}
+        }
+    }
+}
+
+pub(crate) fn decode(data: &[u8]) -> Result<Vec<u8>, FsError> {
+    let mut header = Bytes::new(data.get(..HEADER_SIZE).ok_or_else(|| bad("header"))?);
+    if header.take(SIGNATURE.len())? != SIGNATURE {
+        return Err(bad("signature (only HFE v1 is supported)"));
+    }
+    let _revision = header.byte()?;
+    let tracks = header.byte()? as usize;
+    let sides = header.byte()? as usize;
+    let encoding = header.byte()?;
+    if encoding != ISOIBM_MFM {
+        return Err(bad(format!(
+            "track encoding {} (only MFM is supported)",
+            encoding
+        )));
+    }
+    // скорость, обороты, интерфейс, не используется
+    header.take(6)?;
+    let list = header.word()? as usize * BLOCK;
+
+    let mut sectors = SectorMap::default();
+    let mut errors = Errors::default();
+    let mut entries = Bytes::new(data.get(list..).ok_or_else(|| bad("track list"))?);
+    for track in 0..tracks {
+        let offset = entries.word()? as usize * BLOCK;
+        let len = entries.word()? as usize;
+        let raw = data
+            .get(offset..offset + len)
+            .ok_or_else(|| bad(format!("track {} is beyond end of file", track)))?;
+        for side in 0..sides.min(2) {
+            let side_raw = raw
+                .chunks(BLOCK)
+                .flat_map(|block| block.iter().skip(side * BLOCK / 2).take(BLOCK / 2))
+                .copied()
+                .collect::<Vec<_>>();
+            decode_side(&side_raw, &mut sectors, &mut errors);
+        }
+    }
+    if errors.id > 0 || errors.data > 0 {
+        warn!(
+            "Bad CRC in {} sector headers (skipped) and {} sectors",
+            errors.id, errors.data
+        );
+    }
+    sectors.into_image()
+}
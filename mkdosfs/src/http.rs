@@ -0,0 +1,339 @@
+//! HTTP сервер только для чтения: каталог тома как страницы и JSON
+//!
+//! Работает с любым `BkFileSystem`, так что архив дисков можно выложить,
+//! ничего не монтируя. `GET /DIR/` - список файлов, `GET /DIR/FILE` -
+//! сам файл, `GET /api/DIR/...` - то же в JSON. Запросы обслуживаются по
+//! очереди, соединение закрывается после ответа. Если образ поменяли,
+//! том перечитывается перед следующим запросом.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bkfs::{BkFileSystem, Entry, ROOT_INODE};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+/// Size of file chunks read from volume and sent to client
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Max size of request head, longer requests are refused
+const MAX_REQUEST: usize = 8 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ответ с ошибкой или содержимое
+enum Reply {
+    Error(u16, &'static str),
+    Html(String),
+    Json(String),
+    File { inode: u64, size: u64 },
+}
+
+/// Метод и путь из первой строки запроса, заголовки пропускаем
+fn read_request(stream: &TcpStream) -> io::Result<Option<(String, String)>> {
+    let mut reader = BufReader::new(Read::take(stream, MAX_REQUEST as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let request = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => Some((method.to_string(), target.to_string())),
+        _ => None,
+    };
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(request)
+}
+
+/// `%D0%90` -> `А`, `None` если получилась не UTF-8 строка
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Ссылка на запись по частям пути
+fn href(prefix: &str, path: &[&str], name: &str) -> String {
+    let mut href = prefix.to_string();
+    for part in path.iter().chain(std::iter::once(&name)) {
+        href.push('/');
+        href.push_str(&percent_encode(part));
+    }
+    href
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Read only HTTP frontend of a volume, see `serve()`
+struct Server<'a, B> {
+    fs: &'a mut B,
+    title: &'a str,
+}
+
+impl<B: BkFileSystem> Server<'_, B> {
+    fn mtime(&self, entry: &Entry<'_>) -> SystemTime {
+        entry.mtime.unwrap_or_else(|| self.fs.last_modified())
+    }
+
+    /// Запись по частям пути, `None` в ответе - корень
+    fn resolve(&self, path: &[&str]) -> Result<Option<Entry<'_>>, Reply> {
+        let mut found = None;
+        let mut inode = ROOT_INODE;
+        for name in path {
+            let entry = self
+                .fs
+                .lookup(inode, name)
+                .ok_or(Reply::Error(404, "Not Found"))?;
+            inode = entry.inode;
+            found = Some(entry);
+        }
+        Ok(found)
+    }
+
+    fn entries(&self, inode: u64) -> Result<Vec<Entry<'_>>, Reply> {
+        let mut entries = self
+            .fs
+            .list(inode)
+            .map_err(|_| Reply::Error(500, "Internal Server Error"))?;
+        entries.sort_by(|a, b| b.is_dir().cmp(&a.is_dir()).then(a.name.cmp(b.name)));
+        Ok(entries)
+    }
+
+    fn html(&self, path: &[&str]) -> Result<Reply, Reply> {
+        let entry = self.resolve(path)?;
+        if let Some(entry) = entry.filter(|entry| !entry.is_dir()) {
+            return Ok(Reply::File {
+                inode: entry.inode,
+                size: entry.size,
+            });
+        }
+        let inode = entry.map_or(ROOT_INODE, |entry| entry.inode);
+        let dir = html_escape(&format!("/{}", path.join("/")));
+        let title = html_escape(self.title);
+        let mut page = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}: {dir}</title></head>\n\
+             <body><h1>{title}: {dir}</h1>\n<table>\n\
+             <tr><th align=\"left\">Name</th><th align=\"right\">Size</th><th>Date</th></tr>\n",
+        );
+        if let Some((_, parent)) = path.split_last() {
+            let _ = writeln!(
+                page,
+                "<tr><td><a href=\"{}/\">../</a></td></tr>",
+                href("", parent, "").trim_end_matches('/')
+            );
+        }
+        for entry in self.entries(inode)? {
+            let _ =
+                writeln!(
+                page,
+                "<tr><td><a href=\"{}{}\">{}{}</a></td><td align=\"right\">{}</td><td>{}</td></tr>",
+                href("", path, entry.name),
+                if entry.is_dir() { "/" } else { "" },
+                html_escape(entry.name),
+                if entry.is_dir() { "/" } else { "" },
+                if entry.is_dir() { String::new() } else { entry.size.to_string() },
+                OffsetDateTime::from(self.mtime(&entry)).date(),
+            );
+        }
+        let stats = self.fs.stats();
+        let _ = write!(
+            page,
+            "</table>\n<p>Blocks: {} free of {}</p></body></html>\n",
+            stats.free, stats.blocks
+        );
+        Ok(Reply::Html(page))
+    }
+
+    fn json_entry(&self, entry: &Entry<'_>) -> String {
+        format!(
+            "{{\"name\":\"{}\",\"kind\":\"{}\",\"size\":{},\"blocks\":{},\"mtime\":{}}}",
+            json_escape(entry.name),
+            if entry.is_dir() { "dir" } else { "file" },
+            entry.size,
+            entry.blocks,
+            unix_time(self.mtime(entry)),
+        )
+    }
+
+    fn json(&self, path: &[&str]) -> Result<Reply, Reply> {
+        let entry = self.resolve(path)?;
+        if let Some(entry) = entry.filter(|entry| !entry.is_dir()) {
+            return Ok(Reply::Json(self.json_entry(&entry)));
+        }
+        let inode = entry.map_or(ROOT_INODE, |entry| entry.inode);
+        let entries = self
+            .entries(inode)?
+            .iter()
+            .map(|entry| self.json_entry(entry))
+            .collect::<Vec<_>>();
+        let stats = self.fs.stats();
+        Ok(Reply::Json(format!(
+            "{{\"path\":\"/{}\",\"volume\":{{\"blocks\":{},\"free\":{}}},\"entries\":[{}]}}",
+            json_escape(&path.join("/")),
+            stats.blocks,
+            stats.free,
+            entries.join(",")
+        )))
+    }
+
+    fn route(&mut self, method: &str, target: &str) -> Reply {
+        if method != "GET" && method != "HEAD" {
+            return Reply::Error(405, "Method Not Allowed");
+        }
+        let target = target.split(['?', '#']).next().unwrap_or_default();
+        let target = match percent_decode(target) {
+            Some(target) => target,
+            None => return Reply::Error(400, "Bad Request"),
+        };
+        if self.fs.is_modified() {
+            if let Err(e) = self.fs.refresh() {
+                warn!("Can't reopen image: {}", e);
+            }
+        }
+        if self.fs.is_degraded() {
+            return Reply::Error(503, "Service Unavailable");
+        }
+        let path = target
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+        let result = match path.split_first() {
+            Some((&"api", path)) => self.json(path),
+            _ => self.html(&path),
+        };
+        result.unwrap_or_else(|reply| reply)
+    }
+
+    fn send(&self, stream: &mut TcpStream, reply: Reply, head_only: bool) -> io::Result<()> {
+        let (status, content_type, body, file) = match reply {
+            Reply::Error(code, text) => (code, "text/plain", text.to_string(), None),
+            Reply::Html(page) => (200, "text/html; charset=utf-8", page, None),
+            Reply::Json(json) => (200, "application/json", json, None),
+            Reply::File { inode, size } => (
+                200,
+                "application/octet-stream",
+                String::new(),
+                Some((inode, size)),
+            ),
+        };
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let size = file.map_or(body.len() as u64, |(_, size)| size);
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, reason, content_type, size
+        )?;
+        if head_only {
+            return Ok(());
+        }
+        let (inode, size) = match file {
+            Some(file) => file,
+            None => return stream.write_all(body.as_bytes()),
+        };
+        let mut offset = 0;
+        while offset < size {
+            let chunk = std::cmp::min(CHUNK_SIZE as u64, size - offset) as usize;
+            // длина уже отправлена: на ошибке остается только оборвать ответ
+            let data = self
+                .fs
+                .read(inode, offset, chunk)
+                .map_err(io::Error::other)?;
+            if data.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            stream.write_all(&data)?;
+            offset += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let (method, target) = match read_request(&stream)? {
+            Some(request) => request,
+            None => return self.send(&mut stream, Reply::Error(400, "Bad Request"), false),
+        };
+        let reply = self.route(&method, &target);
+        if let Reply::Error(code, _) = reply {
+            info!(%method, %target, code, "HTTP request");
+        }
+        self.send(&mut stream, reply, method == "HEAD")?;
+        stream.flush()
+    }
+}
+
+/// Serve catalog and files of `fs` over HTTP to clients of `listener`
+/// one by one, `title` is shown in pages. Returns only on error of `listener`
+pub fn serve<B: BkFileSystem>(listener: &TcpListener, fs: &mut B, title: &str) -> io::Result<()> {
+    let mut server = Server { fs, title };
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        // ошибка клиента сервер не останавливает
+        if let Err(e) = server.handle(stream) {
+            warn!(%peer, "HTTP client error: {}", e);
+        }
+    }
+    Ok(())
+}
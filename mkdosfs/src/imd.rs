@@ -0,0 +1,92 @@
+//! ImageDisk (`.imd`): заголовок-комментарий до 0x1A, дальше дорожки
+//!
+//! Дорожка: режим, цилиндр, сторона (бит 7 - есть карта цилиндров,
+//! бит 6 - карта сторон), число секторов, код размера (0xFF - таблица
+//! размеров), карта номеров секторов и сами секторы. Перед каждым
+//! сектором байт типа: 0 - данных нет, нечетный - данные как есть,
+//! четный - сектор заполнен одним байтом. Типы 3..8 - удаленные и
+//! сбойные секторы, данные у них тоже берем.
+
+use tracing::warn;
+
+use crate::{
+    container::{Bytes, SectorMap},
+    FsError,
+};
+
+pub(crate) const SIGNATURE: &[u8] = b"IMD ";
+/// End of comment in file header
+const COMMENT_END: u8 = 0x1a;
+
+fn sector_size(code: u8) -> Result<usize, FsError> {
+    match code {
+        0..=6 => Ok(128 << code),
+        _ => Err(FsError::Container(format!(
+            "bad IMD sector size code {}",
+            code
+        ))),
+    }
+}
+
+pub(crate) fn decode(data: &[u8]) -> Result<Vec<u8>, FsError> {
+    let comment = data
+        .iter()
+        .position(|&b| b == COMMENT_END)
+        .ok_or_else(|| FsError::Container("IMD header is not terminated".into()))?;
+    let mut input = Bytes::new(&data[comment + 1..]);
+    let mut sectors = SectorMap::default();
+    let mut bad = 0;
+    while !input.is_empty() {
+        let _mode = input.byte()?;
+        let cylinder = input.byte()?;
+        let head = input.byte()?;
+        let count = input.byte()? as usize;
+        let size_code = input.byte()?;
+        let numbers = input.take(count)?;
+        let cylinders = match head & 0x80 {
+            0 => None,
+            _ => Some(input.take(count)?),
+        };
+        let heads = match head & 0x40 {
+            0 => None,
+            _ => Some(input.take(count)?),
+        };
+        let sizes = match size_code {
+            0xff => (0..count)
+                .map(|_| input.word().map(usize::from))
+                .collect::<Result<Vec<_>, _>>()?,
+            code => vec![sector_size(code)?; count],
+        };
+        for (idx, &size) in sizes.iter().enumerate() {
+            let data = match input.byte()? {
+                0 => continue,
+                kind @ 1..=8 => {
+                    if kind >= 5 {
+                        bad += 1;
+                    }
+                    if kind % 2 == 1 {
+                        input.take(size)?.to_vec()
+                    } else {
+                        vec![input.byte()?; size]
+                    }
+                }
+                kind => {
+                    return Err(FsError::Container(format!(
+                        "bad IMD sector record type {}",
+                        kind
+                    )))
+                }
+            };
+            sectors.insert(
+                cylinders.map_or(cylinder, |c| c[idx]),
+                heads.map_or(head & 0x0f, |h| h[idx]),
+                numbers[idx],
+                data,
+            );
+        }
+    }
+    if bad > 0 {
+        warn!("{} sectors were read with errors", bad);
+    }
+    sectors.into_image()
+}
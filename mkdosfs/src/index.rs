@@ -0,0 +1,188 @@
+//! Индексы каталога, чтобы не перебирать все записи на каждый lookup
+//!
+//! Хранят позиции в `iter_all()` (сначала `entries`, за ними `nested`)
+//! и пересобираются после чтения каталога и после каждого его изменения
+//! (`commit()`). При одинаковых именах (удаленный и живой файл) находится
+//! живая запись, а среди удаленных - первая по порядку в каталоге.
+//!
+//! MK-DOS не запрещает два живых файла с одним именем в каталоге. Первый
+//! остается под своим именем, остальные получают псевдонимы с суффиксом
+//! `~1`, `~2`, ... (не занятые настоящими именами), по ним и находятся.
+//! Удаленные записи отдельно собраны для каталога `.deleted`, повторы
+//! имен там тоже с суффиксами.
+//!
+//! Для `LookupPolicy::CaseInsensitive` есть еще индекс по свернутым именам
+//! (`fold_name()`). Если свернутые имена совпали, находится живая запись,
+//! а из них - первая в каталоге.
+
+use std::collections::HashMap;
+
+use crate::{DirEntry, Fs};
+
+/// Имя для сравнения без учета регистра и пробелов в конце
+pub(crate) fn fold_name(name: &str) -> String {
+    name.trim_end_matches(' ').to_lowercase()
+}
+
+/// Имя `name` с суффиксом `~n`
+fn numbered(name: &str, n: usize) -> String {
+    format!("{}~{}", name, n)
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EntryIndex {
+    by_inode: HashMap<u64, usize>,
+    /// (позиция, удалена ли запись)
+    by_name: HashMap<(u64, String), (usize, bool)>,
+    /// по `fold_name()` имени (с псевдонимами): (позиция, удалена ли запись)
+    by_folded_name: HashMap<(u64, String), (usize, bool)>,
+    by_parent: HashMap<u64, Vec<usize>>,
+    /// псевдонимы живых записей с повторяющимися именами, по иноду
+    aliases: HashMap<u64, String>,
+    /// удаленные записи: (позиция, уникальное имя)
+    deleted: Vec<(usize, String)>,
+    deleted_by_name: HashMap<String, usize>,
+    deleted_by_inode: HashMap<u64, usize>,
+}
+
+impl EntryIndex {
+    fn build<'a>(entries: impl Iterator<Item = &'a DirEntry>) -> Self {
+        let mut index = Self::default();
+        let mut duplicates = Vec::new();
+        for (i, e) in entries.enumerate() {
+            index.by_inode.entry(e.inode).or_insert(i);
+            let named = index
+                .by_name
+                .entry((e.parent_inode, e.name.clone()))
+                .or_insert((i, e.is_deleted));
+            // дыра, оставшаяся от перенесенного файла, не должна его заслонять
+            if named.1 && !e.is_deleted {
+                *named = (i, false);
+            } else if !e.is_deleted && named.0 != i {
+                duplicates.push((i, e));
+            }
+            index.by_parent.entry(e.parent_inode).or_default().push(i);
+            if e.is_deleted {
+                index.add_deleted(i, e);
+            }
+        }
+        // псевдонимы после всех настоящих имен, чтобы не заслонить их
+        for (i, e) in duplicates {
+            let alias = (1..)
+                .map(|n| numbered(&e.name, n))
+                .find(|alias| !index.by_name.contains_key(&(e.parent_inode, alias.clone())))
+                .unwrap_or_default();
+            index
+                .by_name
+                .insert((e.parent_inode, alias.clone()), (i, false));
+            index.aliases.insert(e.inode, alias);
+        }
+        for ((parent, name), &(i, deleted)) in index.by_name.iter() {
+            index
+                .by_folded_name
+                .entry((*parent, fold_name(name)))
+                .and_modify(|found| {
+                    // by_name перебирается в случайном порядке
+                    if (deleted, i) < (found.1, found.0) {
+                        *found = (i, deleted);
+                    }
+                })
+                .or_insert((i, deleted));
+        }
+        index
+    }
+
+    fn add_deleted(&mut self, idx: usize, e: &DirEntry) {
+        let mut name = e.name.clone();
+        let mut n = 0;
+        while self.deleted_by_name.contains_key(&name) {
+            n += 1;
+            name = numbered(&e.name, n);
+        }
+        self.deleted_by_name
+            .insert(name.clone(), self.deleted.len());
+        self.deleted_by_inode.insert(e.inode, self.deleted.len());
+        self.deleted.push((idx, name));
+    }
+}
+
+impl<R> Fs<R> {
+    pub(crate) fn rebuild_index(&mut self) {
+        self.index = EntryIndex::build(self.entries.iter().chain(self.nested.iter()));
+    }
+
+    /// Запись по позиции в `iter_all()`
+    fn entry_at(&self, idx: usize) -> Option<&DirEntry> {
+        match idx.checked_sub(self.entries.len()) {
+            None => self.entries.get(idx),
+            Some(idx) => self.nested.get(idx),
+        }
+    }
+
+    pub(crate) fn indexed_by_inode(&self, inode: u64) -> Option<&DirEntry> {
+        let idx = *self.index.by_inode.get(&inode)?;
+        self.entry_at(idx).filter(|e| e.inode == inode)
+    }
+
+    pub(crate) fn indexed_by_name(&self, name: &str, parent_inode: u64) -> Option<&DirEntry> {
+        let (idx, _) = *self.index.by_name.get(&(parent_inode, name.to_string()))?;
+        self.entry_at(idx)
+            .filter(|e| e.parent_inode == parent_inode && self.indexed_name(e) == name)
+    }
+
+    pub(crate) fn indexed_by_folded_name(
+        &self,
+        name: &str,
+        parent_inode: u64,
+    ) -> Option<&DirEntry> {
+        let (idx, _) = *self
+            .index
+            .by_folded_name
+            .get(&(parent_inode, fold_name(name)))?;
+        self.entry_at(idx)
+            .filter(|e| e.parent_inode == parent_inode)
+    }
+
+    /// Имя записи в каталоге: псевдоним для повторяющегося имени
+    pub(crate) fn indexed_name<'a>(&'a self, e: &'a DirEntry) -> &'a str {
+        match self.index.aliases.get(&e.inode) {
+            Some(alias) if !e.is_deleted => alias,
+            _ => &e.name,
+        }
+    }
+
+    /// Удаленные записи с уникальными именами, в порядке каталога
+    pub(crate) fn indexed_deleted(&self) -> impl Iterator<Item = (&str, &DirEntry)> {
+        self.index
+            .deleted
+            .iter()
+            .filter_map(move |(idx, name)| Some((name.as_str(), self.entry_at(*idx)?)))
+    }
+
+    pub(crate) fn indexed_deleted_by_name(&self, name: &str) -> Option<(&str, &DirEntry)> {
+        let (idx, name) = self
+            .index
+            .deleted
+            .get(*self.index.deleted_by_name.get(name)?)?;
+        Some((name.as_str(), self.entry_at(*idx)?))
+    }
+
+    pub(crate) fn indexed_deleted_by_inode(&self, inode: u64) -> Option<(&str, &DirEntry)> {
+        let (idx, name) = self
+            .index
+            .deleted
+            .get(*self.index.deleted_by_inode.get(&inode)?)?;
+        Some((name.as_str(), self.entry_at(*idx)?))
+    }
+
+    pub(crate) fn indexed_children(&self, parent_inode: u64) -> impl Iterator<Item = &DirEntry> {
+        self.index
+            .by_parent
+            .get(&parent_inode)
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(move |&idx| self.entry_at(idx))
+            .filter(move |e| e.parent_inode == parent_inode)
+    }
+}
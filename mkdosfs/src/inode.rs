@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::DirEntry;
+
+/// Пространство инодов (виртуальных, в образе их нет)
+///
+/// | диапазон               | что                                          |
+/// |------------------------|----------------------------------------------|
+/// | `1`                    | корень                                       |
+/// | `2..=256`              | каталоги, `1 + номер каталога` (u8)          |
+/// | `257..=1000`           | каталоги с повторяющимся номером (коллизии)  |
+/// | `999`                  | виртуальный каталог `.deleted`               |
+/// | `1001..`               | файлы, по порядку записей в каталоге         |
+///
+/// Номер каталога в MK-DOS хранится в байте статуса записи, файлы ссылаются
+/// на него через `dir_no`, поэтому инод каталога вычисляется, а не выдается
+/// по счетчику. Если в образе два каталога с одним номером, второй получает
+/// инод из резервного диапазона, а файлы остаются в первом.
+///
+/// Иноды файлов выдаются по `InodeStrategy`. `Sequential` считает их
+/// заново на каждом открытии: после изменения образа снаружи (или сжатия)
+/// все иноды за измененной записью сдвигаются. `Stable` берет инод из номера
+/// слота в каталоге, а при перечитывании файл с тем же номером каталога,
+/// именем, началом и размером получает прежний инод. Инод, который уже был у
+/// другого файла, новому файлу не достается, так что открытые файлы
+/// переживают перечитывание, а дескрипторы подмененного файла (то же имя,
+/// но другие начало или размер) протухают вместе с его старым инодом.
+///
+/// Прежние иноды берутся только из предыдущего открытия, а вот множество
+/// выданных файлам инодов за время работы только растет: инод исчезнувшего
+/// файла больше никому не выдается, чтобы его дескрипторы так и оставались
+/// недействительными.
+pub const ROOT_INODE: u64 = 1;
+/// Первый инод каталога (номер каталога 1)
+pub const DIR_INODE_FIRST: u64 = 2;
+/// Последний инод каталога, который можно вычислить из номера (u8)
+pub const DIR_INODE_LAST: u64 = ROOT_INODE + u8::MAX as u64;
+/// Последний инод резервного диапазона каталогов
+pub const DIR_INODE_RESERVED_LAST: u64 = 1000;
+/// Первый инод файла
+pub const FILE_INODE_FIRST: u64 = DIR_INODE_RESERVED_LAST + 1;
+/// Inode of virtual directory with deleted files (see `DELETED_DIR_NAME`)
+pub const DELETED_DIR_INODE: u64 = 999;
+/// Name of virtual directory with deleted files in the root
+pub const DELETED_DIR_NAME: &str = ".deleted";
+
+/// Inode for MK-DOS directory number `dir_no` (0 is the root)
+pub fn dir_inode(dir_no: u8) -> u64 {
+    ROOT_INODE + dir_no as u64
+}
+
+/// How file inodes are assigned, see [`crate::Fs::set_inode_strategy()`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum InodeStrategy {
+    /// по порядку записей, заново на каждом открытии
+    #[default]
+    Sequential,
+    /// из номера слота каталога и сохраняются при перечитывании образа
+    Stable,
+}
+
+/// Ключ файла между перечитываниями: номер каталога из записи (до переноса
+/// сирот в корень), имя, удален ли
+type InodeKey = (u8, String, bool);
+
+/// Начало и размер файла: если они другие, файл подменили
+type Extent = (u64, u32);
+
+/// Иноды прошлых открытий для `InodeStrategy::Stable`
+#[derive(Debug, Default, Clone)]
+struct InodeMemory {
+    /// иноды файлов с одним ключом в порядке каталога
+    by_key: HashMap<InodeKey, VecDeque<(u64, Extent)>>,
+    /// все когда-либо выданные иноды файлов
+    issued: HashSet<u64>,
+}
+
+/// Выдает иноды для записей каталога и следит, чтобы они не пересекались
+#[derive(Debug, Clone)]
+pub struct InodeAllocator {
+    strategy: InodeStrategy,
+    memory: InodeMemory,
+    used: HashSet<u64>,
+    next_reserved_dir: u64,
+    next_file: u64,
+    dirs: u64,
+    files: u64,
+    collisions: u64,
+}
+
+impl Default for InodeAllocator {
+    fn default() -> Self {
+        Self {
+            strategy: InodeStrategy::default(),
+            memory: InodeMemory::default(),
+            used: HashSet::from([ROOT_INODE, DELETED_DIR_INODE]),
+            next_reserved_dir: DIR_INODE_LAST + 1,
+            next_file: FILE_INODE_FIRST,
+            dirs: 0,
+            files: 0,
+            collisions: 0,
+        }
+    }
+}
+
+impl InodeAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_strategy(strategy: InodeStrategy) -> Self {
+        Self {
+            strategy,
+            ..Self::default()
+        }
+    }
+
+    pub fn strategy(&self) -> InodeStrategy {
+        self.strategy
+    }
+
+    /// Allocator for next read of the catalog: with `InodeStrategy::Stable`
+    /// it remembers file inodes of `entries`
+    pub(crate) fn reopened<'a>(&self, entries: impl Iterator<Item = &'a DirEntry>) -> Self {
+        let mut next = Self::with_strategy(self.strategy);
+        if self.strategy == InodeStrategy::Sequential {
+            return next;
+        }
+        // выданные иноды копятся, а ключи берутся только из прошлого открытия:
+        // старые ключи указывали бы на файлы, которых уже нет
+        next.memory.issued = self.memory.issued.clone();
+        next.memory
+            .issued
+            .extend(self.used.iter().filter(|&&inode| inode >= FILE_INODE_FIRST));
+        let mut seen: HashMap<InodeKey, VecDeque<(u64, Extent)>> = HashMap::new();
+        for e in entries.filter(|e| !e.is_dir) {
+            seen.entry(file_key(e))
+                .or_default()
+                .push_back((e.inode, extent(e)));
+        }
+        next.memory.by_key = seen;
+        next
+    }
+
+    /// Allocate inode for file `entry` in catalog slot `slot`
+    pub(crate) fn alloc_entry(&mut self, entry: &DirEntry, slot: u64) -> u64 {
+        if self.strategy == InodeStrategy::Sequential {
+            return self.alloc_file();
+        }
+        let remembered = self
+            .memory
+            .by_key
+            .get_mut(&file_key(entry))
+            .and_then(VecDeque::pop_front);
+        // подмененный файл получает новый инод, старый так и остается выданным
+        if let Some((inode, _)) = remembered.filter(|&(_, was)| was == extent(entry)) {
+            if self.used.insert(inode) {
+                self.files += 1;
+                return inode;
+            }
+        }
+        let inode = FILE_INODE_FIRST + slot;
+        if !self.memory.issued.contains(&inode) && self.used.insert(inode) {
+            self.files += 1;
+            return inode;
+        }
+        self.alloc_file()
+    }
+
+    /// Allocate inode for directory with number `dir_no`
+    ///
+    /// Returns `(inode, collided)`, where `collided` is set if computed inode was
+    /// already taken and one from reserved range was used instead.
+    /// `None` if reserved range is exhausted.
+    pub fn alloc_dir(&mut self, dir_no: u8) -> Option<(u64, bool)> {
+        let inode = dir_inode(dir_no);
+        if self.used.insert(inode) {
+            self.dirs += 1;
+            return Some((inode, false));
+        }
+        self.collisions += 1;
+        while self.next_reserved_dir <= DIR_INODE_RESERVED_LAST {
+            let inode = self.next_reserved_dir;
+            self.next_reserved_dir += 1;
+            if self.used.insert(inode) {
+                self.dirs += 1;
+                return Some((inode, true));
+            }
+        }
+        None
+    }
+
+    /// Allocate next file inode
+    pub fn alloc_file(&mut self) -> u64 {
+        loop {
+            let inode = self.next_file;
+            self.next_file += 1;
+            if !self.memory.issued.contains(&inode) && self.used.insert(inode) {
+                self.files += 1;
+                return inode;
+            }
+            self.collisions += 1;
+        }
+    }
+
+    pub fn is_used(&self, inode: u64) -> bool {
+        self.used.contains(&inode)
+    }
+
+    pub fn is_dir_inode(inode: u64) -> bool {
+        (ROOT_INODE..=DIR_INODE_RESERVED_LAST).contains(&inode)
+    }
+
+    pub fn stats(&self) -> InodeStats {
+        InodeStats {
+            dirs: self.dirs,
+            files: self.files,
+            collisions: self.collisions,
+            max_inode: self.used.iter().copied().max().unwrap_or(ROOT_INODE),
+        }
+    }
+}
+
+fn file_key(entry: &DirEntry) -> InodeKey {
+    (entry.dir_no, entry.name.clone(), entry.is_deleted)
+}
+
+fn extent(entry: &DirEntry) -> Extent {
+    (entry.start_block, entry.size)
+}
+
+/// Inode allocation summary
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InodeStats {
+    /// directories (root is not counted)
+    pub dirs: u64,
+    pub files: u64,
+    /// computed inodes which were already taken
+    pub collisions: u64,
+    pub max_inode: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(dir_no: u8, name: &str, start_block: u64, size: u32) -> DirEntry {
+        DirEntry {
+            dir_no,
+            name: name.into(),
+            start_block,
+            size,
+            ..DirEntry::new()
+        }
+    }
+
+    /// Раздает иноды записям, как чтение каталога: слот - индекс
+    fn alloc(inodes: &mut InodeAllocator, entries: &mut [DirEntry]) {
+        for (slot, e) in entries.iter_mut().enumerate() {
+            e.inode = inodes.alloc_entry(e, slot as u64);
+            // сирота переезжает в корень уже после выдачи инода
+            e.parent_inode = ROOT_INODE;
+        }
+    }
+
+    #[test]
+    fn stable_orphan_keeps_inode() {
+        let mut inodes = InodeAllocator::with_strategy(InodeStrategy::Stable);
+        let mut first = [file(0, "A", 20, 100), file(7, "ORPHAN", 21, 100)];
+        alloc(&mut inodes, &mut first);
+
+        // перед сиротой появилась новая запись
+        let mut inodes = inodes.reopened(first.iter());
+        let mut second = [
+            file(0, "NEW", 22, 100),
+            file(0, "A", 20, 100),
+            file(7, "ORPHAN", 21, 100),
+        ];
+        alloc(&mut inodes, &mut second);
+        assert_eq!(second[1].inode, first[0].inode);
+        assert_eq!(second[2].inode, first[1].inode);
+        assert!(second[0].inode != first[0].inode && second[0].inode != first[1].inode);
+
+        let mut inodes = inodes.reopened(second.iter());
+        let mut third = second.clone();
+        alloc(&mut inodes, &mut third);
+        assert_eq!(third[2].inode, first[1].inode);
+    }
+
+    #[test]
+    fn stable_replaced_file_gets_new_inode() {
+        let mut inodes = InodeAllocator::with_strategy(InodeStrategy::Stable);
+        let mut first = [file(0, "A", 20, 100), file(0, "B", 21, 100)];
+        alloc(&mut inodes, &mut first);
+
+        let mut inodes = inodes.reopened(first.iter());
+        let mut second = [file(0, "A", 20, 600), file(0, "B", 30, 100)];
+        alloc(&mut inodes, &mut second);
+        for (old, new) in first.iter().zip(second.iter()) {
+            assert_ne!(old.inode, new.inode, "{}", new.name);
+            assert!(!first.iter().any(|e| e.inode == new.inode));
+        }
+    }
+
+    #[test]
+    fn stable_rereads_forget_gone_files() {
+        let mut inodes = InodeAllocator::with_strategy(InodeStrategy::Stable);
+        let mut entries = [file(0, "A", 20, 100), file(0, "B", 21, 100)];
+        alloc(&mut inodes, &mut entries);
+        let a = entries[0].inode;
+        for n in 0..10u64 {
+            inodes = inodes.reopened(entries.iter());
+            // B каждый раз подменяют: другое начало
+            entries[1] = file(0, "B", 30 + n, 100);
+            alloc(&mut inodes, &mut entries);
+            assert_eq!(entries[0].inode, a);
+            assert_eq!(inodes.memory.by_key.len(), 2);
+            assert_eq!(inodes.memory.issued.len(), 2 + n as usize);
+        }
+        // B удален: ключ забыт, но его иноды новым файлам не достаются
+        let gone = entries[1].inode;
+        inodes = inodes.reopened(entries.iter());
+        let mut last = [file(0, "A", 20, 100), file(0, "C", 40, 100)];
+        alloc(&mut inodes, &mut last);
+        let mut inodes = inodes.reopened(last.iter());
+        let mut after = [file(0, "A", 20, 100), file(0, "B", 50, 100)];
+        alloc(&mut inodes, &mut after);
+        assert!(!inodes.memory.by_key.keys().any(|k| k.1 == "B"));
+        assert_eq!(after[0].inode, a);
+        assert_ne!(after[1].inode, gone);
+        assert_ne!(last[1].inode, gone);
+    }
+
+    #[test]
+    fn sequential_renumbers() {
+        let mut inodes = InodeAllocator::new();
+        let mut first = [file(0, "A", 20, 100)];
+        alloc(&mut inodes, &mut first);
+        let mut inodes = inodes.reopened(first.iter());
+        let mut second = [file(0, "NEW", 21, 100), file(0, "A", 20, 100)];
+        alloc(&mut inodes, &mut second);
+        assert_eq!(second[0].inode, first[0].inode);
+    }
+}
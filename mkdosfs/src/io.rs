@@ -1,71 +1,586 @@
 use std::{
-    fs::{self, File},
-    io::{Read, Seek, SeekFrom},
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
 };
 
-pub enum Reader {
-    File(File),
-    Inverted(BinInvertedReader<File>),
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+#[cfg(feature = "async")]
+use futures_io::{AsyncRead, AsyncSeek};
+
+/// Образ диска целиком в памяти, общий с `bkhdd`
+pub use bkhdd::io::MemImage;
+
+use crate::{overlay::Overlay, GeometryMapper};
+
+/// По чему замечаем, что образ изменился: время изменения, инод и размер.
+/// Путь и открытый дескриптор сравниваем по иноду: образ, записанный во
+/// временный файл и переименованный поверх, виден только по пути
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    pub(crate) modified: SystemTime,
+    ino: u64,
+    len: u64,
 }
 
-impl Reader {
-    pub fn new(reader: File) -> Self {
-        Self::File(reader)
+impl FileStamp {
+    pub(crate) fn new(meta: &std::fs::Metadata) -> Option<Self> {
+        #[cfg(unix)]
+        let ino = std::os::unix::fs::MetadataExt::ino(meta);
+        #[cfg(not(unix))]
+        let ino = 0;
+        Some(Self {
+            modified: meta.modified().ok()?,
+            ino,
+            len: meta.len(),
+        })
     }
+}
 
-    pub fn inverted(reader: File) -> Self {
-        let bir = BinInvertedReader::new(reader);
-        Self::Inverted(bir)
-    }
+/// Доступ к образу для `Fs` поверх любого `Read + Seek`
+///
+/// Позиционные `read_at`/`write_all_at` работают по `&self`: поток данных
+/// закрыт мьютексом (seek + read под блокировкой), а если образ - обычный
+/// файл (`ReaderBuilder::build_file`), то идут через pread/pwrite без
+/// блокировки. Преобразования байтов (инверсия, перестановка в словах)
+/// и окно в образе задаются при открытии, см. `Reader::builder()`.
+/// Пересчет блоков (`GeometryMapper`) применяется к позициям внутри окна.
+pub struct Reader<R = File> {
+    inner: Mutex<R>,
+    /// тот же образ, если это файл: pread/pwrite, время изменения, sync
+    file: Option<File>,
+    /// в порядке применения при чтении
+    transforms: Vec<Transform>,
+    /// начало окна в образе
+    start: u64,
+    /// размер окна, `None` - до конца образа
+    len: Option<u64>,
+    /// пересчет логических блоков в блоки образа
+    geometry: Option<GeometryMapper>,
+    /// записи идут сюда, а не в образ
+    overlay: Option<Arc<Overlay>>,
+}
+
+/// Byte transform between image and data, each one is its own inverse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// every byte inverted (HDD images)
+    Invert,
+    /// bytes in every 16-bit word swapped (dumps of some HDD controllers)
+    SwapWords,
+}
 
-    pub fn into_inner(self) -> File {
+impl Transform {
+    fn apply(self, buf: &mut [u8]) {
         match self {
-            Self::File(h) => h,
-            Self::Inverted(h) => h.into_inner(),
+            Self::Invert => buf.iter_mut().for_each(|b| *b = !*b),
+            Self::SwapWords => swap_words(buf),
         }
     }
+}
 
-    pub fn metadata(&self) -> std::io::Result<fs::Metadata> {
-        match self {
-            Self::File(h) => h.metadata(),
-            Self::Inverted(h) => h.as_ref().metadata(),
+/// Stack of transforms and window for `Reader`, e.g.
+/// `Reader::builder().invert().swap_words().window(offset, size).build(file)`
+#[derive(Debug, Default, Clone)]
+pub struct ReaderBuilder {
+    transforms: Vec<Transform>,
+    start: u64,
+    len: Option<u64>,
+    geometry: Option<GeometryMapper>,
+    overlay: Option<Arc<Overlay>>,
+}
+
+impl ReaderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builder with transforms of image settings (inverted, swapped)
+    pub(crate) fn with_flags(inverted: bool, swapped: bool) -> Self {
+        let builder = Self::new();
+        let builder = if inverted { builder.invert() } else { builder };
+        if swapped {
+            builder.swap_words()
+        } else {
+            builder
+        }
+    }
+
+    /// Invert every byte
+    pub fn invert(self) -> Self {
+        self.transform(Transform::Invert)
+    }
+
+    /// Swap bytes in every 16-bit word
+    pub fn swap_words(self) -> Self {
+        self.transform(Transform::SwapWords)
+    }
+
+    /// Add `transform`, applied to read data after the ones added before
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.transforms.push(transform);
+        self
+    }
+
+    /// Only `size` bytes from `offset` of the image are seen,
+    /// positions are counted from `offset`
+    pub fn window(mut self, offset: u64, size: u64) -> Self {
+        self.start = offset;
+        self.len = Some(size);
+        self
+    }
+
+    /// Blocks of the window are remapped by `geometry`
+    pub fn geometry(mut self, geometry: GeometryMapper) -> Self {
+        self.geometry = Some(geometry);
+        self
+    }
+
+    /// `geometry()` из настроек тома, если она задана
+    pub(crate) fn with_geometry(self, geometry: Option<&GeometryMapper>) -> Self {
+        match geometry {
+            Some(geometry) => self.geometry(geometry.clone()),
+            None => self,
+        }
+    }
+
+    /// Writes go to `overlay` instead of the image, changed blocks are read from it
+    pub fn overlay(mut self, overlay: Overlay) -> Self {
+        self.overlay = Some(Arc::new(overlay));
+        self
+    }
+
+    /// Reader over any `Read + Seek` source
+    pub fn build<R>(self, reader: R) -> Reader<R> {
+        Reader {
+            inner: Mutex::new(reader),
+            file: None,
+            transforms: self.transforms,
+            start: self.start,
+            len: self.len,
+            geometry: self.geometry,
+            overlay: self.overlay,
         }
     }
+
+    /// Reader over image file, reads and writes go through pread/pwrite
+    pub fn build_file(self, file: File) -> std::io::Result<Reader<File>> {
+        let clone = file.try_clone()?;
+        let mut reader = self.build(file);
+        reader.file = Some(clone);
+        Ok(reader)
+    }
 }
 
-impl AsRef<File> for Reader {
-    fn as_ref(&self) -> &File {
-        match self {
-            Self::File(h) => h,
-            Self::Inverted(h) => h.as_ref(),
+/// Swap bytes in every 16-bit word of `buf` (odd last byte stays)
+pub fn swap_words(buf: &mut [u8]) {
+    buf.chunks_exact_mut(2).for_each(|word| word.swap(0, 1));
+}
+
+/// Границы по словам вокруг `offset..offset + len`
+fn word_span(offset: u64, len: usize) -> (u64, usize) {
+    let start = offset & !1;
+    let end = (offset + len as u64 + 1) & !1;
+    (start, (end - start) as usize)
+}
+
+/// Читает, пока не прочитано четное число байт (или EOF),
+/// чтобы не разорвать слово между двумя read
+fn read_words<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut size = reader.read(buf)?;
+    while !size.is_multiple_of(2) && size < buf.len() {
+        match reader.read(&mut buf[size..])? {
+            0 => break,
+            n => size += n,
         }
     }
+    Ok(size)
 }
 
-impl AsMut<File> for Reader {
-    fn as_mut(&mut self) -> &mut File {
-        match self {
-            Self::File(h) => h,
-            Self::Inverted(h) => h.as_mut(),
+/// Positioned read: does not use or move the stream position,
+/// so it needs no seek and works by `&self` (pread for files)
+pub trait ReadAt {
+    /// Read at `offset`, result is short (or 0) at end of data
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize>;
+
+    /// Read whole `buf` at `offset`, `UnexpectedEof` at end of data
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// pread файла
+#[cfg(unix)]
+fn file_read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// pwrite файла
+#[cfg(unix)]
+pub(crate) fn file_write_all_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+// На Windows seek_read/seek_write сдвигают позицию файла. Потоковое чтение
+// Reader всегда делает seek перед чтением, так что это ничего не ломает
+#[cfg(windows)]
+fn file_read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn file_write_all_at(
+    file: &File,
+    mut buf: &[u8],
+    mut offset: u64,
+) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+impl ReadAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        file_read_at(self, buf, offset)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+}
+
+/// Читает `buf` целиком, меньше только на EOF
+pub(crate) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match reader.read(&mut buf[size..]) {
+            Ok(0) => break,
+            Ok(n) => size += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+/// `read_words()` для позиционного чтения
+fn read_words_at<R: ReadAt>(reader: &R, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut size = reader.read_at(buf, offset)?;
+    while !size.is_multiple_of(2) && size < buf.len() {
+        match reader.read_at(&mut buf[size..], offset + size as u64)? {
+            0 => break,
+            n => size += n,
+        }
+    }
+    Ok(size)
+}
+
+fn outside_window() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "write outside of the reader window",
+    )
+}
+
+impl Reader {
+    pub fn builder() -> ReaderBuilder {
+        ReaderBuilder::new()
+    }
+}
+
+impl<R> Reader<R>
+where
+    R: Read + Seek,
+{
+    /// Reader without transforms and window
+    pub fn new(reader: R) -> Self {
+        ReaderBuilder::new().build(reader)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn transforms(&self) -> &[Transform] {
+        &self.transforms
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        self.transforms.contains(&Transform::Invert)
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        self.transforms.contains(&Transform::SwapWords)
+    }
+
+    pub fn overlay(&self) -> Option<&Overlay> {
+        self.overlay.as_deref()
+    }
+
+    // в ридере нет инвариантов, которые могла бы сломать паника
+    fn lock(&self) -> MutexGuard<'_, R> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Образ -> данные
+    fn decode(&self, buf: &mut [u8]) {
+        self.transforms.iter().for_each(|t| t.apply(buf));
+    }
+
+    /// Данные -> образ, в обратном порядке
+    fn encode(&self, buf: &mut [u8]) {
+        self.transforms.iter().rev().for_each(|t| t.apply(buf));
+    }
+
+    /// Сколько из `len` байт с `offset` попадает в окно
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        match self.len {
+            Some(window) => std::cmp::min(len as u64, window.saturating_sub(offset)) as usize,
+            None => len,
+        }
+    }
+
+    /// Modification time of the image, known only for files
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.file.as_ref()?.metadata().ok()?.modified().ok()
+    }
+
+    /// Открытый файл образа, `None` если образ не файл
+    pub(crate) fn stamp(&self) -> Option<FileStamp> {
+        FileStamp::new(&self.file.as_ref()?.metadata().ok()?)
+    }
+
+    /// Size of the image (or of the window) in bytes
+    pub fn size(&self) -> std::io::Result<u64> {
+        if let Some(len) = self.len {
+            return Ok(len);
+        }
+        let size = if let Some(file) = self.file.as_ref() {
+            file.metadata()?.len()
+        } else {
+            let mut inner = self.lock();
+            let pos = inner.stream_position()?;
+            let len = inner.seek(SeekFrom::End(0))?;
+            inner.seek(SeekFrom::Start(pos))?;
+            len
+        };
+        Ok(size.saturating_sub(self.start))
+    }
+
+    /// Read at `offset` without touching the current position (pread)
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        let Some(geometry) = self.geometry.as_ref() else {
+            return self.read_image_at(&mut buf[..len], self.start + offset);
+        };
+        let mut done = 0;
+        for (pos, range) in geometry.segments(offset, len) {
+            let want = range.len();
+            let size = self.read_image_at(&mut buf[range], self.start + pos)?;
+            done += size;
+            if size < want {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Чтение по смещению в образе, слова при перестановке целиком
+    fn read_image_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        if self.is_swapped() && (!offset.is_multiple_of(2) || !buf.len().is_multiple_of(2)) {
+            let (start, len) = word_span(offset, buf.len());
+            let mut words = vec![0u8; len];
+            let size = self.read_image_at(&mut words, start)?;
+            let skip = (offset - start) as usize;
+            let size = std::cmp::min(buf.len(), size.saturating_sub(skip));
+            buf[..size].copy_from_slice(&words[skip..skip + size]);
+            return Ok(size);
+        }
+        let size = self.read_raw_at(buf, offset)?;
+        if let Some(overlay) = self.overlay.as_ref() {
+            overlay.patch(&mut buf[..size], offset)?;
+        }
+        self.decode(&mut buf[..size]);
+        Ok(size)
+    }
+
+    /// Байты самого образа, без файла изменений и преобразований
+    fn read_raw_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        if let Some(file) = self.file.as_ref() {
+            file_read_at(file, buf, offset)
+        } else {
+            let mut inner = self.lock();
+            let _pos = inner.seek(SeekFrom::Start(offset))?;
+            read_words(&mut *inner, buf)
+        }
+    }
+
+    /// Copy the image (or the window) to `out` as it is stored: transforms
+    /// are not undone, changes from the overlay are applied.
+    /// Returns number of copied bytes
+    pub fn copy_image_to<W: Write>(&self, out: &mut W) -> std::io::Result<u64> {
+        let size = self.size()?;
+        let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+        let mut done = 0u64;
+        while done < size {
+            let len = std::cmp::min(buf.len() as u64, size - done) as usize;
+            let offset = self.start + done;
+            let read = self.read_raw_at(&mut buf[..len], offset)?;
+            if read == 0 {
+                break;
+            }
+            if let Some(overlay) = self.overlay.as_ref() {
+                overlay.patch(&mut buf[..read], offset)?;
+            }
+            out.write_all(&buf[..read])?;
+            done += read as u64;
+        }
+        Ok(done)
+    }
+}
+
+/// Сколько байт копируем за раз в `Reader::copy_image_to()`
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+impl<R> Reader<R>
+where
+    R: Read + Seek + Write,
+{
+    /// Write whole `buf` at `offset` without touching the current position (pwrite)
+    pub fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        if self.clamp(offset, buf.len()) < buf.len() {
+            return Err(outside_window());
+        }
+        match self.geometry.as_ref() {
+            Some(geometry) => geometry
+                .segments(offset, buf.len())
+                .into_iter()
+                .try_for_each(|(pos, range)| self.write_image_at(&buf[range], self.start + pos)),
+            None => self.write_image_at(buf, self.start + offset),
+        }
+    }
+
+    fn write_image_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        // неполное слово: дочитываем соседние байты и пишем слова целиком
+        if self.is_swapped() && (!offset.is_multiple_of(2) || !buf.len().is_multiple_of(2)) {
+            let (start, len) = word_span(offset, buf.len());
+            let mut words = vec![0u8; len];
+            let _size = self.read_image_at(&mut words, start)?;
+            let skip = (offset - start) as usize;
+            words[skip..skip + buf.len()].copy_from_slice(buf);
+            return self.write_image_at(&words, start);
+        }
+        let encoded: Vec<u8>;
+        let buf = if self.transforms.is_empty() {
+            buf
+        } else {
+            let mut data = buf.to_vec();
+            self.encode(&mut data);
+            encoded = data;
+            &encoded[..]
+        };
+        if let Some(overlay) = self.overlay.as_ref() {
+            overlay.write_at(buf, offset, |data, pos| self.read_raw_at(data, pos))
+        } else if let Some(file) = self.file.as_ref() {
+            file_write_all_at(file, buf, offset)
+        } else {
+            let mut inner = self.lock();
+            let _pos = inner.seek(SeekFrom::Start(offset))?;
+            inner.write_all(buf)
         }
     }
+
+    /// Flush written data down to the storage
+    pub fn sync_data(&self) -> std::io::Result<()> {
+        self.lock().flush()?;
+        if let Some(file) = self.file.as_ref() {
+            file.sync_data()?;
+        }
+        if let Some(overlay) = self.overlay.as_ref() {
+            overlay.sync_data()?;
+        }
+        Ok(())
+    }
 }
 
-impl Read for Reader {
+impl<R: Read + Seek> Read for Reader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self {
-            Self::File(h) => h.read(buf),
-            Self::Inverted(h) => h.read(buf),
+        // без окна, пересчета блоков и перестановки слов позиции и байты
+        // не сдвигаются
+        if self.len.is_none()
+            && self.start == 0
+            && self.geometry.is_none()
+            && self.overlay.is_none()
+            && !self.is_swapped()
+        {
+            let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+            let size = inner.read(buf)?;
+            self.decode(&mut buf[..size]);
+            return Ok(size);
         }
+        let pos = self.stream_position()?;
+        let size = self.read_at(buf, pos)?;
+        self.seek(SeekFrom::Start(pos + size as u64))?;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> ReadAt for Reader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        Reader::read_at(self, buf, offset)
     }
 }
 
-impl Seek for Reader {
+impl<R: Seek> Seek for Reader<R> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        match self {
-            Self::File(h) => h.seek(pos),
-            Self::Inverted(h) => h.seek(pos),
-        }
+        let start = self.start;
+        let pos = match (pos, self.len) {
+            (SeekFrom::Start(n), _) => SeekFrom::Start(start + n),
+            (SeekFrom::End(n), Some(len)) => SeekFrom::Start(
+                (start + len)
+                    .checked_add_signed(n)
+                    .filter(|p| *p >= start)
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "invalid seek to a negative or overflowing position",
+                        )
+                    })?,
+            ),
+            (pos, _) => pos,
+        };
+        let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+        Ok(inner.seek(pos)?.saturating_sub(start))
     }
 }
 
@@ -109,3 +624,277 @@ impl<R: Seek> Seek for BinInvertedReader<R> {
         self.0.seek(pos)
     }
 }
+
+impl<R: ReadAt> ReadAt for BinInvertedReader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let size = self.0.read_at(buf, offset)?;
+        buf[..size].iter_mut().for_each(|b| *b = !*b);
+        Ok(size)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> AsyncRead for BinInvertedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let size = ready!(Pin::new(&mut self.0).poll_read(cx, buf))?;
+        buf[..size].iter_mut().for_each(|b| *b = !*b);
+        Poll::Ready(Ok(size))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncSeek + Unpin> AsyncSeek for BinInvertedReader<R> {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.0).poll_seek(cx, pos)
+    }
+}
+
+/// Reader swapping bytes in every 16-bit word, composable with
+/// `BinInvertedReader`. Reads from an odd position or of odd length
+/// take the whole words around and seek to the end of the data read
+pub struct SwappedReader<R>(R);
+
+impl<R> SwappedReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(reader: R) -> Self {
+        Self(reader)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.0
+    }
+}
+
+impl<R> AsRef<R> for SwappedReader<R> {
+    fn as_ref(&self) -> &R {
+        &self.0
+    }
+}
+
+impl<R> AsMut<R> for SwappedReader<R> {
+    fn as_mut(&mut self) -> &mut R {
+        &mut self.0
+    }
+}
+
+impl<R: Read + Seek> Read for SwappedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let pos = self.0.stream_position()?;
+        if pos.is_multiple_of(2) && buf.len().is_multiple_of(2) {
+            let size = read_words(&mut self.0, buf)?;
+            swap_words(&mut buf[..size]);
+            return Ok(size);
+        }
+        let (start, len) = word_span(pos, buf.len());
+        let mut words = vec![0u8; len];
+        self.0.seek(SeekFrom::Start(start))?;
+        let size = read_words(&mut self.0, &mut words)?;
+        swap_words(&mut words[..size]);
+        let skip = (pos - start) as usize;
+        let size = std::cmp::min(buf.len(), size.saturating_sub(skip));
+        buf[..size].copy_from_slice(&words[skip..skip + size]);
+        self.0.seek(SeekFrom::Start(pos + size as u64))?;
+        Ok(size)
+    }
+}
+
+impl<R: Seek> Seek for SwappedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<R: ReadAt> ReadAt for SwappedReader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let (start, len) = word_span(offset, buf.len());
+        if start == offset && len == buf.len() {
+            let size = read_words_at(&self.0, buf, offset)?;
+            swap_words(&mut buf[..size]);
+            return Ok(size);
+        }
+        let mut words = vec![0u8; len];
+        let size = read_words_at(&self.0, &mut words, start)?;
+        swap_words(&mut words[..size]);
+        let skip = (offset - start) as usize;
+        let size = std::cmp::min(buf.len(), size.saturating_sub(skip));
+        buf[..size].copy_from_slice(&words[skip..skip + size]);
+        Ok(size)
+    }
+}
+
+/// Окно `start..start + len` внутри `R`
+/// Позиции в `Seek` считаются от начала окна, чтение за концом окна
+/// возвращает 0 (EOF). Используется для образов со смещением
+/// (разделы HDD, логические диски)
+pub struct SubReader<R> {
+    inner: R,
+    start: u64,
+    len: u64,
+    pos: u64,
+    /// позиция inner уже выставлена на start + pos
+    #[cfg(feature = "async")]
+    seeked: bool,
+}
+
+impl<R> SubReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(reader: R, start: u64, len: u64) -> Self {
+        Self {
+            inner: reader,
+            start,
+            len,
+            pos: 0,
+            #[cfg(feature = "async")]
+            seeked: false,
+        }
+    }
+}
+
+impl<R> SubReader<R> {
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Offset of the window in the inner reader
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Size of the window
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn remaining(&self) -> u64 {
+        self.len.saturating_sub(self.pos)
+    }
+
+    fn new_pos(&self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, off) = match pos {
+            SeekFrom::Start(n) => return Ok(n),
+            SeekFrom::End(n) => (self.len, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        if off >= 0 {
+            base.checked_add(off as u64)
+        } else {
+            base.checked_sub(off.unsigned_abs())
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })
+    }
+}
+
+impl<R> AsRef<R> for SubReader<R> {
+    fn as_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R> AsMut<R> for SubReader<R> {
+    fn as_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for SubReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = std::cmp::min(buf.len() as u64, self.remaining()) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let _pos = self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let size = self.inner.read(&mut buf[..max])?;
+        self.pos += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: ReadAt> ReadAt for SubReader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let max = std::cmp::min(buf.len() as u64, self.len.saturating_sub(offset)) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        self.inner.read_at(&mut buf[..max], self.start + offset)
+    }
+}
+
+impl<R: Seek> Seek for SubReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.new_pos(pos)?;
+        #[cfg(feature = "async")]
+        {
+            self.seeked = false;
+        }
+        Ok(self.pos)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncRead for SubReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let max = std::cmp::min(buf.len() as u64, self.remaining()) as usize;
+        if max == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        // seek и read это два разных poll, поэтому запоминаем,
+        // что seek уже сделан, иначе при Pending на чтении начнем сначала
+        if !self.seeked {
+            let target = self.start + self.pos;
+            let _pos = ready!(Pin::new(&mut self.inner).poll_seek(cx, SeekFrom::Start(target)))?;
+            self.seeked = true;
+        }
+        let size = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf[..max]))?;
+        self.pos += size as u64;
+        Poll::Ready(Ok(size))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncSeek + Unpin> AsyncSeek for SubReader<R> {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        // реальный seek внутреннего ридера откладываем до чтения
+        self.pos = self.new_pos(pos)?;
+        self.seeked = false;
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+impl ReadAt for MemImage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let data = self.as_slice();
+        let start = std::cmp::min(offset, data.len() as u64) as usize;
+        let size = std::cmp::min(buf.len(), data.len() - start);
+        buf[..size].copy_from_slice(&data[start..start + size]);
+        Ok(size)
+    }
+}
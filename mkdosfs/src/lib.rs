@@ -1,21 +1,80 @@
 use std::{
     collections::HashSet,
     fmt::Debug,
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom},
-    os::unix::fs::MetadataExt,
-    path::PathBuf,
-    sync::atomic::{AtomicU64, Ordering},
-    time::SystemTime,
+    fs::{File, OpenOptions, TryLockError},
+    io::{BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant, SystemTime},
 };
 
 use bytes::Buf;
-use encoding_rs::KOI8_R;
-use io::Reader;
+use cache::BlockCache;
+use index::EntryIndex;
+use inode::{InodeAllocator, InodeStats, ROOT_INODE};
+use io::{FileStamp, MemImage, Reader, ReaderBuilder};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{debug, instrument, trace, warn};
 
+pub mod andos;
+#[cfg(feature = "serde")]
+pub mod archive;
+#[cfg(feature = "async")]
+mod async_io;
+mod boot;
+mod cache;
+pub mod carve;
+mod check;
+#[cfg(feature = "compress")]
+pub mod compressed;
+pub mod container;
+mod detect;
+mod diagnostics;
+pub mod diff;
+pub mod encoding;
+mod geometry;
+pub mod hash;
+mod hdi;
+mod hfe;
+mod imd;
+pub mod http;
+mod index;
+pub mod inode;
 pub mod io;
+mod logical;
+mod overlay;
+pub mod rt11;
+pub mod tape;
+mod td0;
+mod tree;
+mod volume;
+#[cfg(feature = "watch")]
+mod watch;
+mod write;
+
+pub use andos::{AndosEntry, AndosFs};
+pub use bkhdd::progress::{self, Progress, ProgressFn};
+/// Как реагировать на несоответствия, общий с таблицами разделов `bkhdd`
+pub use bkhdd::ParseMode;
+pub use boot::{boot_code_fits, BOOT_CODE_SIZE};
+pub use cache::{CacheStats, DEFAULT_CACHE_BLOCKS};
+pub use check::{CheckIssue, CheckReport};
+pub use detect::{detect_fs, FsKind};
+pub use diagnostics::{Diagnostics, Warning};
+pub use encoding::Encoding;
+pub use geometry::GeometryMapper;
+pub use inode::InodeStrategy;
+pub use overlay::Overlay;
+pub use rt11::{Rt11Entry, Rt11Fs};
+pub use tree::DirTree;
+pub use volume::XATTR_PREFIX;
+pub use write::{
+    encode_name, host_name, truncate_name, DEFAULT_START_ADDRESS, DEFAULT_START_BLOCK,
+    DISK_400K_BLOCKS, DISK_800K_BLOCKS,
+};
 
 pub const BLOCK_SIZE: usize = 512;
 pub const MKDOS_LABEL: u16 = 0o51414;
@@ -24,6 +83,17 @@ pub const DIR_MARKER: u8 = 0o177;
 pub const DIR_ENTRY_SIZE: usize = 0o30;
 pub const FILE_NAME_SIZE: usize = 14;
 pub const META_SIZE: usize = 0o500;
+/// How often `is_modified()`/`check_modified()` look at the image mtime
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_millis(1000);
+
+// fuse читает один том из нескольких потоков сразу (под RwLock на чтение),
+// поэтому все запросы на чтение идут по `&self`
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Fs>();
+    assert_send_sync::<AndosFs>();
+    assert_send_sync::<Rt11Fs>();
+};
 
 #[derive(Debug, Copy, Clone)]
 pub enum MetaOffset {
@@ -58,14 +128,17 @@ impl From<MetaOffset> for usize {
 }
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Meta {
     /// 30 - Количество файлов в каталоге (НЕ ЗАПИСЕЙ!);
     files: u16,
     /// 32 - Суммарное количество  блоков в файлах (НЕ ЗАПИСЯХ!) каталога;
     blocks: u16,
     /// 400 - Метка принадлежности к формату Micro DOS (123456);
+    #[cfg_attr(feature = "serde", serde(with = "bkhdd::octal"))]
     microdos_label: u16,
     /// 402 - Метка формата каталога MK-DOS (51414);
+    #[cfg_attr(feature = "serde", serde(with = "bkhdd::octal"))]
     mkdos_label: u16,
     /// 466 - Размер диска в блоках, величина абсолютная для системы (в
     ///       отличие от NORD, NORTON и т.п.) принимающая не два значе-
@@ -76,9 +149,16 @@ pub struct Meta {
     disk_size: u16,
     /// 470 - Номер блока первого файла. Величина также изменяемая;
     start_block: u16,
+    #[cfg_attr(feature = "serde", serde(skip, default = "empty_meta_raw"))]
     raw: [u8; META_SIZE],
 }
 
+/// `Default` для массивов длиннее 32 не выведен
+#[cfg(feature = "serde")]
+fn empty_meta_raw() -> [u8; META_SIZE] {
+    [0; META_SIZE]
+}
+
 impl Debug for Meta {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Meta")
@@ -97,6 +177,34 @@ impl Meta {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Files in catalog as counted by meta block
+    pub fn files(&self) -> u16 {
+        self.files
+    }
+
+    /// Blocks of files as counted by meta block
+    pub fn blocks(&self) -> u16 {
+        self.blocks
+    }
+
+    pub fn microdos_label(&self) -> u16 {
+        self.microdos_label
+    }
+
+    pub fn mkdos_label(&self) -> u16 {
+        self.mkdos_label
+    }
+
+    /// Volume size in blocks
+    pub fn disk_size(&self) -> u16 {
+        self.disk_size
+    }
+
+    /// First block of data area
+    pub fn start_block(&self) -> u16 {
+        self.start_block
+    }
 }
 
 impl Default for Meta {
@@ -114,6 +222,7 @@ impl Default for Meta {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DirEntryStatus {
     /// 0 - обычный;
     Normal = 0,
@@ -162,6 +271,7 @@ pub enum DirEntryOffset {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DirEntry {
     /// 0 - Статус файла;
     /// DirEntryStatus
@@ -175,14 +285,15 @@ pub struct DirEntry {
     /// 22 - Длина в блоках;
     pub blocks: u64,
     /// 24 - Адрес;
+    #[cfg_attr(feature = "serde", serde(with = "bkhdd::octal"))]
     pub start_address: u32,
     /// 26 - Длина.
     pub length: u32,
     /// Реальный размер
     pub size: u32,
-    /// virtual inode
-    /// 1..1000 - direcory inode
-    /// 1000.. - other files
+    /// virtual inode, see [`inode`] for the mapping
+    /// 1..=1000 - direcory inode
+    /// 1001.. - other files
     pub inode: u64,
     pub parent_inode: u64,
     pub is_dir: bool,
@@ -193,8 +304,16 @@ pub struct DirEntry {
     pub is_bad: bool,
     pub is_deleted: bool,
     pub is_unknown: bool,
+    /// мусорная запись, сохранена только в режиме ParseMode::Forensic
+    pub is_garbage: bool,
+    /// логический диск, открытый как каталог (см. `Fs::set_open_logical`)
+    pub is_volume: bool,
+    /// пересекается с другой записью или вылезает из области данных,
+    /// читать такую запись нельзя (см. `Fs::corrupt_entries`)
+    pub is_corrupt: bool,
     /// unix mode
     pub mode: u16,
+    #[cfg_attr(feature = "serde", serde(skip))]
     raw: [u8; DIR_ENTRY_SIZE],
 }
 
@@ -218,16 +337,57 @@ impl Debug for DirEntry {
             .field("is_bad", &self.is_bad)
             .field("is_deleted", &self.is_deleted)
             .field("is_unknown", &self.is_unknown)
+            .field("is_garbage", &self.is_garbage)
+            .field("is_volume", &self.is_volume)
+            .field("is_corrupt", &self.is_corrupt)
             .field("mode", &format_args!("{:o}", &self.mode))
             // .field("raw", &self.raw)
             .finish()
     }
 }
 
+/// Size in bytes of file with `blocks` blocks and length word `length`
+///
+/// Length word holds the low 16 bits of the size, so for files of 64K
+/// and longer the size is the one ending in the last block. Some tools store
+/// only the length of the last block there (`< 512`), then the size is
+/// `(blocks - 1) * 512 + length`.
+pub fn file_size(blocks: u64, length: u16) -> u64 {
+    const WORD: u64 = u16::MAX as u64 + 1;
+    let length = length as u64;
+    let max = blocks * BLOCK_SIZE as u64;
+    if max < WORD {
+        return length;
+    }
+    // последний размер не больше max с теми же младшими 16 битами
+    let size = max - (max - length) % WORD;
+    let last_block = (blocks - 1) * BLOCK_SIZE as u64;
+    match () {
+        _ if size > last_block => size,
+        _ if length < BLOCK_SIZE as u64 => last_block + length,
+        // ни одно правило не подошло, считаем файл до конца блоков
+        _ => max,
+    }
+}
+
 impl DirEntry {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Размер записи в блоках с учетом реального размера в байтах
+    pub fn extent_blocks(&self) -> u64 {
+        let size_blocks = (self.size as u64).div_ceil(BLOCK_SIZE as u64);
+        std::cmp::max(self.blocks, size_blocks)
+    }
+
+    /// Name bytes as they are in catalog (image encoding, padded with
+    /// spaces), directory marker is skipped
+    pub fn raw_name(&self) -> &[u8] {
+        let off = DirEntryOffset::Name as usize;
+        let marker = if self.is_dir { 1 } else { 0 };
+        &self.raw[off + marker..off + FILE_NAME_SIZE]
+    }
 }
 
 impl Default for DirEntry {
@@ -250,6 +410,9 @@ impl Default for DirEntry {
             is_bad: false,
             is_deleted: false,
             is_unknown: false,
+            is_garbage: false,
+            is_volume: false,
+            is_corrupt: false,
             // r--r--r-- ;)
             mode: 0o0444,
             raw: [0; DIR_ENTRY_SIZE],
@@ -257,71 +420,320 @@ impl Default for DirEntry {
     }
 }
 
-pub struct Fs {
+/// MK-DOS volume over image backend `R` (file by default)
+pub struct Fs<R = File> {
     /// path to image
     file_path: String,
     /// read only mode
     read_only: bool,
-    reader: Option<Reader>,
-    #[allow(dead_code)]
-    writer: Option<File>,
+    /// advisory lock of image file: shared read only, exclusive for writing
+    lock: bool,
+    /// writes go to this file, the image is opened read only, see `overlay.rs`
+    overlay: Option<PathBuf>,
+    reader: Option<Reader<R>>,
     offset: u64,
     size: u64,
     inverted: bool,
+    /// bytes in 16-bit words are swapped
+    swapped: bool,
+    /// block remapping of side by side dumps
+    geometry: Option<GeometryMapper>,
+    parse_mode: ParseMode,
+    /// read catalog up to start block, skipping blank slots
+    scan_full_catalog: bool,
+    /// file names encoding
+    encoding: Encoding,
+    lookup_policy: LookupPolicy,
     last_modified: SystemTime,
+    /// образ, который мы прочитали (или сами записали), см. `is_modified()`
+    stamp: Option<FileStamp>,
+    /// не чаще чем раз в check_interval смотрим на время изменения образа
+    check_interval: Duration,
+    last_check: Mutex<Option<Instant>>,
+    /// notify watcher, see `watch.rs`
+    #[cfg(feature = "watch")]
+    watcher: Option<watch::ImageWatcher>,
+    /// reopen counter, file handles of older generations are stale
+    generation: u64,
+    /// why image can't be reopened, `None` if everything is fine
+    degraded: Option<String>,
     /// image meta block
     meta: Meta,
-    /// directory inodes
-    dir_inodes: AtomicU64,
-    /// file inodes
-    file_inodes: AtomicU64,
-    /// next free file handle
-    next_fh: AtomicU64,
+    /// inode namespace
+    inodes: InodeAllocator,
+    /// catalog statistics collected by read_entries()
+    stats: FsStats,
+    /// warnings of last read of meta block and catalog
+    diagnostics: Diagnostics,
+    /// callback of long operations, see `progress`
+    progress: Option<ProgressFn>,
     /// directory entries,
     entries: Vec<DirEntry>,
+    /// lookup indexes over entries and nested, see `index.rs`
+    index: EntryIndex,
+    /// show logical disks as directories
+    open_logical: bool,
+    /// read_file() reads deleted files
+    read_deleted: bool,
+    /// read_file() reads bad blocks files
+    read_bad: bool,
+    /// entries of logical disks (read only), see `logical.rs`
+    nested: Vec<DirEntry>,
+    /// LRU cache of image blocks, see `cache.rs`
+    cache: Mutex<BlockCache>,
     _tracing_span: tracing::Span,
 }
 
-impl std::fmt::Debug for Fs {
+impl<R> std::fmt::Debug for Fs<R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Fs")
             .field("file_name", &self.file_path)
             .field("read_only", &self.read_only)
+            .field("lock", &self.lock)
+            .field("overlay", &self.overlay)
             // .field("reader", &self.reader)
-            // .field("writer", &self.writer)
             .field("meta", &self.meta)
             .field("offset", &self.offset)
             .field("size", &self.size)
             .field("inverted", &self.inverted)
-            .field("dir_inodes", &self.dir_inodes)
-            .field("file_inodes", &self.file_inodes)
-            .field("next_fh", &self.next_fh)
+            .field("swapped", &self.swapped)
+            .field("geometry", &self.geometry)
+            .field("parse_mode", &self.parse_mode)
+            .field("encoding", &self.encoding)
+            .field("check_interval", &self.check_interval)
+            .field("watching", &self.is_watching())
+            .field("generation", &self.generation)
+            .field("degraded", &self.degraded)
+            .field("inodes", &self.inodes.stats())
+            .field("stats", &self.stats)
+            .field("diagnostics", &self.diagnostics)
+            .field("progress", &self.progress.is_some())
             .field("entries", &self.entries)
+            .field("open_logical", &self.open_logical)
+            .field("scan_full_catalog", &self.scan_full_catalog)
+            .field("read_deleted", &self.read_deleted)
+            .field("read_bad", &self.read_bad)
+            .field("nested", &self.nested)
+            .field(
+                "cache",
+                &self
+                    .cache
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .stats(),
+            )
             .finish()
     }
 }
 
-impl Default for Fs {
+impl<R> Default for Fs<R> {
     fn default() -> Self {
         Self {
             file_path: String::default(),
             read_only: true,
+            lock: true,
+            overlay: None,
             reader: None,
-            writer: None,
             offset: 0,
             size: 0,
             inverted: false,
+            swapped: false,
+            geometry: None,
+            parse_mode: ParseMode::default(),
+            encoding: Encoding::default(),
+            lookup_policy: LookupPolicy::default(),
             last_modified: SystemTime::UNIX_EPOCH,
+            stamp: None,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            last_check: Mutex::new(None),
+            #[cfg(feature = "watch")]
+            watcher: None,
+            generation: 0,
+            degraded: None,
             meta: Meta::new(),
-            dir_inodes: AtomicU64::new(2),
-            file_inodes: AtomicU64::new(1001),
-            next_fh: AtomicU64::new(1),
+            inodes: InodeAllocator::new(),
+            stats: FsStats::default(),
+            diagnostics: Diagnostics::default(),
+            progress: None,
             entries: Vec::new(),
+            index: EntryIndex::default(),
+            open_logical: false,
+            scan_full_catalog: false,
+            read_deleted: false,
+            read_bad: false,
+            nested: Vec::new(),
+            cache: Mutex::new(BlockCache::new(DEFAULT_CACHE_BLOCKS)),
             _tracing_span: tracing::span!(tracing::Level::TRACE, "Fs"),
         }
     }
 }
 
+// не зависят от типа образа, нужны и в Debug
+impl<R> Fs<R> {
+    /// Image changes are tracked by notify watcher (see `Fs::watch`)
+    pub fn is_watching(&self) -> bool {
+        #[cfg(feature = "watch")]
+        return self.watcher.is_some();
+        #[cfg(not(feature = "watch"))]
+        false
+    }
+
+    /// Incremented on every reopen of the image with
+    /// `InodeStrategy::Sequential`, file handles opened with older
+    /// generation are stale
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Last reopen failed, catalog is empty until `refresh()` succeeds
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.is_some()
+    }
+
+    /// Error of the failed reopen
+    pub fn degraded_reason(&self) -> Option<&str> {
+        self.degraded.as_deref()
+    }
+}
+
+/// Разметка тома в блоках:
+/// - `0..start_block` - системная область (мета блок и каталог)
+/// - `start_block..disk_size` - область данных, только здесь могут лежать файлы
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct VolumeLayout {
+    pub start_block: u64,
+    pub disk_size: u64,
+}
+
+impl VolumeLayout {
+    pub fn system_area(&self) -> Range<u64> {
+        0..self.start_block
+    }
+
+    pub fn data_area(&self) -> Range<u64> {
+        self.start_block..self.disk_size
+    }
+
+    /// Check that `blocks` blocks from `start` lie in the data area,
+    /// returns the extent as a block range
+    pub fn check_extent(&self, start: u64, blocks: u64) -> Result<Range<u64>, FsError> {
+        if start < self.start_block {
+            return Err(FsError::ExtentInSystemArea {
+                start,
+                blocks,
+                data_start: self.start_block,
+            });
+        }
+        match start.checked_add(blocks) {
+            Some(end) if end <= self.disk_size => Ok(start..end),
+            _ => Err(FsError::ExtentBeyondDisk {
+                start,
+                blocks,
+                disk_size: self.disk_size,
+            }),
+        }
+    }
+}
+
+/// Как `find_entrie()` сравнивает имена
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LookupPolicy {
+    /// имя как в каталоге
+    #[default]
+    Exact,
+    /// без учета регистра (и русских букв тоже) и пробелов в конце, если
+    /// точного совпадения нет
+    CaseInsensitive,
+}
+
+/// Образ кончился раньше файла
+fn short_read(entry: Option<&DirEntry>) -> FsError {
+    FsError::CustomIo {
+        desc: format!("Short read of {:?}", entry.map_or("", |e| e.name.as_str())),
+        source: std::io::ErrorKind::UnexpectedEof.into(),
+    }
+}
+
+/// Запись похожа на настоящую: известный статус и файл в области данных.
+/// Так проверяем слоты за пустым при `scan_full_catalog`, в них часто
+/// остаются обрывки старого каталога
+fn plausible_entry(
+    layout: &VolumeLayout,
+    status: u8,
+    is_directory: bool,
+    start_block: u16,
+    blocks: u16,
+) -> bool {
+    if is_directory {
+        // в статусе номер каталога
+        return status != 0;
+    }
+    matches!(status, 0 | 1 | 2 | 0o200 | 0o377)
+        && layout
+            .check_extent(start_block as u64, blocks as u64)
+            .is_ok()
+}
+
+/// В Strict несоответствие - ошибка, иначе предупреждение в лог и в `diagnostics`
+fn inconsistency(
+    mode: ParseMode,
+    span: &tracing::Span,
+    diagnostics: &mut Diagnostics,
+    err: FsError,
+) -> Result<(), FsError> {
+    match mode {
+        ParseMode::Strict => Err(err),
+        ParseMode::Permissive | ParseMode::Forensic => {
+            warn!(parent: span, "{}", err);
+            diagnostics.push(err);
+            Ok(())
+        }
+    }
+}
+
+/// Counters collected while reading the catalog
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsStats {
+    /// all entries read from catalog (including deleted and bad)
+    pub entries: u64,
+    /// normal, protected, logical disks and directories
+    pub normal: u64,
+    pub logical: u64,
+    pub deleted: u64,
+    pub bad: u64,
+    /// files with nonexistent directory (moved to root)
+    pub orphans: u64,
+    /// directories inside themselves (moved to root)
+    pub dir_cycles: u64,
+    /// garbage entries kept in ParseMode::Forensic
+    pub garbage: u64,
+    /// overlapping entries and entries outside data area
+    pub corrupt: u64,
+    pub used_blocks: u64,
+    pub bad_blocks: u64,
+    pub hole_blocks: u64,
+    pub inodes: InodeStats,
+}
+
+/// Volume usage for statfs, see `Fs::statvfs()`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatVfs {
+    pub block_size: u64,
+    /// disk size in blocks
+    pub blocks: u64,
+    /// meta block and catalog (`0..start_block`)
+    pub system_blocks: u64,
+    /// blocks of files (deleted and bad only if they are shown)
+    pub used_blocks: u64,
+    pub free_blocks: u64,
+    /// catalog capacity in entries
+    pub files: u64,
+    /// free catalog entries
+    pub free_files: u64,
+    /// max file name length
+    pub namelen: u32,
+}
+
 #[derive(Error, Debug)]
 pub enum FsError {
     #[error("Fuser init function error): {0}")]
@@ -334,19 +746,201 @@ pub enum FsError {
     LabelMkDos,
     #[error("Unknown size in image with offset. Must use set_size_blocks()")]
     UnknownSize,
+    #[error("Start block record = {0} less than 20 Strange!")]
+    StrangeStartBlock(u16),
+    #[error("Wrong (corrupted?) disk size {meta} in meta block but image size is {image}")]
+    WrongDiskSize { meta: u64, image: u64 },
+    #[error("Uknown Status: 0{0:o}")]
+    UnknownStatus(u8),
+    #[error("{0} orphan files found")]
+    OrphanFiles(u64),
+    #[error("Directory {0:?} is inside itself")]
+    DirectoryCycle(String),
+    #[error("Wrong files count? Meta file count is {meta} but {found} found")]
+    WrongFilesCount { meta: u64, found: u64 },
+    #[error("Wrong used blocks? Meta file blocks is {meta} but {found} found")]
+    WrongUsedBlocks { meta: u64, found: u64 },
+    #[error("Extent {start}+{blocks} overlaps system area 0..{data_start}")]
+    ExtentInSystemArea {
+        start: u64,
+        blocks: u64,
+        data_start: u64,
+    },
+    #[error("Extent {start}+{blocks} is beyond disk size {disk_size}")]
+    ExtentBeyondDisk {
+        start: u64,
+        blocks: u64,
+        disk_size: u64,
+    },
+    #[error("Can't format disk of {disk_size} blocks with start block {start_block}")]
+    BadGeometry { disk_size: u64, start_block: u64 },
+    #[error("Entry {0:?} overlaps previous one")]
+    EntriesOverlap(String),
+    #[error("Entry {0:?} is corrupted (overlaps other entry or is outside data area)")]
+    CorruptEntry(String),
+    #[error("Unknown encoding {0:?}")]
+    UnknownEncoding(String),
+    #[error("Image is opened read only")]
+    ReadOnly,
+    #[error("Image can't be reopened: {0}")]
+    Degraded(String),
+    #[error("Entry not found")]
+    NotFound,
+    #[error("Entry {0:?} already exists")]
+    Exists(String),
+    #[error("Name {0:?} is too long")]
+    NameTooLong(String),
+    #[error("Name {0:?} can't be encoded")]
+    BadName(String),
+    #[error("No free entries in catalog")]
+    CatalogFull,
+    #[error("No free space on disk")]
+    NoSpace,
+    #[error("Entry is protected")]
+    Protected,
+    #[error("Entry is a directory")]
+    IsDirectory,
+    #[error("Status {0:?} can't be set")]
+    BadStatus(DirEntryStatus),
+    #[error("Bad block mapping geometry: {0}")]
+    BadMapping(String),
+    #[error("Bad floppy container: {0}")]
+    Container(String),
+    #[error("Bad compressed image: {0}")]
+    Compressed(String),
+    #[error("Bad manifest: {0}")]
+    BadManifest(String),
+    #[error("Bad FAT boot sector: {0}")]
+    BadBootSector(String),
+    #[error("Boot code of {0} bytes doesn't fit in block 0 (256 bytes or whole block)")]
+    BootCodeSize(usize),
+    #[error("{0} filesystem is not supported")]
+    Unsupported(FsKind),
+    #[error("Bad RT-11 volume: {0}")]
+    BadRt11(String),
+    #[error("Bad overlay {0}")]
+    BadOverlay(String),
+    #[error("Bad tape file: {0}")]
+    BadTape(String),
+    #[error("Partition {0} not found in HDD image")]
+    NoPartition(usize),
+    #[error("HDD image error")]
+    Hdd {
+        #[from]
+        source: bkhdd::HDIError,
+    },
     #[error("Io: {desc}")]
     CustomIo {
         desc: String,
         #[source]
         source: std::io::Error,
     },
+    #[cfg(feature = "watch")]
+    #[error("Can't watch image")]
+    Watch {
+        #[from]
+        source: notify::Error,
+    },
     #[error("Io Error")] // #[error(transparent)]
     Io {
         #[from]
         source: std::io::Error,
     },
-    #[error("Uknown Error")]
-    Unknown,
+    #[error("Image is not opened")]
+    NotOpen,
+    #[error("Image {0} is locked by another process (see --no-lock)")]
+    Locked(String),
+    // не source: ошибка уже в тексте, в цепочке ее второй раз не нужно
+    #[error("{error} at image offset {offset:#o}")]
+    At { offset: u64, error: Box<FsError> },
+}
+
+/// What went wrong, see `FsError::category()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// image contents don't make sense: labels, catalog, containers
+    Parse,
+    /// image can't be read or written
+    Io,
+    /// operation is not possible in current state or with these arguments
+    State,
+}
+
+impl FsError {
+    /// Attach offset in image file where the problem was found
+    pub fn at(self, offset: u64) -> Self {
+        Self::At {
+            offset,
+            error: Box::new(self),
+        }
+    }
+
+    /// Offset in image file where the problem was found, if known
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Self::At { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// The error without offset
+    pub fn root(&self) -> &FsError {
+        match self {
+            Self::At { error, .. } => error.root(),
+            e => e,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        use FsError::*;
+        match self.root() {
+            BadMetaSize(_)
+            | LabelMicroDos
+            | LabelMkDos
+            | StrangeStartBlock(_)
+            | WrongDiskSize { .. }
+            | UnknownStatus(_)
+            | OrphanFiles(_)
+            | DirectoryCycle(_)
+            | WrongFilesCount { .. }
+            | WrongUsedBlocks { .. }
+            | ExtentInSystemArea { .. }
+            | ExtentBeyondDisk { .. }
+            | EntriesOverlap(_)
+            | CorruptEntry(_)
+            | Container(_)
+            | Compressed(_)
+            | BadManifest(_)
+            | BadBootSector(_)
+            | BadRt11(_)
+            | BadOverlay(_)
+            | BadTape(_)
+            | NoPartition(_) => ErrorCategory::Parse,
+            FuserInitError(_) | Hdd { .. } | CustomIo { .. } | Io { .. } => ErrorCategory::Io,
+            #[cfg(feature = "watch")]
+            Watch { .. } => ErrorCategory::Io,
+            UnknownSize
+            | BadGeometry { .. }
+            | UnknownEncoding(_)
+            | ReadOnly
+            | Degraded(_)
+            | NotFound
+            | Exists(_)
+            | NameTooLong(_)
+            | BadName(_)
+            | CatalogFull
+            | NoSpace
+            | Protected
+            | IsDirectory
+            | BadStatus(_)
+            | BadMapping(_)
+            | BootCodeSize(_)
+            | Unsupported(_)
+            | NotOpen
+            | Locked(_) => ErrorCategory::State,
+            At { .. } => unreachable!("root() has no offset"),
+        }
+    }
 }
 
 impl Fs {
@@ -362,31 +956,165 @@ impl Fs {
         let fname = PathBuf::new().join(&self.file_path);
         let h = OpenOptions::new()
             .read(true)
-            .write(!self.read_only)
+            .write(!self.read_only && self.overlay.is_none())
             .append(false)
             .open(&fname)
             .map_err(|e| FsError::CustomIo {
                 desc: format!("Can't open {:?}", &fname),
                 source: e,
             })?;
+        if self.lock {
+            self.lock_image(&h)?;
+        }
+        let mut builder = ReaderBuilder::with_flags(self.inverted, self.swapped)
+            .with_geometry(self.geometry.as_ref());
+        // только читаем - пустой файл изменений не заводим
+        if let Some(path) = self.overlay.as_ref() {
+            if !self.read_only || path.exists() {
+                builder = builder.overlay(Overlay::open(path, h.metadata()?.len())?);
+            }
+        }
+        let reader = builder.build_file(h)?;
+        self.open_reader(reader)?;
+        self.open_logical_disks();
+
+        Ok(())
+    }
+
+    pub fn try_reopen(&mut self) -> Result<(), FsError> {
+        self.inodes = self.inodes.reopened(self.entries.iter());
+        self.stats = FsStats::default();
+        // Нельзя обнулять размер, если работаем по смещению
+        // смещение всегда указывается жестким размером
+        if self.offset == 0 {
+            self.size = 0;
+        }
+        self.meta = Meta::new();
+        self.entries = Vec::new();
+        self.nested = Vec::new();
+        // блокировку держит старый дескриптор, новый ее без этого не получит
+        self.reader = None;
+        self.rebuild_index();
+        self.cache().clear();
+        // открытые до этого файлы протухли, см. generation(),
+        // а стабильные иноды другому файлу не достаются
+        if self.inodes.strategy() == InodeStrategy::Sequential {
+            self.generation += 1;
+        }
+        self.try_open()
+    }
+
+    /// Reopen image if it was changed on disk, but no more often
+    /// than once per check interval (see `set_check_interval`)
+    pub fn check_modified(&mut self) -> bool {
+        if !self.check_due() {
+            return false;
+        }
+        match self.refresh() {
+            Ok(modified) => modified,
+            // каталог уже сброшен, Fs в деградированном состоянии
+            Err(e) => {
+                warn!(parent: &self._tracing_span, "Can't reopen: {}", e);
+                true
+            }
+        }
+    }
+
+    /// Reopen image right now if it was changed on disk
+    /// (or if previous reopen failed)
+    pub fn refresh(&mut self) -> Result<bool, FsError> {
+        *self
+            .last_check
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+        #[cfg(feature = "watch")]
+        if let Some(watcher) = self.watcher.as_ref() {
+            watcher.clear();
+        }
+        // прошлый раз не открылось - пробуем снова, что бы ни было с mtime
+        if self.degraded.is_some() {
+            self.reopen_or_degrade()?;
+            warn!(parent: &self._tracing_span, "Image recovered");
+            return Ok(true);
+        }
+        // до первого открытия сравнивать не с чем
+        let Some(stamp) = self.stamp else {
+            return Ok(false);
+        };
+        let mt = match self.path_stamp() {
+            Some(now) if now != stamp => now.modified,
+            _ => return Ok(false),
+        };
+        warn!(parent: &self._tracing_span, "Disk modified {:?} -> {:?}", self.last_modified, mt);
+        self.reopen_or_degrade()?;
+        warn!(parent: &self._tracing_span, "Image reopened");
+
+        Ok(true)
+    }
+
+    /// try_reopen(), при ошибке запоминаем ее до следующей удачной попытки
+    fn reopen_or_degrade(&mut self) -> Result<(), FsError> {
+        match self.try_reopen() {
+            Ok(()) => {
+                self.degraded = None;
+                Ok(())
+            }
+            Err(e) => {
+                self.degraded = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Fs<MemImage> {
+    /// Open MK-DOS volume from image in memory
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, FsError> {
+        Self::from_reader(MemImage::from(data))
+    }
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek,
+{
+    /// Open MK-DOS volume from any `Read + Seek` source with default settings
+    pub fn from_reader(reader: R) -> Result<Self, FsError> {
+        let mut fs = Self::default();
+        fs.try_open_reader(reader)?;
+        Ok(fs)
+    }
+
+    /// Like `try_open()`, but reads image from `reader`
+    /// (offset, size, inverted and parse mode are taken from settings)
+    pub fn try_open_reader(&mut self, reader: R) -> Result<(), FsError> {
+        let reader = ReaderBuilder::with_flags(self.inverted, self.swapped)
+            .with_geometry(self.geometry.as_ref())
+            .build(reader);
+        self.open_reader(reader)
+    }
+
+    /// Give back image source, `None` if image was not opened
+    pub fn into_reader(self) -> Option<R> {
+        self.reader.map(Reader::into_inner)
+    }
+
+    /// Прочитать мета блок и каталог через `reader`
+    fn open_reader(&mut self, reader: Reader<R>) -> Result<(), FsError> {
         if self.size == 0 {
             if self.offset != 0 {
                 return Err(FsError::UnknownSize);
             }
-            let m = h.metadata()?;
-            self.size = m.blocks() * BLOCK_SIZE as u64;
-            self.last_modified = m.modified()?;
+            self.size = reader.size()?;
         }
-        let reader = if self.inverted {
-            Reader::inverted(h)
-        } else {
-            Reader::new(h)
-        };
+        if let Some(mt) = reader.modified() {
+            self.last_modified = mt;
+        }
+        self.stamp = reader.stamp();
         self.reader = Some(reader);
         self.read_meta()?;
         self.read_entries()?;
-
-        // return Err(FsError::Unknown);
+        self.rebuild_index();
 
         Ok(())
     }
@@ -394,13 +1122,14 @@ impl Fs {
     #[instrument(level = "trace", skip(self))]
     fn read_meta(&mut self) -> Result<(), FsError> {
         // warn!(parent: &self._tracing_span, "TESTING TARGET: _tracing_span");
+        self.diagnostics.clear();
         if let Some(reader) = self.reader.as_mut() {
             let buf = &mut self.meta.raw[..];
             // let size = reader.read_at(buf, 0)?;
             let _pos = reader.seek(SeekFrom::Start(self.offset))?;
             let size = reader.read(buf)?;
             if size < META_SIZE {
-                return Err(FsError::BadMetaSize(size));
+                return Err(FsError::BadMetaSize(size).at(self.offset));
             }
             let mut buf = &self.meta.raw[..];
             buf.advance(MetaOffset::Files as usize);
@@ -408,28 +1137,45 @@ impl Fs {
             self.meta.blocks = buf.get_u16_le();
             buf.advance(MetaOffset::LabelsOffset as usize);
             let label = buf.get_u16_le();
+            self.meta.microdos_label = label;
             if label != MICRODOS_LABEL {
-                return Err(FsError::LabelMicroDos);
+                return Err(
+                    FsError::LabelMicroDos.at(self.offset + MetaOffset::MicrodosLabel as u64)
+                );
             }
             let label = buf.get_u16_le();
+            self.meta.mkdos_label = label;
             if label != MKDOS_LABEL {
-                return Err(FsError::LabelMkDos);
+                return Err(FsError::LabelMkDos.at(self.offset + MetaOffset::MkdosLabel as u64));
             }
             buf.advance(MetaOffset::DiskSizeOffset as usize);
             self.meta.disk_size = buf.get_u16_le();
             self.meta.start_block = buf.get_u16_le();
             if self.meta.start_block < 20 {
-                warn!(parent: &self._tracing_span, "Start block record = {} less than 20 Strange!", self.meta.start_block);
+                inconsistency(
+                    self.parse_mode,
+                    &self._tracing_span,
+                    &mut self.diagnostics,
+                    FsError::StrangeStartBlock(self.meta.start_block)
+                        .at(self.offset + MetaOffset::StartBlock as u64),
+                )?;
             }
 
             trace!(?self.meta);
 
             if (self.size / BLOCK_SIZE as u64) < self.meta.disk_size as u64 {
-                warn!(parent: &self._tracing_span, "Wrong (corrupted?) disk size {} in meta block but image size is {}", self.meta.disk_size, (self.size / BLOCK_SIZE as u64));
+                inconsistency(
+                    self.parse_mode,
+                    &self._tracing_span,
+                    &mut self.diagnostics,
+                    FsError::WrongDiskSize {
+                        meta: self.meta.disk_size as u64,
+                        image: self.size / BLOCK_SIZE as u64,
+                    },
+                )?;
             }
         } else {
-            todo!("Need to Reopen");
-            // Ok(())
+            return Err(FsError::NotOpen);
         }
 
         Ok(())
@@ -438,17 +1184,30 @@ impl Fs {
     #[instrument(level = "trace", skip(self))]
     fn read_entries(&mut self) -> Result<(), FsError> {
         let tspan = self._tracing_span.clone();
+        let mode = self.parse_mode;
+        let encoding = self.encoding;
+        let layout = self.layout();
         let mut cur_pos = MetaOffset::DirEntriesStart as u64 + self.offset;
-        let mut count_all = 0;
-        let mut count_normal = 0;
-        let mut count_logical = 0;
-        let mut count_deleted = 0;
-        let mut count_bad = 0;
-        let mut used_blocks = 0;
-        let mut bad_blocks = 0;
-        let mut hole_blocks = 0;
-        let mut exists_dir_ino = HashSet::from([1u64]);
+        // в Forensic мусорные записи тоже считаем, так что счетчики
+        // бывают больше любого поля мета блока
+        let mut count_garbage = 0u64;
+        // в режиме Forensic читаем и за концом каталога
+        let mut past_end = false;
+        // в режиме scan_full_catalog уже был пустой слот
+        let mut after_hole = false;
+        let mut count_all = 0u64;
+        let mut count_normal = 0u64;
+        let mut count_logical = 0u64;
+        let mut count_deleted = 0u64;
+        let mut count_bad = 0u64;
+        let mut used_blocks = 0u64;
+        let mut bad_blocks = 0u64;
+        let mut hole_blocks = 0u64;
+        let mut exists_dir_ino = HashSet::from([ROOT_INODE]);
         if let Some(reader) = self.reader.as_mut() {
+            // каталог читается подряд по записи: один read на буфер,
+            // а не seek + read на каждые 24 байта
+            let mut reader = BufReader::new(reader);
             let _pos = reader.seek(SeekFrom::Start(cur_pos as u64))?;
             // dbg!(&pos);
             use DirEntryStatus::*;
@@ -456,14 +1215,29 @@ impl Fs {
                 let mut dentry = DirEntry::new();
                 let buf = &mut dentry.raw[..];
                 // reader.seek(SeekFrom::Start(cur_pos as u64))?;
-                let _ = reader.read(buf)?;
+                // на границе буфера запись приходит по частям
+                let _ = io::read_full(&mut reader, buf)?;
                 let mut buf = &dentry.raw[..];
 
                 let f_status = buf.get_u8();
                 let dir_no = buf.get_u8();
                 let name = buf.get(..14).unwrap();
                 if name[0] == 0u8 {
-                    break;
+                    if mode != ParseMode::Forensic && !self.scan_full_catalog {
+                        break;
+                    }
+                    // без имени записи нет, но за ней могут уцелеть старые,
+                    // а в поврежденном каталоге - и живые
+                    if self.scan_full_catalog {
+                        after_hole = true;
+                    } else {
+                        past_end = true;
+                    }
+                    cur_pos += DIR_ENTRY_SIZE as u64;
+                    if cur_pos > self.meta.start_block as u64 * BLOCK_SIZE as u64 + self.offset {
+                        break;
+                    }
+                    continue;
                 }
                 buf.advance(14);
                 let start_block = buf.get_u16_le();
@@ -472,6 +1246,18 @@ impl Fs {
                 let length = buf.get_u16_le();
 
                 let is_directory = name[0] == 0o177u8;
+                // за пустым слотом берем только правдоподобные записи,
+                // мусор пропускаем, а не обрываем на нем каталог
+                if after_hole
+                    && !plausible_entry(&layout, f_status, is_directory, start_block, blocks)
+                {
+                    trace!(parent: &tspan, "Implausible slot at {:#o} skipped", cur_pos);
+                    cur_pos += DIR_ENTRY_SIZE as u64;
+                    if cur_pos > self.meta.start_block as u64 * BLOCK_SIZE as u64 + self.offset {
+                        break;
+                    }
+                    continue;
+                }
                 let status = if is_directory {
                     dentry.is_dir = true;
                     // вот тут бля вопрос спорный, я не помню как именно удаляются каталоги
@@ -491,48 +1277,61 @@ impl Fs {
                         0 => {
                             dentry.is_normal = true;
                             count_normal += 1;
-                            used_blocks += blocks;
+                            used_blocks += blocks as u64;
                             Normal
                         }
                         1 => {
                             dentry.is_protected = true;
                             count_normal += 1;
-                            used_blocks += blocks;
+                            used_blocks += blocks as u64;
                             Protected
                         }
                         2 => {
                             dentry.is_logical = true;
                             count_normal += 1;
                             count_logical += 1;
-                            used_blocks += blocks;
+                            used_blocks += blocks as u64;
                             LogicalDisk
                         }
                         0o200 => {
                             dentry.is_bad = true;
                             count_bad += 1;
-                            bad_blocks += blocks;
+                            bad_blocks += blocks as u64;
                             BadFile
                         }
                         0o377 => {
                             dentry.is_deleted = true;
                             count_deleted += 1;
-                            hole_blocks += blocks;
+                            hole_blocks += blocks as u64;
                             Deleted
                         }
                         n => {
                             // если мы в конце каталога, то ловить уже нечего
                             // иначе проверим совсем ли это мусор
                             // или еще есть смысл сохранить эту запись
-                            if count_normal >= self.meta.files
+                            if count_normal >= self.meta.files as u64
                                 || start_block <= self.meta.start_block
                                 || start_block >= self.meta.disk_size
-                                || blocks > self.meta.disk_size - self.meta.blocks
+                                || blocks > self.meta.disk_size.saturating_sub(self.meta.blocks)
                             {
-                                dbg!(&name);
-                                break;
+                                if mode != ParseMode::Forensic {
+                                    let warning = Warning::GarbageEntry {
+                                        status: n,
+                                        offset: cur_pos,
+                                    };
+                                    warn!(parent: &tspan, "{}", warning);
+                                    self.diagnostics.push(warning);
+                                    break;
+                                }
+                                dentry.is_garbage = true;
                             }
 
-                            warn!(parent: &tspan, "Uknown Status: 0{:o}", n);
+                            inconsistency(
+                                mode,
+                                &tspan,
+                                &mut self.diagnostics,
+                                FsError::UnknownStatus(n).at(cur_pos),
+                            )?;
                             dentry.is_unknown = true;
                             Normal
                         }
@@ -542,90 +1341,164 @@ impl Fs {
 
                 // уберем из имени дирректории служебный симфол
                 let name_off = if is_directory { &name[1..] } else { &name };
-                let (cow, _encoding_used, had_errors) = KOI8_R.decode(name_off);
+                let (cow, had_errors) = encoding.decode(name_off);
                 if had_errors {
-                    warn!(
-                        parent: &tspan,
-                        "Error while recoding file name {:?}", name_off
-                    );
+                    let warning = Warning::BadName {
+                        raw: name_off.to_vec(),
+                        // имя после статуса и номера каталога, у каталога еще 0177
+                        offset: cur_pos + 2 + is_directory as u64,
+                    };
+                    warn!(parent: &tspan, "{}", warning);
+                    self.diagnostics.push(warning);
                 }
 
                 dentry.status = status;
                 dentry.dir_no = dir_no;
                 // а номер каталога 0 - это корень? будем считать, что да
                 // привязываем его к нашим виртуальным инодам, поэтому + 1
-                dentry.parent_inode = 1 + dir_no as u64;
+                dentry.parent_inode = inode::dir_inode(dir_no);
                 dentry.name = String::from(cow.trim_end());
                 // dbg!(&dentry.name);
                 dentry.start_block = start_block as u64;
                 dentry.blocks = blocks as u64;
                 dentry.start_address = start_address as u32;
                 dentry.length = length as u32;
-                // вот как здесь лучше с размером пуступить пока не знаю
-                // сделаем просто
-                // по хорошему, если размер в блоках > 128
-                // то размер должен расчитывать как blocks * 512 + length
-                // при этом в length должна быть < 512
-                // но этого вроде нет, поэтому так
-                if blocks > (u16::MAX as usize / BLOCK_SIZE + 1) as u16 {
-                    dentry.size = blocks as u32 * BLOCK_SIZE as u32;
-                } else {
-                    dentry.size = length as u32;
-                }
+                dentry.size = file_size(blocks as u64, length) as u32;
 
                 if is_directory {
-                    // dentry.inode = self.dir_inodes.fetch_add(1, Ordering::SeqCst)
                     // Изврат, МКТ в курсе :-D
-                    dentry.inode = 1 + f_status as u64;
+                    // номер каталога лежит в статусе, инод из него вычисляется
+                    dentry.inode = match self.inodes.alloc_dir(f_status) {
+                        Some((ino, false)) => ino,
+                        Some((ino, true)) => {
+                            warn!(
+                                parent: &tspan,
+                                "Directory {:?} number {} is already used, inode {} assigned",
+                                dentry.name, f_status, ino
+                            );
+                            self.diagnostics.push(Warning::DirectoryInode {
+                                name: dentry.name.clone(),
+                                number: f_status,
+                                inode: ino,
+                            });
+                            ino
+                        }
+                        None => {
+                            let ino = self.inodes.alloc_file();
+                            warn!(
+                                parent: &tspan,
+                                "No free directory inodes for {:?}, inode {} assigned",
+                                dentry.name, ino
+                            );
+                            self.diagnostics.push(Warning::DirectoryInode {
+                                name: dentry.name.clone(),
+                                number: f_status,
+                                inode: ino,
+                            });
+                            ino
+                        }
+                    };
                     exists_dir_ino.insert(dentry.inode);
                     dentry.mode = 0o755;
                 } else {
-                    dentry.inode = self.file_inodes.fetch_add(1, Ordering::SeqCst)
+                    let slot = (cur_pos - self.offset - MetaOffset::DirEntriesStart as u64)
+                        / DIR_ENTRY_SIZE as u64;
+                    dentry.inode = self.inodes.alloc_entry(&dentry, slot);
                 }
                 if dentry.is_protected {
                     dentry.mode |= 0o1000;
+                } else if !self.read_only && !is_directory {
+                    dentry.mode |= 0o200;
                 }
                 // удаленные и файлы и bad-блоки в dir_no получает 255?
                 // получается, что он по любому не попадает при поиске через
                 // entries_by_parent_inode, но мы вседа это можем подсунуть вот здесь ;)
                 // скажем плюхнуть в корень :)
-                if dentry.is_deleted || dentry.is_bad {
+                if past_end {
+                    dentry.is_garbage = true;
+                }
+                if dentry.is_deleted || dentry.is_bad || dentry.is_garbage {
                     // dbg!(&dentry);
-                    dentry.parent_inode = 1;
+                    dentry.parent_inode = ROOT_INODE;
+                }
+                if dentry.is_garbage {
+                    count_garbage += 1;
                 }
                 if dentry.is_unknown {
                     warn!(parent: &tspan, "File with unknown status {:?}", dentry);
                 }
+                // файлы не могут лежать в системной области и за концом диска
+                if !dentry.is_dir && !dentry.is_garbage && dentry.extent_blocks() != 0 {
+                    if let Err(e) = layout.check_extent(dentry.start_block, dentry.extent_blocks())
+                    {
+                        inconsistency(mode, &tspan, &mut self.diagnostics, e)?;
+                        dentry.is_corrupt = true;
+                    }
+                }
                 self.entries.push(dentry);
 
                 cur_pos += DIR_ENTRY_SIZE as u64;
                 if cur_pos > self.meta.start_block as u64 * BLOCK_SIZE as u64 + self.offset {
-                    dbg!(&cur_pos, &start_block, &self.offset);
-                    dbg!(&self.entries[self.entries.len() - 1]);
+                    let warning = Warning::CatalogOverrun {
+                        offset: cur_pos - DIR_ENTRY_SIZE as u64,
+                        start_block: self.meta.start_block as u64,
+                    };
+                    warn!(parent: &tspan, "{}", warning);
+                    self.diagnostics.push(warning);
                     break;
                 }
             }
         } else {
-            todo!("Need to Reopen");
-            // Ok(())
+            return Err(FsError::NotOpen);
         }
 
+        // файлы не могут делить блоки, достается обоим: кто из них прав, не знаем
+        for (idx, other, _) in check::find_overlaps(&self.entries) {
+            inconsistency(
+                mode,
+                &self._tracing_span,
+                &mut self.diagnostics,
+                FsError::EntriesOverlap(self.entries[idx].name.clone()),
+            )?;
+            self.entries[idx].is_corrupt = true;
+            self.entries[other].is_corrupt = true;
+        }
+        let count_corrupt = self.entries.iter().filter(|e| e.is_corrupt).count();
+
         // А теперь проверим для всех ли файлов существуют фолдеры
         // и если какого-то каталога тупо нет, то кидаем такой файл в корень
         let mut count_orphan_files = 0;
         for mut ent in self.entries.iter_mut() {
             if !exists_dir_ino.contains(&ent.parent_inode) {
                 count_orphan_files += 1;
-                dbg!(&ent.name, &ent.parent_inode);
+                let warning = Warning::OrphanFile {
+                    name: ent.name.clone(),
+                    dir_no: ent.dir_no,
+                };
+                debug!(parent: &tspan, "{}", warning);
+                self.diagnostics.push(warning);
                 // хз че за хрень, но нам подсунули сиротку, кидаем в корень
-                ent.parent_inode = 1;
+                ent.parent_inode = ROOT_INODE;
             }
         }
         if count_orphan_files != 0 {
-            warn!(parent: &self._tracing_span,
-                  "{} orphan files found",
-                  count_orphan_files
-            );
+            inconsistency(
+                mode,
+                &self._tracing_span,
+                &mut self.diagnostics,
+                FsError::OrphanFiles(count_orphan_files),
+            )?;
+        }
+
+        // каталог не может лежать сам в себе (в том числе через другие)
+        let cycles = self.break_dir_cycles();
+        for name in cycles.iter() {
+            inconsistency(
+                mode,
+                &self._tracing_span,
+                &mut self.diagnostics,
+                FsError::DirectoryCycle(name.clone()),
+            )?;
         }
 
         // trace!(parent: &self._tracing_span, "ENTRIES: {:#?}", self.entries);
@@ -633,129 +1506,486 @@ impl Fs {
             count_normal, count_deleted, count_logical, count_bad, count_all, "ENTRIES:"
         );
         // assert_eq!(self.meta.files, count_normal);
-        if count_normal != self.meta.files {
-            warn!(parent: &self._tracing_span,
-                  "Wrong files count? Meta file count is {} but {} found",
-                  self.meta.files, count_normal
-            );
+        if count_normal != self.meta.files as u64 {
+            inconsistency(
+                mode,
+                &self._tracing_span,
+                &mut self.diagnostics,
+                FsError::WrongFilesCount {
+                    meta: self.meta.files as u64,
+                    found: count_normal,
+                },
+            )?;
         }
         debug!(parent: &self._tracing_span,
                used_blocks, bad_blocks, hole_blocks, "ENTRIES:"
         );
         // assert_eq!(self.meta.blocks, used_blocks);
-        if used_blocks + self.meta.start_block != self.meta.blocks {
-            warn!(parent: &self._tracing_span,
-                  "Wrong used blocks? Meta file blocks is {} but {} found",
-                  self.meta.blocks, used_blocks + self.meta.start_block
-            );
+        let found_blocks = used_blocks + self.meta.start_block as u64;
+        if found_blocks != self.meta.blocks as u64 {
+            inconsistency(
+                mode,
+                &self._tracing_span,
+                &mut self.diagnostics,
+                FsError::WrongUsedBlocks {
+                    meta: self.meta.blocks as u64,
+                    found: found_blocks,
+                },
+            )?;
         }
 
+        self.stats = FsStats {
+            entries: count_all,
+            normal: count_normal,
+            logical: count_logical,
+            deleted: count_deleted,
+            bad: count_bad,
+            orphans: count_orphan_files,
+            dir_cycles: cycles.len() as u64,
+            garbage: count_garbage,
+            corrupt: count_corrupt as u64,
+            used_blocks,
+            bad_blocks,
+            hole_blocks,
+            inodes: self.inodes.stats(),
+        };
+
         Ok(())
     }
 
-    pub fn try_reopen(&mut self) -> Result<(), FsError> {
-        self.dir_inodes = AtomicU64::new(2);
-        self.file_inodes = AtomicU64::new(1001);
-        // Нельзя обнулять размер, если работаем по смещению
-        // смещение всегда указывается жестким размером
-        if self.offset == 0 {
-            self.size = 0;
+    pub fn last_modified(&self) -> SystemTime {
+        self.last_modified.clone()
+    }
+
+    /// Проверяет, изменился ли образ на диске, ничего не меняя в `Fs`
+    /// Нужен для вызова под read-lock, перечитываем уже через
+    /// `refresh()` под write-lock. Сам stat делается не чаще,
+    /// чем раз в check_interval, в остальное время - `false`
+    pub fn is_modified(&self) -> bool {
+        // деградировавший Fs пробуем перечитать при каждом обращении
+        if self.degraded.is_some() {
+            return true;
         }
-        self.meta = Meta::new();
-        self.entries = Vec::new();
-        // TODO: закрыть все открытые файлы
-        // но потом надо будет сделать умное закрытие
-        self.try_open()
+        // до первого открытия сравнивать не с чем
+        let Some(stamp) = self.stamp else {
+            return false;
+        };
+        if !self.check_due() {
+            return false;
+        }
+        self.path_stamp().is_some_and(|now| now != stamp)
     }
 
-    pub fn last_modified(&self) -> SystemTime {
-        self.last_modified.clone()
+    /// Файл по пути образа сейчас (по пути, а не по открытому дескриптору:
+    /// образ могли подменить переименованием), `None` если образ не файл
+    fn path_stamp(&self) -> Option<FileStamp> {
+        self.reader.as_ref()?.stamp()?;
+        FileStamp::new(&std::fs::metadata(&self.file_path).ok()?)
     }
 
-    pub fn check_modified(&mut self) -> bool {
-        let modified = if let Some(reader) = self.reader.as_ref() {
-            let inner = reader.as_ref();
-            if let Ok(m) = inner.metadata() {
-                match m.modified() {
-                    Ok(mt) => {
-                        if mt != self.last_modified {
-                            // нам не надо два раза переоткрывать образ
-                            // да да, затычка, как и весь check modfidied на
-                            // данный момент
-                            if self.last_modified != SystemTime::UNIX_EPOCH {
-                                warn!(parent: &self._tracing_span, "Disk modified {:?} -> {:?}", self.last_modified, mt);
-                                self.last_modified = mt;
-                                true
-                            } else {
-                                self.last_modified = mt;
-                                false
-                            }
-                        } else {
-                            false
-                        }
-                    }
-                    Err(_) => false,
-                }
-            } else {
-                false
+    /// Пора ли снова смотреть на время изменения образа (и отметка, что смотрим)
+    /// Если следим через notify, то пора, только когда образ трогали
+    fn check_due(&self) -> bool {
+        if self.degraded.is_some() {
+            return true;
+        }
+        // флаг сбрасываем сразу: образ трогали, но могли и не изменить
+        #[cfg(feature = "watch")]
+        if let Some(watcher) = self.watcher.as_ref() {
+            return watcher.take_changed();
+        }
+        let mut last = self
+            .last_check
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        match *last {
+            Some(t) if now.duration_since(t) < self.check_interval => false,
+            _ => {
+                *last = Some(now);
+                true
             }
-        } else {
-            todo!()
-        };
-        if modified {
-            match self.try_reopen() {
-                Ok(_) => {
-                    warn!(parent: &self._tracing_span, "Try to reopen");
-                    true
-                }
-                // TODO: закрываем все нахрен и вываливаемся с ошибкой
-                Err(e) => panic!("Can't reopen: {:?}", e),
+        }
+    }
+
+    /// Lock image file on open: shared lock read only, exclusive for writing
+    /// (used on next open, on by default). Locks are advisory, they only keep
+    /// tools that lock images from working with the same file at once
+    pub fn set_lock(&mut self, lock: bool) {
+        self.lock = lock;
+    }
+
+    pub fn lock(&self) -> bool {
+        self.lock
+    }
+
+    /// Write changes to `overlay` file instead of the image (used on next open).
+    /// Changes are applied by `Overlay::commit()` or dropped by `Overlay::discard()`
+    pub fn set_overlay(&mut self, overlay: Option<PathBuf>) {
+        self.overlay = overlay;
+    }
+
+    pub fn overlay(&self) -> Option<&Path> {
+        self.overlay.as_deref()
+    }
+
+    /// Save copy of the whole image (with changes from the overlay) to `dest`.
+    /// Changes go straight to the image, so the copy is consistent as long as
+    /// nobody writes through this `Fs` meanwhile, `&self` guarantees that.
+    /// Returns size of the copy in bytes
+    pub fn snapshot<P: AsRef<Path>>(&self, dest: P) -> Result<u64, FsError> {
+        let dest = dest.as_ref();
+        let reader = self.reader.as_ref().ok_or(FsError::NotOpen)?;
+        // сначала во временный файл: оборванная копия не займет место готовой
+        let mut partial = dest.as_os_str().to_owned();
+        partial.push(format!(".part{}", std::process::id()));
+        let partial = PathBuf::from(partial);
+        let copied = File::create(&partial).and_then(|mut out| {
+            let size = reader.copy_image_to(&mut out)?;
+            out.sync_all()?;
+            Ok(size)
+        });
+        let size = match copied {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                return Err(FsError::CustomIo {
+                    desc: format!("Can't save snapshot to {:?}", dest),
+                    source: e,
+                });
             }
+        };
+        std::fs::rename(&partial, dest)?;
+        debug!(parent: &self._tracing_span, ?dest, size, "Snapshot saved");
+        Ok(size)
+    }
+
+    fn lock_image(&self, file: &File) -> Result<(), FsError> {
+        let locked = if self.read_only || self.overlay.is_some() {
+            file.try_lock_shared()
         } else {
-            false
+            file.try_lock()
+        };
+        match locked {
+            Ok(()) => Ok(()),
+            Err(TryLockError::WouldBlock) => Err(FsError::Locked(self.file_path.clone())),
+            Err(TryLockError::Error(e)) => Err(FsError::CustomIo {
+                desc: format!("Can't lock {:?}", self.file_path),
+                source: e,
+            }),
         }
     }
 
-    pub fn entries_by_parent_inode(&mut self, parent_ino: u64) -> Vec<DirEntry> {
-        let _ = self.check_modified();
-        self.entries
-            .iter()
-            .filter(|&entry| entry.parent_inode == parent_ino)
-            // .map(|x| x.clone())
-            .cloned()
-            .collect()
+    /// Set minimal interval between image modification checks,
+    /// `Duration::ZERO` checks on every call
+    pub fn set_check_interval(&mut self, interval: Duration) {
+        self.check_interval = interval;
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        self.check_interval
+    }
+
+    /// All catalog entries including entries of opened logical disks
+    pub fn iter_all(&self) -> impl Iterator<Item = &DirEntry> {
+        self.entries.iter().chain(self.nested.iter())
+    }
+
+    /// Name of `entry` in its directory: repeated names of live entries
+    /// get suffixes (`NAME`, `NAME~1`, `NAME~2`, ...), `find_entrie()` finds them
+    pub fn entry_name<'a>(&'a self, entry: &'a DirEntry) -> &'a str {
+        self.indexed_name(entry)
+    }
+
+    /// Deleted entries with unique names (`NAME`, `NAME~1`, ...) in catalog order
+    pub fn iter_deleted(&self) -> impl Iterator<Item = (&str, &DirEntry)> {
+        self.indexed_deleted()
+    }
+
+    /// Deleted entry by unique name of `iter_deleted()`
+    pub fn find_deleted(&self, name: &str) -> Option<(&str, &DirEntry)> {
+        self.indexed_deleted_by_name(name)
+    }
+
+    /// Deleted entry `inode` with its unique name of `iter_deleted()`
+    pub fn deleted_by_inode(&self, inode: u64) -> Option<(&str, &DirEntry)> {
+        self.indexed_deleted_by_inode(inode)
+    }
+
+    /// Entries of directory `parent_inode` in catalog order
+    pub fn iter_dir(&self, parent_inode: u64) -> impl Iterator<Item = &DirEntry> {
+        self.indexed_children(parent_inode)
+    }
+
+    /// Все запросы ниже работают по `&self` и не перечитывают образ,
+    /// перед ними надо звать `check_modified()` (или `is_modified()` и `refresh()`)
+    #[deprecated(note = "use `iter_dir()`, it does not clone entries")]
+    pub fn entries_by_parent_inode(&self, parent_ino: u64) -> Vec<DirEntry> {
+        self.iter_dir(parent_ino).cloned().collect()
     }
 
     /// ищет `name` в фолдере с `parent_inode`
     /// понятно что для mkdos это нафиг не надо ибо подкаталоги
     /// просто для красоты, но если будем маскировать логические диски
     /// под каталоги, то надо будет именно так
-    pub fn find_entrie(&mut self, name: &str, parent_inode: u64) -> Option<&DirEntry> {
-        let _ = self.check_modified();
+    pub fn find_entrie(&self, name: &str, parent_inode: u64) -> Option<&DirEntry> {
         // dbg!(&self, &name, &parent_inode);
-        self.entries
-            .iter()
-            .find(|&entry| entry.parent_inode == parent_inode && entry.name == name)
+        let found = self.indexed_by_name(name, parent_inode);
+        match self.lookup_policy {
+            LookupPolicy::CaseInsensitive if found.is_none() => {
+                self.indexed_by_folded_name(name, parent_inode)
+            }
+            _ => found,
+        }
     }
 
-    pub fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
-        if let Some(reader) = self.reader.as_mut() {
-            let _pos = reader.seek(SeekFrom::Start(self.offset + offset))?;
-            reader.read(buf)
-        } else {
-            todo!()
+    /// Позиционное чтение, не двигает позицию ридера,
+    /// поэтому может вызываться параллельно из разных потоков
+    /// Идет через кэш блоков, блокировка кэша на время чтения образа не держится
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        let reader = match self.reader.as_ref() {
+            Some(reader) => reader,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    FsError::NotOpen.to_string(),
+                ))
+            }
+        };
+        let blocks = cache::block_range(offset, buf.len());
+        if !self.cache().fits(blocks.end - blocks.start) {
+            return reader.read_at(buf, self.offset + offset);
+        }
+        if let Some(size) = self.cache().read(buf, offset) {
+            return Ok(size);
+        }
+
+        let bs = BLOCK_SIZE as u64;
+        let mut data = vec![0u8; ((blocks.end - blocks.start) * bs) as usize];
+        let size = reader.read_at(&mut data, self.offset + blocks.start * bs)?;
+        data.truncate(size);
+        self.cache().insert(blocks.start, &data);
+
+        let skip = (offset - blocks.start * bs) as usize;
+        let size = std::cmp::min(buf.len(), size.saturating_sub(skip));
+        buf[..size].copy_from_slice(&data[skip..skip + size]);
+        Ok(size)
+    }
+
+    /// Check that `count` blocks from `start` lie on the disk
+    pub(crate) fn check_blocks(&self, start: u64, count: u64) -> Result<(), FsError> {
+        match start.checked_add(count) {
+            Some(end) if end <= self.disk_size() => Ok(()),
+            _ => Err(FsError::ExtentBeyondDisk {
+                start,
+                blocks: count,
+                disk_size: self.disk_size(),
+            }),
+        }
+    }
+
+    /// Read `count` raw blocks of the volume from block `start`
+    /// (system area included, block 0 is the meta block)
+    pub fn read_blocks(&self, start: u64, count: u64) -> Result<Vec<u8>, FsError> {
+        self.check_blocks(start, count)?;
+        let mut buf = vec![0u8; count as usize * BLOCK_SIZE];
+        let size = self.read_exact_at(&mut buf, start * BLOCK_SIZE as u64)?;
+        // образ может быть короче, чем записано в мета блоке
+        if size < buf.len() {
+            return Err(FsError::CustomIo {
+                desc: format!("short read of blocks {}+{}", start, count),
+                source: std::io::ErrorKind::UnexpectedEof.into(),
+            });
+        }
+        Ok(buf)
+    }
+
+    // в кэше нет инвариантов, которые могла бы сломать паника
+    pub(crate) fn cache(&self) -> MutexGuard<'_, BlockCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Set block cache size in blocks, 0 disables the cache
+    pub fn set_cache_blocks(&mut self, blocks: usize) {
+        self.cache().set_capacity(blocks);
+    }
+
+    /// Block cache size and hit statistics
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache().stats()
+    }
+
+    /// Entries marked `is_corrupt` while reading the catalog
+    pub fn corrupt_entries(&self) -> Vec<DirEntry> {
+        self.iter_all()
+            .filter(|&entry| entry.is_corrupt)
+            .cloned()
+            .collect()
+    }
+
+    pub fn entrie_by_inode(&self, inode: u64) -> Option<&DirEntry> {
+        self.indexed_by_inode(inode)
+    }
+
+    /// Whole file contents (`size` bytes from its start block)
+    pub fn read_entry_data(&self, entry: &DirEntry) -> Result<Vec<u8>, FsError> {
+        self.check_entry_extent(entry)?;
+        let mut buf = vec![0u8; entry.size as usize];
+        let size = self.read_exact_at(&mut buf, entry.start_block * BLOCK_SIZE as u64)?;
+        if size != buf.len() {
+            return Err(FsError::CustomIo {
+                desc: format!("Short read of {:?}", entry.name),
+                source: std::io::ErrorKind::UnexpectedEof.into(),
+            });
+        }
+        Ok(buf)
+    }
+
+    /// Read up to `size` bytes of file `inode` from `offset`,
+    /// result is short (or empty) at end of file
+    pub fn read_file(&self, inode: u64, offset: u64, size: usize) -> Result<Vec<u8>, FsError> {
+        let (_, size) = self.file_extent(inode, offset, size)?;
+        let mut buf = vec![0u8; size];
+        let read = self.read_file_into(inode, offset, &mut buf)?;
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    /// Like `read_file()`, but into `buf`: returns number of bytes read,
+    /// less than `buf.len()` at end of file
+    pub fn read_file_into(
+        &self,
+        inode: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError> {
+        let (start, size) = self.file_extent(inode, offset, buf.len())?;
+        if size == 0 {
+            return Ok(0);
+        }
+        if self.read_exact_at(&mut buf[..size], start)? != size {
+            return Err(short_read(self.entrie_by_inode(inode)));
+        }
+        Ok(size)
+    }
+
+    /// Смещение `offset` файла `inode` в томе и сколько байт оттуда читать
+    /// (не больше `size` и не за концом файла)
+    pub(crate) fn file_extent(
+        &self,
+        inode: u64,
+        offset: u64,
+        size: usize,
+    ) -> Result<(u64, usize), FsError> {
+        let entry = self.entrie_by_inode(inode).ok_or(FsError::NotFound)?;
+        // скрытые записи читаем только если их показывают
+        if (entry.is_deleted && !self.read_deleted) || (entry.is_bad && !self.read_bad) {
+            return Err(FsError::NotFound);
+        }
+        if entry.is_dir || entry.is_volume {
+            return Err(FsError::IsDirectory);
+        }
+        // битые записи не отдаем, там может быть чужой файл
+        if entry.is_corrupt {
+            return Err(FsError::CorruptEntry(entry.name.clone()));
+        }
+        // не отдаем байты системной области или за концом диска
+        self.check_entry_extent(entry)?;
+
+        let size = std::cmp::min(size as u64, (entry.size as u64).saturating_sub(offset)) as usize;
+        Ok((entry.start_block * BLOCK_SIZE as u64 + offset, size))
+    }
+
+    /// Allow `read_file()` for deleted files
+    pub fn set_read_deleted(&mut self, read_deleted: bool) {
+        self.read_deleted = read_deleted;
+    }
+
+    /// Allow `read_file()` for bad blocks files
+    pub fn set_read_bad(&mut self, read_bad: bool) {
+        self.read_bad = read_bad;
+    }
+
+    /// System/data area layout of the opened image
+    pub fn layout(&self) -> VolumeLayout {
+        VolumeLayout {
+            start_block: self.meta.start_block as u64,
+            disk_size: self.meta.disk_size as u64,
         }
     }
 
-    pub fn entrie_by_inode(&mut self, inode: u64) -> Option<&DirEntry> {
-        let _ = self.check_modified();
-        self.entries.iter().find(|&entry| entry.inode == inode)
+    /// Блоки, которые занимает запись, с проверкой, что они в области данных
+    /// Учитывается и длина в байтах, если она больше длины в блоках
+    pub fn check_entry_extent(&self, entry: &DirEntry) -> Result<Range<u64>, FsError> {
+        self.layout()
+            .check_extent(entry.start_block, entry.extent_blocks())
+    }
+
+    /// Warnings of the last read of the image (not fatal in current parse mode)
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+    /// Report progress of squeeze and `archive::extract_all()` to `progress`
+    pub fn set_progress(&mut self, progress: ProgressFn) {
+        self.progress = Some(progress);
+    }
+
+    pub fn clear_progress(&mut self) {
+        self.progress = None;
+    }
+
+    pub(crate) fn report_progress(&self, progress: Progress) {
+        if let Some(report) = self.progress.as_ref() {
+            report(progress);
+        }
+    }
+
+    /// Catalog and inode statistics of the opened image
+    pub fn stats(&self) -> FsStats {
+        FsStats {
+            inodes: self.inodes.stats(),
+            ..self.stats
+        }
+    }
+
+    /// Usage of the volume for statfs
+    ///
+    /// Deleted and bad files are counted as used only when they are
+    /// shown (`set_read_deleted`, `set_read_bad`), every catalog entry
+    /// (holes and bad included) takes a catalog slot
+    pub fn statvfs(&self) -> StatVfs {
+        let used_blocks = self
+            .entries
+            .iter()
+            .filter(|e| e.occupies_disk())
+            .filter(|e| (!e.is_deleted || self.read_deleted) && (!e.is_bad || self.read_bad))
+            .map(|e| e.blocks)
+            .sum::<u64>();
+        let disk_size = self.meta.disk_size as u64;
+        let system_blocks = self.meta.start_block as u64;
+        let files = self.catalog_capacity() as u64;
+        StatVfs {
+            block_size: BLOCK_SIZE as u64,
+            blocks: disk_size,
+            system_blocks,
+            used_blocks,
+            free_blocks: disk_size.saturating_sub(system_blocks + used_blocks),
+            files,
+            free_files: files.saturating_sub(self.entries.len() as u64),
+            namelen: FILE_NAME_SIZE as u32,
+        }
     }
 
     pub fn block_size(&self) -> u64 {
         BLOCK_SIZE as u64
     }
 
+    /// Volume header as read from catalog
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
     pub fn files(&self) -> u64 {
         self.meta.files as u64
     }
@@ -792,4 +2022,221 @@ impl Fs {
     pub fn set_inverted(&mut self, inverted: bool) {
         self.inverted = inverted;
     }
+
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+
+    /// Swap bytes in every 16-bit word of the image (used on next open)
+    pub fn set_swapped(&mut self, swapped: bool) {
+        self.swapped = swapped;
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        self.swapped
+    }
+
+    /// Remap blocks of side by side, sector interleaved image
+    /// (used on next open)
+    pub fn set_geometry(&mut self, geometry: Option<GeometryMapper>) {
+        self.geometry = geometry;
+    }
+
+    pub fn geometry(&self) -> Option<&GeometryMapper> {
+        self.geometry.as_ref()
+    }
+
+    /// Image file path given to `new()`
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    /// Offset from start of image in blocks
+    pub fn offset_blocks(&self) -> u64 {
+        self.offset / BLOCK_SIZE as u64
+    }
+
+    /// Size in blocks (0 - whole image)
+    pub fn size_blocks(&self) -> u64 {
+        self.size / BLOCK_SIZE as u64
+    }
+
+    /// Don't stop reading catalog at the first blank slot: scan it up to
+    /// the start block, skip blank slots and implausible entries after them
+    /// (for damaged images, used on next open). Volume is read only then:
+    /// writing would drop the slots
+    pub fn set_scan_full_catalog(&mut self, scan: bool) {
+        self.scan_full_catalog = scan;
+    }
+
+    pub fn scan_full_catalog(&self) -> bool {
+        self.scan_full_catalog
+    }
+
+    /// Set the fs's parse mode (used on next open).
+    pub fn set_parse_mode(&mut self, mode: ParseMode) {
+        self.parse_mode = mode;
+    }
+
+    pub fn parse_mode(&self) -> ParseMode {
+        self.parse_mode
+    }
+
+    /// Set the fs's file names encoding (used on next open).
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Set how file inodes are assigned, call before the first open:
+    /// with `InodeStrategy::Stable` they survive `try_reopen()`
+    pub fn set_inode_strategy(&mut self, strategy: InodeStrategy) {
+        self.inodes = InodeAllocator::with_strategy(strategy);
+    }
+
+    pub fn inode_strategy(&self) -> InodeStrategy {
+        self.inodes.strategy()
+    }
+
+    /// Set how `find_entrie()` (and so FUSE lookup) matches names
+    pub fn set_lookup_policy(&mut self, policy: LookupPolicy) {
+        self.lookup_policy = policy;
+    }
+
+    pub fn lookup_policy(&self) -> LookupPolicy {
+        self.lookup_policy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bkfs::BkFileSystem;
+
+    const K64: u64 = 0x10000;
+
+    #[test]
+    fn file_size_small() {
+        assert_eq!(file_size(1, 100), 100);
+        assert_eq!(file_size(10, 5000), 5000);
+        assert_eq!(file_size(127, 65000), 65000);
+    }
+
+    #[test]
+    fn file_size_exactly_64k() {
+        // младшие 16 бит размера 64K - ноль
+        assert_eq!(file_size(128, 0), K64);
+    }
+
+    #[test]
+    fn file_size_mkdos_over_64k() {
+        // 70000 = 0x11170, MK-DOS пишет 0x1170
+        assert_eq!(file_size(137, (70000 - K64) as u16), 70000);
+        assert_eq!(file_size(129, 1), K64 + 1);
+        assert_eq!(file_size(200, (102400 - K64) as u16), 102400);
+        assert_eq!(file_size(1600, (819200 % K64) as u16), 819200);
+    }
+
+    #[test]
+    fn file_size_tool_last_block() {
+        // длина последнего блока: 70000 - 136 * 512 = 368
+        assert_eq!(file_size(137, 368), 70000);
+        assert_eq!(file_size(200, 1), 199 * 512 + 1);
+    }
+
+    #[test]
+    fn file_size_no_blocks() {
+        assert_eq!(file_size(0, 0), 0);
+    }
+
+    #[test]
+    fn file_size_ambiguous() {
+        // 256 блоков, длина 0: 128K по MK-DOS или 127.5K с пустым
+        // последним блоком - верим MK-DOS
+        assert_eq!(file_size(256, 0), 2 * K64);
+        // в блоке за 64K оба правила дают одно и то же
+        assert_eq!(file_size(129, 100), K64 + 100);
+    }
+
+    #[test]
+    fn file_size_no_rule() {
+        // ни младшие 16 бит, ни длина последнего блока не сходятся
+        assert_eq!(file_size(137, 1000), 137 * 512);
+    }
+
+    /// Образ 800K с файлами (имя, блоков, слово длины) подряд от
+    /// DEFAULT_START_BLOCK, байт файла - номер его блока
+    fn image(files: &[(&str, u16, u16)]) -> Vec<u8> {
+        let mut data = vec![0u8; DISK_800K_BLOCKS as usize * BLOCK_SIZE];
+        let mut put =
+            |off: usize, word: u16| data[off..off + 2].copy_from_slice(&word.to_le_bytes());
+        let used: u16 = files.iter().map(|f| f.1).sum();
+        put(MetaOffset::Files as usize, files.len() as u16);
+        put(MetaOffset::Blocks as usize, DEFAULT_START_BLOCK + used);
+        put(MetaOffset::MicrodosLabel as usize, MICRODOS_LABEL);
+        put(MetaOffset::MkdosLabel as usize, MKDOS_LABEL);
+        put(MetaOffset::DiskSize as usize, DISK_800K_BLOCKS);
+        put(MetaOffset::StartBlock as usize, DEFAULT_START_BLOCK);
+        let mut start = DEFAULT_START_BLOCK;
+        for (i, (name, blocks, length)) in files.iter().enumerate() {
+            let off = MetaOffset::DirEntriesStart as usize + i * DIR_ENTRY_SIZE;
+            let mut raw = [b' '; FILE_NAME_SIZE];
+            raw[..name.len()].copy_from_slice(name.as_bytes());
+            data[off + 2..off + 2 + FILE_NAME_SIZE].copy_from_slice(&raw);
+            let words = [start, *blocks, 0o1000, *length];
+            for (j, word) in words.iter().enumerate() {
+                let pos = off + 2 + FILE_NAME_SIZE + j * 2;
+                data[pos..pos + 2].copy_from_slice(&word.to_le_bytes());
+            }
+            for block in start..start + blocks {
+                let pos = block as usize * BLOCK_SIZE;
+                data[pos..pos + BLOCK_SIZE].fill(block as u8);
+            }
+            start += blocks;
+        }
+        data
+    }
+
+    #[test]
+    fn read_file_over_64k() {
+        // BkFileSystem (и FUSE поверх него) работает с образом в файле
+        let path = std::env::temp_dir().join(format!("mkdosfs-64k-{}.img", std::process::id()));
+        std::fs::write(
+            &path,
+            image(&[
+                ("MKDOS.BIN", 137, (70000 - K64) as u16),
+                ("TOOL.BIN", 137, 368),
+            ]),
+        )
+        .unwrap();
+        let mut fs = Fs::new(path.to_str().unwrap());
+        fs.set_read_only(true);
+        let opened = fs.try_open();
+        let _ = std::fs::remove_file(&path);
+        opened.unwrap();
+        for name in ["MKDOS.BIN", "TOOL.BIN"] {
+            let entry = fs.iter_all().find(|e| e.name == name).unwrap();
+            assert_eq!(entry.size, 70000, "{name}");
+            let inode = entry.inode;
+            let last = (entry.start_block + entry.blocks - 1) as u8;
+
+            let data = fs.read_file(inode, 0, 100_000).unwrap();
+            assert_eq!(data.len(), 70000, "{name}");
+            assert_eq!(data[0], entry.start_block as u8);
+            assert_eq!(data[69999], last);
+            let tail = fs.read_file(inode, 69990, 4096).unwrap();
+            assert_eq!(tail.len(), 10, "{name}");
+            assert!(fs.read_file(inode, 70000, 4096).unwrap().is_empty());
+
+            // то же видит FUSE через BkFileSystem
+            assert_eq!(fs.metadata(inode).unwrap().size, 70000, "{name}");
+            assert_eq!(
+                BkFileSystem::read(&fs, inode, 65530, 8192).unwrap().len(),
+                4470
+            );
+        }
+    }
 }
@@ -0,0 +1,91 @@
+//! Логические диски как каталоги
+//!
+//! Запись со статусом LogicalDisk - это вложенный образ MK-DOS, лежащий
+//! с `start_block`. Его каталог читается отдельным `Fs` и подклеивается
+//! к нашему: корень вложенного диска - это сама запись логического диска,
+//! остальные записи получают свободные иноды, а `start_block` пересчитывается
+//! от начала нашего тома, так что читать их можно как обычные файлы.
+//! Вложенные записи только для чтения, вложенность одна (логический диск
+//! внутри логического диска остается файлом).
+
+use std::collections::HashMap;
+
+use tracing::warn;
+
+use crate::{inode::ROOT_INODE, DirEntry, Fs, FsError, Warning, BLOCK_SIZE};
+
+impl Fs {
+    /// Show logical disks as directories with their catalogs (used on next open)
+    pub fn set_open_logical(&mut self, open_logical: bool) {
+        self.open_logical = open_logical;
+    }
+
+    pub fn open_logical(&self) -> bool {
+        self.open_logical
+    }
+
+    /// Читает каталоги всех логических дисков, битые остаются файлами
+    pub(crate) fn open_logical_disks(&mut self) {
+        self.nested = Vec::new();
+        if !self.open_logical {
+            return;
+        }
+        for idx in 0..self.entries.len() {
+            let entry = &self.entries[idx];
+            if !entry.is_logical || entry.is_deleted || entry.is_garbage {
+                continue;
+            }
+            match self.read_logical_disk(entry) {
+                Ok(inner) => {
+                    let entry = &mut self.entries[idx];
+                    entry.is_volume = true;
+                    entry.mode = 0o555;
+                    let ld_inode = entry.inode;
+                    let base = entry.start_block;
+                    self.attach_nested(ld_inode, base, inner);
+                }
+                Err(e) => {
+                    let warning = Warning::LogicalDisk {
+                        name: entry.name.clone(),
+                        error: e.into(),
+                    };
+                    warn!(parent: &self._tracing_span, "{}", warning);
+                    self.diagnostics.push(warning);
+                }
+            }
+        }
+        self.rebuild_index();
+    }
+
+    fn read_logical_disk(&self, entry: &DirEntry) -> Result<Vec<DirEntry>, FsError> {
+        let mut inner = Fs::new(&self.file_path);
+        // образ уже заблокирован нами же
+        inner.set_lock(false);
+        inner.set_overlay(self.overlay.clone());
+        inner.set_offset(self.offset + entry.start_block * BLOCK_SIZE as u64);
+        inner.set_size(entry.blocks * BLOCK_SIZE as u64);
+        inner.set_inverted(self.inverted);
+        inner.set_swapped(self.swapped);
+        inner.set_geometry(self.geometry.clone());
+        inner.set_encoding(self.encoding);
+        inner.set_parse_mode(self.parse_mode);
+        inner.try_open()?;
+        Ok(inner.entries)
+    }
+
+    /// Подклеивает записи вложенного диска под инод `ld_inode`
+    fn attach_nested(&mut self, ld_inode: u64, base: u64, inner: Vec<DirEntry>) {
+        let mut inodes = HashMap::from([(ROOT_INODE, ld_inode)]);
+        for e in inner.iter() {
+            inodes.insert(e.inode, self.inodes.alloc_file());
+        }
+        for mut e in inner {
+            e.inode = inodes[&e.inode];
+            e.parent_inode = inodes.get(&e.parent_inode).copied().unwrap_or(ld_inode);
+            e.start_block += base;
+            // писать во вложенный диск не даем
+            e.mode &= !0o222;
+            self.nested.push(e);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Копирование при записи: изменения образа в отдельном файле
+//!
+//! С `Fs::set_overlay()` образ открывается только на чтение, а все записи
+//! идут в файл изменений: блок 0 - заголовок, за ним битовая карта
+//! измененных блоков образа, дальше место под каждый блок образа по его
+//! номеру. Файл разреженный, на диске занимают место только записанные
+//! блоки. При чтении измененные блоки берутся из него. `Overlay::commit()`
+//! переносит изменения в образ, `Overlay::discard()` их выбрасывает.
+//!
+//! Блоки считаются от начала файла образа (до окна раздела и инверсии),
+//! в файле изменений лежат байты как в образе.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{
+    io::{file_write_all_at as write_all_at, ReadAt},
+    FsError, BLOCK_SIZE,
+};
+
+const MAGIC: [u8; 8] = *b"BKCOW\0\x01\0";
+/// Заголовок: MAGIC и размер образа (u64 LE)
+const HEADER_SIZE: u64 = BLOCK_SIZE as u64;
+const BS: u64 = BLOCK_SIZE as u64;
+
+/// Changes of image kept in a side file, the image itself is not written
+pub struct Overlay {
+    path: PathBuf,
+    file: File,
+    /// size of the image in bytes
+    size: u64,
+    /// бит на блок образа, 1 - блок лежит в файле изменений
+    bitmap: Mutex<Vec<u8>>,
+}
+
+impl std::fmt::Debug for Overlay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Overlay")
+            .field("path", &self.path)
+            .field("size", &self.size)
+            .field("changed_blocks", &self.changed_blocks())
+            .finish()
+    }
+}
+
+/// Размер битовой карты в байтах, целыми блоками
+fn bitmap_len(size: u64) -> u64 {
+    size.div_ceil(BS).div_ceil(8).div_ceil(BS) * BS
+}
+
+/// Блоки, которые задевает `len` байт с `offset`: номер и диапазон в блоке
+fn block_pieces(offset: u64, len: usize) -> impl Iterator<Item = (u64, Range<usize>)> {
+    let end = offset + len as u64;
+    (offset / BS..end.div_ceil(BS)).map(move |block| {
+        let from = std::cmp::max(offset, block * BS);
+        let to = std::cmp::min(end, (block + 1) * BS);
+        (
+            block,
+            (from - block * BS) as usize..(to - block * BS) as usize,
+        )
+    })
+}
+
+fn bad_overlay(path: &Path, desc: &str) -> FsError {
+    FsError::BadOverlay(format!("{}: {}", path.display(), desc))
+}
+
+/// Размер образа из заголовка файла изменений
+fn read_header(path: &Path, file: &File) -> Result<u64, FsError> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    file.read_exact_at(&mut header, 0)
+        .map_err(|_| bad_overlay(path, "no header"))?;
+    if header[..8] != MAGIC {
+        return Err(bad_overlay(path, "not an overlay file"));
+    }
+    Ok(u64::from_le_bytes(header[8..16].try_into().unwrap()))
+}
+
+impl Overlay {
+    /// Open changes of image of `size` bytes, new empty overlay is created
+    /// if there is no file at `path`
+    pub fn open<P: AsRef<Path>>(path: P, size: u64) -> Result<Self, FsError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let bitmap_size = bitmap_len(size);
+        if file.metadata()?.len() == 0 {
+            let mut header = [0u8; HEADER_SIZE as usize];
+            header[..8].copy_from_slice(&MAGIC);
+            header[8..16].copy_from_slice(&size.to_le_bytes());
+            write_all_at(&file, &header, 0)?;
+            file.set_len(HEADER_SIZE + bitmap_size)?;
+            file.sync_all()?;
+        } else {
+            let stored = read_header(path, &file)?;
+            if stored != size {
+                return Err(bad_overlay(
+                    path,
+                    &format!("made for image of {} bytes, not {}", stored, size),
+                ));
+            }
+        }
+        let mut bitmap = vec![0u8; bitmap_size as usize];
+        file.read_exact_at(&mut bitmap, HEADER_SIZE)
+            .map_err(|_| bad_overlay(path, "truncated bitmap"))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            bitmap: Mutex::new(bitmap),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size of the image the overlay is made for
+    pub fn image_size(&self) -> u64 {
+        self.size
+    }
+
+    // в карте нет инвариантов, которые могла бы сломать паника
+    fn bitmap(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.bitmap.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Number of changed blocks of the image
+    pub fn changed_blocks(&self) -> u64 {
+        self.bitmap()
+            .iter()
+            .map(|byte| byte.count_ones() as u64)
+            .sum()
+    }
+
+    fn is_changed(bitmap: &[u8], block: u64) -> bool {
+        bitmap[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    fn data_offset(&self, block: u64) -> u64 {
+        HEADER_SIZE + bitmap_len(self.size) + block * BS
+    }
+
+    /// Измененные блоки поверх `buf`, прочитанного из образа с `offset`
+    pub(crate) fn patch(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let bitmap = self.bitmap();
+        for (block, range) in block_pieces(offset, buf.len()) {
+            if !Self::is_changed(&bitmap, block) {
+                continue;
+            }
+            let at = (block * BS + range.start as u64 - offset) as usize;
+            let len = range.len();
+            self.file.read_exact_at(
+                &mut buf[at..at + len],
+                self.data_offset(block) + range.start as u64,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Запись `buf` с `offset` образа. Недописанные блоки сначала
+    /// копируются из образа через `read_image`
+    pub(crate) fn write_at<F>(&self, buf: &[u8], offset: u64, read_image: F) -> io::Result<()>
+    where
+        F: Fn(&mut [u8], u64) -> io::Result<usize>,
+    {
+        if offset + buf.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past end of image in overlay",
+            ));
+        }
+        let mut bitmap = self.bitmap();
+        for (block, range) in block_pieces(offset, buf.len()) {
+            let at = (block * BS + range.start as u64 - offset) as usize;
+            let piece = &buf[at..at + range.len()];
+            if Self::is_changed(&bitmap, block) || range.len() == BLOCK_SIZE {
+                write_all_at(
+                    &self.file,
+                    piece,
+                    self.data_offset(block) + range.start as u64,
+                )?;
+            } else {
+                // последний блок образа бывает неполным
+                let mut data = vec![0u8; std::cmp::min(BS, self.size - block * BS) as usize];
+                let mut done = 0;
+                while done < data.len() {
+                    match read_image(&mut data[done..], block * BS + done as u64)? {
+                        0 => break,
+                        n => done += n,
+                    }
+                }
+                data[range].copy_from_slice(piece);
+                write_all_at(&self.file, &data, self.data_offset(block))?;
+            }
+            let idx = (block / 8) as usize;
+            if !Self::is_changed(&bitmap, block) {
+                bitmap[idx] |= 1 << (block % 8);
+                // карту пишем после данных: упали между ними - блока просто нет
+                write_all_at(&self.file, &bitmap[idx..idx + 1], HEADER_SIZE + idx as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Write changed blocks into `image` and remove the overlay file.
+    /// Returns number of written blocks
+    pub fn commit<P: AsRef<Path>>(self, image: P) -> Result<u64, FsError> {
+        let image = image.as_ref();
+        let out = OpenOptions::new().write(true).open(image)?;
+        if out.try_lock().is_err() {
+            return Err(FsError::Locked(image.display().to_string()));
+        }
+        let size = out.metadata()?.len();
+        if size != self.size {
+            return Err(bad_overlay(
+                &self.path,
+                &format!("made for image of {} bytes, not {}", self.size, size),
+            ));
+        }
+        let mut written = 0;
+        let bitmap = self.bitmap().clone();
+        let mut data = [0u8; BLOCK_SIZE];
+        for block in 0..self.size.div_ceil(BS) {
+            if !Self::is_changed(&bitmap, block) {
+                continue;
+            }
+            let len = std::cmp::min(BS, self.size - block * BS) as usize;
+            self.file
+                .read_exact_at(&mut data[..len], self.data_offset(block))?;
+            write_all_at(&out, &data[..len], block * BS)?;
+            written += 1;
+        }
+        out.sync_all()?;
+        fs::remove_file(&self.path)?;
+        Ok(written)
+    }
+
+    /// Remove overlay file at `path` dropping its changes.
+    /// Returns number of dropped blocks
+    pub fn discard<P: AsRef<Path>>(path: P) -> Result<u64, FsError> {
+        let blocks = Self::open_existing(path.as_ref())?.changed_blocks();
+        fs::remove_file(path)?;
+        Ok(blocks)
+    }
+
+    /// Open existing overlay at `path` with image size from its header
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, FsError> {
+        let path = path.as_ref();
+        let size = read_header(path, &File::open(path)?)?;
+        Self::open(path, size)
+    }
+}
@@ -0,0 +1,459 @@
+//! RT-11: файловая система ДВК и разделов винчестера БК
+//!
+//! В блоке 1 (home block) номер блока первого сегмента каталога, сегменты
+//! по два блока связаны в список. Каждый сегмент начинается с блока данных
+//! своей первой записи, файлы непрерывные и идут подряд, так что начало
+//! файла - сумма длин предыдущих записей сегмента (включая пустые).
+//! Имена в RAD50 (6 + 3 символа), подкаталогов нет. Только чтение.
+
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{Read, Seek},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use time::{Date, Month};
+use tracing::{instrument, warn};
+
+use crate::{
+    inode::ROOT_INODE,
+    io::{Reader, ReaderBuilder},
+    FsError, GeometryMapper, BLOCK_SIZE,
+};
+
+pub const RT11_HOME_BLOCK: u64 = 1;
+/// Сегмент каталога - два блока
+pub const RT11_SEGMENT_SIZE: usize = 2 * BLOCK_SIZE;
+const RT11_HEADER_WORDS: usize = 5;
+const RT11_ENTRY_WORDS: usize = 7;
+/// RT-11 V5 допускает не больше 31 сегмента
+const RT11_MAX_SEGMENTS: u16 = 31;
+
+/// 724 - номер блока первого сегмента каталога
+const HOME_FIRST_SEGMENT: usize = 0o724;
+/// 760 - идентификатор системы, "DECRT11A    "
+const HOME_SYSTEM_ID: usize = 0o760;
+
+const E_TENT: u16 = 0o400;
+const E_MPTY: u16 = 0o1000;
+const E_PERM: u16 = 0o2000;
+const E_EOS: u16 = 0o4000;
+const E_PROT: u16 = 0o100000;
+
+const RAD50: &[u8; 40] = b" ABCDEFGHIJKLMNOPQRSTUVWXYZ$.%0123456789";
+
+/// Decode one RAD50 word (3 characters)
+pub fn rad50_decode(word: u16) -> [u8; 3] {
+    let char = |n: u16| RAD50.get(n as usize).copied().unwrap_or(b'?');
+    [char(word / 1600), char(word / 40 % 40), char(word % 40)]
+}
+
+/// Дата как `1986-03-15`, а не кортеж из time
+#[cfg(feature = "serde")]
+mod iso_date {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use time::{Date, Month};
+
+    pub fn serialize<S: Serializer>(date: &Option<Date>, serializer: S) -> Result<S::Ok, S::Error> {
+        match date {
+            Some(date) => serializer.collect_str(date),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Date>, D::Error> {
+        let text = match Option::<String>::deserialize(deserializer)? {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        let invalid = || de::Error::invalid_value(de::Unexpected::Str(&text), &"YYYY-MM-DD date");
+        let mut parts = text.splitn(3, '-').map(str::parse::<i32>);
+        let (year, month, day) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) => (year, month, day),
+            _ => return Err(invalid()),
+        };
+        let month = Month::try_from(month as u8).map_err(|_| invalid())?;
+        Date::from_calendar_date(year, month, day as u8)
+            .map(Some)
+            .map_err(|_| invalid())
+    }
+}
+
+/// RT-11 catalog entry (permanent file)
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rt11Entry {
+    /// `NAME.TYP`
+    pub name: String,
+    /// virtual inode, files are numbered in catalog order after `ROOT_INODE`
+    pub inode: u64,
+    /// always `ROOT_INODE`, RT-11 has no directories
+    pub parent_inode: u64,
+    pub start_block: u64,
+    pub blocks: u64,
+    pub is_protected: bool,
+    /// creation date, `None` if not set
+    #[cfg_attr(feature = "serde", serde(with = "iso_date"))]
+    pub date: Option<Date>,
+    /// unix mode
+    pub mode: u16,
+}
+
+impl Rt11Entry {
+    /// Size in bytes (RT-11 knows length only in blocks)
+    pub fn size(&self) -> u64 {
+        self.blocks * BLOCK_SIZE as u64
+    }
+}
+
+/// Дата RT-11: месяц в битах 10-13, день 5-9, год-1972 в 0-4,
+/// в битах 14-15 (V5.5) - сколько раз по 32 года добавить
+fn decode_date(word: u16) -> Option<Date> {
+    if word == 0 {
+        return None;
+    }
+    let month = Month::try_from(((word >> 10) & 0o17) as u8).ok()?;
+    let day = ((word >> 5) & 0o37) as u8;
+    let year = 1972 + (word & 0o37) as i32 + 32 * (word >> 14) as i32;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// RT-11 volume over image backend `R` (file by default)
+pub struct Rt11Fs<R = File> {
+    /// path to image
+    file_path: String,
+    reader: Option<Reader<R>>,
+    /// offset from start of image in bytes
+    offset: u64,
+    /// size of volume in bytes, 0 - whole image
+    size: u64,
+    inverted: bool,
+    /// bytes in 16-bit words are swapped
+    swapped: bool,
+    /// block remapping of side by side dumps
+    geometry: Option<GeometryMapper>,
+    last_modified: SystemTime,
+    /// first block after catalog data (end of last segment)
+    used_end: u64,
+    /// blocks of empty entries
+    free_blocks: u64,
+    entries: Vec<Rt11Entry>,
+    _tracing_span: tracing::Span,
+}
+
+impl<R> Debug for Rt11Fs<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rt11Fs")
+            .field("file_name", &self.file_path)
+            .field("offset", &self.offset)
+            .field("size", &self.size)
+            .field("inverted", &self.inverted)
+            .field("swapped", &self.swapped)
+            .field("geometry", &self.geometry)
+            .field("free_blocks", &self.free_blocks)
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+impl<R> Default for Rt11Fs<R> {
+    fn default() -> Self {
+        Self {
+            file_path: String::default(),
+            reader: None,
+            offset: 0,
+            size: 0,
+            inverted: false,
+            swapped: false,
+            geometry: None,
+            last_modified: SystemTime::UNIX_EPOCH,
+            used_end: 0,
+            free_blocks: 0,
+            entries: Vec::new(),
+            _tracing_span: tracing::span!(tracing::Level::TRACE, "Rt11Fs"),
+        }
+    }
+}
+
+impl Rt11Fs {
+    pub fn new(fname: &str) -> Self {
+        Self {
+            file_path: fname.into(),
+            ..Default::default()
+        }
+    }
+
+    #[instrument(level = "trace", skip(self), fields(file_path, ?self.file_path))]
+    pub fn try_open(&mut self) -> Result<(), FsError> {
+        let fname = PathBuf::new().join(&self.file_path);
+        let h = OpenOptions::new()
+            .read(true)
+            .open(&fname)
+            .map_err(|e| FsError::CustomIo {
+                desc: format!("Can't open {:?}", &fname),
+                source: e,
+            })?;
+        let reader = ReaderBuilder::with_flags(self.inverted, self.swapped)
+            .with_geometry(self.geometry.as_ref())
+            .build_file(h)?;
+        self.open_reader(reader)
+    }
+
+    /// Open partition `n` of HDD image (raw or HDI) on next open
+    pub fn set_partition(&mut self, n: usize) -> Result<(), FsError> {
+        let loc = crate::hdi::partition_location(&self.file_path, n)?;
+        self.set_offset_blocks(loc.offset);
+        self.set_size_blocks(loc.size);
+        self.set_inverted(loc.inverted);
+        Ok(())
+    }
+}
+
+impl<R> Rt11Fs<R>
+where
+    R: Read + Seek,
+{
+    /// Open RT-11 volume from any `Read + Seek` source with default settings
+    pub fn from_reader(reader: R) -> Result<Self, FsError> {
+        let mut fs = Self::default();
+        fs.try_open_reader(reader)?;
+        Ok(fs)
+    }
+
+    /// Like `try_open()`, but reads image from `reader`
+    pub fn try_open_reader(&mut self, reader: R) -> Result<(), FsError> {
+        let reader = ReaderBuilder::with_flags(self.inverted, self.swapped)
+            .with_geometry(self.geometry.as_ref())
+            .build(reader);
+        self.open_reader(reader)
+    }
+
+    fn open_reader(&mut self, reader: Reader<R>) -> Result<(), FsError> {
+        if self.size == 0 {
+            if self.offset != 0 {
+                return Err(FsError::UnknownSize);
+            }
+            self.size = reader.size()?;
+        }
+        if let Some(mt) = reader.modified() {
+            self.last_modified = mt;
+        }
+        self.reader = Some(reader);
+        self.entries = Vec::new();
+        self.free_blocks = 0;
+        self.used_end = 0;
+
+        let mut home = vec![0u8; BLOCK_SIZE];
+        self.read_full(&mut home, RT11_HOME_BLOCK * BLOCK_SIZE as u64)?;
+        if &home[HOME_SYSTEM_ID..HOME_SYSTEM_ID + 8] != b"DECRT11A" {
+            // не все утилиты пишут идентификатор, дальше проверяем каталог
+            warn!(parent: &self._tracing_span, "No DECRT11A system id in home block");
+        }
+        let first = u16::from_le_bytes([home[HOME_FIRST_SEGMENT], home[HOME_FIRST_SEGMENT + 1]]);
+        if first < 2 || first as u64 >= self.size_blocks() {
+            return Err(FsError::BadRt11(format!(
+                "first segment at block {}",
+                first
+            )));
+        }
+        self.read_directory(first as u64)
+    }
+
+    fn read_full(&self, buf: &mut [u8], offset: u64) -> Result<(), FsError> {
+        let size = self.read_exact_at(buf, offset)?;
+        if size != buf.len() {
+            return Err(FsError::CustomIo {
+                desc: format!("Short read at {}", offset),
+                source: std::io::ErrorKind::UnexpectedEof.into(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Позиционное чтение от начала тома
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+        match self.reader.as_ref() {
+            Some(reader) => reader.read_at(buf, self.offset + offset),
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn read_directory(&mut self, first: u64) -> Result<(), FsError> {
+        let mut segment = 1u16;
+        let mut total = None;
+        let mut seen = HashSet::new();
+        while segment != 0 {
+            if !seen.insert(segment) || segment > total.unwrap_or(RT11_MAX_SEGMENTS) {
+                return Err(FsError::BadRt11(format!("bad segment link {}", segment)));
+            }
+            let mut raw = vec![0u8; RT11_SEGMENT_SIZE];
+            let block = first + (segment as u64 - 1) * 2;
+            self.read_full(&mut raw, block * BLOCK_SIZE as u64)?;
+            let words: Vec<u16> = raw
+                .chunks_exact(2)
+                .map(|w| u16::from_le_bytes([w[0], w[1]]))
+                .collect();
+
+            // заголовок: всего сегментов, следующий, старший занятый,
+            // доп. байт в записи, блок данных первой записи
+            if total.is_none() {
+                if words[0] == 0 || words[0] > RT11_MAX_SEGMENTS || words[3] & 1 != 0 {
+                    return Err(FsError::BadRt11(format!(
+                        "bad directory header {:o} {:o}",
+                        words[0], words[3]
+                    )));
+                }
+                total = Some(words[0]);
+            }
+            let next = words[1];
+            let entry_words = RT11_ENTRY_WORDS + words[3] as usize / 2;
+            let mut start = words[4] as u64;
+
+            let mut pos = RT11_HEADER_WORDS;
+            while pos + RT11_ENTRY_WORDS <= words.len() {
+                let e = &words[pos..pos + RT11_ENTRY_WORDS];
+                pos += entry_words;
+                let status = e[0];
+                if status & E_EOS != 0 {
+                    break;
+                }
+                let blocks = e[4] as u64;
+                if status & E_MPTY != 0 {
+                    self.free_blocks += blocks;
+                } else if status & E_PERM != 0 && status & E_TENT == 0 {
+                    self.push_entry(status, &e[1..4], start, blocks, e[6]);
+                }
+                start += blocks;
+            }
+            self.used_end = std::cmp::max(self.used_end, start);
+            segment = next;
+        }
+        Ok(())
+    }
+
+    fn push_entry(&mut self, status: u16, name: &[u16], start_block: u64, blocks: u64, date: u16) {
+        let mut raw = Vec::with_capacity(9);
+        name.iter().for_each(|&w| raw.extend(rad50_decode(w)));
+        let base = String::from_utf8_lossy(&raw[..6]).trim_end().to_string();
+        let typ = String::from_utf8_lossy(&raw[6..]).trim_end().to_string();
+        let name = if typ.is_empty() {
+            base
+        } else {
+            format!("{}.{}", base, typ)
+        };
+
+        let is_protected = status & E_PROT != 0;
+        if start_block + blocks > self.size_blocks() {
+            warn!(parent: &self._tracing_span, "{:?} is beyond volume end", name);
+        }
+        self.entries.push(Rt11Entry {
+            name,
+            inode: ROOT_INODE + 1 + self.entries.len() as u64,
+            parent_inode: ROOT_INODE,
+            start_block,
+            blocks,
+            is_protected,
+            date: decode_date(date),
+            mode: if is_protected { 0o444 } else { 0o644 },
+        });
+    }
+
+    /// Read up to `size` bytes of file `inode` from `offset`,
+    /// result is short (or empty) at end of file
+    pub fn read_file(&self, inode: u64, offset: u64, size: usize) -> Result<Vec<u8>, FsError> {
+        let entry = self.entrie_by_inode(inode).ok_or(FsError::NotFound)?;
+        if entry.start_block + entry.blocks > self.size_blocks() {
+            return Err(FsError::ExtentBeyondDisk {
+                start: entry.start_block,
+                blocks: entry.blocks,
+                disk_size: self.size_blocks(),
+            });
+        }
+        let size = std::cmp::min(size as u64, entry.size().saturating_sub(offset)) as usize;
+        let mut buf = vec![0u8; size];
+        self.read_full(&mut buf, entry.start_block * BLOCK_SIZE as u64 + offset)?;
+        Ok(buf)
+    }
+}
+
+impl<R> Rt11Fs<R> {
+    /// All files of the volume
+    pub fn iter_all(&self) -> impl Iterator<Item = &Rt11Entry> {
+        self.entries.iter()
+    }
+
+    /// Files of directory `parent_inode` (only root has them)
+    pub fn iter_dir(&self, parent_inode: u64) -> impl Iterator<Item = &Rt11Entry> {
+        self.entries
+            .iter()
+            .filter(move |e| e.parent_inode == parent_inode)
+    }
+
+    pub fn entrie_by_inode(&self, inode: u64) -> Option<&Rt11Entry> {
+        let idx = inode.checked_sub(ROOT_INODE + 1)?;
+        self.entries.get(idx as usize)
+    }
+
+    /// ищет `name` в каталоге с `parent_inode`, RAD50 - только заглавные
+    pub fn find_entrie(&self, name: &str, parent_inode: u64) -> Option<&Rt11Entry> {
+        let name = name.to_uppercase();
+        self.iter_dir(parent_inode).find(|e| e.name == name)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        BLOCK_SIZE as u64
+    }
+
+    /// Volume size in blocks
+    pub fn size_blocks(&self) -> u64 {
+        self.size / BLOCK_SIZE as u64
+    }
+
+    /// Disk size in blocks as described by catalog (or volume size, if smaller)
+    pub fn disk_size(&self) -> u64 {
+        std::cmp::min(self.used_end, self.size_blocks())
+    }
+
+    /// Blocks of empty catalog entries
+    pub fn free_blocks(&self) -> u64 {
+        self.free_blocks
+    }
+
+    pub fn files(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn last_modified(&self) -> SystemTime {
+        self.last_modified
+    }
+
+    pub fn set_offset_blocks(&mut self, offset: u64) {
+        self.offset = offset * BLOCK_SIZE as u64;
+    }
+
+    pub fn set_size_blocks(&mut self, size: u64) {
+        self.size = size * BLOCK_SIZE as u64;
+    }
+
+    pub fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
+    }
+
+    /// Swap bytes in every 16-bit word of the image (used on next open)
+    pub fn set_swapped(&mut self, swapped: bool) {
+        self.swapped = swapped;
+    }
+
+    /// Remap blocks of side by side, sector interleaved image
+    /// (used on next open)
+    pub fn set_geometry(&mut self, geometry: Option<GeometryMapper>) {
+        self.geometry = geometry;
+    }
+}
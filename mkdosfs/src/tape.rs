@@ -0,0 +1,236 @@
+//! Файлы с магнитофона БК: `.bin` с заголовком и `.tap`
+//!
+//! На ленте файл идет заголовком (адрес загрузки, длина, имя в 16 байт
+//! KOI-8), за ним данные и контрольная сумма: слово, сумма байтов данных с
+//! переносом, прибавленным обратно (ADD + ADC, как считает монитор).
+//! Эмуляторы сохраняют такие файлы по-разному:
+//!
+//! - `.bin`: адрес и длина, за ними данные (`BinHeader`). Бывает и с именем
+//!   после длины, и с контрольной суммой в конце - видно по размеру файла;
+//! - `.tap`: записи с ленты подряд, каждая целиком: адрес, длина, имя,
+//!   данные и контрольная сумма. На одной ленте бывает несколько файлов.
+//!
+//! Запись звука (`.wav`) не разбирается, ее сначала переводят в `.tap`.
+
+use std::{fs, path::Path, str::FromStr};
+
+use bkfs::{BinHeader, BIN_HEADER_SIZE};
+use tracing::debug;
+
+use crate::{truncate_name, DirEntry, Encoding, Fs, FsError};
+
+/// Size of file name in tape header
+pub const TAPE_NAME_SIZE: usize = 16;
+const CHECKSUM_SIZE: usize = 2;
+/// Адрес, длина и имя
+const TAPE_HEADER_SIZE: usize = BIN_HEADER_SIZE as usize + TAPE_NAME_SIZE;
+
+/// Container of tape files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeFormat {
+    /// one file with `.bin` header, optional name and checksum
+    Bin,
+    /// tape records one after another
+    Tap,
+}
+
+impl TapeFormat {
+    /// Format by file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        ext.parse().ok()
+    }
+}
+
+impl FromStr for TapeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bin" => Ok(Self::Bin),
+            "tap" => Ok(Self::Tap),
+            _ => Err(format!("unknown tape format {:?}", s)),
+        }
+    }
+}
+
+/// File read from tape container
+#[derive(Debug, Clone)]
+pub struct TapeFile {
+    /// name from tape header, `None` if the container has no name
+    pub name: Option<String>,
+    pub start_address: u16,
+    pub data: Vec<u8>,
+    /// stored and computed checksum if they differ
+    pub checksum_mismatch: Option<(u16, u16)>,
+}
+
+/// Checksum of data on BK tape: sum of bytes with end-around carry
+pub fn tape_checksum(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |sum, &byte| {
+        let (sum, carry) = sum.overflowing_add(u16::from(byte));
+        sum + u16::from(carry)
+    })
+}
+
+fn word(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+/// Имя из заголовка без хвостовых пробелов и нулей, `None` если пустое
+fn tape_name(raw: &[u8], encoding: Encoding) -> Option<String> {
+    let len = raw
+        .iter()
+        .rposition(|&b| b != b' ' && b != 0)
+        .map_or(0, |pos| pos + 1);
+    if len == 0 {
+        return None;
+    }
+    let (name, _had_errors) = encoding.decode(&raw[..len]);
+    Some(name.into_owned())
+}
+
+fn checksum_mismatch(data: &[u8], stored: u16) -> Option<(u16, u16)> {
+    let computed = tape_checksum(data);
+    (stored != computed).then_some((stored, computed))
+}
+
+/// Parse `.bin` file: header, optional name, data, optional checksum
+pub fn parse_bin(data: &[u8], encoding: Encoding) -> Result<TapeFile, FsError> {
+    if let Some(header) = BinHeader::parse(data) {
+        return Ok(TapeFile {
+            name: None,
+            start_address: header.start_address,
+            data: data[BIN_HEADER_SIZE as usize..].to_vec(),
+            checksum_mismatch: None,
+        });
+    }
+    if data.len() < BIN_HEADER_SIZE as usize {
+        return Err(FsError::BadTape(format!(
+            "{} bytes is too short for .bin header",
+            data.len()
+        )));
+    }
+    let start_address = word(data, 0);
+    let length = word(data, 2) as usize;
+    let rest = data.len() - BIN_HEADER_SIZE as usize;
+    // что есть кроме данных, видно только по размеру
+    let (named, with_checksum) = match rest.checked_sub(length) {
+        Some(CHECKSUM_SIZE) => (false, true),
+        Some(TAPE_NAME_SIZE) => (true, false),
+        Some(extra) if extra == TAPE_NAME_SIZE + CHECKSUM_SIZE => (true, true),
+        _ => {
+            return Err(FsError::BadTape(format!(
+                "length {} in .bin header doesn't match {} bytes after it",
+                length, rest
+            )))
+        }
+    };
+    let start = match named {
+        true => TAPE_HEADER_SIZE,
+        false => BIN_HEADER_SIZE as usize,
+    };
+    let body = &data[start..start + length];
+    Ok(TapeFile {
+        name: named
+            .then(|| tape_name(&data[BIN_HEADER_SIZE as usize..TAPE_HEADER_SIZE], encoding))
+            .flatten(),
+        start_address,
+        data: body.to_vec(),
+        checksum_mismatch: with_checksum
+            .then(|| checksum_mismatch(body, word(data, start + length)))
+            .flatten(),
+    })
+}
+
+/// Parse `.tap` file: tape records (header with name, data, checksum)
+pub fn parse_tap(data: &[u8], encoding: Encoding) -> Result<Vec<TapeFile>, FsError> {
+    let mut files = Vec::new();
+    let mut pos = 0;
+    // лента бывает дописана нулями до конца
+    while data[pos..].iter().any(|&b| b != 0) {
+        let record = &data[pos..];
+        if record.len() < TAPE_HEADER_SIZE {
+            return Err(FsError::BadTape(format!(
+                "record {} at offset {}: truncated header",
+                files.len(),
+                pos
+            )));
+        }
+        let length = word(record, 2) as usize;
+        let end = TAPE_HEADER_SIZE + length;
+        if record.len() < end + CHECKSUM_SIZE {
+            return Err(FsError::BadTape(format!(
+                "record {} at offset {}: {} bytes of data expected",
+                files.len(),
+                pos,
+                length
+            )));
+        }
+        let body = &record[TAPE_HEADER_SIZE..end];
+        let file = TapeFile {
+            name: tape_name(
+                &record[BIN_HEADER_SIZE as usize..TAPE_HEADER_SIZE],
+                encoding,
+            ),
+            start_address: word(record, 0),
+            data: body.to_vec(),
+            checksum_mismatch: checksum_mismatch(body, word(record, end)),
+        };
+        debug!(name = ?file.name, address = file.start_address, length, "Tape record");
+        files.push(file);
+        pos += end + CHECKSUM_SIZE;
+    }
+    Ok(files)
+}
+
+/// Read files of tape container `path`, format is taken from
+/// extension if not given
+pub fn read_tape(
+    path: &Path,
+    format: Option<TapeFormat>,
+    encoding: Encoding,
+) -> Result<Vec<TapeFile>, FsError> {
+    let is_wav = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+    let format = match format.or_else(|| TapeFormat::from_path(path)) {
+        Some(format) => format,
+        None if is_wav => {
+            return Err(FsError::BadTape(format!(
+                "{}: sound recordings are not supported, convert it to .tap first",
+                path.display()
+            )))
+        }
+        None => {
+            return Err(FsError::BadTape(format!(
+                "{}: unknown format, .bin or .tap expected",
+                path.display()
+            )))
+        }
+    };
+    let data = fs::read(path)?;
+    match format {
+        TapeFormat::Bin => Ok(vec![parse_bin(&data, encoding)?]),
+        TapeFormat::Tap => parse_tap(&data, encoding),
+    }
+}
+
+impl Fs {
+    /// Write tape `file` as `name` in directory `parent_inode` with its
+    /// start address. Nothing is left in catalog if the file doesn't fit
+    pub fn import_tape_file(
+        &mut self,
+        parent_inode: u64,
+        name: &str,
+        file: &TapeFile,
+    ) -> Result<DirEntry, FsError> {
+        let name = truncate_name(name, false);
+        let entry = self.create_entry(parent_inode, &name)?;
+        if let Err(e) = self.write_entry(entry.inode, 0, &file.data) {
+            self.unlink_entry(parent_inode, &name)?;
+            return Err(e);
+        }
+        self.set_start_address(entry.inode, file.start_address)
+    }
+}
@@ -0,0 +1,340 @@
+//! Teledisk (`.td0`): заголовок 12 байт, комментарий, дорожки
+//!
+//! Сигнатура `TD` - данные как есть, `td` - все после заголовка сжато
+//! LZHUF (LZSS с адаптивным Хаффманом, как в LZHUF.C Yoshizaki).
+//! Дорожка: число секторов (0xFF - конец), цилиндр, сторона, CRC.
+//! Сектор: цилиндр, сторона, номер, код размера, флаги, CRC, затем блок
+//! данных, если флаги 0x10 (не занят DOS) и 0x20 (нет данных) не стоят:
+//! длина, способ (0 - как есть, 1 - повтор двух байт, 2 - RLE) и данные.
+//! Старое сжатие Teledisk 1.x (версии до 2.0) не поддерживается.
+
+use std::borrow::Cow;
+
+use crate::{
+    container::{Bytes, SectorMap},
+    FsError,
+};
+
+const HEADER_SIZE: usize = 12;
+/// Stepping byte flag: comment block follows header
+const HAS_COMMENT: u8 = 0x80;
+/// Sector flags: no data block follows sector header
+const NO_DATA: u8 = 0x30;
+const END_OF_TRACKS: u8 = 0xff;
+
+/// `TD`/`td` and sane version (1.0 - 2.1)
+pub(crate) fn is_signature(header: &[u8]) -> bool {
+    header.len() >= HEADER_SIZE
+        && (header.starts_with(b"TD") || header.starts_with(b"td"))
+        && (10..=21).contains(&header[4])
+}
+
+fn bad(what: &str) -> FsError {
+    FsError::Container(format!("bad TD0 {}", what))
+}
+
+/// RLE: блоки `0, N, N байт` или `K, N, 2^K байт` (повторить N раз)
+fn unpack_rle(payload: &[u8], size: usize) -> Result<Vec<u8>, FsError> {
+    let mut input = Bytes::new(payload);
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        match input.byte()? {
+            0 => {
+                let len = input.byte()? as usize;
+                data.extend_from_slice(input.take(len)?);
+            }
+            kind @ 1..=7 => {
+                let count = input.byte()? as usize;
+                let pattern = input.take(1 << kind)?;
+                for _ in 0..count {
+                    data.extend_from_slice(pattern);
+                }
+            }
+            _ => return Err(bad("RLE block")),
+        }
+    }
+    Ok(data)
+}
+
+fn sector_data(block: &[u8], size: usize) -> Result<Vec<u8>, FsError> {
+    let (&method, payload) = block.split_first().ok_or_else(|| bad("data block"))?;
+    let mut data = match method {
+        0 => payload.to_vec(),
+        1 => {
+            if payload.len() < 4 {
+                return Err(bad("repeated data block"));
+            }
+            let count = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+            payload[2..4].repeat(count)
+        }
+        2 => unpack_rle(payload, size)?,
+        _ => return Err(bad("data encoding")),
+    };
+    data.resize(size, 0);
+    Ok(data)
+}
+
+pub(crate) fn decode(data: &[u8]) -> Result<Vec<u8>, FsError> {
+    if !is_signature(data) {
+        return Err(bad("header"));
+    }
+    let version = data[4];
+    let stepping = data[7];
+    let body = match &data[..2] {
+        b"td" if version < 20 => {
+            return Err(FsError::Container(
+                "TD0 compression of Teledisk 1.x is not supported".into(),
+            ))
+        }
+        b"td" => Cow::Owned(Lzhuf::new(&data[HEADER_SIZE..]).decode()),
+        _ => Cow::Borrowed(&data[HEADER_SIZE..]),
+    };
+
+    let mut input = Bytes::new(&body);
+    if stepping & HAS_COMMENT != 0 {
+        // CRC, длина, дата и время, текст
+        input.take(2)?;
+        let len = input.word()? as usize;
+        input.take(6 + len)?;
+    }
+    let mut sectors = SectorMap::default();
+    // у сжатых образов конец бывает оборван - хватит и того, что прочитано
+    while !input.is_empty() {
+        let count = input.byte()?;
+        if count == END_OF_TRACKS {
+            break;
+        }
+        // цилиндр, сторона и CRC дорожки повторяются в секторах
+        input.take(3)?;
+        for _ in 0..count {
+            let header = input.take(6)?;
+            let (cylinder, head, number, size_code, flags) =
+                (header[0], header[1], header[2], header[3], header[4]);
+            if flags & NO_DATA != 0 {
+                continue;
+            }
+            let len = input.word()? as usize;
+            let block = input.take(len)?;
+            if size_code > 6 {
+                return Err(bad("sector size"));
+            }
+            let data = sector_data(block, 128 << size_code)?;
+            // бит 7 стороны - FM запись
+            sectors.insert(cylinder, head & 0x7f, number, data);
+        }
+    }
+    sectors.into_image()
+}
+
+/// Размер окна LZSS
+const N: usize = 4096;
+/// Максимальная длина совпадения
+const F: usize = 60;
+/// Совпадения короче THRESHOLD + 1 кодируются символами
+const THRESHOLD: usize = 2;
+/// Символы: 256 байт и длины совпадений
+const N_CHAR: usize = 256 - THRESHOLD + F;
+/// Размер дерева Хаффмана
+const T: usize = N_CHAR * 2 - 1;
+/// Корень дерева
+const R: usize = T - 1;
+/// Частота корня, после которой дерево перестраивается
+const MAX_FREQ: u16 = 0x8000;
+
+/// Распаковщик LZHUF: адаптивный Хаффман для символов и длин,
+/// старшие 6 бит смещения - статическим кодом (`D_CODE`, `D_LEN`)
+struct Lzhuf<'a> {
+    input: &'a [u8],
+    /// следующий бит от начала `input`
+    bit: usize,
+    freq: [u16; T + 1],
+    /// родители узлов, `prnt[T..]` - листья (символы)
+    prnt: [usize; T + N_CHAR],
+    son: [usize; T],
+}
+
+/// Старшие 6 бит смещения по первому байту кода
+fn d_code(byte: u8) -> usize {
+    match byte {
+        0x00..=0x1f => 0,
+        0x20..=0x4f => 1 + (byte as usize - 0x20) / 16,
+        0x50..=0x8f => 4 + (byte as usize - 0x50) / 8,
+        0x90..=0xbf => 12 + (byte as usize - 0x90) / 4,
+        0xc0..=0xef => 24 + (byte as usize - 0xc0) / 2,
+        0xf0..=0xff => 48 + (byte as usize - 0xf0),
+    }
+}
+
+/// Длина кода смещения в битах по первому байту
+fn d_len(byte: u8) -> usize {
+    match byte {
+        0x00..=0x1f => 3,
+        0x20..=0x4f => 4,
+        0x50..=0x8f => 5,
+        0x90..=0xbf => 6,
+        0xc0..=0xef => 7,
+        0xf0..=0xff => 8,
+    }
+}
+
+impl<'a> Lzhuf<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        let mut lz = Self {
+            input,
+            bit: 0,
+            freq: [0; T + 1],
+            prnt: [0; T + N_CHAR],
+            son: [0; T],
+        };
+        for i in 0..N_CHAR {
+            lz.freq[i] = 1;
+            lz.son[i] = i + T;
+            lz.prnt[i + T] = i;
+        }
+        let mut i = 0;
+        for j in N_CHAR..=R {
+            lz.freq[j] = lz.freq[i] + lz.freq[i + 1];
+            lz.son[j] = i;
+            lz.prnt[i] = j;
+            lz.prnt[i + 1] = j;
+            i += 2;
+        }
+        lz.freq[T] = 0xffff;
+        lz.prnt[R] = 0;
+        lz
+    }
+
+    /// `None` - вход кончился
+    fn get_bit(&mut self) -> Option<usize> {
+        let byte = *self.input.get(self.bit / 8)?;
+        let bit = (byte >> (7 - self.bit % 8)) & 1;
+        self.bit += 1;
+        Some(bit as usize)
+    }
+
+    fn get_byte(&mut self) -> Option<u8> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.get_bit()? as u8;
+        }
+        Some(byte)
+    }
+
+    /// Перестроить дерево, когда частоты переполняются
+    fn reconstruct(&mut self) {
+        // листья в начало, частоты пополам
+        let mut j = 0;
+        for i in 0..T {
+            if self.son[i] >= T {
+                self.freq[j] = self.freq[i].div_ceil(2);
+                self.son[j] = self.son[i];
+                j += 1;
+            }
+        }
+        // узлы заново, с сортировкой по частоте
+        let mut i = 0;
+        for j in N_CHAR..T {
+            let f = self.freq[i] + self.freq[i + 1];
+            let mut k = j;
+            while k > 0 && f < self.freq[k - 1] {
+                k -= 1;
+            }
+            self.freq.copy_within(k..j, k + 1);
+            self.freq[k] = f;
+            self.son.copy_within(k..j, k + 1);
+            self.son[k] = i;
+            i += 2;
+        }
+        for i in 0..T {
+            let k = self.son[i];
+            self.prnt[k] = i;
+            if k < T {
+                self.prnt[k + 1] = i;
+            }
+        }
+    }
+
+    /// Учесть символ `c`: частоты вверх по дереву, с перестановкой узлов
+    fn update(&mut self, c: usize) {
+        if self.freq[R] == MAX_FREQ {
+            self.reconstruct();
+        }
+        let mut c = self.prnt[c + T];
+        loop {
+            self.freq[c] += 1;
+            let k = self.freq[c];
+            let mut l = c + 1;
+            if k > self.freq[l] {
+                while k > self.freq[l + 1] {
+                    l += 1;
+                }
+                self.freq[c] = self.freq[l];
+                self.freq[l] = k;
+
+                let i = self.son[c];
+                self.prnt[i] = l;
+                if i < T {
+                    self.prnt[i + 1] = l;
+                }
+                let j = self.son[l];
+                self.son[l] = i;
+                self.prnt[j] = c;
+                if j < T {
+                    self.prnt[j + 1] = c;
+                }
+                self.son[c] = j;
+                c = l;
+            }
+            c = self.prnt[c];
+            if c == 0 {
+                break;
+            }
+        }
+    }
+
+    fn decode_char(&mut self) -> Option<usize> {
+        let mut c = self.son[R];
+        while c < T {
+            c = self.son[c + self.get_bit()?];
+        }
+        c -= T;
+        self.update(c);
+        Some(c)
+    }
+
+    fn decode_position(&mut self) -> Option<usize> {
+        let byte = self.get_byte()?;
+        let high = d_code(byte) << 6;
+        let mut low = byte as usize;
+        for _ in 0..d_len(byte) - 2 {
+            low = (low << 1) | self.get_bit()?;
+        }
+        Some(high | (low & 0x3f))
+    }
+
+    /// Все, что удается распаковать до конца входа
+    fn decode(mut self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.input.len() * 2);
+        let mut text = [b' '; N];
+        let mut r = N - F;
+        while let Some(c) = self.decode_char() {
+            if c < 256 {
+                out.push(c as u8);
+                text[r] = c as u8;
+                r = (r + 1) % N;
+                continue;
+            }
+            let Some(position) = self.decode_position() else {
+                break;
+            };
+            let start = (r + N - position - 1) % N;
+            for k in 0..c - 255 + THRESHOLD {
+                let byte = text[(start + k) % N];
+                out.push(byte);
+                text[r] = byte;
+                r = (r + 1) % N;
+            }
+        }
+        out
+    }
+}
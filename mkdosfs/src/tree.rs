@@ -0,0 +1,157 @@
+//! Дерево каталогов
+//!
+//! В каталоге MK-DOS все записи лежат подряд, иерархия задается номерами:
+//! у каталога номер лежит в байте статуса, а файл (или вложенный каталог)
+//! ссылается на свой каталог через `dir_no`. В битом каталоге можно
+//! получить петлю (каталог внутри самого себя), такие петли рвем на чтении.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Seek},
+};
+
+use crate::{index::fold_name, inode::ROOT_INODE, DirEntry, Fs, LookupPolicy};
+
+/// Directory tree node, see [`Fs::tree()`]
+#[derive(Debug, Clone, Default)]
+pub struct DirTree {
+    /// `None` for root
+    pub entry: Option<DirEntry>,
+    pub inode: u64,
+    /// directories first, then files, in catalog order
+    pub children: Vec<DirTree>,
+}
+
+impl DirTree {
+    pub fn name(&self) -> &str {
+        self.entry.as_ref().map_or("", |e| e.name.as_str())
+    }
+
+    pub fn is_dir(&self) -> bool {
+        match self.entry.as_ref() {
+            Some(e) => e.is_dir,
+            None => true,
+        }
+    }
+
+    /// Nodes in depth-first order with their depth (root has depth 0)
+    pub fn walk(&self) -> Vec<(usize, &DirTree)> {
+        let mut out = Vec::new();
+        let mut stack = vec![(0, self)];
+        while let Some((depth, node)) = stack.pop() {
+            out.push((depth, node));
+            stack.extend(node.children.iter().rev().map(|c| (depth + 1, c)));
+        }
+        out
+    }
+}
+
+/// Запись участвует в иерархии (удаленные, плохие и мусор лежат в корне
+/// только для показа)
+fn in_tree(e: &DirEntry) -> bool {
+    !e.is_deleted && !e.is_bad && !e.is_garbage
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek,
+{
+    /// Hierarchy of live entries (deleted, bad and garbage are skipped)
+    pub fn tree(&self) -> DirTree {
+        let mut children: HashMap<u64, Vec<&DirEntry>> = HashMap::new();
+        for e in self.iter_all().filter(|e| in_tree(e)) {
+            children.entry(e.parent_inode).or_default().push(e);
+        }
+
+        fn build(
+            inode: u64,
+            entry: Option<&DirEntry>,
+            children: &HashMap<u64, Vec<&DirEntry>>,
+            seen: &mut HashSet<u64>,
+        ) -> DirTree {
+            let mut node = DirTree {
+                entry: entry.cloned(),
+                inode,
+                children: Vec::new(),
+            };
+            // петли порваны еще на чтении, это просто страховка
+            if !seen.insert(inode) {
+                return node;
+            }
+            if let Some(list) = children.get(&inode) {
+                let (dirs, files): (Vec<&DirEntry>, Vec<&DirEntry>) =
+                    list.iter().partition(|e| e.is_dir);
+                for e in dirs {
+                    node.children.push(build(e.inode, Some(e), children, seen));
+                }
+                for e in files {
+                    node.children.push(DirTree {
+                        entry: Some(e.clone()),
+                        inode: e.inode,
+                        children: Vec::new(),
+                    });
+                }
+            }
+            node
+        }
+
+        build(ROOT_INODE, None, &children, &mut HashSet::new())
+    }
+
+    /// Find entry by path relative to root, like `DIR/SUBDIR/FILE`,
+    /// names are matched by `lookup_policy()`
+    pub fn lookup_path(&self, path: &str) -> Option<&DirEntry> {
+        let mut parent = ROOT_INODE;
+        let mut found = None;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let in_parent = |e: &&DirEntry| e.parent_inode == parent && in_tree(e);
+            let entry = self
+                .iter_all()
+                .filter(in_parent)
+                .find(|e| e.name == name)
+                .or_else(|| match self.lookup_policy() {
+                    LookupPolicy::Exact => None,
+                    LookupPolicy::CaseInsensitive => {
+                        let folded = fold_name(name);
+                        self.iter_all()
+                            .filter(in_parent)
+                            .find(|e| fold_name(&e.name) == folded)
+                    }
+                })?;
+            parent = entry.inode;
+            found = Some(entry);
+        }
+        found
+    }
+
+    /// Рвет петли в иерархии каталогов, каталог из петли переезжает в корень.
+    /// Возвращает имена перенесенных каталогов.
+    pub(crate) fn break_dir_cycles(&mut self) -> Vec<String> {
+        let mut parents: HashMap<u64, u64> = self
+            .entries
+            .iter()
+            .filter(|e| e.is_dir && in_tree(e))
+            .map(|e| (e.inode, e.parent_inode))
+            .collect();
+
+        let mut moved = Vec::new();
+        for e in self.entries.iter_mut().filter(|e| e.is_dir && in_tree(e)) {
+            let mut cur = parents[&e.inode];
+            // по цепочке не больше, чем всего каталогов
+            for _ in 0..parents.len() {
+                if cur == e.inode {
+                    e.parent_inode = ROOT_INODE;
+                    parents.insert(e.inode, ROOT_INODE);
+                    moved.push(e.name.clone());
+                    break;
+                }
+                match parents.get(&cur) {
+                    Some(&parent) => cur = parent,
+                    // дошли до корня (или до сироты, которую уже перенесли)
+                    None => break,
+                }
+            }
+        }
+        moved
+    }
+}
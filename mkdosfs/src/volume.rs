@@ -0,0 +1,450 @@
+//! `bkfs::BkFileSystem` для MK-DOS, ANDOS и RT-11
+//!
+//! Иноды и имена отдаются как есть. У MK-DOS удаленные и bad записи
+//! видны только если их разрешено читать (`set_read_deleted`, `set_read_bad`),
+//! запись поддерживается только у MK-DOS.
+//!
+//! Удаленные файлы MK-DOS не смешиваются с живыми: они лежат в виртуальном
+//! каталоге `.deleted` в корне (`DELETED_DIR_INODE`) под уникальными
+//! именами, см. `Fs::iter_deleted()`. Менять там ничего нельзя.
+//!
+//! Поля записи каталога MK-DOS, которым нет места в `Entry`, отдаются
+//! расширенными атрибутами (`XATTR_PREFIX`), текстом:
+//! - `start_address` - адрес загрузки, восьмеричный (`001000`)
+//! - `status` - байт статуса как есть, восьмеричный (`0`, `1`, `200`, `377`)
+//! - `start_block`, `blocks` - десятичные
+
+use std::{
+    io::{Read, Seek},
+    path::Path,
+    time::SystemTime,
+};
+
+use bkfs::{BinHeader, BkFileSystem, Entry, ErrorKind, FileKind, VolumeStats};
+use tracing::info;
+
+use crate::{
+    inode::{DELETED_DIR_INODE, DELETED_DIR_NAME, ROOT_INODE},
+    AndosEntry, AndosFs, DirEntry, DirEntryOffset, Fs, FsError, Rt11Entry, Rt11Fs, BLOCK_SIZE,
+};
+
+/// Prefix of MK-DOS extended attributes
+pub const XATTR_PREFIX: &str = "user.mkdos.";
+
+impl FsError {
+    /// Format independent kind of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self.root() {
+            FsError::ReadOnly => ErrorKind::ReadOnly,
+            FsError::NotFound => ErrorKind::NotFound,
+            FsError::Exists(_) => ErrorKind::Exists,
+            FsError::NameTooLong(_) => ErrorKind::NameTooLong,
+            FsError::BadName(_) => ErrorKind::BadName,
+            FsError::CatalogFull | FsError::NoSpace => ErrorKind::NoSpace,
+            FsError::Protected => ErrorKind::Protected,
+            FsError::IsDirectory => ErrorKind::IsDirectory,
+            FsError::Unsupported(_) => ErrorKind::Unsupported,
+            FsError::Degraded(_) | FsError::NotOpen | FsError::Locked(_) => ErrorKind::Unavailable,
+            _ => ErrorKind::Io,
+        }
+    }
+}
+
+impl From<FsError> for bkfs::Error {
+    fn from(e: FsError) -> Self {
+        bkfs::Error::new(e.kind(), e)
+    }
+}
+
+fn mkdos_entry<'a>(fs: &'a Fs, e: &'a DirEntry) -> Entry<'a> {
+    Entry {
+        inode: e.inode,
+        parent_inode: e.parent_inode,
+        // у повторяющихся имен псевдонимы NAME~1, ...
+        name: fs.entry_name(e),
+        // логический диск, открытый как каталог, тоже каталог
+        kind: if e.is_dir || e.is_volume {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        },
+        size: e.size as u64,
+        blocks: e.blocks,
+        mode: e.mode,
+        // дат в MK-DOS нет
+        mtime: None,
+    }
+}
+
+/// Запись удаленного файла в `.deleted` под уникальным именем
+fn deleted_entry<'a>(fs: &'a Fs, name: &'a str, e: &'a DirEntry) -> Entry<'a> {
+    Entry {
+        name,
+        parent_inode: DELETED_DIR_INODE,
+        ..mkdos_entry(fs, e)
+    }
+}
+
+const DELETED_DIR: Entry<'static> = Entry {
+    inode: DELETED_DIR_INODE,
+    parent_inode: ROOT_INODE,
+    name: DELETED_DIR_NAME,
+    kind: FileKind::Directory,
+    size: 0,
+    blocks: 0,
+    mode: 0o555,
+    mtime: None,
+};
+
+impl Fs {
+    /// Скрытые записи не показываем вовсе, удаленные - только в `.deleted`
+    fn is_visible(&self, e: &DirEntry) -> bool {
+        !e.is_deleted && (!e.is_bad || self.read_bad)
+    }
+
+    /// В `.deleted` ничего не создаем и не переименовываем
+    fn check_writable_dir(&self, parent_inode: u64) -> bkfs::Result<()> {
+        if parent_inode == DELETED_DIR_INODE {
+            return Err(ErrorKind::Protected.into());
+        }
+        Ok(())
+    }
+}
+
+impl BkFileSystem for Fs {
+    fn lookup(&self, parent_inode: u64, name: &str) -> Option<Entry<'_>> {
+        match parent_inode {
+            ROOT_INODE if self.read_deleted && name == DELETED_DIR_NAME => Some(DELETED_DIR),
+            DELETED_DIR_INODE if self.read_deleted => self
+                .find_deleted(name)
+                .map(|(name, e)| deleted_entry(self, name, e)),
+            _ => self
+                .find_entrie(name, parent_inode)
+                .filter(|e| self.is_visible(e))
+                .map(|e| mkdos_entry(self, e)),
+        }
+    }
+
+    fn metadata(&self, inode: u64) -> Option<Entry<'_>> {
+        if !self.read_deleted {
+            return self
+                .entrie_by_inode(inode)
+                .filter(|e| self.is_visible(e))
+                .map(|e| mkdos_entry(self, e));
+        }
+        if inode == DELETED_DIR_INODE {
+            return Some(DELETED_DIR);
+        }
+        match self.deleted_by_inode(inode) {
+            Some((name, e)) => Some(deleted_entry(self, name, e)),
+            None => self
+                .entrie_by_inode(inode)
+                .filter(|e| self.is_visible(e))
+                .map(|e| mkdos_entry(self, e)),
+        }
+    }
+
+    fn list(&self, inode: u64) -> bkfs::Result<Vec<Entry<'_>>> {
+        if inode != ROOT_INODE && !self.metadata(inode).ok_or(FsError::NotFound)?.is_dir() {
+            return Err(ErrorKind::NotDirectory.into());
+        }
+        if inode == DELETED_DIR_INODE {
+            return Ok(self
+                .iter_deleted()
+                .map(|(name, e)| deleted_entry(self, name, e))
+                .collect());
+        }
+        let mut entries: Vec<_> = self
+            .iter_dir(inode)
+            .filter(|e| self.is_visible(e))
+            .map(|e| mkdos_entry(self, e))
+            .collect();
+        if inode == ROOT_INODE && self.read_deleted {
+            entries.push(DELETED_DIR);
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, inode: u64, offset: u64, size: usize) -> bkfs::Result<Vec<u8>> {
+        Ok(self.read_file(inode, offset, size)?)
+    }
+
+    fn read_into(&self, inode: u64, offset: u64, buf: &mut [u8]) -> bkfs::Result<usize> {
+        Ok(self.read_file_into(inode, offset, buf)?)
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        Some(self.disk_size() * BLOCK_SIZE as u64)
+    }
+
+    fn read_raw(&self, offset: u64, size: usize) -> bkfs::Result<Vec<u8>> {
+        // размер тома из мета блока, образ за ним не отдаем
+        let end = std::cmp::min(
+            offset.saturating_add(size as u64),
+            self.disk_size() * BLOCK_SIZE as u64,
+        );
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let mut buf = vec![0u8; (end - offset) as usize];
+        let size = self
+            .read_exact_at(&mut buf, offset)
+            .map_err(FsError::from)?;
+        buf.truncate(size);
+        Ok(buf)
+    }
+
+    fn snapshot(&self, dest: &Path) -> bkfs::Result<u64> {
+        Ok(Fs::snapshot(self, dest)?)
+    }
+
+    fn stats(&self) -> VolumeStats {
+        let stat = self.statvfs();
+        VolumeStats {
+            blocks: stat.blocks,
+            free: stat.free_blocks,
+            files: stat.files,
+            free_files: stat.free_files,
+            namelen: stat.namelen,
+        }
+    }
+
+    fn last_modified(&self) -> SystemTime {
+        Fs::last_modified(self)
+    }
+
+    fn block_size(&self) -> u32 {
+        BLOCK_SIZE as u32
+    }
+
+    fn xattrs(&self, inode: u64) -> Vec<(String, Vec<u8>)> {
+        let e = match self
+            .entrie_by_inode(inode)
+            .filter(|e| self.is_visible(e) || (e.is_deleted && self.read_deleted))
+        {
+            Some(e) => e,
+            None => return Vec::new(),
+        };
+        [
+            ("start_address", format!("{:06o}", e.start_address)),
+            (
+                "status",
+                format!("{:o}", e.raw[DirEntryOffset::Status as usize]),
+            ),
+            ("start_block", e.start_block.to_string()),
+            ("blocks", e.blocks.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (format!("{}{}", XATTR_PREFIX, name), value.into_bytes()))
+        .collect()
+    }
+
+    fn bin_header(&self, inode: u64) -> Option<BinHeader> {
+        self.entrie_by_inode(inode)
+            .filter(|e| self.is_visible(e) || (e.is_deleted && self.read_deleted))
+            .filter(|e| !e.is_dir && !e.is_volume)
+            .map(|e| BinHeader {
+                start_address: e.start_address as u16,
+                length: e.length as u16,
+            })
+    }
+
+    fn is_modified(&self) -> bool {
+        Fs::is_modified(self)
+    }
+
+    fn refresh(&mut self) -> bkfs::Result<()> {
+        Fs::refresh(self)?;
+        Ok(())
+    }
+
+    fn is_degraded(&self) -> bool {
+        Fs::is_degraded(self)
+    }
+
+    fn generation(&self) -> u64 {
+        Fs::generation(self)
+    }
+
+    fn is_read_only(&self) -> bool {
+        Fs::is_read_only(self)
+    }
+
+    fn create(&mut self, parent_inode: u64, name: &str) -> bkfs::Result<u64> {
+        self.check_writable_dir(parent_inode)?;
+        Ok(self.create_entry(parent_inode, name)?.inode)
+    }
+
+    fn write(&mut self, inode: u64, offset: u64, data: &[u8]) -> bkfs::Result<usize> {
+        Ok(self.write_entry(inode, offset, data)?)
+    }
+
+    fn truncate(&mut self, inode: u64, size: u64) -> bkfs::Result<()> {
+        Ok(self.truncate_entry(inode, size)?)
+    }
+
+    fn unlink(&mut self, parent_inode: u64, name: &str) -> bkfs::Result<()> {
+        self.check_writable_dir(parent_inode)?;
+        // по имени в каталоге, оно может быть псевдонимом
+        let inode = self
+            .find_entrie(name, parent_inode)
+            .filter(|e| !e.is_deleted)
+            .ok_or(FsError::NotFound)?
+            .inode;
+        Ok(self.unlink_inode(inode)?)
+    }
+
+    fn rename(
+        &mut self,
+        parent_inode: u64,
+        name: &str,
+        new_name: &str,
+        replace: bool,
+    ) -> bkfs::Result<()> {
+        self.check_writable_dir(parent_inode)?;
+        let inode = match self.find_entrie(name, parent_inode) {
+            Some(entry) if !entry.is_deleted => entry.inode,
+            _ => return Err(ErrorKind::NotFound.into()),
+        };
+        match self.rename_entry(inode, new_name) {
+            // как rename(2): существующий файл заменяется
+            Err(FsError::Exists(target)) if replace => {
+                self.unlink_entry(parent_inode, &target)?;
+                self.rename_entry(inode, new_name)?;
+            }
+            result => {
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    fn strip_bin_header(&mut self, inode: u64) -> bkfs::Result<Option<BinHeader>> {
+        Ok(Fs::strip_bin_header(self, inode)?)
+    }
+
+    fn flush(&mut self) -> bkfs::Result<()> {
+        Ok(Fs::flush(self)?)
+    }
+
+    fn destroy(&mut self) {
+        info!(stats = ?self.cache_stats(), "Block cache");
+    }
+}
+
+fn andos_entry<'a, R>(fs: &AndosFs<R>, e: &'a AndosEntry) -> Entry<'a> {
+    Entry {
+        inode: e.inode,
+        parent_inode: e.parent_inode,
+        name: &e.name,
+        kind: if e.is_dir {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        },
+        size: e.size as u64,
+        blocks: e.blocks(fs.bpb()),
+        mode: e.mode,
+        mtime: None,
+    }
+}
+
+impl<R> BkFileSystem for AndosFs<R>
+where
+    R: Read + Seek,
+{
+    fn lookup(&self, parent_inode: u64, name: &str) -> Option<Entry<'_>> {
+        self.find_entrie(name, parent_inode)
+            .map(|e| andos_entry(self, e))
+    }
+
+    fn metadata(&self, inode: u64) -> Option<Entry<'_>> {
+        self.entrie_by_inode(inode).map(|e| andos_entry(self, e))
+    }
+
+    fn list(&self, inode: u64) -> bkfs::Result<Vec<Entry<'_>>> {
+        if inode != ROOT_INODE && !self.entrie_by_inode(inode).ok_or(FsError::NotFound)?.is_dir {
+            return Err(ErrorKind::NotDirectory.into());
+        }
+        Ok(self.iter_dir(inode).map(|e| andos_entry(self, e)).collect())
+    }
+
+    fn read(&self, inode: u64, offset: u64, size: usize) -> bkfs::Result<Vec<u8>> {
+        Ok(self.read_file(inode, offset, size)?)
+    }
+
+    fn stats(&self) -> VolumeStats {
+        VolumeStats {
+            blocks: self.disk_size(),
+            free: self.free_blocks(),
+            files: self.files(),
+            free_files: 0,
+            // 8.3
+            namelen: 12,
+        }
+    }
+
+    fn last_modified(&self) -> SystemTime {
+        AndosFs::last_modified(self)
+    }
+}
+
+fn rt11_entry(e: &Rt11Entry) -> Entry<'_> {
+    // даты RT-11 без времени, берем полночь UTC
+    let mtime = e
+        .date
+        .map(|d| d.midnight().assume_utc().unix_timestamp())
+        .and_then(|secs| u64::try_from(secs).ok())
+        .map(|secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+    Entry {
+        inode: e.inode,
+        parent_inode: e.parent_inode,
+        name: &e.name,
+        kind: FileKind::File,
+        size: e.size(),
+        blocks: e.blocks,
+        mode: e.mode,
+        mtime,
+    }
+}
+
+impl<R> BkFileSystem for Rt11Fs<R>
+where
+    R: Read + Seek,
+{
+    fn lookup(&self, parent_inode: u64, name: &str) -> Option<Entry<'_>> {
+        self.find_entrie(name, parent_inode).map(rt11_entry)
+    }
+
+    fn metadata(&self, inode: u64) -> Option<Entry<'_>> {
+        self.entrie_by_inode(inode).map(rt11_entry)
+    }
+
+    fn list(&self, inode: u64) -> bkfs::Result<Vec<Entry<'_>>> {
+        // каталогов в RT-11 нет
+        if inode != ROOT_INODE {
+            self.entrie_by_inode(inode).ok_or(FsError::NotFound)?;
+            return Err(ErrorKind::NotDirectory.into());
+        }
+        Ok(self.iter_dir(inode).map(rt11_entry).collect())
+    }
+
+    fn read(&self, inode: u64, offset: u64, size: usize) -> bkfs::Result<Vec<u8>> {
+        Ok(self.read_file(inode, offset, size)?)
+    }
+
+    fn stats(&self) -> VolumeStats {
+        VolumeStats {
+            blocks: self.disk_size(),
+            free: self.free_blocks(),
+            files: self.files(),
+            free_files: 0,
+            // 6.3
+            namelen: 10,
+        }
+    }
+
+    fn last_modified(&self) -> SystemTime {
+        Rt11Fs::last_modified(self)
+    }
+}
@@ -0,0 +1,83 @@
+//! Слежение за образом через notify (inotify, kqueue, FSEvents)
+//!
+//! Вместо опроса mtime раз в check_interval поток notify выставляет флаг,
+//! что образ трогали, и только тогда `is_modified()` смотрит на файл по
+//! пути образа: mtime, инод и размер (свою же запись отличаем по ним же,
+//! см. `refresh_modified()`). Флаг сбрасывается при этой проверке.
+//! Следим за каталогом образа, а не за самим файлом: образ часто пишут
+//! во временный файл и переименовывают поверх старого, открытый дескриптор
+//! при этом смотрит на старый файл.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::warn;
+
+use crate::{Fs, FsError};
+
+pub(crate) struct ImageWatcher {
+    /// поток notify живет, пока жив watcher
+    _watcher: RecommendedWatcher,
+    changed: Arc<AtomicBool>,
+}
+
+impl ImageWatcher {
+    fn new(path: &Path) -> Result<Self, FsError> {
+        let image = path.canonicalize()?;
+        let dir = image.parent().unwrap_or(&image).to_path_buf();
+        let changed = Arc::new(AtomicBool::new(false));
+
+        let flag = Arc::clone(&changed);
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            match res {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        return;
+                    }
+                    if event.paths.contains(&image) {
+                        flag.store(true, Ordering::Release);
+                    }
+                }
+                // что-то потеряли, пусть лучше проверят mtime
+                Err(e) => {
+                    warn!("Image watcher error: {}", e);
+                    flag.store(true, Ordering::Release);
+                }
+            }
+        })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            changed,
+        })
+    }
+
+    /// Образ трогали с прошлой проверки, флаг сбрасывается
+    pub(crate) fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::AcqRel)
+    }
+
+    pub(crate) fn clear(&self) {
+        self.changed.store(false, Ordering::Release);
+    }
+}
+
+impl Fs {
+    /// Watch image file for changes instead of polling its mtime
+    pub fn watch(&mut self) -> Result<(), FsError> {
+        self.watcher = Some(ImageWatcher::new(Path::new(&self.file_path))?);
+        Ok(())
+    }
+
+    /// Stop watching, fall back to mtime polling
+    pub fn unwatch(&mut self) {
+        self.watcher = None;
+    }
+}
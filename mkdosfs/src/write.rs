@@ -0,0 +1,1050 @@
+//! Запись в образ: форматирование, создание, запись, усечение,
+//! переименование, удаление файлов и сжатие (SQUEEZE)
+//!
+//! Файлы в MK-DOS лежат непрерывно, порядок записей в каталоге совпадает
+//! с порядком на диске, дыры от удаленных файлов - это записи со статусом
+//! 0377. Свободное место - все что после последней записи.
+
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{Read, Seek, Write},
+    ops::Range,
+    path::Path,
+};
+
+use bkfs::{BinHeader, BIN_HEADER_SIZE};
+use tracing::{debug, warn};
+
+use crate::{
+    inode::ROOT_INODE, progress::Operation, DirEntry, DirEntryOffset, DirEntryStatus, Encoding, Fs,
+    FsError, Meta, MetaOffset, ParseMode, Progress, BLOCK_SIZE, DIR_ENTRY_SIZE, DIR_MARKER,
+    FILE_NAME_SIZE,
+};
+
+/// Адрес загрузки по умолчанию для новых файлов
+pub const DEFAULT_START_ADDRESS: u32 = 0o1000;
+
+/// Дискета 80 дорожек, 2 стороны (800K)
+pub const DISK_800K_BLOCKS: u16 = 1600;
+/// Дискета 40 дорожек, 2 стороны или 80 дорожек, 1 сторона (400K)
+pub const DISK_400K_BLOCKS: u16 = 800;
+/// Первый блок файлов, который по умолчанию ставит INIT
+pub const DEFAULT_START_BLOCK: u16 = 20;
+
+impl Meta {
+    /// Мета блок чистого диска: метки, размер диска и пустые счетчики
+    fn formatted(disk_size: u16, start_block: u16) -> Self {
+        let mut meta = Self {
+            disk_size,
+            start_block,
+            // счетчик блоков в MK-DOS включает системную область
+            blocks: start_block,
+            ..Default::default()
+        };
+        let words = [
+            (MetaOffset::Files, meta.files),
+            (MetaOffset::Blocks, meta.blocks),
+            (MetaOffset::MicrodosLabel, meta.microdos_label),
+            (MetaOffset::MkdosLabel, meta.mkdos_label),
+            (MetaOffset::DiskSize, meta.disk_size),
+            (MetaOffset::StartBlock, meta.start_block),
+        ];
+        for (off, word) in words {
+            let off = off as usize;
+            meta.raw[off..off + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        meta
+    }
+}
+
+/// Кодирует имя в `encoding`, дополняя пробелами до 14 символов
+/// (у каталога первый символ - DIR_MARKER). Пробелы в начале и в конце
+/// имени - `BadName`
+pub fn encode_name(
+    name: &str,
+    is_dir: bool,
+    encoding: Encoding,
+) -> Result<[u8; FILE_NAME_SIZE], FsError> {
+    // хвостовые пробелы при чтении не видно, "A " совпало бы с "A"
+    if name.is_empty() || name.starts_with(' ') || name.ends_with(' ') {
+        return Err(FsError::BadName(name.into()));
+    }
+    let bytes = match encoding.encode(name) {
+        Some(bytes) if !bytes.contains(&0) => bytes,
+        _ => return Err(FsError::BadName(name.into())),
+    };
+    let off = if is_dir { 1 } else { 0 };
+    if bytes.len() + off > FILE_NAME_SIZE {
+        return Err(FsError::NameTooLong(name.into()));
+    }
+    let mut raw = [b' '; FILE_NAME_SIZE];
+    if is_dir {
+        raw[0] = DIR_MARKER;
+    }
+    raw[off..off + bytes.len()].copy_from_slice(&bytes);
+
+    Ok(raw)
+}
+
+/// Обрезает имя до того, что влезет в запись: 14 символов, у каталога 13
+/// (первый байт занят DIR_MARKER). Все кодировки однобайтовые, так что символ = байт.
+/// Хвостовые пробелы тоже убираем, при чтении их все равно не видно.
+pub fn truncate_name(name: &str, is_dir: bool) -> String {
+    let max = if is_dir {
+        FILE_NAME_SIZE - 1
+    } else {
+        FILE_NAME_SIZE
+    };
+    let name: String = name.chars().take(max).collect();
+    name.trim_end().into()
+}
+
+/// Name for host file system (no '/' and trailing spaces)
+pub fn host_name(name: &str) -> String {
+    let name = name.trim_end().replace('/', "_");
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name,
+    }
+}
+
+/// Сколько блоков копируем за раз при сжатии
+const SQUEEZE_CHUNK_BLOCKS: u64 = 64;
+
+fn blocks_for(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE as u64)
+}
+
+impl DirEntry {
+    /// Переносит поля записи обратно в raw (имя не трогаем)
+    pub(crate) fn sync_raw(&mut self) {
+        // у каталога в байте статуса лежит его номер,
+        // у записи с неизвестным статусом оставляем как было
+        if !(self.is_unknown || (self.is_dir && !self.is_deleted)) {
+            self.raw[DirEntryOffset::Status as usize] = u8::from(self.status);
+        }
+        self.raw[DirEntryOffset::DirNo as usize] = self.dir_no;
+        let words = [
+            (DirEntryOffset::StartBlock, self.start_block as u16),
+            (DirEntryOffset::Blocks, self.blocks as u16),
+            (DirEntryOffset::StartAddress, self.start_address as u16),
+            (DirEntryOffset::Length, self.length as u16),
+        ];
+        for (off, word) in words {
+            let off = off as usize;
+            self.raw[off..off + 2].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    pub(crate) fn set_raw_name(&mut self, raw_name: &[u8; FILE_NAME_SIZE]) {
+        let off = DirEntryOffset::Name as usize;
+        self.raw[off..off + FILE_NAME_SIZE].copy_from_slice(raw_name);
+    }
+
+    /// Номер каталога (для корня 0), на который ссылаются файлы через dir_no
+    pub fn dir_number(&self) -> Option<u8> {
+        if self.is_dir {
+            Some(self.raw[DirEntryOffset::Status as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Запись занимает место на диске (не каталог и не мусор)
+    pub(crate) fn occupies_disk(&self) -> bool {
+        !self.is_dir && !self.is_garbage
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.size = size as u32;
+        self.length = (size & 0xffff) as u32;
+    }
+}
+
+impl Fs {
+    /// Create new empty MK-DOS image at `path`, like MK-DOS INIT does
+    ///
+    /// Image is `disk_size_blocks` blocks long (see [`DISK_800K_BLOCKS`],
+    /// [`DISK_400K_BLOCKS`]), files start from `start_block`
+    /// (usually [`DEFAULT_START_BLOCK`]). Existing file is overwritten.
+    pub fn format<P: AsRef<Path>>(
+        path: P,
+        disk_size_blocks: u16,
+        start_block: u16,
+    ) -> Result<(), FsError> {
+        Self::format_with_progress(path, disk_size_blocks, start_block, &|_| {})
+    }
+
+    /// `format()` reporting blocks of new image to `progress`
+    pub fn format_with_progress<P: AsRef<Path>>(
+        path: P,
+        disk_size_blocks: u16,
+        start_block: u16,
+        progress: &dyn Fn(Progress),
+    ) -> Result<(), FsError> {
+        if start_block < DEFAULT_START_BLOCK {
+            return Err(FsError::StrangeStartBlock(start_block));
+        }
+        if start_block >= disk_size_blocks {
+            return Err(FsError::BadGeometry {
+                disk_size: disk_size_blocks as u64,
+                start_block: start_block as u64,
+            });
+        }
+        let meta = Meta::formatted(disk_size_blocks, start_block);
+        let total = disk_size_blocks as u64;
+        progress(Progress::new(Operation::Format, 0, total));
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // каталог и данные - нули, set_len их и дает
+        file.set_len(disk_size_blocks as u64 * BLOCK_SIZE as u64)?;
+        file.write_all(&meta.raw)?;
+        file.sync_all()?;
+        progress(Progress::new(Operation::Format, total, total));
+
+        Ok(())
+    }
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek,
+{
+    /// Set the fs's read only mode (used on next open).
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if let Some(reason) = self.degraded.as_ref() {
+            return Err(FsError::Degraded(reason.clone()));
+        }
+        // в каталоге с мусорными записями писать нельзя, потеряем данные
+        if self.parse_mode == ParseMode::Forensic || self.scan_full_catalog {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Первый блок свободной области (за последней записью на диске)
+    pub fn free_start_block(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter(|e| e.occupies_disk())
+            .map(|e| e.start_block + e.blocks)
+            .max()
+            .unwrap_or(0)
+            .max(self.meta.start_block as u64)
+    }
+
+    /// Свободные блоки в конце диска (дыры от удаленных файлов не считаются)
+    pub fn free_blocks(&self) -> u64 {
+        (self.meta.disk_size as u64).saturating_sub(self.free_start_block())
+    }
+
+    /// Free space map: blocks of data area not covered by any catalog entry,
+    /// deleted ones included (their blocks can be undeleted)
+    pub fn unreferenced_extents(&self) -> Vec<Range<u64>> {
+        let data = self.layout().data_area();
+        let mut used: Vec<Range<u64>> = self
+            .entries
+            .iter()
+            .filter(|e| e.occupies_disk() && e.blocks > 0)
+            .map(|e| e.start_block..e.start_block + e.blocks)
+            .collect();
+        used.sort_by_key(|extent| extent.start);
+        let mut free = Vec::new();
+        let mut pos = data.start;
+        // записи могут пересекаться и вылезать за диск, берем только промежутки
+        for extent in used {
+            if extent.start > pos {
+                free.push(pos..std::cmp::min(extent.start, data.end));
+            }
+            pos = pos.max(extent.end);
+            if pos >= data.end {
+                break;
+            }
+        }
+        if pos < data.end {
+            free.push(pos..data.end);
+        }
+        free.retain(|extent| !extent.is_empty());
+        free
+    }
+
+    /// Сколько записей влезает в каталог
+    pub fn catalog_capacity(&self) -> usize {
+        (self.meta.start_block as usize * BLOCK_SIZE)
+            .saturating_sub(MetaOffset::DirEntriesStart as usize)
+            / DIR_ENTRY_SIZE
+    }
+
+    fn check_catalog_space(&self, new_entries: usize) -> Result<(), FsError> {
+        if self.entries.len() + new_entries > self.catalog_capacity() {
+            return Err(FsError::CatalogFull);
+        }
+        Ok(())
+    }
+
+    /// Счетчики файлов и занятых блоков мета блока по каталогу
+    pub(crate) fn catalog_counters(&self) -> (u16, u16) {
+        let mut files = 0u16;
+        let mut blocks = self.meta.start_block;
+        for e in self.entries.iter().filter(|e| !e.is_garbage) {
+            // счетчики мета блока 16-битные и переполняются так же
+            if e.is_dir && !e.is_deleted {
+                files = files.wrapping_add(1);
+            } else if e.is_normal || e.is_protected || e.is_logical {
+                files = files.wrapping_add(1);
+                blocks = blocks.wrapping_add(e.blocks as u16);
+            }
+        }
+        (files, blocks)
+    }
+
+    /// Запись (или каталог) внутри открытого логического диска
+    fn is_nested(&self, inode: u64) -> bool {
+        self.nested.iter().any(|e| e.inode == inode)
+            || self.entries.iter().any(|e| e.is_volume && e.inode == inode)
+    }
+
+    fn entry_index(&self, inode: u64) -> Result<usize, FsError> {
+        if self.is_nested(inode) {
+            return Err(FsError::ReadOnly);
+        }
+        self.entries
+            .iter()
+            .position(|e| e.inode == inode)
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Живая (не удаленная) запись с именем `name` в каталоге `parent_inode`
+    fn find_live_entry(&self, name: &str, parent_inode: u64) -> Option<&DirEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.parent_inode == parent_inode && e.name == name && !e.is_deleted)
+    }
+
+    /// Номер каталога MK-DOS для инода каталога
+    fn dir_no_of(&self, parent_inode: u64) -> Result<u8, FsError> {
+        if parent_inode == ROOT_INODE {
+            return Ok(0);
+        }
+        if self.is_nested(parent_inode) {
+            return Err(FsError::ReadOnly);
+        }
+        self.entries
+            .iter()
+            .find(|e| e.inode == parent_inode && !e.is_deleted)
+            .and_then(|e| e.dir_number())
+            .ok_or(FsError::NotFound)
+    }
+}
+
+impl<R> Fs<R>
+where
+    R: Read + Seek + Write,
+{
+    pub(crate) fn write_image_at(&self, buf: &[u8], offset: u64) -> Result<(), FsError> {
+        if let Some(reader) = self.reader.as_ref() {
+            reader.write_all_at(buf, self.offset + offset)?;
+            self.cache().invalidate(offset, buf.len());
+            Ok(())
+        } else {
+            Err(FsError::NotOpen)
+        }
+    }
+
+    /// Write raw blocks from block `start`, `data` must be whole blocks.
+    /// Catalog is not reread: after writing the system area call `try_reopen()`
+    pub fn write_blocks(&mut self, start: u64, data: &[u8]) -> Result<(), FsError> {
+        self.check_writable()?;
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(FsError::CustomIo {
+                desc: format!("{} bytes is not a whole number of blocks", data.len()),
+                source: std::io::ErrorKind::InvalidInput.into(),
+            });
+        }
+        self.check_blocks(start, (data.len() / BLOCK_SIZE) as u64)?;
+        self.write_image_at(data, start * BLOCK_SIZE as u64)
+    }
+
+    /// Пересчитывает счетчики мета блока и пишет их в образ
+    pub(crate) fn write_meta(&mut self) -> Result<(), FsError> {
+        let (files, blocks) = self.catalog_counters();
+        self.meta.files = files;
+        self.meta.blocks = blocks;
+        let off = MetaOffset::Files as usize;
+        self.meta.raw[off..off + 2].copy_from_slice(&files.to_le_bytes());
+        let off = MetaOffset::Blocks as usize;
+        self.meta.raw[off..off + 2].copy_from_slice(&blocks.to_le_bytes());
+        let off = MetaOffset::Files as usize;
+        self.write_image_at(&self.meta.raw[off..off + 4], off as u64)
+    }
+
+    /// Пишет весь каталог (и завершающую пустую запись, если есть место)
+    fn write_catalog(&mut self) -> Result<(), FsError> {
+        let capacity = self.catalog_capacity();
+        let mut buf = Vec::with_capacity((self.entries.len() + 1) * DIR_ENTRY_SIZE);
+        for e in self.entries.iter_mut() {
+            e.sync_raw();
+            buf.extend_from_slice(&e.raw);
+        }
+        if self.entries.len() < capacity {
+            buf.extend_from_slice(&[0u8; DIR_ENTRY_SIZE]);
+        }
+        self.write_image_at(&buf, MetaOffset::DirEntriesStart as u64)?;
+        self.write_meta()
+    }
+
+    /// Запоминаем свое же время изменения, чтобы check_modified() не
+    /// перечитывал образ после нашей записи
+    pub(crate) fn refresh_modified(&mut self) {
+        // свой дескриптор: если образ подменили, путь покажет другой инод
+        if let Some(stamp) = self.reader.as_ref().and_then(|r| r.stamp()) {
+            self.last_modified = stamp.modified;
+            self.stamp = Some(stamp);
+        }
+    }
+
+    fn commit(&mut self) -> Result<(), FsError> {
+        // индексы - по памяти, даже если запись на диск не удастся
+        self.rebuild_index();
+        self.write_catalog()?;
+        self.refresh_modified();
+        Ok(())
+    }
+
+    /// Create new empty file `name` in directory `parent_inode`
+    pub fn create_entry(&mut self, parent_inode: u64, name: &str) -> Result<DirEntry, FsError> {
+        self.check_writable()?;
+        if self.find_live_entry(name, parent_inode).is_some() {
+            return Err(FsError::Exists(name.into()));
+        }
+        let dir_no = self.dir_no_of(parent_inode)?;
+        let raw_name = encode_name(name, false, self.encoding)?;
+        self.check_catalog_space(1)?;
+
+        let mut entry = DirEntry {
+            status: DirEntryStatus::Normal,
+            dir_no,
+            name: name.into(),
+            start_block: self.free_start_block(),
+            start_address: DEFAULT_START_ADDRESS,
+            inode: self.inodes.alloc_file(),
+            parent_inode,
+            is_normal: true,
+            mode: 0o644,
+            ..Default::default()
+        };
+        entry.set_raw_name(&raw_name);
+        entry.sync_raw();
+        debug!(parent: &self._tracing_span, ?entry, "Create");
+        self.entries.push(entry.clone());
+        self.commit()?;
+
+        Ok(entry)
+    }
+
+    /// Create new empty directory `name` in directory `parent_inode`
+    pub fn create_dir(&mut self, parent_inode: u64, name: &str) -> Result<DirEntry, FsError> {
+        self.check_writable()?;
+        if self.find_live_entry(name, parent_inode).is_some() {
+            return Err(FsError::Exists(name.into()));
+        }
+        let dir_no = self.dir_no_of(parent_inode)?;
+        let raw_name = encode_name(name, true, self.encoding)?;
+        self.check_catalog_space(1)?;
+        // номер каталога лежит в байте статуса: 0 - корень, 0377 - удаленный
+        let used: HashSet<u8> = self
+            .entries
+            .iter()
+            .filter(|e| !e.is_deleted)
+            .filter_map(|e| e.dir_number())
+            .collect();
+        let number = (1..0o377u8)
+            .find(|n| !used.contains(n))
+            .ok_or(FsError::CatalogFull)?;
+        let (inode, _) = self.inodes.alloc_dir(number).ok_or(FsError::CatalogFull)?;
+
+        let mut entry = DirEntry {
+            status: DirEntryStatus::Directory,
+            dir_no,
+            name: name.into(),
+            start_block: self.free_start_block(),
+            inode,
+            parent_inode,
+            is_dir: true,
+            mode: 0o755,
+            ..Default::default()
+        };
+        entry.set_raw_name(&raw_name);
+        entry.raw[DirEntryOffset::Status as usize] = number;
+        entry.sync_raw();
+        debug!(parent: &self._tracing_span, ?entry, "Create directory");
+        self.entries.push(entry.clone());
+        self.commit()?;
+
+        Ok(entry)
+    }
+
+    /// Write `data` at `offset` of file `inode`, file grows if needed
+    pub fn write_entry(&mut self, inode: u64, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        let idx = self.entry_index(inode)?;
+        let entry = &self.entries[idx];
+        if entry.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        if entry.is_protected {
+            return Err(FsError::Protected);
+        }
+        let old_size = entry.size as u64;
+        let end = offset + data.len() as u64;
+        let size = std::cmp::max(old_size, end);
+        let idx = self.resize_entry(idx, size)?;
+        self.rebuild_index();
+        let start = self.entries[idx].start_block * BLOCK_SIZE as u64;
+        if offset > old_size {
+            // дыра до offset - нули, а не старые данные удаленных файлов
+            let zeros = vec![0u8; (offset - old_size) as usize];
+            self.write_image_at(&zeros, start + old_size)?;
+        }
+        self.write_image_at(data, start + offset)?;
+        self.commit()?;
+
+        Ok(data.len())
+    }
+
+    /// Set size of file `inode` (grow or shrink)
+    pub fn truncate_entry(&mut self, inode: u64, size: u64) -> Result<(), FsError> {
+        self.check_writable()?;
+        let idx = self.entry_index(inode)?;
+        let entry = &self.entries[idx];
+        if entry.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        if entry.is_protected {
+            return Err(FsError::Protected);
+        }
+        let old_size = entry.size as u64;
+        let idx = self.resize_entry(idx, size)?;
+        self.rebuild_index();
+        if size > old_size {
+            // новые байты должны быть нулями
+            let start = self.entries[idx].start_block * BLOCK_SIZE as u64;
+            let zeros = vec![0u8; (size - old_size) as usize];
+            self.write_image_at(&zeros, start + old_size)?;
+        }
+        self.commit()
+    }
+
+    /// Rename file or directory `inode` in place
+    ///
+    /// Name longer than 14 characters (13 for directory) is truncated,
+    /// returns updated entry. Fails with `Exists` if truncated name is taken.
+    pub fn rename_entry(&mut self, inode: u64, new_name: &str) -> Result<DirEntry, FsError> {
+        self.check_writable()?;
+        let idx = self.entry_index(inode)?;
+        let entry = &self.entries[idx];
+        if entry.is_deleted {
+            return Err(FsError::NotFound);
+        }
+        if entry.is_protected {
+            return Err(FsError::Protected);
+        }
+        let name = truncate_name(new_name, entry.is_dir);
+        let raw_name = encode_name(&name, entry.is_dir, self.encoding)?;
+        if let Some(other) = self.find_live_entry(&name, entry.parent_inode) {
+            if other.inode != inode {
+                return Err(FsError::Exists(name));
+            }
+        }
+
+        debug!(parent: &self._tracing_span, from = ?entry.name, to = ?name, "Rename");
+        let entry = &mut self.entries[idx];
+        entry.name = name;
+        entry.set_raw_name(&raw_name);
+        let entry = entry.clone();
+        self.commit()?;
+
+        Ok(entry)
+    }
+
+    /// Strip BK `.bin` header from file `inode` if it has one (length in
+    /// header matches the rest of file): load address goes to catalog like
+    /// MK-DOS does on load, data is moved to the file start
+    pub fn strip_bin_header(&mut self, inode: u64) -> Result<Option<BinHeader>, FsError> {
+        self.check_writable()?;
+        let entry = self.entrie_by_inode(inode).ok_or(FsError::NotFound)?;
+        if entry.is_dir || entry.is_volume || entry.is_deleted {
+            return Ok(None);
+        }
+        let data = self.read_entry_data(entry)?;
+        let header = match BinHeader::parse(&data) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        debug!(parent: &self._tracing_span, ?header, "Strip .bin header");
+        let body = &data[BIN_HEADER_SIZE as usize..];
+        self.write_entry(inode, 0, body)?;
+        self.truncate_entry(inode, body.len() as u64)?;
+        self.set_start_address(inode, header.start_address)?;
+
+        Ok(Some(header))
+    }
+
+    /// Set load address of file `inode` (kept in catalog as 16 bit word)
+    pub fn set_start_address(&mut self, inode: u64, address: u16) -> Result<DirEntry, FsError> {
+        self.check_writable()?;
+        let idx = self.entry_index(inode)?;
+        let entry = &mut self.entries[idx];
+        if entry.is_dir || entry.is_deleted {
+            return Err(FsError::NotFound);
+        }
+        if entry.is_protected {
+            return Err(FsError::Protected);
+        }
+        entry.start_address = address as u32;
+        let entry = entry.clone();
+        self.commit()?;
+
+        Ok(entry)
+    }
+
+    /// Set status of file `inode`: normal, protected or logical disk
+    pub fn set_status(&mut self, inode: u64, status: DirEntryStatus) -> Result<DirEntry, FsError> {
+        self.check_writable()?;
+        let idx = self.entry_index(inode)?;
+        let entry = &mut self.entries[idx];
+        if entry.is_dir || entry.is_deleted {
+            return Err(FsError::NotFound);
+        }
+        let protected = match status {
+            DirEntryStatus::Normal | DirEntryStatus::LogicalDisk => false,
+            DirEntryStatus::Protected => true,
+            status => return Err(FsError::BadStatus(status)),
+        };
+        entry.status = status;
+        entry.is_unknown = false;
+        entry.is_normal = matches!(status, DirEntryStatus::Normal);
+        entry.is_protected = protected;
+        entry.is_logical = matches!(status, DirEntryStatus::LogicalDisk);
+        // как на чтении: защищенный - sticky и без записи
+        entry.mode = if protected {
+            (entry.mode & !0o222) | 0o1000
+        } else {
+            (entry.mode & !0o1000) | 0o200
+        };
+        let entry = entry.clone();
+        self.commit()?;
+
+        Ok(entry)
+    }
+
+    /// Mark file `name` in directory `parent_inode` as deleted
+    pub fn unlink_entry(&mut self, parent_inode: u64, name: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        if self.is_nested(parent_inode) {
+            return Err(FsError::ReadOnly);
+        }
+        let inode = self
+            .find_live_entry(name, parent_inode)
+            .ok_or(FsError::NotFound)?
+            .inode;
+        self.unlink_inode(inode)
+    }
+
+    /// Delete file `inode` (one of files with the same name too)
+    pub fn unlink_inode(&mut self, inode: u64) -> Result<(), FsError> {
+        self.check_writable()?;
+        let idx = self.entry_index(inode)?;
+        let entry = &mut self.entries[idx];
+        if entry.is_deleted {
+            return Err(FsError::NotFound);
+        }
+        if entry.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        if entry.is_protected {
+            return Err(FsError::Protected);
+        }
+        // как в MK-DOS: запись остается дыркой со статусом 0377
+        entry.status = DirEntryStatus::Deleted;
+        entry.is_normal = false;
+        entry.is_logical = false;
+        entry.is_bad = false;
+        entry.is_deleted = true;
+        entry.parent_inode = ROOT_INODE;
+        self.trim_trailing_holes();
+        self.commit()
+    }
+
+    /// Restore deleted file `inode`, returns restored entry
+    ///
+    /// File goes back to its directory (to the root if the directory is gone).
+    /// Fails with `EntriesOverlap` if its blocks are taken by a live file
+    /// and with `Exists` if the name is taken.
+    pub fn undelete(&mut self, inode: u64) -> Result<DirEntry, FsError> {
+        self.check_writable()?;
+        let idx = self.entry_index(inode)?;
+        let entry = &self.entries[idx];
+        if !entry.is_deleted {
+            return Err(FsError::NotFound);
+        }
+        if entry.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        let extent = self.check_entry_extent(entry)?;
+        // блоки дырки могли уже отдать другому файлу
+        let taken = self.entries.iter().any(|e| {
+            e.occupies_disk()
+                && !e.is_deleted
+                && e.start_block < extent.end
+                && extent.start < e.start_block + e.extent_blocks()
+        });
+        if !extent.is_empty() && taken {
+            return Err(FsError::EntriesOverlap(entry.name.clone()));
+        }
+        let (dir_no, parent_inode) = match self
+            .entries
+            .iter()
+            .find(|e| e.is_dir && !e.is_deleted && e.dir_number() == Some(entry.dir_no))
+        {
+            Some(dir) if entry.dir_no != 0 => (entry.dir_no, dir.inode),
+            _ => (0, ROOT_INODE),
+        };
+        if self.find_live_entry(&entry.name, parent_inode).is_some() {
+            return Err(FsError::Exists(entry.name.clone()));
+        }
+
+        debug!(parent: &self._tracing_span, name = ?entry.name, "Undelete");
+        let entry = &mut self.entries[idx];
+        entry.status = DirEntryStatus::Normal;
+        entry.is_deleted = false;
+        entry.is_normal = true;
+        entry.dir_no = dir_no;
+        entry.parent_inode = parent_inode;
+        let entry = entry.clone();
+        self.commit()?;
+
+        Ok(entry)
+    }
+
+    /// Sync image to disk
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        if let Some(reader) = self.reader.as_ref() {
+            reader.sync_data()?;
+        }
+        self.refresh_modified();
+        Ok(())
+    }
+
+    /// Удаленные записи в конце каталога не нужны, за ними и так свободное место
+    fn trim_trailing_holes(&mut self) {
+        while let Some(last) = self.entries.last() {
+            if last.is_deleted && !last.is_dir {
+                self.entries.pop();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Дыра (удаленная запись) на месте блоков файла `from`, имя как у него
+    fn hole_entry(&mut self, from: DirEntry, start_block: u64, blocks: u64) -> DirEntry {
+        let mut hole = DirEntry {
+            status: DirEntryStatus::Deleted,
+            dir_no: from.dir_no,
+            name: from.name,
+            start_block,
+            blocks,
+            start_address: from.start_address,
+            length: ((blocks * BLOCK_SIZE as u64) & 0xffff) as u32,
+            size: (blocks * BLOCK_SIZE as u64) as u32,
+            inode: self.inodes.alloc_file(),
+            parent_inode: ROOT_INODE,
+            is_deleted: true,
+            raw: from.raw,
+            ..Default::default()
+        };
+        hole.sync_raw();
+        hole
+    }
+
+    /// Меняет размер файла, при необходимости забирая соседнюю дыру или
+    /// перенося файл в конец диска. Возвращает новый индекс записи.
+    pub(crate) fn resize_entry(&mut self, idx: usize, size: u64) -> Result<usize, FsError> {
+        // куда растет или сжимается битая запись, непонятно
+        if self.entries[idx].is_corrupt {
+            return Err(FsError::CorruptEntry(self.entries[idx].name.clone()));
+        }
+        let need = blocks_for(size);
+        let (start, blocks) = (self.entries[idx].start_block, self.entries[idx].blocks);
+        let disk_size = self.meta.disk_size as u64;
+
+        if need <= blocks {
+            let tail = blocks - need;
+            let is_last = start + blocks >= self.free_start_block();
+            if tail != 0 && (is_last || self.check_catalog_space(1).is_ok()) {
+                self.entries[idx].blocks = need;
+                if !is_last {
+                    // хвост превращаем в дыру сразу за файлом
+                    let hole = self.hole_entry(self.entries[idx].clone(), start + need, tail);
+                    self.entries.insert(idx + 1, hole);
+                }
+            }
+            self.entries[idx].set_size(size);
+            return Ok(idx);
+        }
+
+        let new_end = start + need;
+        // кто мешает расти на месте (пустые файлы не мешают)
+        let blockers: Vec<usize> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|&(i, e)| {
+                i != idx
+                    && e.occupies_disk()
+                    && e.blocks != 0
+                    && e.start_block < new_end
+                    && e.start_block + e.blocks > start + blocks
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        let in_place = match blockers.as_slice() {
+            [] => new_end <= disk_size,
+            &[h] => {
+                let hole = &self.entries[h];
+                hole.is_deleted
+                    && hole.start_block == start + blocks
+                    && hole.blocks >= need - blocks
+            }
+            _ => false,
+        };
+
+        if in_place {
+            let mut idx = idx;
+            if let [h] = blockers.as_slice() {
+                let h = *h;
+                let extra = need - blocks;
+                self.entries[h].start_block += extra;
+                self.entries[h].blocks -= extra;
+                if self.entries[h].blocks == 0 {
+                    self.entries.remove(h);
+                    if h < idx {
+                        idx -= 1;
+                    }
+                }
+            }
+            // пустые файлы, попавшие в новый диапазон, сдвигаем за него
+            for (i, e) in self.entries.iter_mut().enumerate() {
+                if i != idx
+                    && e.occupies_disk()
+                    && e.blocks == 0
+                    && e.start_block >= start
+                    && e.start_block < new_end
+                {
+                    e.start_block = new_end;
+                }
+            }
+            self.entries[idx].blocks = need;
+            self.entries[idx].set_size(size);
+            return Ok(idx);
+        }
+
+        // переносим файл в конец диска
+        let new_start = self.free_start_block();
+        if new_start + need > disk_size {
+            return Err(FsError::NoSpace);
+        }
+        if blocks != 0 {
+            self.check_catalog_space(1)?;
+            let old_size = std::cmp::min(self.entries[idx].size as u64, blocks * BLOCK_SIZE as u64);
+            let mut buf = vec![0u8; old_size as usize];
+            let _size = self.read_exact_at(&mut buf, start * BLOCK_SIZE as u64)?;
+            self.write_image_at(&buf, new_start * BLOCK_SIZE as u64)?;
+        }
+        warn!(
+            parent: &self._tracing_span,
+            "Moving {:?} from block {} to {}", self.entries[idx].name, start, new_start
+        );
+        let mut entry = self.entries.remove(idx);
+        if blocks != 0 {
+            let hole = self.hole_entry(entry.clone(), start, blocks);
+            self.entries.insert(idx, hole);
+        }
+        entry.start_block = new_start;
+        entry.blocks = need;
+        entry.set_size(size);
+        self.entries.push(entry);
+
+        Ok(self.entries.len() - 1)
+    }
+
+    /// Move file data down to close holes left by deleted entries,
+    /// like MK-DOS SQUEEZE does
+    ///
+    /// Deleted entries are removed from catalog, start blocks of files are
+    /// rewritten and meta counters updated. Bad files (bad block areas) stay
+    /// where they are. Returns number of reclaimed blocks.
+    pub fn squeeze(&mut self) -> Result<u64, FsError> {
+        self.check_writable()?;
+        let free_before = self.free_blocks();
+        let old_len = self.entries.len();
+
+        // сначала только планируем: куда переедет каждый файл
+        let mut next = self.meta.start_block as u64;
+        let mut plan = Vec::with_capacity(old_len);
+        // файлы только съезжают вниз, поэтому до плохой области
+        // они доезжают, только если и раньше в нее не залезали
+        for e in self.entries.clone() {
+            if e.is_deleted {
+                continue;
+            }
+            if !e.occupies_disk() {
+                let start = e.start_block;
+                plan.push((e, start));
+                continue;
+            }
+            self.check_entry_extent(&e)?;
+            if e.start_block < next {
+                return Err(FsError::EntriesOverlap(e.name.clone()));
+            }
+            if e.is_bad {
+                // плохие блоки не двигаем, перед ними остается дыра
+                if e.start_block > next {
+                    let hole = self.hole_entry(e.clone(), next, e.start_block - next);
+                    let start = hole.start_block;
+                    plan.push((hole, start));
+                }
+                next = e.start_block + e.blocks;
+                let start = e.start_block;
+                plan.push((e, start));
+                continue;
+            }
+            let blocks = e.blocks;
+            plan.push((e, next));
+            next += blocks;
+        }
+
+        let mut entries = Vec::with_capacity(plan.len());
+        let total = plan.len() as u64;
+        for (n, (mut e, to)) in plan.into_iter().enumerate() {
+            if e.occupies_disk() && !e.is_bad && to != e.start_block {
+                debug!(
+                    parent: &self._tracing_span,
+                    "Squeeze {:?} from block {} to {}", e.name, e.start_block, to
+                );
+                self.move_blocks(e.start_block, to, e.blocks)?;
+                e.start_block = to;
+            }
+            self.report_progress(
+                Progress::new(Operation::Squeeze, n as u64 + 1, total).with_item(&e.name),
+            );
+            entries.push(e);
+        }
+        self.entries = entries;
+        self.trim_trailing_holes();
+        // логические диски могли переехать, их каталоги надо перечитать
+        self.nested.clear();
+        self.entries.iter_mut().for_each(|e| e.is_volume = false);
+        self.commit()?;
+
+        // старые записи за концом каталога затираем, чтобы не всплывали
+        let new_len = self.entries.len();
+        let capacity = self.catalog_capacity();
+        if old_len > new_len && new_len < capacity {
+            let from = MetaOffset::DirEntriesStart as usize + (new_len + 1) * DIR_ENTRY_SIZE;
+            let count = std::cmp::min(old_len, capacity) - new_len - 1;
+            if count > 0 {
+                self.write_image_at(&vec![0u8; count * DIR_ENTRY_SIZE], from as u64)?;
+                self.refresh_modified();
+            }
+        }
+
+        Ok(self.free_blocks().saturating_sub(free_before))
+    }
+
+    /// Копирует `blocks` блоков вниз с `from` на `to` (to < from),
+    /// по порядку от начала, поэтому пересечение областей не страшно
+    fn move_blocks(&self, from: u64, to: u64, blocks: u64) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < blocks {
+            let n = std::cmp::min(SQUEEZE_CHUNK_BLOCKS, blocks - done);
+            let mut buf = vec![0u8; (n * BLOCK_SIZE as u64) as usize];
+            self.read_exact_at(&mut buf, (from + done) * BLOCK_SIZE as u64)?;
+            self.write_image_at(&buf, (to + done) * BLOCK_SIZE as u64)?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{inode::ROOT_INODE, io::MemImage, META_SIZE};
+
+    /// Чистый образ 800K в памяти, открытый на запись
+    fn formatted() -> Fs<MemImage> {
+        let mut data = vec![0u8; DISK_800K_BLOCKS as usize * BLOCK_SIZE];
+        let meta = Meta::formatted(DISK_800K_BLOCKS, DEFAULT_START_BLOCK);
+        data[..META_SIZE].copy_from_slice(&meta.raw);
+        let mut fs = Fs::from_bytes(data).unwrap();
+        fs.set_read_only(false);
+        fs
+    }
+
+    #[test]
+    fn write_past_eof_zero_fills() {
+        let mut fs = formatted();
+        let old = fs.create_entry(ROOT_INODE, "OLD").unwrap();
+        fs.write_entry(old.inode, 0, &[0xaa; 2048]).unwrap();
+        fs.unlink_inode(old.inode).unwrap();
+
+        // новый файл ложится на блоки удаленного
+        let new = fs.create_entry(ROOT_INODE, "NEW").unwrap();
+        assert_eq!(new.start_block, old.start_block);
+        fs.write_entry(new.inode, 0, b"head").unwrap();
+        fs.write_entry(new.inode, 1500, b"tail").unwrap();
+
+        let data = fs.read_file(new.inode, 0, 4096).unwrap();
+        assert_eq!(data.len(), 1504);
+        assert_eq!(&data[..4], b"head");
+        assert!(data[4..1500].iter().all(|&b| b == 0));
+        assert_eq!(&data[1500..], b"tail");
+    }
+
+    #[test]
+    fn trailing_space_name() {
+        let mut fs = formatted();
+        fs.create_entry(ROOT_INODE, "A").unwrap();
+        assert!(matches!(
+            fs.create_entry(ROOT_INODE, "A "),
+            Err(FsError::BadName(_))
+        ));
+        assert!(matches!(
+            fs.create_dir(ROOT_INODE, "A "),
+            Err(FsError::BadName(_))
+        ));
+        assert_eq!(fs.iter_all().filter(|e| e.name == "A").count(), 1);
+    }
+}